@@ -0,0 +1,110 @@
+//! Receives the UDP stream (see `wrover::udp`), putting frames back together.
+//!
+//! receiver [--listen <addr:port>] [--join <multicast group>] [--out <folder>] [--stdout]
+//!          [--max-frame <bytes>]
+//!
+//! Listens on `0.0.0.0:5000` by default. `--join 239.1.2.3` joins a multicast group first.
+//! `--out frames` saves every frame as `frames/<id>.jpg`, `--stdout` writes them back to back
+//! for piping into a player (`receiver --stdout | ffplay -f mjpeg -`). Frame and loss counts
+//! go to stderr every second either way. Frames over `--max-frame` bytes (512 KiB unless
+//! told otherwise) are dropped and counted as invalid.
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use wrover::udp::{Reassembler, ReceiveStats, DEFAULT_PORT, MAX_FRAME};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut listen = format!("0.0.0.0:{}", DEFAULT_PORT);
+    let mut join = None;
+    let mut out = None;
+    let mut stdout = false;
+    let mut max_frame = MAX_FRAME;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--listen" => listen = value()?,
+            "--join" => join = Some(value()?.parse::<Ipv4Addr>()?),
+            "--out" => out = Some(PathBuf::from(value()?)),
+            "--stdout" => stdout = true,
+            "--max-frame" => max_frame = value()?.parse()?,
+            _ => anyhow::bail!(
+                "Usage: receiver [--listen <addr:port>] [--join <multicast group>] \
+                 [--out <folder>] [--stdout] [--max-frame <bytes>]"
+            ),
+        }
+    }
+
+    let socket = UdpSocket::bind(&listen)?;
+    if let Some(group) = join {
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    socket.set_read_timeout(Some(REPORT_INTERVAL))?;
+    if let Some(dir) = &out {
+        fs::create_dir_all(dir)?;
+    }
+    eprintln!("Listening on {}", socket.local_addr()?);
+
+    let mut reassembler = Reassembler::default().with_max_frame(max_frame);
+    let mut buf = vec![0; 65536];
+    let mut last = (Instant::now(), ReceiveStats::default());
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(len) => {
+                if let Some(frame) = reassembler.push(&buf[..len]) {
+                    if let Some(dir) = &out {
+                        fs::write(dir.join(format!("{}.jpg", frame.id)), &frame.data)?;
+                    }
+                    if stdout {
+                        let mut stdout = io::stdout().lock();
+                        stdout.write_all(&frame.data)?;
+                        stdout.flush()?;
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if last.0.elapsed() >= REPORT_INTERVAL {
+            let stats = reassembler.stats();
+            report(&stats, &last.1, last.0.elapsed());
+            last = (Instant::now(), stats);
+        }
+    }
+}
+
+fn report(now: &ReceiveStats, before: &ReceiveStats, elapsed: Duration) {
+    let frames = now.frames - before.frames;
+    let lost = now.lost - before.lost;
+    let loss = match frames + lost {
+        0 => 0.0,
+        total => 100.0 * lost as f64 / total as f64,
+    };
+    eprintln!(
+        "{:.1} fps, {} lost ({:.1}%), {} late, {} invalid; {} frames and {} lost in total",
+        frames as f64 / elapsed.as_secs_f64(),
+        lost,
+        loss,
+        now.late - before.late,
+        now.invalid - before.invalid,
+        now.frames,
+        now.lost,
+    );
+}
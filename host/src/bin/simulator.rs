@@ -0,0 +1,253 @@
+//! The streaming server with a fake camera, for running on a laptop or in CI.
+//!
+//! simulator [--dir <folder of .jpg>] [--fps <n>] [--port <n>] [--label <camera name>]
+//!           [--ntp <server>] [--tz <POSIX TZ>] [--timelapse <schedule>] [--save <folder>]
+//!           [--motion <sensitivity>] [--settings <file>] [--mqtt <url>] [--webhook <url>]
+//!           [--upload <url>] [--rtsp <port>] [--udp <host:port>]
+//!
+//! Without `--dir` it serves a generated test pattern. Without `--ntp` frames use the PC's
+//! own clock. `--timelapse` starts a time-lapse right away, saving shots under `--save`
+//! (default `timelapse/`). `--motion` turns on motion detection, with a snapshot saved
+//! under `motion/` whenever motion starts and a clip from 5 s before it under `events/`. `--settings` loads and saves settings changed over
+//! HTTP (such as masks) in a JSON file. `--mqtt mqtt://localhost:1883` connects to a broker
+//! as `wrover-simulator`, publishing under `wrover/wrover-simulator`. `--webhook <url>` POSTs a
+//! snapshot there at startup and whenever motion starts (plain `http://` only). `--upload <url>`
+//! PUTs time-lapse shots and motion snapshots under that URL too, spooling them under `spool/`
+//! while it can't be reached. `--rtsp 8554` serves the stream at `rtsp://localhost:8554/stream`
+//! as well. `--udp 127.0.0.1:5000` sends fragmented frames there, for the `receiver` binary.
+//! `/ws` always streams frames over a WebSocket.
+
+use std::time::Duration;
+
+use wrover::camera::{Camera, FrameBuffer, FrameSize, PlaybackCamera, TestPattern};
+use wrover::http::App;
+use wrover::motion::{
+    ClipOnMotion, Motion, MotionConfig, MotionDetector, PostOnMotion, PublishOnMotion,
+    SnapshotOnMotion,
+};
+use wrover::mqtt::MqttConfig;
+use wrover::overlay::{Overlay, OverlayConfig};
+use wrover::prebuffer::{AviDirSink, EventBuffer, Prebuffer, PrebufferConfig};
+use wrover::rtsp::{Rtsp, RtspConfig};
+use wrover::settings::{
+    FileSettings, MqttSettings, PrebufferSettings, RtspSettings, UdpSettings, UploadSettings,
+    WebSocketSettings, WebhookSettings,
+};
+use wrover::sink::{DirSink, FrameSink};
+use wrover::sntp;
+use wrover::timelapse::{Schedule, Timelapse, TimelapseConfig, Window};
+use wrover::udp::{UdpConfig, UdpStream};
+use wrover::upload::{Spool, UploadConfig, UploadSink, Uploader};
+use wrover::webhook::{Webhook, WebhookConfig};
+use wrover::ws::{WsConfig, WsStream};
+use wrover_host::client::TcpHttpClient;
+use wrover_host::server::HostServer;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut dir = None;
+    let mut fps = 10.0;
+    let mut port = 8080;
+    let mut label = None;
+    let mut ntp = None;
+    let mut tz = None;
+    let mut timelapse = None;
+    let mut save = "timelapse".to_owned();
+    let mut motion = None;
+    let mut settings = None;
+    let mut mqtt_url = None;
+    let mut webhook_url = None;
+    let mut upload_url = None;
+    let mut rtsp_port = None;
+    let mut udp_target = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--dir" => dir = Some(value()?),
+            "--fps" => fps = value()?.parse()?,
+            "--port" => port = value()?.parse()?,
+            "--label" => label = Some(value()?),
+            "--pattern" => dir = None,
+            "--ntp" => ntp = Some(value()?),
+            "--tz" => tz = Some(value()?),
+            "--timelapse" => timelapse = Some(value()?),
+            "--save" => save = value()?,
+            "--motion" => motion = Some(value()?.parse()?),
+            "--settings" => settings = Some(value()?),
+            "--mqtt" => mqtt_url = Some(value()?),
+            "--webhook" => webhook_url = Some(value()?),
+            "--upload" => upload_url = Some(value()?),
+            "--rtsp" => rtsp_port = Some(value()?.parse()?),
+            "--udp" => udp_target = Some(value()?),
+            _ => anyhow::bail!(
+                "Usage: simulator [--dir <folder>] [--fps <n>] [--port <n>] [--label <name>] \
+                 [--ntp <server>] [--tz <TZ>] [--timelapse <schedule>] [--save <folder>] \
+                 [--motion <sensitivity>] [--settings <file>] [--mqtt <url>] \
+                 [--webhook <url>] [--upload <url>] [--rtsp <port>] [--udp <host:port>]"
+            ),
+        }
+    }
+
+    if let Some(tz) = tz {
+        sntp::apply_timezone(&tz)?;
+    }
+    if let Some(server) = ntp {
+        // Not fatal, the PC clock is a fine fallback
+        let _ = sntp::sync(&[server]);
+    }
+
+    let camera: Box<dyn Camera> = match dir {
+        Some(dir) => Box::new(PlaybackCamera::new(dir, fps)?),
+        None => Box::new(TestPattern::new(FrameSize::Vga, fps)),
+    };
+
+    let app = App::new(camera);
+    if let Some(path) = settings {
+        let store = FileSettings::new(path);
+        app.set_settings(store.load()?, Some(Box::new(store)));
+    }
+    if let Some(name) = label {
+        let overlay = OverlayConfig::from_settings(name, &app.settings().overlay);
+        app.set_overlay(Some(Overlay::new(overlay)));
+    }
+
+    let uploader = match upload_url {
+        Some(url) => {
+            let settings = UploadSettings {
+                url,
+                ..Default::default()
+            };
+            let config = UploadConfig::from_settings(&settings, "wrover-simulator")?;
+            Some(Uploader::spawn(
+                std::sync::Arc::downgrade(&app),
+                config,
+                TcpHttpClient::new(Duration::from_secs(10)),
+                Some(Spool::new("spool")),
+            ))
+        }
+        None => None,
+    };
+
+    if let Some(schedule) = timelapse {
+        let config = TimelapseConfig {
+            schedule: Schedule::parse(&schedule)?,
+            window: Window::Always,
+            shot_controls: Vec::new(),
+            description: (schedule, String::new()),
+        };
+        let mut dir = DirSink::new(save);
+        let mut upload = uploader.clone().map(UploadSink);
+        let sink = move |frame: &FrameBuffer| {
+            dir.store(frame)?;
+            match &mut upload {
+                Some(upload) => upload.store(frame),
+                None => Ok(()),
+            }
+        };
+        app.set_timelapse(Some(Timelapse::spawn(
+            std::sync::Arc::downgrade(&app),
+            config,
+            sink,
+            true,
+        )));
+    }
+
+    let mqtt = match mqtt_url {
+        Some(url) => {
+            let settings = MqttSettings {
+                url,
+                ..Default::default()
+            };
+            let config = MqttConfig::from_settings(&settings, "wrover-simulator");
+            Some(wrover_host::mqtt::start(
+                std::sync::Arc::downgrade(&app),
+                config,
+            )?)
+        }
+        None => None,
+    };
+
+    let webhook = match webhook_url {
+        Some(url) => {
+            let settings = WebhookSettings {
+                url,
+                on_boot: true,
+                ..Default::default()
+            };
+            let config = WebhookConfig::from_settings(&settings, "wrover-simulator")?;
+            Some(Webhook::spawn(
+                std::sync::Arc::downgrade(&app),
+                config,
+                TcpHttpClient::new(Duration::from_secs(10)),
+            ))
+        }
+        None => None,
+    };
+    app.set_webhook(webhook.clone());
+
+    if let Some(sensitivity) = motion {
+        let config = MotionConfig {
+            sensitivity,
+            ..Default::default()
+        };
+        let motion = Motion::spawn(
+            std::sync::Arc::downgrade(&app),
+            MotionDetector::new(config)?,
+            2.0,
+            true,
+        );
+        motion.add_handler(SnapshotOnMotion(DirSink::new("motion")));
+        let events = EventBuffer::spawn(
+            std::sync::Arc::downgrade(&app),
+            Prebuffer::new(PrebufferConfig::from_settings(&PrebufferSettings::default())),
+            5.0,
+            AviDirSink::new("events", 5),
+        );
+        motion.add_handler(ClipOnMotion(events));
+        if let Some(mqtt) = &mqtt {
+            motion.add_handler(PublishOnMotion(mqtt.clone()));
+        }
+        if let Some(webhook) = &webhook {
+            motion.add_handler(PostOnMotion(webhook.clone()));
+        }
+        if let Some(uploader) = &uploader {
+            motion.add_handler(SnapshotOnMotion(UploadSink(uploader.clone())));
+        }
+        app.set_motion(Some(motion));
+    }
+
+    if let Some(port) = rtsp_port {
+        let config = RtspConfig {
+            port,
+            ..RtspConfig::from_settings(&RtspSettings::default())
+        };
+        let rtsp = Rtsp::spawn(std::sync::Arc::downgrade(&app), config)?;
+        println!("RTSP at rtsp://{}/stream", rtsp.local_addr());
+    }
+
+    if let Some(target) = udp_target {
+        let config = UdpConfig::from_settings(&UdpSettings {
+            target,
+            ..Default::default()
+        })?;
+        UdpStream::spawn(std::sync::Arc::downgrade(&app), config)?;
+    }
+
+    let config = WsConfig::from_settings(&WebSocketSettings::default());
+    app.set_websocket(Some(WsStream::spawn(
+        std::sync::Arc::downgrade(&app),
+        config,
+    )));
+
+    let server = HostServer::bind(("0.0.0.0", port), app)?;
+    println!("Server ready! Visit http://{}", server.local_addr());
+    server.run();
+
+    Ok(())
+}
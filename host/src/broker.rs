@@ -0,0 +1,216 @@
+//! Stand-in MQTT broker, so the MQTT code can be tested without installing Mosquitto.
+//!
+//! Enough of MQTT 3.1.1 for one process talking to itself: subscriptions with `+` and `#`,
+//! retained messages and last wills. Everything is delivered at QoS 0, and sessions are
+//! always clean.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use wrover::mqtt::QoS;
+
+use crate::mqtt::{
+    get_bytes, get_str, get_u16, Packet, CONNACK, CONNECT, DISCONNECT, PINGREQ, PINGRESP, PUBACK,
+    PUBLISH, SUBACK, SUBSCRIBE,
+};
+
+struct Session {
+    id: u64,
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: Vec<Session>,
+    retained: BTreeMap<String, Vec<u8>>,
+    next_id: u64,
+}
+
+impl State {
+    fn route(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if retain {
+            if payload.is_empty() {
+                self.retained.remove(topic);
+            } else {
+                self.retained.insert(topic.to_owned(), payload.to_vec());
+            }
+        }
+        let packet = Packet::publish(topic, QoS::AtMostOnce, false, 0, payload).to_bytes();
+        for session in &mut self.sessions {
+            if session.filters.iter().any(|f| matches(f, topic)) {
+                let _ = io::Write::write_all(&mut session.stream, &packet);
+            }
+        }
+    }
+}
+
+/// Whether `topic` matches the subscription `filter`
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+pub struct MqttBroker {
+    listener: TcpListener,
+    state: Arc<Mutex<State>>,
+}
+
+impl MqttBroker {
+    pub fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            state: Arc::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Runs on a background thread
+    pub fn spawn(self) -> SocketAddr {
+        let addr = self.local_addr();
+        thread::spawn(move || self.run());
+        addr
+    }
+
+    /// Accepts clients forever
+    pub fn run(self) {
+        for stream in self.listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let state = self.state.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(stream, &state) {
+                    log::debug!("MQTT client dropped: {}", e);
+                }
+            });
+        }
+    }
+}
+
+struct Will {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+fn serve(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let connect = Packet::read(&mut stream)?;
+    if connect.kind != CONNECT {
+        return Ok(());
+    }
+    let will = parse_will(&connect.body)?;
+    Packet::new(CONNACK, 0, vec![0, 0]).write(&mut stream)?;
+
+    let id = {
+        let mut state = state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.sessions.push(Session {
+            id,
+            stream: stream.try_clone()?,
+            filters: Vec::new(),
+        });
+        id
+    };
+
+    let result = session(&mut stream, id, state);
+
+    let mut state = state.lock().unwrap();
+    state.sessions.retain(|session| session.id != id);
+    // A clean DISCONNECT comes back as Ok(true), anything else sends the will
+    if !matches!(result, Ok(true)) {
+        if let Some(will) = will {
+            state.route(&will.topic, &will.payload, will.retain);
+        }
+    }
+    result.map(|_| ())
+}
+
+/// Handles packets until the client goes. `true` if it said goodbye.
+fn session(stream: &mut TcpStream, id: u64, state: &Mutex<State>) -> io::Result<bool> {
+    loop {
+        let packet = match Packet::read(stream) {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        match packet.kind {
+            PUBLISH => {
+                let (topic, qos, retain, packet_id, payload) = packet.parse_publish()?;
+                if qos == QoS::AtLeastOnce {
+                    let mut state = state.lock().unwrap();
+                    let session = state.sessions.iter_mut().find(|s| s.id == id).unwrap();
+                    Packet::new(PUBACK, 0, packet_id.to_be_bytes().to_vec())
+                        .write(&mut session.stream)?;
+                }
+                state.lock().unwrap().route(&topic, payload, retain);
+            }
+            SUBSCRIBE => {
+                let mut at = 0;
+                let packet_id = get_u16(&packet.body, &mut at)?;
+                let mut filters = Vec::new();
+                while at < packet.body.len() {
+                    filters.push(get_str(&packet.body, &mut at)?);
+                    at += 1; // requested QoS
+                }
+
+                let mut state = state.lock().unwrap();
+                let retained: Vec<_> = state
+                    .retained
+                    .iter()
+                    .filter(|(topic, _)| filters.iter().any(|f| matches(f, topic)))
+                    .map(|(topic, payload)| {
+                        Packet::publish(topic, QoS::AtMostOnce, true, 0, payload)
+                    })
+                    .collect();
+                let session = state.sessions.iter_mut().find(|s| s.id == id).unwrap();
+                let mut ack = packet_id.to_be_bytes().to_vec();
+                ack.extend(filters.iter().map(|_| 0));
+                Packet::new(SUBACK, 0, ack).write(&mut session.stream)?;
+                for message in retained {
+                    message.write(&mut session.stream)?;
+                }
+                session.filters.extend(filters);
+            }
+            PINGREQ => {
+                let mut state = state.lock().unwrap();
+                let session = state.sessions.iter_mut().find(|s| s.id == id).unwrap();
+                Packet::new(PINGRESP, 0, Vec::new()).write(&mut session.stream)?;
+            }
+            DISCONNECT => return Ok(true),
+            _ => {}
+        }
+    }
+}
+
+fn parse_will(body: &[u8]) -> io::Result<Option<Will>> {
+    let mut at = 0;
+    get_str(body, &mut at)?; // "MQTT"
+    at += 1; // protocol level
+    let flags = *body.get(at).ok_or(io::ErrorKind::UnexpectedEof)?;
+    at += 3; // flags, keep-alive
+    get_str(body, &mut at)?; // client id
+    if flags & 0x04 == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Will {
+        topic: get_str(body, &mut at)?,
+        payload: get_bytes(body, &mut at)?,
+        retain: flags & 0x20 != 0,
+    }))
+}
@@ -0,0 +1,140 @@
+//! Stand-in for whatever receives webhooks or uploads: keeps every request and answers
+//! with a status picked by the test.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub method: String,
+    /// With the query, if any
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Received {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    received: Vec<Received>,
+    /// Statuses for the next requests, 200 once they run out
+    replies: VecDeque<u16>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    arrived: Condvar,
+}
+
+pub struct Catcher {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl Catcher {
+    /// Listens on a background thread
+    pub fn spawn(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::<Shared>::default();
+        let state = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = state.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &state) {
+                        log::debug!("Catcher connection dropped: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, shared })
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Answers the next requests with these, in order
+    pub fn reply_with(&self, statuses: &[u16]) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.replies.extend(statuses);
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.shared.state.lock().unwrap().received.clone()
+    }
+
+    /// Everything received, once there are at least `count` requests. Panics after `timeout`.
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Received> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        while state.received.len() < count {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                panic!("Got {} requests, expected {}", state.received.len(), count);
+            }
+            state = self.shared.arrived.wait_timeout(state, left).unwrap().0;
+        }
+        state.received.clone()
+    }
+}
+
+fn serve(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(());
+    };
+
+    let mut headers = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let status = {
+        let mut state = shared.state.lock().unwrap();
+        state.received.push(Received {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers,
+            body,
+        });
+        shared.arrived.notify_all();
+        state.replies.pop_front().unwrap_or(200)
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
@@ -0,0 +1,121 @@
+//! Plain HTTP/1.1 client on `std::net`, standing in for the ESP-IDF one. No HTTPS.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use wrover::client::{is_success, HttpClient, Request};
+use wrover::ota::{Fetch, Receive};
+
+pub struct TcpHttpClient {
+    timeout: Duration,
+}
+
+impl TcpHttpClient {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+/// Host, port and path of an `http://` URL
+pub fn split_url(url: &str) -> anyhow::Result<(String, u16, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow::anyhow!("Only http:// URLs work on the host, got {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Bad port in URL {}", url))?;
+            Ok((host.to_owned(), port, path.to_owned()))
+        }
+        None => Ok((authority.to_owned(), 80, path.to_owned())),
+    }
+}
+
+impl TcpHttpClient {
+    fn connect(&self, host: &str, port: u16) -> anyhow::Result<TcpStream> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Can't resolve {}", host))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+}
+
+fn parse_status(status_line: &str) -> anyhow::Result<u16> {
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Bad status line: {:?}", status_line.trim()))
+}
+
+impl HttpClient for TcpHttpClient {
+    fn send(&mut self, request: &Request) -> anyhow::Result<u16> {
+        let (host, port, path) = split_url(&request.url)?;
+        let mut stream = self.connect(&host, port)?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            request.method.as_str(),
+            path,
+            host,
+            port,
+            request.body.len()
+        );
+        for (name, value) in &request.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += "\r\n";
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&request.body)?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        parse_status(&status_line)
+    }
+}
+
+impl Fetch for TcpHttpClient {
+    fn fetch(&mut self, url: &str, receive: &mut Receive) -> anyhow::Result<()> {
+        let (host, port, path) = split_url(url)?;
+        let mut stream = self.connect(&host, port)?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+            path, host, port
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = parse_status(&line)?;
+        if !is_success(status) {
+            anyhow::bail!("GET {} answered {}", url, status);
+        }
+        let mut len = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    len = value.trim().parse().ok();
+                }
+            }
+        }
+
+        // Without a length the body runs until the server hangs up
+        let mut body = reader.take(len.map_or(u64::MAX, |len: usize| len as u64));
+        receive(len, &mut |buf| Ok(body.read(buf)?))
+    }
+}
@@ -0,0 +1,9 @@
+//! Host-side adapters for the wrover library.
+
+pub mod broker;
+pub mod catcher;
+pub mod client;
+pub mod mqtt;
+pub mod ntp;
+pub mod server;
+pub mod ws;
@@ -0,0 +1,364 @@
+//! A small MQTT 3.1.1 client over plain TCP, so the simulator can talk to a real broker
+//! (Mosquitto) or to `broker::MqttBroker`.
+//!
+//! Just what `wrover::mqtt` needs: QoS 0 and 1 publishes and subscriptions (without waiting
+//! for acks), a last will and keep-alive pings. It doesn't reconnect.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use wrover::http::App;
+use wrover::mqtt::{Mqtt, MqttClient, MqttConfig, MqttEvent, QoS, Will};
+
+pub const CONNECT: u8 = 0x10;
+pub const CONNACK: u8 = 0x20;
+pub const PUBLISH: u8 = 0x30;
+pub const PUBACK: u8 = 0x40;
+pub const SUBSCRIBE: u8 = 0x80;
+pub const SUBACK: u8 = 0x90;
+pub const PINGREQ: u8 = 0xc0;
+pub const PINGRESP: u8 = 0xd0;
+pub const DISCONNECT: u8 = 0xe0;
+
+/// One control packet: the first byte split into type and flags, and everything after the
+/// length
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub kind: u8,
+    pub flags: u8,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: u8, flags: u8, body: Vec<u8>) -> Self {
+        Self { kind, flags, body }
+    }
+
+    pub fn read(r: &mut impl Read) -> io::Result<Self> {
+        let mut first = [0];
+        r.read_exact(&mut first)?;
+        let mut len = 0usize;
+        for shift in (0..28).step_by(7) {
+            let mut byte = [0];
+            r.read_exact(&mut byte)?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                let mut body = vec![0; len];
+                r.read_exact(&mut body)?;
+                return Ok(Self::new(first[0] & 0xf0, first[0] & 0x0f, body));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Remaining length is too long",
+        ))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.kind | self.flags];
+        let mut len = self.body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.to_bytes())
+    }
+
+    /// A `PUBLISH`; `id` only goes in for QoS 1
+    pub fn publish(topic: &str, qos: QoS, retain: bool, id: u16, payload: &[u8]) -> Self {
+        let mut body = Vec::new();
+        put_str(&mut body, topic);
+        let mut flags = retain as u8;
+        if qos == QoS::AtLeastOnce {
+            flags |= 0x02;
+            body.extend_from_slice(&id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        Self::new(PUBLISH, flags, body)
+    }
+
+    /// Topic, QoS, retain flag, packet id (0 for QoS 0) and payload of a `PUBLISH`
+    pub fn parse_publish(&self) -> io::Result<(String, QoS, bool, u16, &[u8])> {
+        let mut at = 0;
+        let topic = get_str(&self.body, &mut at)?;
+        let (qos, id) = match (self.flags >> 1) & 0x03 {
+            0 => (QoS::AtMostOnce, 0),
+            _ => (QoS::AtLeastOnce, get_u16(&self.body, &mut at)?),
+        };
+        Ok((topic, qos, self.flags & 0x01 != 0, id, &self.body[at..]))
+    }
+}
+
+pub fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub fn get_u16(body: &[u8], at: &mut usize) -> io::Result<u16> {
+    let bytes = body.get(*at..*at + 2).ok_or_else(truncated)?;
+    *at += 2;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn get_bytes(body: &[u8], at: &mut usize) -> io::Result<Vec<u8>> {
+    let len = get_u16(body, at)? as usize;
+    let bytes = body.get(*at..*at + len).ok_or_else(truncated)?;
+    *at += len;
+    Ok(bytes.to_vec())
+}
+
+pub fn get_str(body: &[u8], at: &mut usize) -> io::Result<String> {
+    String::from_utf8(get_bytes(body, at)?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Topic isn't UTF-8"))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Packet is truncated")
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttOptions {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+    pub keep_alive: Duration,
+}
+
+impl MqttOptions {
+    pub fn new(client_id: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            username: None,
+            password: None,
+            will: None,
+            keep_alive: Duration::from_secs(30),
+        }
+    }
+
+    pub fn from_config(config: &MqttConfig) -> Self {
+        Self {
+            client_id: config.client_id.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            will: Some(config.will()),
+            keep_alive: Duration::from_secs(30),
+        }
+    }
+
+    fn connect_packet(&self) -> Packet {
+        let mut flags = 0x02; // clean session
+        if let Some(will) = &self.will {
+            flags |= 0x04 | ((will.qos == QoS::AtLeastOnce) as u8) << 3;
+            flags |= (will.retain as u8) << 5;
+        }
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+
+        let mut body = Vec::new();
+        put_str(&mut body, "MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(&(self.keep_alive.as_secs() as u16).to_be_bytes());
+        put_str(&mut body, &self.client_id);
+        if let Some(will) = &self.will {
+            put_str(&mut body, &will.topic);
+            put_bytes(&mut body, &will.payload);
+        }
+        if let Some(username) = &self.username {
+            put_str(&mut body, username);
+        }
+        if let Some(password) = &self.password {
+            put_str(&mut body, password);
+        }
+        Packet::new(CONNECT, 0, body)
+    }
+}
+
+/// The sending half; a reader thread hands everything received to the `Sender` given to
+/// `connect`
+pub struct TcpMqttClient {
+    stream: Arc<Mutex<TcpStream>>,
+    next_id: AtomicU16,
+}
+
+impl TcpMqttClient {
+    /// Connects and waits for the broker to accept. `Connected` is the first event sent.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        options: &MqttOptions,
+        events: Sender<MqttEvent>,
+    ) -> anyhow::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        options.connect_packet().write(&mut stream)?;
+
+        let ack = Packet::read(&mut stream)?;
+        if ack.kind != CONNACK || ack.body.len() != 2 {
+            anyhow::bail!("Expected CONNACK, got packet type {:#x}", ack.kind);
+        }
+        if ack.body[1] != 0 {
+            anyhow::bail!("Broker refused the connection (code {})", ack.body[1]);
+        }
+        let _ = events.send(MqttEvent::Connected);
+
+        let mut reader = stream.try_clone()?;
+        let stream = Arc::new(Mutex::new(stream));
+        let writer = Arc::downgrade(&stream);
+        thread::spawn(move || {
+            read_loop(&mut reader, &writer, &events);
+            let _ = events.send(MqttEvent::Disconnected);
+        });
+
+        let pinger = Arc::downgrade(&stream);
+        let interval = options.keep_alive / 2;
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(stream) = pinger.upgrade() else {
+                break;
+            };
+            let ping = Packet::new(PINGREQ, 0, Vec::new());
+            if ping.write(&mut *stream.lock().unwrap()).is_err() {
+                break;
+            }
+        });
+
+        Ok(Self {
+            stream,
+            next_id: AtomicU16::new(1),
+        })
+    }
+
+    fn send(&self, packet: Packet) -> anyhow::Result<()> {
+        packet.write(&mut *self.stream.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn packet_id(&self) -> u16 {
+        // 0 isn't a valid id
+        self.next_id.fetch_add(1, Ordering::Relaxed).max(1)
+    }
+
+    pub fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let id = self.packet_id();
+        self.send(Packet::publish(topic, qos, retain, id, payload))
+    }
+
+    pub fn subscribe(&self, topic: &str, qos: QoS) -> anyhow::Result<()> {
+        let mut body = self.packet_id().to_be_bytes().to_vec();
+        put_str(&mut body, topic);
+        body.push((qos == QoS::AtLeastOnce) as u8);
+        self.send(Packet::new(SUBSCRIBE, 0x02, body))
+    }
+
+    /// Says goodbye, so the broker doesn't publish the will
+    pub fn disconnect(&self) -> anyhow::Result<()> {
+        self.send(Packet::new(DISCONNECT, 0, Vec::new()))?;
+        self.stream.lock().unwrap().shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// Drops the connection without a word, like a board losing power
+    pub fn abort(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+fn read_loop(reader: &mut TcpStream, writer: &Weak<Mutex<TcpStream>>, events: &Sender<MqttEvent>) {
+    loop {
+        let packet = match Packet::read(reader) {
+            Ok(packet) => packet,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    log::debug!("MQTT connection closed: {}", e);
+                }
+                return;
+            }
+        };
+        if packet.kind != PUBLISH {
+            continue;
+        }
+        let Ok((topic, qos, _, id, payload)) = packet.parse_publish() else {
+            log::warn!("Malformed PUBLISH from the broker");
+            return;
+        };
+        if qos == QoS::AtLeastOnce {
+            let Some(stream) = writer.upgrade() else {
+                return;
+            };
+            let ack = Packet::new(PUBACK, 0, id.to_be_bytes().to_vec());
+            if ack.write(&mut *stream.lock().unwrap()).is_err() {
+                return;
+            }
+        }
+        let message = MqttEvent::Message {
+            topic,
+            payload: payload.to_vec(),
+        };
+        if events.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+impl MqttClient for TcpMqttClient {
+    fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        TcpMqttClient::publish(self, topic, qos, retain, payload)
+    }
+
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> anyhow::Result<()> {
+        TcpMqttClient::subscribe(self, topic, qos)
+    }
+}
+
+/// `wrover::mqtt::Mqtt` on a TCP connection to the broker in `config.url`, like
+/// `wrover::mqtt::esp::start` on the board
+pub fn start(weak_app: Weak<App>, config: MqttConfig) -> anyhow::Result<Arc<Mqtt>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let addr = config.host_port()?;
+    let client = TcpMqttClient::connect(addr, &MqttOptions::from_config(&config), tx)?;
+
+    let mqtt = Mqtt::spawn(weak_app, config, client);
+    let handler = mqtt.clone();
+    thread::spawn(move || {
+        for event in rx {
+            handler.handle(event);
+        }
+    });
+    Ok(mqtt)
+}
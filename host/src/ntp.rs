@@ -0,0 +1,61 @@
+//! Stand-in NTP server so time sync can be tested without the internet.
+//!
+//! Its clock starts at whatever time it is given and runs at real speed from there, which
+//! makes the synced time easy to recognise.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use wrover::sntp;
+
+pub struct NtpServer {
+    socket: UdpSocket,
+    start: SystemTime,
+}
+
+impl NtpServer {
+    /// Serves a clock that reads `start` right now
+    pub fn bind(addr: impl ToSocketAddrs, start: SystemTime) -> anyhow::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            start,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Answers requests forever
+    pub fn run(self) {
+        let started = Instant::now();
+        let mut buf = [0; 512];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    log::warn!("NTP receive failed: {}", e);
+                    continue;
+                }
+            };
+
+            let now = self.start + started.elapsed();
+            match sntp::reply(&buf[..len], now, 1) {
+                Ok(reply) => {
+                    if let Err(e) = self.socket.send_to(&reply, peer) {
+                        log::warn!("NTP reply to {} failed: {}", peer, e);
+                    }
+                }
+                Err(e) => log::debug!("Ignoring packet from {}: {}", peer, e),
+            }
+        }
+    }
+
+    /// Runs the server on a background thread and returns its address
+    pub fn spawn(self) -> SocketAddr {
+        let addr = self.local_addr();
+        thread::spawn(move || self.run());
+        addr
+    }
+}
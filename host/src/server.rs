@@ -0,0 +1,183 @@
+//! Bare-bones HTTP/1.1 server on `std::net`, standing in for `EspHttpServer`.
+//!
+//! One thread per connection and `Connection: close` on everything, which is about what the
+//! ESP-IDF server does with its default config too.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use wrover::http::{stream_content_type, App, Response, MAX_BODY};
+
+use crate::ws;
+
+pub struct HostServer {
+    listener: TcpListener,
+    app: Arc<App>,
+}
+
+impl HostServer {
+    pub fn bind(addr: impl ToSocketAddrs, app: Arc<App>) -> anyhow::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            app,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Accepts connections forever
+    pub fn run(self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let app = self.app.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &app) {
+                    log::debug!("Connection ended: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Runs the server on a background thread and returns its address
+    pub fn spawn(self) -> SocketAddr {
+        let addr = self.local_addr();
+        thread::spawn(move || self.run());
+        addr
+    }
+}
+
+fn handle(mut stream: TcpStream, app: &App) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Only the body length, credentials and the WebSocket key matter
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut websocket_key = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_owned());
+            } else if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return send(&mut stream, Response::text(400, "Bad request"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    log::debug!("{} {}", method, target);
+
+    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    let post = method == "POST";
+    let scope = match app.authorize(post, path, query, authorization.as_deref(), peer) {
+        Ok(scope) => scope,
+        Err(response) => {
+            discard(&mut reader, content_length)?;
+            return send(&mut stream, response);
+        }
+    };
+
+    match method {
+        "GET" => {}
+        // Firmware, which goes straight to flash as it arrives
+        "POST" if path == "/ota" => {
+            let mut body = (&mut reader).take(content_length as u64);
+            let response = app.upload_ota(Some(content_length), &mut |buf| Ok(body.read(buf)?));
+            discard(&mut body, content_length)?;
+            return send(&mut stream, response);
+        }
+        "POST" if content_length > MAX_BODY => {
+            return send(&mut stream, Response::text(413, "Body too large"));
+        }
+        "POST" => {
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            return send(&mut stream, app.post(path, &body));
+        }
+        _ => return send(&mut stream, Response::text(405, "Method not allowed")),
+    }
+
+    match path {
+        "/" | "/stream" => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+                stream_content_type()
+            )?;
+            app.stream(|chunk| stream.write_all(chunk).map_err(Into::into));
+            Ok(())
+        }
+        "/ws" => match (app.websocket(), websocket_key) {
+            (Some(websocket), Some(key)) => ws::serve(stream, reader, &key, &websocket, scope),
+            (Some(_), None) => send(&mut stream, Response::text(400, "Expected a WebSocket")),
+            (None, _) => send(&mut stream, Response::text(404, "Not found")),
+        },
+        _ => send(&mut stream, app.route(path, query)),
+    }
+}
+
+/// Reads and drops a body that won't be used: closing with it unread resets the connection,
+/// and the client may never see the response
+fn discard(body: &mut impl Read, len: usize) -> io::Result<()> {
+    io::copy(&mut body.take(len as u64), &mut io::sink())?;
+    Ok(())
+}
+
+fn send(stream: &mut TcpStream, response: Response) -> anyhow::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
@@ -0,0 +1,220 @@
+//! WebSocket (RFC 6455) for `HostServer`: the handshake and framing ESP-IDF's server does on
+//! the board. Enough for `/ws`, plus the client side of the framing for tests.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use wrover::auth::Scope;
+use wrover::client::base64;
+use wrover::http::MAX_BODY;
+use wrover::ws::{Message, WsStream};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+/// Close code for "try again later", sent when there are too many clients
+const CLOSE_TRY_AGAIN: u16 = 1013;
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+/// Writes one unfragmented frame. Clients have to `mask` theirs, servers mustn't.
+pub fn write_frame(
+    writer: &mut impl Write,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    writer.write_all(&frame)
+}
+
+/// Reads one message, unmasked, joining fragments. Control frames in between fragments are
+/// returned as they come.
+pub fn read_message(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let (fin, opcode, payload) = read_frame(reader)?;
+        if opcode >= OP_CLOSE {
+            return Ok((opcode, payload));
+        }
+        let (_, data) = message.get_or_insert_with(|| (opcode, Vec::new()));
+        if data.len() + payload.len() > MAX_BODY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message too big",
+            ));
+        }
+        data.extend_from_slice(&payload);
+        if fin {
+            return Ok(message.take().unwrap());
+        }
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_BODY as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too big"));
+    }
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0; 4];
+        reader.read_exact(&mut mask)?;
+        Some(mask)
+    } else {
+        None
+    };
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((head[0] & 0x80 != 0, head[0] & 0x0f, payload))
+}
+
+/// Finishes the handshake and runs `/ws` on the connection until the client leaves. `scope`
+/// is what `App::authorize` allowed.
+pub fn serve(
+    mut stream: TcpStream,
+    mut reader: BufReader<TcpStream>,
+    key: &str,
+    ws: &Arc<WsStream>,
+    scope: Scope,
+) -> anyhow::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+
+    let writer = Arc::new(Mutex::new(stream));
+    let sending = writer.clone();
+    let client = ws.connect(move |message: Message<'_>| {
+        let mut stream = sending.lock().unwrap();
+        let result = match message {
+            Message::Text(text) => write_frame(&mut *stream, OP_TEXT, text.as_bytes(), None),
+            Message::Binary(data) => write_frame(&mut *stream, OP_BINARY, data, None),
+        };
+        result.map_err(Into::into)
+    });
+    let client = match client {
+        Ok(client) => client.with_scope(scope),
+        Err(e) => {
+            log::warn!("Turned a WebSocket client away: {}", e);
+            let mut stream = writer.lock().unwrap();
+            write_frame(&mut *stream, OP_CLOSE, &CLOSE_TRY_AGAIN.to_be_bytes(), None)?;
+            return Ok(());
+        }
+    };
+
+    loop {
+        let (opcode, payload) = read_message(&mut reader)?;
+        match opcode {
+            OP_TEXT => client.receive(&String::from_utf8_lossy(&payload)),
+            OP_PING => write_frame(&mut *writer.lock().unwrap(), OP_PONG, &payload, None)?,
+            OP_CLOSE => {
+                // Echo the close, then hang up
+                write_frame(&mut *writer.lock().unwrap(), OP_CLOSE, &payload, None)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// SHA-1, which the handshake needs and nothing else does
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (out, word) in digest.chunks_exact_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
@@ -0,0 +1,454 @@
+//! Authentication: the `Authorization` header parser, password hashes, `POST /auth` changes
+//! and lockouts on their own, then requests through `HostServer` with auth turned on.
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::Reply;
+use serde_json::{json, Value};
+use wrover::auth::{self, Authenticator, Change, Credentials, Scope, SecretHash};
+use wrover::camera::{Control, FrameSize, TestPattern};
+use wrover::client::{base64, base64_decode};
+use wrover::http::{self, App};
+use wrover::settings::{AuthSettings, Settings};
+use wrover::ws::{Message, WsConfig, WsStream};
+use wrover_host::server::HostServer;
+
+fn basic(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64(format!("{}:{}", username, password).as_bytes())
+    )
+}
+
+#[test]
+fn base64_round_trips() {
+    for text in ["", "f", "fo", "foo", "foob", "fooba", "foobar"] {
+        assert_eq!(
+            base64_decode(&base64(text.as_bytes())).unwrap(),
+            text.as_bytes()
+        );
+    }
+    assert_eq!(base64_decode("Zm9vYg").unwrap(), b"foob");
+    assert!(base64_decode("Zm9v YmFy").is_err());
+    assert!(base64_decode("Zm9v!").is_err());
+    assert!(base64_decode("Z").is_err());
+}
+
+#[test]
+fn headers_parse() {
+    assert_eq!(
+        Credentials::parse("Basic YWxhZGRpbjpvcGVuc2VzYW1l").unwrap(),
+        Credentials::Basic {
+            username: "aladdin".into(),
+            password: "opensesame".into()
+        }
+    );
+    // Scheme in any case, extra spaces, and a password with colons in it
+    assert_eq!(
+        Credentials::parse(&format!("  bASIC   {}  ", &basic("ann", "a:b:c")[6..])).unwrap(),
+        Credentials::Basic {
+            username: "ann".into(),
+            password: "a:b:c".into()
+        }
+    );
+    assert_eq!(
+        Credentials::parse(&basic("", "")).unwrap(),
+        Credentials::Basic {
+            username: "".into(),
+            password: "".into()
+        }
+    );
+    assert_eq!(
+        Credentials::parse("Bearer wr_0123abcd").unwrap(),
+        Credentials::Bearer("wr_0123abcd".into())
+    );
+    assert_eq!(
+        Credentials::parse("bearer  tok").unwrap(),
+        Credentials::Bearer("tok".into())
+    );
+
+    assert!(Credentials::parse("").is_err());
+    assert!(Credentials::parse("Basic").is_err());
+    assert!(Credentials::parse("Bearer ").is_err());
+    assert!(Credentials::parse("Basic !!!!").is_err());
+    // No colon
+    assert!(Credentials::parse(&format!("Basic {}", base64(b"ann"))).is_err());
+    // Not UTF-8
+    assert!(Credentials::parse(&format!("Basic {}", base64(&[0xff, b':', 0xfe]))).is_err());
+    assert!(Credentials::parse("Digest username=\"ann\"").is_err());
+}
+
+#[test]
+fn hashes_verify() {
+    let hash = SecretHash::new("hunter2", 10);
+    assert!(hash.verify("hunter2"));
+    assert!(!hash.verify("hunter3"));
+    assert!(!hash.verify(""));
+
+    let text = hash.to_string();
+    assert!(text.starts_with("sha256$10$"));
+    assert!(!text.contains("hunter2"));
+    let parsed: SecretHash = text.parse().unwrap();
+    assert_eq!(parsed, hash);
+    assert!(parsed.verify("hunter2"));
+
+    // Salted, so the same password hashes differently
+    assert_ne!(SecretHash::new("hunter2", 10), hash);
+
+    assert!("md5$1$00$00".parse::<SecretHash>().is_err());
+    assert!("sha256$0$00000000000000000000000000000000$00"
+        .parse::<SecretHash>()
+        .is_err());
+    assert!(text[..text.len() - 2].parse::<SecretHash>().is_err());
+    assert!(text.replace("$10$", "$x$").parse::<SecretHash>().is_err());
+}
+
+#[test]
+fn tokens_are_random() {
+    let a = auth::generate_token();
+    let b = auth::generate_token();
+    assert!(a.starts_with("wr_"));
+    assert_eq!(a.len(), 35);
+    assert_ne!(a, b);
+}
+
+fn change(json: Value) -> Change {
+    serde_json::from_value(json).unwrap()
+}
+
+#[test]
+fn changes() {
+    let mut settings = AuthSettings::default();
+
+    // Nobody could turn it off again
+    assert!(change(json!({"action": "enable"}))
+        .apply(&mut settings.clone())
+        .is_err());
+
+    change(json!({"action": "set_user", "name": "ann", "password": "pw", "scope": "admin"}))
+        .apply(&mut settings)
+        .unwrap();
+    let token = change(json!({"action": "create_token", "name": "ci", "scope": "read"}))
+        .apply(&mut settings)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        change(json!({"action": "enable"}))
+            .apply(&mut settings)
+            .unwrap(),
+        None
+    );
+    assert!(settings.enabled);
+    assert_eq!(settings.users[0].scope, Scope::Admin);
+    assert!(settings.users[0]
+        .hash
+        .parse::<SecretHash>()
+        .unwrap()
+        .verify("pw"));
+    assert!(settings.tokens[0]
+        .hash
+        .parse::<SecretHash>()
+        .unwrap()
+        .verify(&token));
+
+    // Replacing a user keeps one of them
+    change(json!({"action": "set_user", "name": "ann", "password": "new", "scope": "admin"}))
+        .apply(&mut settings)
+        .unwrap();
+    assert_eq!(settings.users.len(), 1);
+
+    // Bad ones
+    for bad in [
+        json!({"action": "set_user", "name": "", "password": "pw", "scope": "read"}),
+        json!({"action": "set_user", "name": "a:b", "password": "pw", "scope": "read"}),
+        json!({"action": "set_user", "name": "bob", "password": "", "scope": "read"}),
+        json!({"action": "create_token", "name": "ci", "scope": "read"}),
+        json!({"action": "revoke_token", "name": "nope"}),
+        json!({"action": "remove_user", "name": "nope"}),
+        // The last admin, while it's on
+        json!({"action": "remove_user", "name": "ann"}),
+    ] {
+        assert!(
+            change(bad.clone()).apply(&mut settings.clone()).is_err(),
+            "{}",
+            bad
+        );
+    }
+    assert!(serde_json::from_value::<Change>(json!({"action": "explode"})).is_err());
+    assert!(serde_json::from_value::<Change>(
+        json!({"action": "set_user", "name": "x", "password": "y", "scope": "root"})
+    )
+    .is_err());
+
+    let summary: Value = serde_json::from_str(&auth::summary_json(&settings, None)).unwrap();
+    assert_eq!(summary["enabled"], true);
+    assert_eq!(summary["users"], json!([{"name": "ann", "scope": "admin"}]));
+    assert_eq!(summary["tokens"], json!([{"name": "ci", "scope": "read"}]));
+    assert!(summary.get("token").is_none());
+    assert!(!summary.to_string().contains("sha256"));
+}
+
+#[test]
+fn scopes_for_routes() {
+    let scope = |post, path, query| http::required_scope(post, path, query);
+    assert_eq!(scope(false, "/", ""), Scope::Read);
+    assert_eq!(scope(false, "/stream", ""), Scope::Read);
+    assert_eq!(scope(false, "/status", ""), Scope::Read);
+    assert_eq!(scope(false, "/masks", ""), Scope::Read);
+    assert_eq!(scope(false, "/motion", ""), Scope::Read);
+    assert_eq!(scope(false, "/motion", "action=disable"), Scope::Admin);
+    assert_eq!(scope(false, "/timelapse", "action=start"), Scope::Admin);
+    assert_eq!(scope(false, "/control", "var=quality&val=10"), Scope::Admin);
+    assert_eq!(scope(false, "/auth", ""), Scope::Admin);
+    // It shows webhook and MQTT secrets
+    assert_eq!(scope(false, "/settings", ""), Scope::Admin);
+    assert_eq!(scope(true, "/masks", ""), Scope::Admin);
+}
+
+/// An admin `ann`, a read-only `guest` and a read-only token
+fn settings() -> (AuthSettings, String) {
+    let mut settings = AuthSettings {
+        lockout_secs: 1,
+        max_failures: 3,
+        ..Default::default()
+    };
+    for (name, scope) in [("ann", "admin"), ("guest", "read")] {
+        change(json!({"action": "set_user", "name": name, "password": "pw", "scope": scope}))
+            .apply(&mut settings)
+            .unwrap();
+    }
+    let token = change(json!({"action": "create_token", "name": "ci", "scope": "read"}))
+        .apply(&mut settings)
+        .unwrap()
+        .unwrap();
+    change(json!({"action": "enable"}))
+        .apply(&mut settings)
+        .unwrap();
+    (settings, token)
+}
+
+#[test]
+fn checks_credentials_and_scopes() {
+    let (settings, token) = settings();
+    let authenticator = Authenticator::default();
+    let check =
+        |header: Option<&str>, needed| authenticator.check(&settings, header, None, needed, "cam");
+
+    assert_eq!(
+        check(Some(&basic("ann", "pw")), Scope::Admin).ok(),
+        Some(Scope::Admin)
+    );
+    assert_eq!(
+        check(Some(&basic("guest", "pw")), Scope::Read).ok(),
+        Some(Scope::Read)
+    );
+    let bearer = format!("Bearer {}", token);
+    assert_eq!(check(Some(&bearer), Scope::Read).ok(), Some(Scope::Read));
+
+    let missing = check(None, Scope::Read).unwrap_err();
+    assert_eq!(missing.status, 401);
+    assert_eq!(
+        missing.header("WWW-Authenticate"),
+        Some("Basic realm=\"cam\", charset=\"UTF-8\"")
+    );
+    let wrong = check(Some("Bearer wr_nope"), Scope::Read).unwrap_err();
+    assert_eq!(wrong.status, 401);
+    assert!(wrong
+        .header("WWW-Authenticate")
+        .unwrap()
+        .starts_with("Bearer"));
+    assert_eq!(
+        check(Some(&basic("ann", "PW")), Scope::Read)
+            .unwrap_err()
+            .status,
+        401
+    );
+    assert_eq!(
+        check(Some(&basic("guest", "pw")), Scope::Admin)
+            .unwrap_err()
+            .status,
+        403
+    );
+    assert_eq!(check(Some(&bearer), Scope::Admin).unwrap_err().status, 403);
+}
+
+#[test]
+fn failures_lock_out_one_peer() {
+    let (settings, _) = settings();
+    let authenticator = Authenticator::default();
+    let mallory: Option<IpAddr> = Some("10.0.0.66".parse().unwrap());
+    let alice: Option<IpAddr> = Some("10.0.0.7".parse().unwrap());
+    let check = |header: &str, peer| {
+        authenticator
+            .check(&settings, Some(header), peer, Scope::Read, "cam")
+            .map_err(|response| response.status)
+    };
+
+    // A success in between starts the count again
+    assert_eq!(check(&basic("ann", "1"), mallory), Err(401));
+    assert_eq!(check(&basic("ann", "2"), mallory), Err(401));
+    assert!(check(&basic("ann", "pw"), mallory).is_ok());
+    assert_eq!(check(&basic("ann", "3"), mallory), Err(401));
+    assert_eq!(check(&basic("ann", "4"), mallory), Err(401));
+    assert_eq!(check(&basic("ann", "5"), mallory), Err(401));
+
+    // Now even the right password is turned away, but only for this peer
+    let locked = authenticator
+        .check(
+            &settings,
+            Some(&basic("ann", "pw")),
+            mallory,
+            Scope::Read,
+            "cam",
+        )
+        .unwrap_err();
+    assert_eq!(locked.status, 429);
+    assert_eq!(locked.header("Retry-After"), Some("1"));
+    assert!(check(&basic("ann", "pw"), alice).is_ok());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(check(&basic("ann", "pw"), mallory).is_ok());
+}
+
+fn serve() -> (Arc<App>, SocketAddr, String) {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let (auth, token) = settings();
+    app.set_settings(
+        Settings {
+            camera_name: "porch".into(),
+            auth,
+            ..Default::default()
+        },
+        None,
+    );
+    let ws = WsStream::spawn(
+        Arc::downgrade(&app),
+        WsConfig {
+            fps: 10.0,
+            max_clients: 4,
+        },
+    );
+    app.set_websocket(Some(ws));
+    let addr = HostServer::bind("127.0.0.1:0", app.clone())
+        .unwrap()
+        .spawn();
+    (app, addr, token)
+}
+
+fn request(addr: SocketAddr, method: &str, target: &str, auth: Option<&str>, body: &str) -> Reply {
+    let headers: Vec<_> = auth
+        .map(|auth| ("Authorization", auth))
+        .into_iter()
+        .collect();
+    common::request(addr, method, target, &headers, body.as_bytes())
+}
+
+#[test]
+fn server_asks_for_credentials() {
+    let (app, addr, token) = serve();
+    let admin = basic("ann", "pw");
+    let bearer = format!("Bearer {}", token);
+    let quality = app.control_value(Control::Quality);
+
+    let denied = request(addr, "GET", "/status", None, "");
+    assert_eq!(denied.status, 401);
+    assert_eq!(
+        denied.header("WWW-Authenticate"),
+        Some("Basic realm=\"porch\", charset=\"UTF-8\"")
+    );
+    assert_eq!(request(addr, "GET", "/", None, "").status, 401);
+    assert_eq!(request(addr, "GET", "/ws", None, "").status, 401);
+
+    assert_eq!(
+        request(addr, "GET", "/status", Some(&bearer), "").status,
+        200
+    );
+    let forbidden = request(
+        addr,
+        "GET",
+        "/control?var=quality&val=20",
+        Some(&bearer),
+        "",
+    );
+    assert_eq!(forbidden.status, 403);
+    assert_eq!(app.control_value(Control::Quality), quality);
+
+    let ok = request(addr, "GET", "/control?var=quality&val=20", Some(&admin), "");
+    assert_eq!(ok.status, 200);
+    assert_eq!(app.control_value(Control::Quality), 20);
+    assert_eq!(
+        request(addr, "POST", "/masks", Some(&bearer), "{}").status,
+        403
+    );
+}
+
+#[test]
+fn tokens_are_managed_over_http() {
+    let (app, addr, _) = serve();
+    let admin = basic("ann", "pw");
+
+    let made = request(
+        addr,
+        "POST",
+        "/auth",
+        Some(&admin),
+        r#"{"action":"create_token","name":"robot","scope":"admin"}"#,
+    );
+    let made = made.json();
+    let token = made["token"].as_str().unwrap().to_owned();
+    assert!(!made.to_string().contains("sha256"));
+
+    let bearer = format!("Bearer {}", token);
+    let listed = request(addr, "GET", "/auth", Some(&bearer), "");
+    let listed = listed.json();
+    assert_eq!(listed["tokens"].as_array().unwrap().len(), 2);
+    assert!(listed.get("token").is_none());
+    assert_eq!(app.settings().auth.tokens.len(), 2);
+
+    let bad = request(
+        addr,
+        "POST",
+        "/auth",
+        Some(&bearer),
+        r#"{"action":"explode"}"#,
+    );
+    assert_eq!(bad.status, 400);
+
+    let revoked = request(
+        addr,
+        "POST",
+        "/auth",
+        Some(&admin),
+        r#"{"action":"revoke_token","name":"robot"}"#,
+    );
+    assert_eq!(revoked.status, 200);
+    assert_eq!(request(addr, "GET", "/auth", Some(&bearer), "").status, 401);
+}
+
+#[test]
+fn read_only_websockets_cannot_change_things() {
+    let (app, _, _) = serve();
+    let ws = app.websocket().unwrap();
+    let quality = app.control_value(Control::Quality);
+    let client = ws
+        .connect(|_: Message<'_>| Ok(()))
+        .unwrap()
+        .with_scope(Scope::Read);
+    client.receive(r#"{"cmd":"control","var":"quality","val":30}"#);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(app.control_value(Control::Quality), quality);
+}
+
+#[test]
+fn everything_is_open_until_enabled() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    assert_eq!(
+        app.authorize(true, "/auth", "", None, None).ok(),
+        Some(Scope::Admin)
+    );
+}
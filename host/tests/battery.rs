@@ -0,0 +1,286 @@
+//! The battery monitor: the volts-to-percent curve, thresholds with hysteresis, what they do
+//! to streams, then the real thread reading a fake ADC into `/status` and `/metrics`.
+
+mod common;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{json_of, wait_for};
+use serde_json::Value;
+use wrover::battery::{self, Battery, BatteryAdc, StreamLimits, Thresholds};
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::settings::{
+    BatteryAction, BatterySettings, BatteryThreshold, Settings, TriggerAction, TriggerSettings,
+};
+
+fn threshold(below_percent: u8, action: BatteryAction) -> BatteryThreshold {
+    BatteryThreshold {
+        below_percent,
+        action,
+    }
+}
+
+/// Volts at the pin that the test sets, `None` to fail the read
+#[derive(Clone)]
+struct FakeAdc(Arc<Mutex<Option<f32>>>);
+
+impl BatteryAdc for FakeAdc {
+    fn read_volts(&mut self) -> anyhow::Result<f32> {
+        self.0
+            .lock()
+            .unwrap()
+            .ok_or_else(|| anyhow::anyhow!("ADC timeout"))
+    }
+}
+
+#[test]
+fn maps_volts_to_percent() {
+    let curve = BatterySettings::default().curve;
+    let percent = |volts| battery::percent(&curve, volts);
+    assert_eq!(percent(4.20), 100.0);
+    assert_eq!(percent(3.84), 50.0);
+    assert_eq!(percent(3.27), 0.0);
+    // Straight lines between the points
+    assert!((percent(3.82) - 45.0).abs() < 0.01, "{}", percent(3.82));
+    assert!((percent(3.44) - 2.5).abs() < 0.01, "{}", percent(3.44));
+    // Clamped outside the curve, like on a charger or with nothing connected
+    assert_eq!(percent(4.35), 100.0);
+    assert_eq!(percent(0.0), 0.0);
+
+    // Curves don't have to start full or end empty
+    let partial = [(4.0, 80), (3.5, 10)];
+    assert_eq!(battery::percent(&partial, 4.1), 80.0);
+    assert_eq!(battery::percent(&partial, 3.75), 45.0);
+    assert_eq!(battery::percent(&partial, 3.0), 10.0);
+    assert_eq!(battery::percent(&[], 3.7), 0.0);
+}
+
+#[test]
+fn thresholds_have_hysteresis() {
+    let mut thresholds = Thresholds::new(
+        vec![
+            threshold(30, BatteryAction::ReduceFps),
+            threshold(15, BatteryAction::StopStreaming),
+            threshold(10, BatteryAction::ReduceFps),
+        ],
+        5,
+    );
+    assert!(thresholds.update(50.0).is_empty());
+    assert_eq!(thresholds.update(29.0), [(BatteryAction::ReduceFps, true)]);
+    assert!(thresholds.update(31.0).is_empty());
+    assert_eq!(
+        thresholds.update(8.0),
+        [
+            (BatteryAction::StopStreaming, true),
+            (BatteryAction::ReduceFps, true)
+        ]
+    );
+    // Each action once, however many thresholds ask for it
+    assert_eq!(
+        thresholds.active(),
+        [BatteryAction::ReduceFps, BatteryAction::StopStreaming]
+    );
+
+    // Charging: nothing's undone until it's 5% above the threshold
+    assert!(thresholds.update(14.0).is_empty());
+    assert_eq!(
+        thresholds.update(20.0),
+        [
+            (BatteryAction::StopStreaming, false),
+            (BatteryAction::ReduceFps, false)
+        ]
+    );
+    assert_eq!(thresholds.active(), [BatteryAction::ReduceFps]);
+    assert_eq!(thresholds.update(35.0), [(BatteryAction::ReduceFps, false)]);
+    assert!(thresholds.active().is_empty());
+}
+
+#[test]
+fn limits_streams() {
+    let base = Duration::from_millis(100);
+    let none = StreamLimits::default();
+    assert_eq!(none.interval(base), Some(base));
+
+    let slow = StreamLimits::for_actions(&[BatteryAction::ReduceFps], 2.0);
+    assert_eq!(slow.max_fps, Some(2.0));
+    assert!(!slow.paused);
+    assert_eq!(slow.interval(base), Some(Duration::from_millis(500)));
+    // Streams that are already slower stay as they are
+    assert_eq!(
+        slow.interval(Duration::from_secs(1)),
+        Some(Duration::from_secs(1))
+    );
+
+    let paused = StreamLimits::for_actions(
+        &[BatteryAction::ReduceFps, BatteryAction::StopStreaming],
+        2.0,
+    );
+    assert_eq!(paused.interval(base), None);
+    assert_eq!(
+        StreamLimits::for_actions(&[BatteryAction::DeepSleep], 2.0),
+        none
+    );
+}
+
+#[test]
+fn settings_are_checked() {
+    let mut settings = Settings::default();
+    assert!(settings.battery.validate().is_ok());
+    // The host builds for the Freenove, which reads the battery on GPIO33
+    assert!(battery::validate(&settings).is_ok());
+    settings.triggers = vec![TriggerSettings {
+        gpio: 0,
+        ..Default::default()
+    }];
+    assert!(battery::validate(&settings).is_ok());
+    settings.triggers[0].gpio = 33;
+    assert!(battery::validate(&settings).is_err());
+    settings.triggers[0].gpio = 0;
+
+    // Deep sleep with nothing to wake it, unless no threshold asks for it
+    settings.sleep.interval_secs = 0;
+    assert!(battery::validate(&settings).is_err());
+    settings.triggers[0].actions = vec![TriggerAction::Wake];
+    assert!(battery::validate(&settings).is_ok());
+    settings.triggers[0].actions.clear();
+    settings
+        .battery
+        .thresholds
+        .retain(|threshold| threshold.action != BatteryAction::DeepSleep);
+    assert!(battery::validate(&settings).is_ok());
+
+    for bad in [
+        BatterySettings {
+            interval_secs: 0,
+            ..Default::default()
+        },
+        BatterySettings {
+            curve: vec![(4.2, 100)],
+            ..Default::default()
+        },
+        BatterySettings {
+            curve: vec![(3.3, 0), (4.2, 100)],
+            ..Default::default()
+        },
+        BatterySettings {
+            curve: vec![(4.2, 100), (3.9, 100), (3.3, 20), (3.2, 30)],
+            ..Default::default()
+        },
+        BatterySettings {
+            curve: vec![(4.2, 120), (3.3, 0)],
+            ..Default::default()
+        },
+        BatterySettings {
+            thresholds: vec![threshold(0, BatteryAction::DeepSleep)],
+            ..Default::default()
+        },
+        BatterySettings {
+            hysteresis_percent: 51,
+            ..Default::default()
+        },
+        BatterySettings {
+            low_fps: 0.0,
+            ..Default::default()
+        },
+    ] {
+        assert!(bad.validate().is_err(), "{:?}", bad);
+    }
+
+    let parsed: BatterySettings = serde_json::from_str(
+        r#"{"enabled": true, "curve": [[4.1, 100], [3.4, 0]],
+            "thresholds": [{"below_percent": 20, "action": "stop_streaming"}]}"#,
+    )
+    .unwrap();
+    assert!(parsed.validate().is_ok());
+    assert_eq!(parsed.curve, [(4.1, 100), (3.4, 0)]);
+    assert_eq!(
+        parsed.thresholds,
+        [threshold(20, BatteryAction::StopStreaming)]
+    );
+    assert_eq!(parsed.interval_secs, 30);
+}
+
+#[test]
+fn monitors_the_battery() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    assert!(!app.has_battery());
+    assert_eq!(json_of(app.route("/status", ""))["battery"], Value::Null);
+
+    // 1.9 V at the pin, 3.8 V at the cell: 40%
+    let volts = Arc::new(Mutex::new(Some(1.9)));
+    let sleeps = Arc::new(AtomicU32::new(0));
+    let slept = sleeps.clone();
+    let settings = BatterySettings {
+        enabled: true,
+        interval_secs: 1,
+        ..Default::default()
+    };
+    let monitor = Battery::spawn(
+        Arc::downgrade(&app),
+        FakeAdc(volts.clone()),
+        2.0,
+        settings,
+        move || {
+            slept.fetch_add(1, Ordering::Relaxed);
+        },
+    );
+    app.set_battery(Some(monitor.clone()));
+    assert!(app.has_battery());
+    wait_for("a reading", || app.battery_reading().is_some());
+
+    let status = json_of(app.route("/status", ""));
+    assert!((status["battery"]["volts"].as_f64().unwrap() - 3.8).abs() < 0.001);
+    assert_eq!(status["battery"]["percent"], 40.0);
+    assert_eq!(status["battery"]["actions"], serde_json::json!([]));
+    let metrics = app.route("/metrics", "");
+    assert_eq!(metrics.status, 200);
+    let metrics = String::from_utf8(metrics.body).unwrap();
+    assert!(
+        metrics.contains("# TYPE wrover_battery_volts gauge\n"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("\nwrover_battery_percent 40\n"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("\nwrover_frames_total "), "{}", metrics);
+    assert_eq!(app.stream_limits(), StreamLimits::default());
+
+    // Nearly flat (smoothed, so a step at a time): every threshold at once
+    *volts.lock().unwrap() = Some(1.5);
+    wait_for("deep sleep", || sleeps.load(Ordering::Relaxed) == 1);
+    assert_eq!(
+        monitor.actions(),
+        [
+            BatteryAction::ReduceFps,
+            BatteryAction::StopStreaming,
+            BatteryAction::DeepSleep
+        ]
+    );
+    assert_eq!(app.stream_limits().interval(Duration::ZERO), None);
+
+    // A failed read keeps the last reading and says why
+    *volts.lock().unwrap() = None;
+    wait_for("the error", || {
+        json_of(app.route("/status", ""))["battery"]["last_error"] == "ADC timeout"
+    });
+    assert!(app.battery_reading().unwrap().percent < 5.0);
+
+    // Charging again: streams come back slowly, then at full speed
+    *volts.lock().unwrap() = Some(2.1);
+    wait_for("streams to come back", || !app.stream_limits().paused);
+    wait_for("full speed", || {
+        app.stream_limits() == StreamLimits::default()
+    });
+    assert!(monitor.actions().is_empty());
+    assert_eq!(sleeps.load(Ordering::Relaxed), 1);
+    assert_eq!(
+        json_of(app.route("/status", ""))["battery"]["last_error"],
+        Value::Null
+    );
+}
@@ -0,0 +1,205 @@
+//! The software JPEG codec: camera photos from `tests/fixtures` decoded, scaled and
+//! re-encoded, then synthetic round trips, padded rows, and inputs that aren't JPEGs.
+//!
+//! `tower.jpg` and `tower_grayscale.jpg` are photos from the `jpeg-decoder` crate's
+//! benchmarks (MIT or Apache-2.0), as a camera wrote them.
+
+use std::fs;
+use std::path::Path;
+
+use wrover::codec::{self, Image, PixelFormat};
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+/// Smooth diagonal gradients, which JPEG keeps close to the original
+fn gradient(width: u32, height: u32, format: PixelFormat) -> Image {
+    let mut image = Image::new(width, height, format);
+    for y in 0..height {
+        let row = image.row_mut(y);
+        for (x, px) in row.chunks_exact_mut(format.bytes_per_pixel()).enumerate() {
+            let x = x as u32;
+            let value = |a: u32, b: u32| ((a * 255 / width + b * 255 / height) / 2) as u8;
+            match format {
+                PixelFormat::Grayscale => px[0] = value(x, y),
+                PixelFormat::Rgb888 => {
+                    px[0] = value(x, y);
+                    px[1] = value(width - 1 - x, y);
+                    px[2] = 128;
+                }
+            }
+        }
+    }
+    image
+}
+
+/// Averages `factor` x `factor` blocks, what scaled decoding approximates
+fn shrink(image: &Image, factor: u32) -> Image {
+    let bytes = image.format.bytes_per_pixel();
+    let mut out = Image::new(image.width / factor, image.height / factor, image.format);
+    for y in 0..out.height {
+        for x in 0..out.width as usize {
+            for channel in 0..bytes {
+                let sum: u32 = (0..factor)
+                    .flat_map(|dy| {
+                        let row = image.row(y * factor + dy);
+                        (0..factor as usize)
+                            .map(move |dx| row[(x * factor as usize + dx) * bytes + channel] as u32)
+                    })
+                    .sum();
+                out.row_mut(y)[x * bytes + channel] = (sum / (factor * factor)) as u8;
+            }
+        }
+    }
+    out
+}
+
+/// Mean absolute difference per byte
+fn difference(a: &Image, b: &Image) -> f32 {
+    assert_eq!((a.width, a.height, a.format), (b.width, b.height, b.format));
+    let total: u64 = (0..a.height)
+        .flat_map(|y| a.row(y).iter().zip(b.row(y)))
+        .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
+        .sum();
+    total as f32 / (a.width * a.height) as f32 / a.format.bytes_per_pixel() as f32
+}
+
+#[test]
+fn decodes_camera_photos() {
+    let jpeg = fixture("tower.jpg");
+    assert_eq!(codec::jpeg_dimensions(&jpeg).unwrap(), (512, 512));
+    let colour = codec::decode_jpeg(&jpeg).unwrap();
+    assert_eq!(
+        (colour.width, colour.height, colour.format),
+        (512, 512, PixelFormat::Rgb888)
+    );
+    assert_eq!(colour.stride, 512 * 3);
+    assert_eq!(colour.data.len(), 512 * 512 * 3);
+
+    let gray = codec::decode_jpeg(&fixture("tower_grayscale.jpg")).unwrap();
+    assert_eq!(
+        (gray.width, gray.height, gray.format),
+        (512, 512, PixelFormat::Grayscale)
+    );
+    // The same photo, so our luminance is close to the one it was saved with
+    let difference = difference(&colour.to_grayscale(), &gray);
+    assert!(difference < 3.0, "{}", difference);
+}
+
+#[test]
+fn re_encodes_camera_photos() {
+    for name in ["tower.jpg", "tower_grayscale.jpg"] {
+        let original = codec::decode_jpeg(&fixture(name)).unwrap();
+        let jpeg = codec::encode_jpeg(&original, 90).unwrap();
+        let decoded = codec::decode_jpeg(&jpeg).unwrap();
+        assert_eq!(decoded.format, original.format);
+        let fine = difference(&original, &decoded);
+        assert!(fine < 3.0, "{}: {}", name, fine);
+
+        // Lower quality: smaller, further off, still the same photo
+        let rough = codec::encode_jpeg(&original, 30).unwrap();
+        assert!(rough.len() < jpeg.len() / 2, "{}", name);
+        let rough = difference(&original, &codec::decode_jpeg(&rough).unwrap());
+        assert!(fine < rough && rough < 8.0, "{}: {}", name, rough);
+    }
+}
+
+#[test]
+fn decodes_camera_photos_scaled() {
+    for name in ["tower.jpg", "tower_grayscale.jpg"] {
+        let jpeg = fixture(name);
+        let full = codec::decode_jpeg(&jpeg).unwrap();
+        for factor in [2, 4, 8] {
+            let size = (512 / factor) as u16;
+            let scaled = codec::decode_jpeg_scaled(&jpeg, size, size).unwrap();
+            let expected = shrink(&full, factor);
+            let difference = difference(&expected, &scaled);
+            assert!(difference < 4.0, "{} at 1/{}: {}", name, factor, difference);
+        }
+    }
+}
+
+#[test]
+fn round_trips_colour() {
+    let image = gradient(64, 48, PixelFormat::Rgb888);
+    let jpeg = codec::encode_jpeg(&image, 90).unwrap();
+    assert_eq!(&jpeg[..2], [0xff, 0xd8]);
+    assert_eq!(codec::jpeg_dimensions(&jpeg).unwrap(), (64, 48));
+
+    let decoded = codec::decode_jpeg(&jpeg).unwrap();
+    assert_eq!(decoded.format, PixelFormat::Rgb888);
+    assert_eq!(decoded.stride, 64 * 3);
+    let difference = difference(&image, &decoded);
+    assert!(difference < 3.0, "{}", difference);
+}
+
+#[test]
+fn round_trips_grayscale() {
+    let image = gradient(40, 30, PixelFormat::Grayscale);
+    let jpeg = codec::encode_jpeg(&image, 90).unwrap();
+    let decoded = codec::decode_jpeg(&jpeg).unwrap();
+    assert_eq!(decoded.format, PixelFormat::Grayscale);
+    assert_eq!((decoded.width, decoded.height), (40, 30));
+    let difference = difference(&image, &decoded);
+    assert!(difference < 2.0, "{}", difference);
+}
+
+#[test]
+fn decodes_scaled_down() {
+    let jpeg = codec::encode_jpeg(&gradient(320, 240, PixelFormat::Rgb888), 80).unwrap();
+    let size = |width, height| {
+        let decoded = codec::decode_jpeg_scaled(&jpeg, width, height).unwrap();
+        assert_eq!(decoded.data.len(), decoded.stride * decoded.height as usize);
+        (decoded.width, decoded.height)
+    };
+    // Exactly 1/2, 1/4 and 1/8
+    assert_eq!(size(160, 120), (160, 120));
+    assert_eq!(size(80, 60), (80, 60));
+    assert_eq!(size(40, 30), (40, 30));
+    // Otherwise the smallest of those that still covers the size asked for
+    assert_eq!(size(100, 70), (160, 120));
+    assert_eq!(size(10, 10), (40, 30));
+    assert_eq!(size(640, 480), (320, 240));
+}
+
+#[test]
+fn encodes_padded_rows() {
+    let packed = gradient(30, 20, PixelFormat::Grayscale);
+    let mut data = vec![0xaa; 32 * 20];
+    for y in 0..20 {
+        data[y * 32..y * 32 + 30].copy_from_slice(packed.row(y as u32));
+    }
+    let padded = Image::from_raw(30, 20, 32, PixelFormat::Grayscale, data).unwrap();
+    assert_eq!(
+        codec::encode_jpeg(&padded, 85).unwrap(),
+        codec::encode_jpeg(&packed, 85).unwrap()
+    );
+
+    assert!(Image::from_raw(30, 20, 29, PixelFormat::Grayscale, vec![0; 600]).is_err());
+    assert!(Image::from_raw(30, 20, 32, PixelFormat::Grayscale, vec![0; 600]).is_err());
+    assert!(codec::encode_jpeg(&Image::new(70_000, 1, PixelFormat::Grayscale), 85).is_err());
+}
+
+#[test]
+fn rejects_what_isnt_a_jpeg() {
+    let jpeg = fixture("tower.jpg");
+    let garbage: Vec<u8> = (0..2000u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let inputs: [&[u8]; 5] = [
+        &[],
+        &garbage,
+        &jpeg[..2],
+        &jpeg[..jpeg.len() / 3],
+        // The start of one glued to the end of another
+        &[&jpeg[..20], &garbage[..100]].concat(),
+    ];
+    for input in inputs {
+        assert!(codec::decode_jpeg(input).is_err(), "{} bytes", input.len());
+        assert!(codec::decode_jpeg_scaled(input, 32, 24).is_err());
+    }
+    assert!(codec::jpeg_dimensions(&garbage).is_err());
+    assert!(codec::jpeg_dimensions(&[]).is_err());
+}
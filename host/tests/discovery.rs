@@ -0,0 +1,222 @@
+//! Home Assistant discovery payloads, and that they go out (retained) on connect
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::mqtt::discovery::{self, Device};
+use wrover::mqtt::{MqttConfig, MqttEvent, QoS, Topics};
+use wrover::settings::MqttSettings;
+use wrover_host::broker::MqttBroker;
+use wrover_host::mqtt::{self as host_mqtt, MqttOptions, TcpMqttClient};
+
+const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0xa1, 0xb2, 0xc3];
+
+fn configs(flash: bool, battery: bool) -> BTreeMap<String, Value> {
+    let device = Device::new("porch", "Freenove WROVER", Some(MAC), "unused");
+    let topics = Topics::new("home/porch");
+    discovery::messages("homeassistant/", &device, &topics, flash, battery)
+        .into_iter()
+        .collect()
+}
+
+#[test]
+fn node_id_comes_from_the_mac() {
+    let device = Device::new("porch", "Freenove WROVER", Some(MAC), "wrover-a1b2c3");
+    assert_eq!(device.node_id, "wrover_240ac4a1b2c3");
+    // Only the host has no MAC; anything Home Assistant won't take in a topic is replaced
+    let device = Device::new("porch", "Freenove WROVER", None, "wrover sim/1");
+    assert_eq!(device.node_id, "wrover_sim_1");
+}
+
+#[test]
+fn every_entity_has_a_config() {
+    let configs = configs(true, false);
+    let topics: Vec<_> = configs.keys().map(String::as_str).collect();
+    assert_eq!(
+        topics,
+        [
+            "homeassistant/binary_sensor/wrover_240ac4a1b2c3/motion/config",
+            "homeassistant/button/wrover_240ac4a1b2c3/snapshot/config",
+            "homeassistant/camera/wrover_240ac4a1b2c3/camera/config",
+            "homeassistant/light/wrover_240ac4a1b2c3/flash/config",
+            "homeassistant/sensor/wrover_240ac4a1b2c3/free_heap/config",
+            "homeassistant/sensor/wrover_240ac4a1b2c3/rssi/config",
+            "homeassistant/sensor/wrover_240ac4a1b2c3/uptime/config",
+        ]
+    );
+
+    for (topic, config) in &configs {
+        let object = topic.rsplit('/').nth(1).unwrap();
+        assert_eq!(
+            config["unique_id"],
+            format!("wrover_240ac4a1b2c3_{}", object)
+        );
+        assert_eq!(config["availability_topic"], "home/porch/status");
+        let device = &config["device"];
+        assert_eq!(device["identifiers"][0], "wrover_240ac4a1b2c3");
+        assert_eq!(device["name"], "porch");
+        assert_eq!(device["model"], "Freenove WROVER");
+        assert_eq!(device["connections"][0][1], "24:0a:c4:a1:b2:c3");
+    }
+
+    let camera = &configs["homeassistant/camera/wrover_240ac4a1b2c3/camera/config"];
+    assert_eq!(camera["topic"], "home/porch/snapshot");
+
+    let light = &configs["homeassistant/light/wrover_240ac4a1b2c3/flash/config"];
+    assert_eq!(light["command_topic"], "home/porch/cmd/flash");
+    assert_eq!(light["state_topic"], "home/porch/flash");
+    assert_eq!(light["payload_on"], "ON");
+    assert_eq!(
+        light["brightness_command_topic"],
+        "home/porch/cmd/flash_brightness"
+    );
+    assert_eq!(
+        light["brightness_state_topic"],
+        "home/porch/flash/brightness"
+    );
+
+    let motion = &configs["homeassistant/binary_sensor/wrover_240ac4a1b2c3/motion/config"];
+    assert_eq!(motion["state_topic"], "home/porch/motion");
+    assert_eq!(motion["device_class"], "motion");
+
+    let rssi = &configs["homeassistant/sensor/wrover_240ac4a1b2c3/rssi/config"];
+    assert_eq!(rssi["state_topic"], "home/porch/telemetry");
+    assert_eq!(rssi["value_template"], "{{ value_json.rssi }}");
+    assert_eq!(rssi["unit_of_measurement"], "dBm");
+    let uptime = &configs["homeassistant/sensor/wrover_240ac4a1b2c3/uptime/config"];
+    assert_eq!(uptime["value_template"], "{{ value_json.uptime_s }}");
+}
+
+#[test]
+fn no_light_without_a_flash() {
+    let configs = configs(false, false);
+    assert_eq!(configs.len(), 6);
+    assert!(configs.keys().all(|topic| !topic.contains("/light/")));
+}
+
+#[test]
+fn battery_sensors_with_a_battery() {
+    let configs = configs(false, true);
+    assert_eq!(configs.len(), 8);
+    let volts = &configs["homeassistant/sensor/wrover_240ac4a1b2c3/battery_voltage/config"];
+    assert_eq!(volts["value_template"], "{{ value_json.battery_volts }}");
+    assert_eq!(volts["unit_of_measurement"], "V");
+    let percent = &configs["homeassistant/sensor/wrover_240ac4a1b2c3/battery/config"];
+    assert_eq!(percent["device_class"], "battery");
+    assert_eq!(
+        percent["value_template"],
+        "{{ value_json.battery_percent }}"
+    );
+}
+
+#[test]
+fn unique_ids_survive_renames() {
+    let before = Device::new("porch", "Freenove WROVER", Some(MAC), "a");
+    let after = Device::new("garden", "Freenove WROVER", Some(MAC), "b");
+    let ids = |device: &Device, base: &str| -> Vec<Value> {
+        discovery::messages("homeassistant", device, &Topics::new(base), false, false)
+            .into_iter()
+            .map(|(_, config)| config["unique_id"].clone())
+            .collect()
+    };
+    assert_eq!(ids(&before, "home/porch"), ids(&after, "home/garden"));
+}
+
+#[test]
+fn discovery_can_be_turned_off() {
+    let settings = MqttSettings {
+        discovery: false,
+        ..Default::default()
+    };
+    let config = MqttConfig::from_settings(&settings, "wrover-a1b2c3");
+    assert_eq!(config.discovery_prefix, None);
+    let config = MqttConfig::from_settings(&MqttSettings::default(), "wrover-a1b2c3");
+    assert_eq!(config.discovery_prefix.as_deref(), Some("homeassistant"));
+}
+
+#[test]
+fn announced_on_connect() {
+    let addr = match std::env::var("MQTT_BROKER") {
+        Ok(addr) => std::net::ToSocketAddrs::to_socket_addrs(&addr)
+            .unwrap()
+            .next()
+            .unwrap(),
+        Err(_) => MqttBroker::bind("127.0.0.1:0").unwrap().spawn(),
+    };
+    static RUN: AtomicUsize = AtomicUsize::new(0);
+    let run = format!(
+        "{}-{}",
+        std::process::id(),
+        RUN.fetch_add(1, Ordering::Relaxed)
+    );
+    let prefix = format!("wrover-test/{}/homeassistant", run);
+    let node_id = format!("wrover-discovery-{}", run);
+
+    let (tx, rx) = mpsc::channel();
+    let observer =
+        TcpMqttClient::connect(addr, &MqttOptions::new(format!("observer-{}", run)), tx).unwrap();
+    observer
+        .subscribe(&format!("{}/#", prefix), QoS::AtLeastOnce)
+        .unwrap();
+
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let config = MqttConfig::from_settings(
+        &MqttSettings {
+            url: format!("mqtt://{}", addr),
+            base_topic: format!("wrover-test/{}/camera", run),
+            discovery_prefix: prefix.clone(),
+            ..Default::default()
+        },
+        &node_id,
+    );
+    let _mqtt = host_mqtt::start(Arc::downgrade(&app), config).unwrap();
+
+    // Six entities, no light: the test pattern has no flash
+    let mut seen = BTreeMap::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seen.len() < 6 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(left) {
+            Ok(MqttEvent::Message { topic, payload }) => {
+                let config: Value = serde_json::from_slice(&payload).unwrap();
+                seen.insert(topic, config);
+            }
+            Ok(_) => {}
+            Err(_) => panic!("Only got {:?}", seen.keys().collect::<Vec<_>>()),
+        }
+    }
+    let camera = &seen[&format!("{}/camera/{}/camera/config", prefix, node_id)];
+    assert_eq!(camera["device"]["name"], "wrover");
+    assert_eq!(
+        camera["topic"],
+        format!("wrover-test/{}/camera/snapshot", run)
+    );
+    assert!(seen.keys().all(|topic| !topic.contains("/light/")));
+
+    // Retained, so Home Assistant gets them whenever it turns up
+    let (tx, late) = mpsc::channel();
+    let latecomer =
+        TcpMqttClient::connect(addr, &MqttOptions::new(format!("late-{}", run)), tx).unwrap();
+    latecomer
+        .subscribe(&format!("{}/camera/#", prefix), QoS::AtLeastOnce)
+        .unwrap();
+    loop {
+        match late.recv_timeout(Duration::from_secs(5)) {
+            Ok(MqttEvent::Message { topic, .. }) => {
+                assert_eq!(
+                    topic,
+                    format!("{}/camera/{}/camera/config", prefix, node_id)
+                );
+                break;
+            }
+            Ok(_) => {}
+            Err(_) => panic!("Discovery config wasn't retained"),
+        }
+    }
+}
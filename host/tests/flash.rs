@@ -0,0 +1,199 @@
+//! The flash LED: settings checks and the duty cap, the max-on timer, lighting it for
+//! `/capture`, and `/flash` through `App`, all with a fake LED that remembers what it was told.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{json_of, wait_for};
+use serde_json::{json, Value};
+use wrover::auth::Scope;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::flash::{Flash, FlashControl};
+use wrover::http::{self, App};
+use wrover::settings::{FlashSettings, Settings};
+
+#[derive(Default)]
+struct Led {
+    on: bool,
+    brightness: Option<u8>,
+    /// Every `set`, in order
+    switches: Vec<bool>,
+}
+
+#[derive(Clone, Default)]
+struct FakeLed(Arc<Mutex<Led>>);
+
+impl Flash for FakeLed {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        let mut led = self.0.lock().unwrap();
+        led.on = on;
+        led.switches.push(on);
+        Ok(())
+    }
+
+    fn is_on(&self) -> bool {
+        self.0.lock().unwrap().on
+    }
+
+    fn set_brightness(&mut self, percent: u8) -> anyhow::Result<()> {
+        self.0.lock().unwrap().brightness = Some(percent);
+        Ok(())
+    }
+}
+
+#[test]
+fn settings_are_checked() {
+    let settings = FlashSettings::default();
+    assert!(settings.validate().is_ok());
+    assert_eq!(settings.duty(), 50);
+
+    // Capped, however bright it's asked to be
+    let bright = FlashSettings {
+        brightness: 100,
+        max_brightness: 40,
+        ..settings.clone()
+    };
+    assert_eq!(bright.duty(), 40);
+
+    for bad in [
+        FlashSettings {
+            brightness: 0,
+            ..settings.clone()
+        },
+        FlashSettings {
+            brightness: 101,
+            ..settings.clone()
+        },
+        FlashSettings {
+            max_brightness: 0,
+            ..settings.clone()
+        },
+        FlashSettings {
+            max_on_secs: 0,
+            ..settings.clone()
+        },
+        FlashSettings {
+            max_on_secs: 3601,
+            ..settings
+        },
+    ] {
+        assert!(bad.validate().is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn turns_itself_off() {
+    let led = FakeLed::default();
+    let settings = FlashSettings {
+        brightness: 90,
+        max_brightness: 70,
+        max_on_secs: 1,
+        ..Default::default()
+    };
+    let control = FlashControl::spawn(Box::new(led.clone()), settings);
+    assert_eq!(led.0.lock().unwrap().brightness, Some(70));
+
+    let started = Instant::now();
+    control.set(true).unwrap();
+    let status: Value = serde_json::from_str(&control.status_json()).unwrap();
+    assert_eq!(status["on"], true);
+    assert!(status["off_in_ms"].as_u64().unwrap() <= 1000);
+
+    wait_for("the max-on timer", || !control.is_on());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    let status: Value = serde_json::from_str(&control.status_json()).unwrap();
+    assert_eq!(status["off_in_ms"], Value::Null);
+
+    // Switching it on again while on doesn't restart the clock
+    control.set(true).unwrap();
+    thread::sleep(Duration::from_millis(600));
+    control.set(true).unwrap();
+    thread::sleep(Duration::from_millis(900));
+    assert!(!control.is_on());
+}
+
+#[test]
+fn lit_for_a_moment() {
+    let led = FakeLed::default();
+    let control = FlashControl::spawn(Box::new(led.clone()), FlashSettings::default());
+    assert!(control.lit(|| control.is_on()).unwrap());
+    assert!(!control.is_on());
+
+    // Left alone if it was already on
+    control.set(true).unwrap();
+    control.lit(|| ()).unwrap();
+    assert!(control.is_on());
+    assert_eq!(led.0.lock().unwrap().switches, [true, false, true]);
+}
+
+#[test]
+fn flash_over_http() {
+    let app = App::new(TestPattern::new(FrameSize::Qvga, 30.0));
+    app.set_settings(Settings::default(), None);
+    assert_eq!(app.route("/flash", "").status, 404);
+    assert_eq!(app.post("/flash", b"{}").status, 404);
+    assert!(app.switch_flash(true).is_err());
+
+    let led = FakeLed::default();
+    app.set_flash(Some(Box::new(led.clone())));
+    let status = json_of(app.route("/flash", ""));
+    assert_eq!(status["on"], false);
+    assert_eq!(status["duty"], 50);
+    assert_eq!(status["settings"]["max_on_secs"], 60);
+
+    assert_eq!(json_of(app.route("/flash", "action=on"))["on"], true);
+    assert_eq!(json_of(app.route("/flash", "action=toggle"))["on"], false);
+    assert_eq!(app.route("/flash", "action=blink").status, 400);
+    assert_eq!(app.flash_state(), Some(false));
+
+    let settings = json!({"brightness": 80, "max_brightness": 70, "on_capture": true});
+    let status = json_of(app.post("/flash", settings.to_string().as_bytes()));
+    assert_eq!(status["duty"], 70);
+    assert_eq!(led.0.lock().unwrap().brightness, Some(70));
+    assert_eq!(app.settings().flash.brightness, 80);
+    assert!(app.settings().flash.on_capture);
+
+    // Bad settings change nothing
+    let bad = json!({"brightness": 0});
+    assert_eq!(app.post("/flash", bad.to_string().as_bytes()).status, 400);
+    assert_eq!(app.post("/flash", b"bright").status, 400);
+    assert_eq!(app.settings().flash.brightness, 80);
+
+    // Settings loaded later reach the LED too
+    let mut settings = Settings::default();
+    settings.flash.brightness = 20;
+    app.set_settings(settings, None);
+    assert_eq!(led.0.lock().unwrap().brightness, Some(20));
+}
+
+#[test]
+fn flashes_for_captures() {
+    let app = App::new(TestPattern::new(FrameSize::Qvga, 30.0));
+    let led = FakeLed::default();
+    app.set_flash(Some(Box::new(led.clone())));
+
+    assert_eq!(app.capture().status, 200);
+    assert!(led.0.lock().unwrap().switches.is_empty());
+
+    app.configure_flash(|flash| flash.on_capture = true)
+        .unwrap();
+    let frames = app.frame_count();
+    assert_eq!(app.capture().status, 200);
+    assert_eq!(led.0.lock().unwrap().switches, [true, false]);
+    // Only the lit frame counts, not the ones exposure settled on
+    assert_eq!(app.frame_count(), frames + 1);
+    assert_eq!(app.flash_state(), Some(false));
+}
+
+#[test]
+fn only_admins_switch_the_flash() {
+    assert_eq!(http::required_scope(false, "/flash", ""), Scope::Read);
+    assert_eq!(
+        http::required_scope(false, "/flash", "action=on"),
+        Scope::Admin
+    );
+    assert_eq!(http::required_scope(true, "/flash", ""), Scope::Admin);
+}
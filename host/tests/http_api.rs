@@ -0,0 +1,214 @@
+//! Exercises the HTTP API end to end against the simulator, no board needed.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpStream};
+
+use common::{get, open, read_head, wait_for};
+use wrover::camera::{Camera, FrameSize, PlaybackCamera, TestPattern};
+use wrover::codec::{self, Image, PixelFormat};
+use wrover::http::{App, STREAM_BOUNDARY};
+use wrover_host::server::HostServer;
+
+fn start(camera: impl Camera + 'static) -> SocketAddr {
+    HostServer::bind("127.0.0.1:0", App::new(camera))
+        .unwrap()
+        .spawn()
+}
+
+fn start_pattern() -> SocketAddr {
+    start(TestPattern::new(FrameSize::Qqvga, 30.0))
+}
+
+fn status_json(addr: SocketAddr) -> serde_json::Value {
+    let response = get(addr, "/status");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("application/json"));
+    serde_json::from_slice(&response.body).expect("status is not valid JSON")
+}
+
+/// Reads one `multipart/x-mixed-replace` part and returns its body
+fn read_part(reader: &mut BufReader<TcpStream>) -> Vec<u8> {
+    let mut line = String::new();
+    // Blank line, then the boundary
+    while line.trim().is_empty() {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    assert_eq!(line.trim_end(), format!("--{}", STREAM_BOUNDARY));
+
+    let mut content_type = None;
+    let mut len = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(": ") {
+            Some(("Content-Type", v)) => content_type = Some(v.to_owned()),
+            Some(("Content-Length", v)) => len = Some(v.parse::<usize>().unwrap()),
+            _ => {}
+        }
+    }
+
+    assert_eq!(content_type.as_deref(), Some("image/jpeg"));
+    let mut body = vec![0; len.expect("part without Content-Length")];
+    reader.read_exact(&mut body).unwrap();
+    body
+}
+
+#[test]
+fn capture_returns_a_jpeg() {
+    let addr = start_pattern();
+
+    let response = get(addr, "/capture");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("image/jpeg"));
+
+    let image = codec::decode_jpeg(&response.body).unwrap();
+    assert_eq!((image.width, image.height), FrameSize::Qqvga.dimensions());
+}
+
+#[test]
+fn stream_is_multipart_jpeg() {
+    let addr = start_pattern();
+
+    let mut reader = open(addr, "GET", "/stream", &[], b"");
+    let (status, headers) = read_head(&mut reader);
+    assert_eq!(status, 200);
+    let content_type = &headers.iter().find(|(k, _)| k == "Content-Type").unwrap().1;
+    assert_eq!(
+        content_type,
+        &format!("multipart/x-mixed-replace; boundary={}", STREAM_BOUNDARY)
+    );
+
+    for _ in 0..3 {
+        codec::decode_jpeg(&read_part(&mut reader)).unwrap();
+    }
+}
+
+#[test]
+fn status_lists_controls() {
+    let addr = start_pattern();
+    let status = status_json(addr);
+
+    assert!(status["uptime_ms"].is_u64());
+    assert_eq!(status["clients"], 0);
+    assert_eq!(status["framesize"], FrameSize::Qqvga.index());
+    for key in [
+        "quality",
+        "brightness",
+        "contrast",
+        "saturation",
+        "hmirror",
+        "vflip",
+    ] {
+        assert!(status[key].is_i64(), "missing {}", key);
+    }
+}
+
+#[test]
+fn control_changes_the_camera() {
+    let addr = start_pattern();
+
+    let response = get(
+        addr,
+        &format!("/control?var=framesize&val={}", FrameSize::Qvga.index()),
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(status_json(addr)["framesize"], FrameSize::Qvga.index());
+
+    let image = codec::decode_jpeg(&get(addr, "/capture").body).unwrap();
+    assert_eq!((image.width, image.height), FrameSize::Qvga.dimensions());
+}
+
+#[test]
+fn control_rejects_bad_requests() {
+    let addr = start_pattern();
+
+    for path in [
+        "/control",
+        "/control?var=framesize",
+        "/control?var=nope&val=1",
+        "/control?var=quality&val=abc",
+        "/control?var=quality&val=99",
+        "/control?var=framesize&val=-1",
+    ] {
+        assert_eq!(get(addr, path).status, 400, "{}", path);
+    }
+    assert_eq!(status_json(addr)["quality"], 12);
+}
+
+#[test]
+fn unknown_route_is_404() {
+    let addr = start_pattern();
+    assert_eq!(get(addr, "/nope").status, 404);
+}
+
+#[test]
+fn several_stream_clients_at_once() {
+    let addr = start_pattern();
+
+    let mut clients: Vec<_> = (0..3)
+        .map(|_| {
+            let mut reader = open(addr, "GET", "/stream", &[], b"");
+            assert_eq!(read_head(&mut reader).0, 200);
+            reader
+        })
+        .collect();
+
+    for _ in 0..2 {
+        for client in &mut clients {
+            codec::decode_jpeg(&read_part(client)).unwrap();
+        }
+    }
+    assert_eq!(status_json(addr)["clients"], 3);
+
+    // The other endpoints keep working while streams are open
+    assert_eq!(get(addr, "/capture").status, 200);
+}
+
+#[test]
+fn disconnected_clients_are_released() {
+    let addr = start_pattern();
+
+    let mut reader = open(addr, "GET", "/stream", &[], b"");
+    read_head(&mut reader);
+    read_part(&mut reader);
+    assert_eq!(status_json(addr)["clients"], 1);
+
+    drop(reader);
+    wait_for("stream client to go away", || {
+        status_json(addr)["clients"] == 0
+    });
+
+    let mut reader = open(addr, "GET", "/stream", &[], b"");
+    read_head(&mut reader);
+    codec::decode_jpeg(&read_part(&mut reader)).unwrap();
+}
+
+#[test]
+fn playback_serves_recorded_frames_in_order() {
+    let dir = std::env::temp_dir().join(format!("wrover-playback-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (i, width) in [64, 96, 128].into_iter().enumerate() {
+        let jpeg = codec::encode_jpeg(&Image::new(width, 48, PixelFormat::Rgb888), 80).unwrap();
+        std::fs::write(dir.join(format!("frame{}.jpg", i)), jpeg).unwrap();
+    }
+    std::fs::write(dir.join("notes.txt"), "not a frame").unwrap();
+
+    let addr = start(PlaybackCamera::new(&dir, 50.0).unwrap());
+    let widths: Vec<_> = (0..4)
+        .map(|_| {
+            codec::decode_jpeg(&get(addr, "/capture").body)
+                .unwrap()
+                .width
+        })
+        .collect();
+    assert_eq!(widths, [64, 96, 128, 64]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,266 @@
+//! Mask rasterisation, and privacy masks applied to what the HTTP API hands out.
+
+mod common;
+
+use common::{get, request, temp_dir};
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::codec::{self, Image, PixelFormat};
+use wrover::http::App;
+use wrover::mask::{block_mask, rasterize, Bitmap, PrivacyMask, Shape};
+use wrover::settings::{FileSettings, MaskSettings, Settings};
+use wrover_host::server::HostServer;
+
+fn rect(x: f32, y: f32, width: f32, height: f32) -> Shape {
+    Shape::Rect {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+/// `#` for set pixels, one line per row
+fn art(bitmap: &Bitmap) -> Vec<String> {
+    (0..bitmap.height)
+        .map(|y| {
+            bitmap
+                .row(y)
+                .iter()
+                .map(|&bit| if bit { '#' } else { '.' })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn rectangles_cover_pixel_centres() {
+    let bitmap = rasterize(&[rect(0.25, 0.5, 0.5, 0.25)], 8, 8);
+    assert_eq!(
+        art(&bitmap),
+        [
+            "........", "........", "........", "........", "..####..", "..####..", "........",
+            "........",
+        ]
+    );
+
+    // An edge that falls between pixel centres rounds to the nearest centre
+    let bitmap = rasterize(&[rect(0.2, 0.0, 0.2, 0.1)], 10, 10);
+    assert_eq!(art(&bitmap)[0], "..##......");
+    assert_eq!(bitmap.count(), 2);
+}
+
+#[test]
+fn triangles_follow_the_diagonal() {
+    let triangle = Shape::Polygon(vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+    assert_eq!(
+        art(&rasterize(&[triangle], 4, 4)),
+        ["###.", "##..", "#...", "...."]
+    );
+}
+
+#[test]
+fn concave_polygons_leave_the_notch_open() {
+    // A U: the gap in the middle row stays clear
+    let u = Shape::Polygon(vec![
+        [0.0, 0.0],
+        [0.25, 0.0],
+        [0.25, 0.5],
+        [0.75, 0.5],
+        [0.75, 0.0],
+        [1.0, 0.0],
+        [1.0, 1.0],
+        [0.0, 1.0],
+    ]);
+    assert_eq!(
+        art(&rasterize(&[u], 4, 4)),
+        ["#..#", "#..#", "####", "####"]
+    );
+}
+
+#[test]
+fn self_intersecting_polygons_use_even_odd() {
+    // Two overlapping squares drawn as one outline: the overlap is a hole
+    let outline = Shape::Polygon(vec![
+        [0.0, 0.0],
+        [0.75, 0.0],
+        [0.75, 0.75],
+        [0.25, 0.75],
+        [0.25, 0.25],
+        [1.0, 0.25],
+        [1.0, 1.0],
+        [0.0, 1.0],
+    ]);
+    let bitmap = rasterize(&[outline], 4, 4);
+    assert!(!bitmap.get(1, 1));
+    assert!(!bitmap.get(2, 2));
+    assert!(bitmap.get(0, 0));
+    assert!(bitmap.get(3, 3));
+}
+
+#[test]
+fn vertices_on_a_scanline_count_once() {
+    // A diamond with its corners exactly on pixel centres (i + 0.5) / 8
+    let diamond = Shape::Polygon(vec![
+        [0.5625, 0.0625],
+        [0.9375, 0.4375],
+        [0.5625, 0.8125],
+        [0.1875, 0.4375],
+    ]);
+    assert_eq!(
+        art(&rasterize(&[diamond], 8, 8)),
+        [
+            "........", "...##...", "..####..", ".######.", "..####..", "...##...", "........",
+            "........",
+        ]
+    );
+}
+
+#[test]
+fn shapes_are_clipped_and_combined() {
+    let shapes = [
+        rect(-1.0, -1.0, 1.25, 1.25),
+        Shape::Polygon(vec![[0.75, 0.75], [5.0, 0.75], [5.0, 5.0], [0.75, 5.0]]),
+        // Off the frame entirely
+        rect(2.0, 2.0, 1.0, 1.0),
+    ];
+    assert_eq!(
+        art(&rasterize(&shapes, 4, 4)),
+        ["#...", "....", "....", "...#"]
+    );
+}
+
+#[test]
+fn blocks_are_masked_when_mostly_covered() {
+    // 4x2 blocks of 4px; the left third covers block 0 fully, block 1 a third
+    let mask = block_mask(&[rect(0.0, 0.0, 1.0 / 3.0, 1.0)], 4, 2, 4);
+    assert!(mask.is_ignored(0, 0) && mask.is_ignored(0, 1));
+    assert!(!mask.is_ignored(1, 0) && !mask.is_ignored(1, 1));
+    assert_eq!(mask.ignored_count(), 2);
+
+    let mask = block_mask(&[rect(0.0, 0.0, 0.45, 1.0)], 4, 2, 4);
+    assert!(mask.is_ignored(1, 0));
+}
+
+#[test]
+fn rejects_bad_shapes() {
+    assert!(Shape::Polygon(vec![[0.0, 0.0], [1.0, 1.0]])
+        .validate()
+        .is_err());
+    assert!(
+        Shape::Polygon(vec![[0.0, 0.0], [1.0, f32::NAN], [0.0, 1.0]])
+            .validate()
+            .is_err()
+    );
+    assert!(rect(0.0, 0.0, 0.0, 1.0).validate().is_err());
+    assert!(rect(0.1, 0.1, 0.5, 0.5).validate().is_ok());
+}
+
+#[test]
+fn masks_are_saved_as_tagged_json() {
+    let masks = MaskSettings {
+        privacy: vec![rect(0.0, 0.0, 0.5, 0.25)],
+        motion: vec![Shape::Polygon(vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]])],
+    };
+    let json = serde_json::to_value(&masks).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "privacy": [{"rect": {"x": 0.0, "y": 0.0, "width": 0.5, "height": 0.25}}],
+            "motion": [{"polygon": [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]}],
+        })
+    );
+
+    // Older settings without masks still load
+    let settings = Settings::from_json(br#"{"camera_name": "porch"}"#).unwrap();
+    assert_eq!(settings.masks, MaskSettings::default());
+}
+
+#[test]
+fn privacy_mask_blacks_out_pixels() {
+    let mut image = Image::new(4, 2, PixelFormat::Rgb888);
+    image.data.fill(200);
+    let mut privacy = PrivacyMask::new(vec![rect(0.5, 0.0, 0.5, 0.5)]);
+    privacy.apply(&mut image);
+    assert_eq!(
+        image.row(0),
+        [200, 200, 200, 200, 200, 200, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(image.row(1), [200; 12]);
+
+    // A different frame size gets its own raster
+    let mut gray = Image::new(2, 2, PixelFormat::Grayscale);
+    gray.data.fill(200);
+    privacy.apply(&mut gray);
+    assert_eq!(gray.data, [200, 0, 200, 200]);
+}
+
+/// Mean brightness of a block of a decoded frame
+fn mean(image: &Image, x: u32, y: u32, size: u32) -> u32 {
+    let gray = image.to_grayscale();
+    let mut sum = 0;
+    for row in y..y + size {
+        sum += gray.row(row)[x as usize..(x + size) as usize]
+            .iter()
+            .map(|&v| v as u32)
+            .sum::<u32>();
+    }
+    sum / (size * size)
+}
+
+#[test]
+fn masks_over_http() {
+    let dir = temp_dir("masks");
+    let path = dir.join("settings.json");
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    app.set_settings(
+        Settings::default(),
+        Some(Box::new(FileSettings::new(&path))),
+    );
+    let addr = HostServer::bind("127.0.0.1:0", app.clone())
+        .unwrap()
+        .spawn();
+
+    let masks = get(addr, "/masks");
+    assert_eq!(masks.status, 200);
+    assert_eq!(masks.body, br#"{"privacy":[],"motion":[]}"#);
+
+    // The top half of the test pattern is white and yellow bars on the left
+    let before = codec::decode_jpeg(&get(addr, "/capture").body).unwrap();
+    assert!(mean(&before, 4, 4, 8) > 200);
+
+    let masks = br#"{"privacy":[{"rect":{"x":0,"y":0,"width":0.5,"height":1}}],"motion":[]}"#;
+    let saved = request(addr, "POST", "/masks", &[], masks);
+    assert_eq!(saved.status, 200, "{}", saved.text());
+
+    // Snapshots and stream frames are both masked
+    let after = codec::decode_jpeg(&get(addr, "/capture").body).unwrap();
+    assert!(mean(&after, 4, 4, 8) < 10);
+    // Magenta, on the right
+    assert!(mean(&after, 84, 4, 8) > 60);
+    let streamed = codec::decode_jpeg(&app.stream_frame().unwrap().data).unwrap();
+    assert!(mean(&streamed, 4, 60, 8) < 10);
+
+    // Saved, and loads back
+    let saved = FileSettings::new(&path).load().unwrap();
+    assert_eq!(saved.masks.privacy, [rect(0.0, 0.0, 0.5, 1.0)]);
+    assert_eq!(app.settings().masks, saved.masks);
+
+    let bad = request(addr, "POST", "/masks", &[], b"{\"privacy\": 3}");
+    assert_eq!(bad.status, 400);
+    let bad = request(
+        addr,
+        "POST",
+        "/masks",
+        &[],
+        br#"{"motion":[{"polygon":[[0,0]]}]}"#,
+    );
+    assert_eq!(bad.status, 400);
+    assert!(bad.text().contains("at least 3 points"));
+    // Rejected masks change nothing
+    assert_eq!(app.settings().masks, saved.masks);
+
+    let page = get(addr, "/masks/edit");
+    assert_eq!(page.status, 200);
+    assert!(page.text().contains("fetch(\"/masks\""));
+    assert_eq!(request(addr, "POST", "/capture", &[], b"").status, 404);
+}
@@ -0,0 +1,392 @@
+//! Motion detector run over recorded frame sequences, plus `/motion` over HTTP.
+//!
+//! Sequences are rendered (textured backdrop, sensor noise, a box for the thing that moves),
+//! saved as JPEGs and played back through `PlaybackCamera`, so the detector sees the same
+//! compression artifacts as it would on the board.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{get, get_json, temp_dir, wait_for};
+use wrover::camera::{Camera, FrameBuffer, FrameSize, PlaybackCamera, TestPattern};
+use wrover::codec::{self, Image, PixelFormat};
+use wrover::http::App;
+use wrover::motion::{shrink, BlockMask, Motion, MotionConfig, MotionDetector, MotionEvent};
+use wrover_host::server::HostServer;
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+/// 5 fps, what the board checks at by default
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// A box of `level` at `x`, `y`
+#[derive(Clone, Copy)]
+struct Object {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    level: u8,
+    /// Darken what's behind by `level` instead of painting over it
+    shadow: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Shot {
+    /// Added to every pixel, for lights going on
+    brightness: i32,
+    object: Option<Object>,
+}
+
+fn backdrop(x: u32, y: u32) -> i32 {
+    // Soft stripes and a gradient, so blocks aren't flat
+    80 + ((x * 7 + y * 13) % 48) as i32 + (y / 4) as i32
+}
+
+/// Renders one frame. `seed` varies the noise from frame to frame.
+fn render(shot: Shot, seed: u32) -> Image {
+    let mut image = Image::new(WIDTH, HEIGHT, PixelFormat::Grayscale);
+    let mut rng = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+    for y in 0..HEIGHT {
+        let row = image.row_mut(y);
+        for x in 0..WIDTH {
+            rng = rng.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = ((rng >> 16) % 7) as i32 - 3;
+            let inside = shot.object.is_some_and(|o| {
+                (o.x..o.x + o.width).contains(&x) && (o.y..o.y + o.height).contains(&y)
+            });
+            let level = match shot.object {
+                Some(object) if inside && object.shadow => backdrop(x, y) - object.level as i32,
+                Some(object) if inside => object.level as i32,
+                _ => backdrop(x, y),
+            };
+            row[x as usize] = (level + shot.brightness + noise).clamp(0, 255) as u8;
+        }
+    }
+    image
+}
+
+/// Saves the sequence as numbered JPEGs, like a clip pulled off the card
+fn record(name: &str, shots: &[Shot]) -> PathBuf {
+    let dir = temp_dir(name);
+    for (i, &shot) in shots.iter().enumerate() {
+        let jpeg = codec::encode_jpeg(&render(shot, i as u32), 80).unwrap();
+        fs::write(dir.join(format!("{:04}.jpg", i)), jpeg).unwrap();
+    }
+    dir
+}
+
+/// Plays a recording through the detector, returning events with the frame they came on
+fn replay(dir: &Path, count: usize, config: MotionConfig) -> Vec<(usize, MotionEvent)> {
+    let mut camera = PlaybackCamera::new(dir, 1000.0).unwrap();
+    let mut detector = MotionDetector::new(config).unwrap();
+    let mut events = Vec::new();
+    for i in 0..count {
+        let mut frame = camera.capture().unwrap();
+        frame.timestamp = INTERVAL * i as u32;
+        if let Some(event) = detector.feed(&frame).unwrap() {
+            events.push((i, event));
+        }
+    }
+    events
+}
+
+fn still(count: usize) -> Vec<Shot> {
+    vec![Shot::default(); count]
+}
+
+/// `count` frames of a person-sized box crossing the frame left to right
+fn walker(count: usize, y: u32, level: u8) -> Vec<Shot> {
+    (0..count as u32)
+        .map(|i| Shot {
+            brightness: 0,
+            object: Some(Object {
+                x: 10 + i * 6,
+                y,
+                width: 16,
+                height: 40,
+                level,
+                shadow: false,
+            }),
+        })
+        .collect()
+}
+
+fn sequence(parts: &[Vec<Shot>]) -> Vec<Shot> {
+    parts.concat()
+}
+
+fn kinds(events: &[(usize, MotionEvent)]) -> Vec<(usize, &'static str)> {
+    events
+        .iter()
+        .map(|(i, event)| match event {
+            MotionEvent::Started { .. } => (*i, "started"),
+            MotionEvent::Stopped { .. } => (*i, "stopped"),
+        })
+        .collect()
+}
+
+#[test]
+fn still_scene_never_triggers() {
+    let shots = still(60);
+    let dir = record("motion-still", &shots);
+    assert_eq!(
+        kinds(&replay(&dir, shots.len(), MotionConfig::default())),
+        []
+    );
+}
+
+#[test]
+fn walker_starts_and_stops() {
+    let shots = sequence(&[still(10), walker(20, 40, 230), still(40)]);
+    let dir = record("motion-walker", &shots);
+    let events = replay(&dir, shots.len(), MotionConfig::default());
+
+    // Two frames in a row to start; stops 5s (25 frames) after the last one with motion
+    assert_eq!(kinds(&events), [(11, "started"), (54, "stopped")]);
+    match events[0].1 {
+        MotionEvent::Started { at, area } => {
+            assert_eq!(at, INTERVAL * 11);
+            assert!(area > 0.01 && area < 0.2, "{}", area);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        events[1].1,
+        MotionEvent::Stopped {
+            at: INTERVAL * 54,
+            duration: INTERVAL * 43,
+        }
+    );
+}
+
+#[test]
+fn one_frame_glitch_is_ignored() {
+    let glitch = walker(1, 40, 230);
+    let shots = sequence(&[still(10), glitch, still(10)]);
+    let dir = record("motion-glitch", &shots);
+    assert_eq!(
+        kinds(&replay(&dir, shots.len(), MotionConfig::default())),
+        []
+    );
+
+    let config = MotionConfig {
+        start_frames: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        kinds(&replay(&dir, shots.len(), config))[0],
+        (10, "started")
+    );
+}
+
+#[test]
+fn lights_going_on_are_not_motion() {
+    let lit = vec![
+        Shot {
+            brightness: 60,
+            object: None,
+        };
+        20
+    ];
+    let shots = sequence(&[still(10), lit]);
+    let dir = record("motion-lights", &shots);
+    assert_eq!(
+        kinds(&replay(&dir, shots.len(), MotionConfig::default())),
+        []
+    );
+}
+
+#[test]
+fn masked_blocks_are_ignored() {
+    // Something pacing up and down the left edge only
+    let pacing: Vec<Shot> = (0..20)
+        .map(|i| Shot {
+            brightness: 0,
+            object: Some(Object {
+                x: 4,
+                y: 10 + (i % 5) * 12,
+                width: 20,
+                height: 30,
+                level: 230,
+                shadow: false,
+            }),
+        })
+        .collect();
+    let shots = sequence(&[still(5), pacing]);
+    let dir = record("motion-mask", &shots);
+
+    assert_eq!(
+        kinds(&replay(&dir, shots.len(), MotionConfig::default()))[0].1,
+        "started"
+    );
+
+    let mut config = MotionConfig::default();
+    let mut mask = BlockMask::new(config.cols, config.rows);
+    // 160 px wide over 20 columns: the left 32 px are the first 4 columns
+    mask.ignore_rect(0, 0, 4, 100);
+    assert_eq!(mask.ignored_count(), 4 * 15);
+    config.mask = Some(mask);
+    assert_eq!(kinds(&replay(&dir, shots.len(), config)), []);
+}
+
+#[test]
+fn sensitivity_sets_the_smallest_change() {
+    // A shadow, barely darker than the wall it falls on
+    let faint: Vec<Shot> = walker(20, 40, 16)
+        .into_iter()
+        .map(|mut shot| {
+            shot.object.as_mut().unwrap().shadow = true;
+            shot
+        })
+        .collect();
+    let shots = sequence(&[still(5), faint]);
+    let dir = record("motion-faint", &shots);
+
+    let with = |sensitivity| {
+        let config = MotionConfig {
+            sensitivity,
+            ..Default::default()
+        };
+        kinds(&replay(&dir, shots.len(), config))
+    };
+    assert_eq!(with(95).first().map(|e| e.1), Some("started"));
+    assert_eq!(with(20), []);
+}
+
+#[test]
+fn parked_object_fades_into_the_background() {
+    let mut detector = MotionDetector::new(MotionConfig::default()).unwrap();
+    let parked = Shot {
+        brightness: 0,
+        object: Some(Object {
+            x: 40,
+            y: 40,
+            width: 40,
+            height: 30,
+            level: 240,
+            shadow: false,
+        }),
+    };
+
+    let mut events = Vec::new();
+    for i in 0..1000u32 {
+        let shot = if i < 5 { Shot::default() } else { parked };
+        if let Some(event) = detector.process(&render(shot, i), INTERVAL * i) {
+            events.push((i as usize, event));
+        }
+    }
+
+    let kinds = kinds(&events);
+    assert_eq!(kinds.len(), 2, "{:?}", kinds);
+    assert_eq!(kinds[0], (6, "started"));
+    assert_eq!(kinds[1].1, "stopped");
+    // Slow enough that someone standing still for a bit doesn't vanish
+    assert!((200..800).contains(&kinds[1].0), "{:?}", kinds);
+}
+
+#[test]
+fn reset_ends_motion() {
+    let mut detector = MotionDetector::new(MotionConfig::default()).unwrap();
+    assert_eq!(detector.reset(), None);
+
+    let shots = sequence(&[still(3), walker(4, 40, 230)]);
+    for (i, &shot) in shots.iter().enumerate() {
+        detector.process(&render(shot, i as u32), INTERVAL * i as u32);
+    }
+    assert!(detector.is_active());
+    assert!(detector.changed_blocks().iter().any(|&changed| changed));
+
+    assert_eq!(
+        detector.reset(),
+        Some(MotionEvent::Stopped {
+            at: INTERVAL * 6,
+            duration: INTERVAL * 2,
+        })
+    );
+    assert!(!detector.is_active());
+    // The next frame only sets the background again
+    assert_eq!(detector.process(&render(shots[6], 99), INTERVAL * 7), None);
+}
+
+#[test]
+fn rejects_bad_config() {
+    let config = MotionConfig {
+        mask: Some(BlockMask::new(10, 10)),
+        ..Default::default()
+    };
+    assert!(MotionDetector::new(config).is_err());
+
+    let config = MotionConfig {
+        cols: 0,
+        ..Default::default()
+    };
+    assert!(MotionDetector::new(config).is_err());
+}
+
+#[test]
+fn shrink_averages_blocks() {
+    let mut image = Image::new(4, 4, PixelFormat::Grayscale);
+    for y in 0..4 {
+        for (x, px) in image.row_mut(y).iter_mut().enumerate() {
+            *px = (x as u32 * 10 + y * 40) as u8;
+        }
+    }
+    let small = shrink(&image, 2, 2);
+    assert_eq!(small.data, [25, 45, 105, 125]);
+
+    // Uneven ratios still cover every output pixel
+    let small = shrink(&image, 3, 3);
+    assert_eq!((small.width, small.height), (3, 3));
+    let big = shrink(&image, 8, 8);
+    assert_eq!(big.row(0)[..2], [0, 0]);
+}
+
+#[test]
+fn handlers_hear_about_motion() {
+    let shots = sequence(&[still(10), walker(20, 40, 230)]);
+    let dir = record("motion-http", &shots);
+    let app = App::new(PlaybackCamera::new(&dir, 100.0).unwrap());
+
+    let detector = MotionDetector::new(MotionConfig::default()).unwrap();
+    let motion = Motion::spawn(Arc::downgrade(&app), detector, 100.0, true);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    motion.add_handler({
+        let seen = seen.clone();
+        move |event: &MotionEvent, frame: &FrameBuffer| {
+            assert!(!frame.data.is_empty());
+            seen.lock().unwrap().push(*event);
+        }
+    });
+    app.set_motion(Some(motion.clone()));
+    let addr = HostServer::bind("127.0.0.1:0", app).unwrap().spawn();
+
+    wait_for("motion", || !seen.lock().unwrap().is_empty());
+    let status = get_json(addr, "/motion");
+    assert_eq!(status["enabled"], true);
+    assert_eq!(status["active"], true);
+    assert_eq!(status["events"], 1);
+    assert_eq!(status["sensitivity"], 60);
+
+    // Turning it off mid-motion closes the event off
+    let status = get_json(addr, "/motion?action=disable");
+    assert_eq!(status["enabled"], false);
+    wait_for("stop", || !motion.is_active());
+    let seen = seen.lock().unwrap().clone();
+    assert!(matches!(seen[0], MotionEvent::Started { .. }), "{:?}", seen);
+    assert!(matches!(seen[1], MotionEvent::Stopped { .. }), "{:?}", seen);
+
+    assert_eq!(get_json(addr, "/motion?action=enable")["enabled"], true);
+    assert_eq!(get(addr, "/motion?action=dance").status, 400);
+}
+
+#[test]
+fn motion_is_404_when_not_set_up() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let addr = HostServer::bind("127.0.0.1:0", app).unwrap().spawn();
+    assert_eq!(get(addr, "/motion").status, 404);
+}
@@ -0,0 +1,387 @@
+//! MQTT status, events and commands, against the stand-in broker. Set `MQTT_BROKER` to a
+//! `host:port` to run them against a real one (e.g. a local Mosquitto) instead.
+
+use std::io::Cursor;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use wrover::camera::{Camera, Control, FrameSize, TestPattern};
+use wrover::flash::Flash;
+use wrover::http::App;
+use wrover::motion::{MotionEvent, MotionHandler, PublishOnMotion};
+use wrover::mqtt::{Command, MqttConfig, MqttEvent, QoS, Switch, Topics, Will};
+use wrover::settings::MqttSettings;
+use wrover_host::broker::{self, MqttBroker};
+use wrover_host::mqtt::{self as host_mqtt, MqttOptions, Packet, TcpMqttClient};
+
+fn broker() -> SocketAddr {
+    match std::env::var("MQTT_BROKER") {
+        Ok(addr) => addr.to_socket_addrs().unwrap().next().unwrap(),
+        Err(_) => MqttBroker::bind("127.0.0.1:0").unwrap().spawn(),
+    }
+}
+
+/// A topic base no other test (or earlier run, on a real broker) uses
+fn base(name: &str) -> String {
+    static RUN: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "wrover-test/{}-{}/{}",
+        std::process::id(),
+        RUN.fetch_add(1, Ordering::Relaxed),
+        name
+    )
+}
+
+/// Another client, watching what gets published
+struct Observer {
+    client: TcpMqttClient,
+    rx: Receiver<MqttEvent>,
+}
+
+impl Observer {
+    /// Subscribed to everything under `base` by the time this returns
+    fn new(addr: SocketAddr, base: &str) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let id = format!("observer-{}", base.replace('/', "-"));
+        let client = TcpMqttClient::connect(addr, &MqttOptions::new(id), tx).unwrap();
+        client
+            .subscribe(&format!("{}/#", base), QoS::AtLeastOnce)
+            .unwrap();
+        let observer = Self { client, rx };
+
+        // A broker handles a client's packets in order, so once our own message comes
+        // back the subscription is in place
+        let marker = format!("{}/marker", base);
+        observer.publish(&marker, b"x");
+        observer.expect(&marker);
+        observer
+    }
+
+    fn publish(&self, topic: &str, payload: &[u8]) {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .unwrap();
+    }
+
+    /// Payload of the next message on `topic`, skipping others
+    fn expect(&self, topic: &str) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(left) {
+                Ok(MqttEvent::Message { topic: t, payload }) if t == topic => return payload,
+                Ok(_) => {}
+                Err(_) => panic!("Nothing published on {}", topic),
+            }
+        }
+    }
+
+    fn expect_json(&self, topic: &str) -> serde_json::Value {
+        serde_json::from_slice(&self.expect(topic)).unwrap()
+    }
+
+    /// True if nothing arrives on `topic` for `wait`
+    fn quiet(&self, topic: &str, wait: Duration) -> bool {
+        let deadline = Instant::now() + wait;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(left) {
+                Ok(MqttEvent::Message { topic: t, .. }) if t == topic => return false,
+                Ok(_) => {}
+                Err(_) => return true,
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct FakeFlash(bool);
+
+impl Flash for FakeFlash {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        self.0 = on;
+        Ok(())
+    }
+
+    fn is_on(&self) -> bool {
+        self.0
+    }
+}
+
+#[test]
+fn topics_hang_off_the_base() {
+    let topics = Topics::new("home/porch/");
+    assert_eq!(topics.base(), "home/porch");
+    assert_eq!(topics.status(), "home/porch/status");
+    assert_eq!(topics.commands(), "home/porch/cmd/+");
+    assert_eq!(topics.command_name("home/porch/cmd/flash"), Some("flash"));
+    assert_eq!(topics.command_name("home/porch/result"), None);
+    assert_eq!(topics.command_name("home/porch/cmd/"), None);
+    assert_eq!(topics.command_name("home/porch/cmd/a/b"), None);
+    assert_eq!(topics.command_name("home/other/cmd/flash"), None);
+}
+
+#[test]
+fn commands_parse() {
+    assert_eq!(Command::parse("snapshot", b"").unwrap(), Command::Snapshot);
+    assert_eq!(
+        Command::parse("resolution", b"vga").unwrap(),
+        Command::Resolution(FrameSize::Vga)
+    );
+    assert_eq!(
+        Command::parse("resolution", b" 4\n").unwrap(),
+        Command::Resolution(FrameSize::Qvga)
+    );
+    assert!(Command::parse("resolution", b"huge").is_err());
+    assert!(Command::parse("resolution", b"99").is_err());
+    assert_eq!(
+        Command::parse("flash", b"ON").unwrap(),
+        Command::Flash(Switch::On)
+    );
+    assert_eq!(
+        Command::parse("flash", b"off").unwrap(),
+        Command::Flash(Switch::Off)
+    );
+    assert_eq!(
+        Command::parse("flash", b"").unwrap(),
+        Command::Flash(Switch::Toggle)
+    );
+    assert!(Command::parse("flash", b"dim").is_err());
+    assert_eq!(Command::parse("reboot", b"now").unwrap(), Command::Reboot);
+    assert!(Command::parse("explode", b"").is_err());
+    assert!(Command::parse("flash", &[0xff, 0xfe]).is_err());
+    assert_eq!(
+        Command::parse("flash_brightness", b" 40 ").unwrap(),
+        Command::FlashBrightness(40)
+    );
+    assert!(Command::parse("flash_brightness", b"0").is_err());
+    assert!(Command::parse("flash_brightness", b"101").is_err());
+    assert!(Command::parse("flash_brightness", b"bright").is_err());
+}
+
+#[test]
+fn config_defaults_come_from_the_device_id() {
+    let config = MqttConfig::from_settings(&MqttSettings::default(), "wrover-a1b2c3");
+    assert_eq!(config.client_id, "wrover-a1b2c3");
+    assert_eq!(config.topics.base(), "wrover/wrover-a1b2c3");
+    assert_eq!(config.username, None);
+    assert_eq!(config.telemetry_interval, Duration::from_secs(60));
+    assert_eq!(
+        config.host_port().unwrap(),
+        ("homeassistant.local".to_owned(), 1883)
+    );
+    assert_eq!(
+        config.will(),
+        Will {
+            topic: "wrover/wrover-a1b2c3/status".into(),
+            payload: b"offline".to_vec(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    );
+
+    let settings = MqttSettings {
+        url: "mqtt://10.0.0.2:1884".into(),
+        client_id: "porch".into(),
+        username: "cam".into(),
+        base_topic: "home/porch".into(),
+        ..Default::default()
+    };
+    let config = MqttConfig::from_settings(&settings, "wrover-a1b2c3");
+    assert_eq!(config.client_id, "porch");
+    assert_eq!(config.topics.base(), "home/porch");
+    assert_eq!(config.username.as_deref(), Some("cam"));
+    assert_eq!(config.password, None);
+    assert_eq!(config.host_port().unwrap(), ("10.0.0.2".to_owned(), 1884));
+}
+
+#[test]
+fn packets_round_trip() {
+    // Lengths that take one, two and three bytes to encode
+    for len in [0, 127, 128, 16_383, 16_384, 100_000] {
+        let packet = Packet::publish("a/b", QoS::AtLeastOnce, true, 7, &vec![1; len]);
+        let bytes = packet.to_bytes();
+        let read = Packet::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(read, packet);
+        let (topic, qos, retain, id, payload) = read.parse_publish().unwrap();
+        assert_eq!(
+            (topic.as_str(), qos, retain, id, payload.len()),
+            ("a/b", QoS::AtLeastOnce, true, 7, len)
+        );
+    }
+
+    let bytes = Packet::publish("t", QoS::AtMostOnce, false, 9, b"hi").to_bytes();
+    assert_eq!(bytes, [0x30, 5, 0, 1, b't', b'h', b'i']);
+    assert!(Packet::read(&mut Cursor::new(&bytes[..4])).is_err());
+}
+
+#[test]
+fn topic_filters() {
+    assert!(broker::matches("a/b", "a/b"));
+    assert!(!broker::matches("a/b", "a/b/c"));
+    assert!(broker::matches("a/+/c", "a/b/c"));
+    assert!(!broker::matches("a/+", "a/b/c"));
+    assert!(broker::matches("a/#", "a/b/c"));
+    assert!(broker::matches("#", "a"));
+    assert!(!broker::matches("a/b/c", "a/b"));
+}
+
+#[test]
+fn last_will_goes_out_when_the_connection_drops() {
+    let addr = broker();
+    let base = base("will");
+    let observer = Observer::new(addr, &base);
+    let options = |name: &str| {
+        let mut options = MqttOptions::new(format!("{}-{}", base.replace('/', "-"), name));
+        options.will = Some(Will {
+            topic: format!("{}/{}", base, name),
+            payload: b"offline".to_vec(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        });
+        options
+    };
+
+    let (tx, _rx) = mpsc::channel();
+    let polite = TcpMqttClient::connect(addr, &options("polite"), tx.clone()).unwrap();
+    polite.disconnect().unwrap();
+    let rude = TcpMqttClient::connect(addr, &options("rude"), tx).unwrap();
+    rude.abort();
+
+    assert_eq!(observer.expect(&format!("{}/rude", base)), b"offline");
+    assert!(observer.quiet(&format!("{}/polite", base), Duration::from_millis(300)));
+}
+
+#[test]
+fn camera_over_mqtt() {
+    let addr = broker();
+    let base = base("camera");
+    let observer = Observer::new(addr, &base);
+    let topics = Topics::new(&base);
+
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    app.set_flash(Some(Box::<FakeFlash>::default()));
+    let mut config = MqttConfig::from_settings(
+        &MqttSettings {
+            url: format!("mqtt://{}", addr),
+            base_topic: base.clone(),
+            motion_snapshots: true,
+            ..Default::default()
+        },
+        &base.replace('/', "-"),
+    );
+    config.telemetry_interval = Duration::from_millis(200);
+    let mqtt = host_mqtt::start(Arc::downgrade(&app), config).unwrap();
+
+    // Announced on connect
+    assert_eq!(observer.expect(&topics.status()), b"online");
+    assert_eq!(observer.expect(&topics.flash()), b"OFF");
+    let telemetry = observer.expect_json(&topics.telemetry());
+    assert_eq!(telemetry["resolution"], "QQVGA");
+    assert_eq!(telemetry["flash"], false);
+    assert!(telemetry["uptime_s"].is_u64());
+    // And again every interval
+    observer.expect_json(&topics.telemetry());
+    assert!(mqtt.is_connected());
+
+    observer.publish(&topics.command("snapshot"), b"");
+    let jpeg = observer.expect(&topics.snapshot());
+    assert_eq!(&jpeg[..2], [0xff, 0xd8]);
+    let result = observer.expect_json(&topics.result());
+    assert_eq!(result["command"], "snapshot");
+    assert_eq!(result["ok"], true);
+
+    observer.publish(&topics.command("resolution"), b"QVGA");
+    assert_eq!(observer.expect_json(&topics.result())["ok"], true);
+    assert_eq!(
+        app.control_value(Control::FrameSize),
+        FrameSize::Qvga.index()
+    );
+
+    observer.publish(&topics.command("resolution"), b"huge");
+    let result = observer.expect_json(&topics.result());
+    assert_eq!(result["ok"], false);
+    assert_eq!(result["error"], "Unknown resolution: huge");
+
+    observer.publish(&topics.command("flash"), b"ON");
+    assert_eq!(observer.expect(&topics.flash()), b"ON");
+    assert_eq!(app.flash_state(), Some(true));
+    observer.publish(&topics.command("flash"), b"TOGGLE");
+    assert_eq!(observer.expect(&topics.flash()), b"OFF");
+    assert_eq!(app.flash_state(), Some(false));
+    observer.publish(&topics.command("flash_brightness"), b"30");
+    // Past the 50s that went out with each switch
+    while observer.expect(&topics.flash_brightness()) != b"30" {}
+    assert_eq!(app.settings().flash.brightness, 30);
+
+    // Motion goes out as ON/OFF, JSON details and the frame that started it
+    let mut handler = PublishOnMotion(mqtt.clone());
+    let frame = TestPattern::new(FrameSize::Qqvga, 30.0).capture().unwrap();
+    let started = MotionEvent::Started {
+        at: Duration::from_millis(1500),
+        area: 0.25,
+    };
+    handler.event(&started, &frame);
+    assert_eq!(observer.expect(&topics.motion()), b"ON");
+    let event = observer.expect_json(&topics.events());
+    assert_eq!(event["event"], "motion_started");
+    assert_eq!(event["area"], 0.25);
+    assert_eq!(observer.expect(&topics.snapshot()), frame.data);
+    let stopped = MotionEvent::Stopped {
+        at: Duration::from_millis(9500),
+        duration: Duration::from_secs(8),
+    };
+    handler.event(&stopped, &frame);
+    assert_eq!(observer.expect(&topics.motion()), b"OFF");
+    assert_eq!(observer.expect_json(&topics.events())["duration_ms"], 8000);
+
+    let rebooted = Arc::new(AtomicBool::new(false));
+    let flag = rebooted.clone();
+    mqtt.on_reboot(move || flag.store(true, Ordering::Relaxed));
+    observer.publish(&topics.command("reboot"), b"");
+    assert_eq!(observer.expect_json(&topics.result())["ok"], true);
+    assert_eq!(observer.expect(&topics.status()), b"offline");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !rebooted.load(Ordering::Relaxed) {
+        assert!(Instant::now() < deadline, "never rebooted");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let status: serde_json::Value = serde_json::from_str(&mqtt.status_json()).unwrap();
+    assert_eq!(status["connected"], true);
+    assert_eq!(status["commands"], 7);
+    assert_eq!(status["failures"], 0);
+}
+
+#[test]
+fn unknown_commands_get_an_error_reply() {
+    let addr = broker();
+    let base = base("unknown");
+    let observer = Observer::new(addr, &base);
+    let topics = Topics::new(&base);
+
+    // No flash on this one
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let config = MqttConfig::from_settings(
+        &MqttSettings {
+            url: format!("mqtt://{}", addr),
+            base_topic: base.clone(),
+            ..Default::default()
+        },
+        &base.replace('/', "-"),
+    );
+    let _mqtt = host_mqtt::start(Arc::downgrade(&app), config).unwrap();
+    observer.expect(&topics.status());
+
+    observer.publish(&topics.command("explode"), b"");
+    let result = observer.expect_json(&topics.result());
+    assert_eq!(result["ok"], false);
+    assert_eq!(result["error"], "Unknown command: explode");
+
+    observer.publish(&topics.command("flash"), b"ON");
+    let result = observer.expect_json(&topics.result());
+    assert_eq!(result["error"], "This board has no flash LED");
+}
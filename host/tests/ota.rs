@@ -0,0 +1,400 @@
+//! Firmware updates: image headers, writing to a fake slot with the checks and failures in
+//! between, then uploads and pulls through `HostServer` with auth on.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use common::wait_for;
+use serde_json::{json, Value};
+use wrover::auth::Change;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::client::base64;
+use wrover::http::App;
+use wrover::ota::{Busy, ImageInfo, Ota, Slot, SlotWriter, State, HEADER_LEN};
+use wrover::settings::{AuthSettings, Settings};
+use wrover_host::client::TcpHttpClient;
+use wrover_host::server::HostServer;
+
+/// An app image as far as the header goes, padded out to `len` with a pattern
+fn image(project: &str, version: &str, chip_id: u16, len: usize) -> Vec<u8> {
+    let mut image: Vec<u8> = (0..len.max(HEADER_LEN)).map(|i| (i % 251) as u8).collect();
+    image[..32].fill(0);
+    image[0] = 0xe9;
+    image[1] = 4;
+    image[12..14].copy_from_slice(&chip_id.to_le_bytes());
+    let desc = &mut image[32..HEADER_LEN];
+    desc.fill(0);
+    desc[..4].copy_from_slice(&0xabcd_5432u32.to_le_bytes());
+    desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+    desc[48..48 + project.len()].copy_from_slice(project.as_bytes());
+    desc[112..118].copy_from_slice(b"v5.3.3");
+    image.truncate(len);
+    image
+}
+
+fn running() -> ImageInfo {
+    ImageInfo {
+        project: "wrover".into(),
+        version: "1.0.0".into(),
+        idf_version: "v5.3.3".into(),
+        chip_id: 0,
+    }
+}
+
+#[derive(Default)]
+struct Flash {
+    written: Vec<u8>,
+    completed: bool,
+    aborted: u32,
+    restarts: u32,
+}
+
+#[derive(Clone)]
+struct FakeSlot {
+    flash: Arc<Mutex<Flash>>,
+    capacity: usize,
+}
+
+impl FakeSlot {
+    fn new(capacity: usize) -> Self {
+        Self {
+            flash: Arc::default(),
+            capacity,
+        }
+    }
+}
+
+impl Slot for FakeSlot {
+    fn running(&self) -> ImageInfo {
+        running()
+    }
+
+    fn pending_verify(&self) -> bool {
+        false
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn begin(&mut self) -> anyhow::Result<Box<dyn SlotWriter + '_>> {
+        let mut flash = self.flash.lock().unwrap();
+        flash.written.clear();
+        flash.completed = false;
+        Ok(Box::new(FakeWriter {
+            flash: &self.flash,
+            done: false,
+        }))
+    }
+
+    fn restart(&mut self) {
+        self.flash.lock().unwrap().restarts += 1;
+    }
+}
+
+struct FakeWriter<'a> {
+    flash: &'a Mutex<Flash>,
+    done: bool,
+}
+
+impl SlotWriter for FakeWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.flash.lock().unwrap().written.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn complete(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.done = true;
+        self.flash.lock().unwrap().completed = true;
+        Ok(())
+    }
+}
+
+impl Drop for FakeWriter<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.flash.lock().unwrap().aborted += 1;
+        }
+    }
+}
+
+/// Hands out `data` a few odd-sized bytes at a time, the way sockets do
+fn reader(data: &[u8]) -> impl FnMut(&mut [u8]) -> anyhow::Result<usize> + '_ {
+    let mut offset = 0;
+    move |buf| {
+        let len = buf.len().min(1000).min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        offset += len;
+        Ok(len)
+    }
+}
+
+fn status(ota: &Ota) -> Value {
+    serde_json::from_str(&ota.status_json()).unwrap()
+}
+
+#[test]
+fn headers_parse() {
+    let info = ImageInfo::parse(&image("wrover", "1.2.3", 9, 1000)).unwrap();
+    assert_eq!(info.project, "wrover");
+    assert_eq!(info.version, "1.2.3");
+    assert_eq!(info.idf_version, "v5.3.3");
+    assert_eq!(info.chip_id, 9);
+
+    assert!(ImageInfo::parse(&image("wrover", "1", 0, HEADER_LEN - 1)).is_err());
+    let mut bad_magic = image("wrover", "1", 0, 1000);
+    bad_magic[0] = 0xe8;
+    assert!(ImageInfo::parse(&bad_magic).is_err());
+    let mut no_desc = image("wrover", "1", 0, 1000);
+    no_desc[32] = 0;
+    assert!(ImageInfo::parse(&no_desc).is_err());
+}
+
+#[test]
+fn writes_and_restarts() {
+    let slot = FakeSlot::new(100_000);
+    let ota = Ota::new(slot.clone(), None);
+    assert_eq!(status(&ota)["state"], "idle");
+    assert_eq!(status(&ota)["running"]["version"], "1.0.0");
+
+    let data = image("wrover", "1.1.0", 0, 50_000);
+    let info = ota.update(Some(data.len()), &mut reader(&data)).unwrap();
+    assert_eq!(info.version, "1.1.0");
+    {
+        let flash = slot.flash.lock().unwrap();
+        assert_eq!(flash.written, data);
+        assert!(flash.completed);
+    }
+
+    let status = status(&ota);
+    assert_eq!(status["state"], "restarting");
+    assert_eq!(status["received"], 50_000);
+    assert_eq!(status["image"]["version"], "1.1.0");
+    wait_for("the restart", || slot.flash.lock().unwrap().restarts == 1);
+
+    // Nothing more until it's back
+    let err = ota.update(None, &mut reader(&data)).unwrap_err();
+    assert!(err.is::<Busy>());
+}
+
+#[test]
+fn bad_images_are_abandoned() {
+    let slot = FakeSlot::new(10_000);
+    let ota = Ota::new(slot.clone(), None);
+    let attempt = |data: &[u8], total: Option<usize>| {
+        let err = ota.update(total, &mut reader(data)).unwrap_err();
+        assert_eq!(ota.state(), State::Failed);
+        err.to_string()
+    };
+
+    let other_chip = image("wrover", "2", 9, 5000);
+    assert!(attempt(&other_chip, None).contains("chip"));
+    let other_project = image("blinky", "2", 0, 5000);
+    assert!(attempt(&other_project, None).contains("blinky"));
+    let too_big = image("wrover", "2", 0, 12_000);
+    assert!(attempt(&too_big, Some(12_000)).contains("fit"));
+    // Without a length it's only found out on the way
+    assert!(attempt(&too_big, None).contains("fit"));
+    let cut_short = image("wrover", "2", 0, 5000);
+    assert!(attempt(&cut_short, Some(6000)).contains("5000 of 6000"));
+    assert!(attempt(&[0xe9; 100], None).contains("short"));
+
+    let flash = slot.flash.lock().unwrap();
+    assert!(!flash.completed);
+    // Only the two that got past the header were started on the slot at all
+    assert_eq!(flash.aborted, 2);
+    drop(flash);
+    assert_eq!(status(&ota)["error"], "Too short to be firmware");
+
+    // Failures don't block the next try
+    let good = image("wrover", "2", 0, 5000);
+    ota.update(None, &mut reader(&good)).unwrap();
+    assert!(slot.flash.lock().unwrap().completed);
+}
+
+#[test]
+fn read_errors_abandon_the_update() {
+    let slot = FakeSlot::new(100_000);
+    let ota = Ota::new(slot.clone(), None);
+    let data = image("wrover", "2", 0, 20_000);
+    let mut good = reader(&data);
+    let mut calls = 0;
+    let result = ota.update(None, &mut |buf| {
+        calls += 1;
+        if calls == 5 {
+            anyhow::bail!("Connection reset");
+        }
+        good(buf)
+    });
+    assert!(result.is_err());
+    assert_eq!(status(&ota)["error"], "Connection reset");
+    assert_eq!(slot.flash.lock().unwrap().aborted, 1);
+}
+
+/// Serves `body` (or a 404 if there isn't one) to every GET, for pulls
+fn file_server(body: Option<Vec<u8>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let response = match &body {
+                Some(body) => {
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                    [head.as_bytes(), body].concat()
+                }
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            stream.write_all(&response).unwrap();
+        }
+    });
+    addr
+}
+
+fn basic(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64(format!("{}:{}", username, password).as_bytes())
+    )
+}
+
+/// With `ann` as admin and `guest` read-only, unless `auth` is off
+fn serve(slot: FakeSlot, auth: bool) -> (Arc<App>, SocketAddr) {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let mut settings = AuthSettings::default();
+    for (name, scope) in [("ann", "admin"), ("guest", "read")] {
+        let user = json!({"action": "set_user", "name": name, "password": "pw", "scope": scope});
+        let change: Change = serde_json::from_value(user).unwrap();
+        change.apply(&mut settings).unwrap();
+    }
+    settings.enabled = auth;
+    app.set_settings(
+        Settings {
+            auth: settings,
+            ..Default::default()
+        },
+        None,
+    );
+    let fetch = TcpHttpClient::new(Duration::from_secs(5));
+    app.set_ota(Some(Ota::new(slot, Some(Box::new(fetch)))));
+    let addr = HostServer::bind("127.0.0.1:0", app.clone())
+        .unwrap()
+        .spawn();
+    (app, addr)
+}
+
+fn request(addr: SocketAddr, method: &str, target: &str, auth: &str, body: &[u8]) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: cam\r\nAuthorization: {}\r\nContent-Length: {}\r\n\r\n",
+        method,
+        target,
+        auth,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = serde_json::from_str(body).unwrap_or(Value::String(body.into()));
+    (status, body)
+}
+
+#[test]
+fn uploads_need_an_admin() {
+    let slot = FakeSlot::new(1_000_000);
+    let (_app, addr) = serve(slot.clone(), true);
+    let data = image("wrover", "3.0.0", 0, 300_000);
+
+    let (status, _) = request(addr, "POST", "/ota", &basic("guest", "pw"), &data);
+    assert_eq!(status, 403);
+    let (status, ota) = request(addr, "GET", "/ota", &basic("guest", "pw"), b"");
+    assert_eq!(status, 200);
+    assert_eq!(ota["state"], "idle");
+    assert_eq!(ota["pull"], true);
+
+    let (status, ota) = request(addr, "POST", "/ota", &basic("ann", "pw"), &data);
+    assert_eq!(status, 200, "{}", ota);
+    assert_eq!(ota["state"], "restarting");
+    assert_eq!(ota["image"]["version"], "3.0.0");
+    assert_eq!(slot.flash.lock().unwrap().written, data);
+    wait_for("the restart", || slot.flash.lock().unwrap().restarts == 1);
+
+    let (status, _) = request(addr, "POST", "/ota", &basic("ann", "pw"), &data);
+    assert_eq!(status, 409);
+}
+
+#[test]
+fn refused_without_auth() {
+    let slot = FakeSlot::new(1_000_000);
+    let (_app, addr) = serve(slot.clone(), false);
+    let data = image("wrover", "3.0.0", 0, 1000);
+    let (status, body) = request(addr, "POST", "/ota", "", &data);
+    assert_eq!(status, 403);
+    assert!(body.as_str().unwrap().contains("auth"));
+    let pull = json!({"url": "http://127.0.0.1:1/x.bin"}).to_string();
+    assert_eq!(
+        request(addr, "POST", "/ota/pull", "", pull.as_bytes()).0,
+        403
+    );
+    assert!(slot.flash.lock().unwrap().written.is_empty());
+}
+
+#[test]
+fn bad_uploads_answer_400() {
+    let slot = FakeSlot::new(1_000_000);
+    let (_app, addr) = serve(slot.clone(), true);
+    let (status, body) = request(addr, "POST", "/ota", &basic("ann", "pw"), &[0; 5000]);
+    assert_eq!(status, 400);
+    assert!(body.as_str().unwrap().contains("Not an ESP32 app image"));
+}
+
+#[test]
+fn pulls_from_a_url() {
+    let slot = FakeSlot::new(1_000_000);
+    let (app, addr) = serve(slot.clone(), true);
+    let admin = basic("ann", "pw");
+
+    // A missing file fails in the background
+    let missing = format!("http://{}/missing.bin", file_server(None));
+    let pull = json!({ "url": missing }).to_string();
+    let (status, _) = request(addr, "POST", "/ota/pull", &admin, pull.as_bytes());
+    assert_eq!(status, 202);
+    wait_for("the failure", || {
+        app.route("/ota", "")
+            .body
+            .windows(6)
+            .any(|w| w == b"failed")
+    });
+    let (_, ota) = request(addr, "GET", "/ota", &admin, b"");
+    assert!(ota["error"].as_str().unwrap().contains("404"), "{}", ota);
+    assert_eq!(ota["source"], missing.as_str());
+
+    let data = image("wrover", "4.0.0", 0, 200_000);
+    let url = format!("http://{}/wrover.bin", file_server(Some(data.clone())));
+    let pull = json!({ "url": url }).to_string();
+    let (status, _) = request(addr, "POST", "/ota/pull", &admin, pull.as_bytes());
+    assert_eq!(status, 202);
+    wait_for("the restart", || slot.flash.lock().unwrap().restarts == 1);
+    assert_eq!(slot.flash.lock().unwrap().written, data);
+    let (_, ota) = request(addr, "GET", "/ota", &admin, b"");
+    assert_eq!(ota["state"], "restarting");
+    assert_eq!(ota["total"], 200_000);
+
+    let (status, _) = request(addr, "POST", "/ota/pull", &admin, b"{}");
+    assert_eq!(status, 400);
+}
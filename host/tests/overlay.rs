@@ -0,0 +1,186 @@
+//! Text overlay rendering, checked against golden images in `tests/golden`.
+//!
+//! Set `WROVER_UPDATE_GOLDEN=1` to rewrite the golden files after an intentional change.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use wrover::camera::{FrameBuffer, FrameFormat};
+use wrover::clock;
+use wrover::codec::{self, Image, PixelFormat};
+use wrover::overlay::{self, Corner, Overlay, OverlayConfig};
+use wrover::settings::Settings;
+
+/// Compares against (or rewrites) a binary PGM golden image
+fn assert_golden(name: &str, image: &Image) {
+    assert_eq!(image.format, PixelFormat::Grayscale);
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+
+    let mut pgm = format!("P5\n{} {}\n255\n", image.width, image.height).into_bytes();
+    pgm.extend_from_slice(&image.data);
+
+    if std::env::var_os("WROVER_UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, &pgm).unwrap();
+        return;
+    }
+
+    let golden = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    if golden != pgm {
+        let art: Vec<String> = (0..image.height)
+            .map(|y| {
+                image
+                    .row(y)
+                    .iter()
+                    .map(|&p| if p > 127 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        panic!("{} does not match, rendered:\n{}", name, art.join("\n"));
+    }
+}
+
+fn gray(width: u32, height: u32, level: u8) -> Image {
+    let mut image = Image::new(width, height, PixelFormat::Grayscale);
+    image.data.fill(level);
+    image
+}
+
+#[test]
+fn font_matches_golden() {
+    let text = "WROVER 0123456789:-";
+    let (width, height) = overlay::text_size(text, 1);
+    let mut image = Image::new(width, height, PixelFormat::Grayscale);
+    overlay::draw_text(&mut image, 0, 0, text, 1);
+    assert_golden("font_digits.pgm", &image);
+
+    let text = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let (width, height) = overlay::text_size(text, 1);
+    let mut image = Image::new(width, height, PixelFormat::Grayscale);
+    overlay::draw_text(&mut image, 0, 0, text, 1);
+    assert_golden("font_letters.pgm", &image);
+}
+
+#[test]
+fn scaled_label_in_corner_matches_golden() {
+    let overlay = Overlay::new(OverlayConfig::new("CAM1", Corner::BottomRight));
+    let mut image = gray(160, 40, 128);
+    overlay.draw(&mut image, "CAM1 12:00");
+    assert_golden("label_bottom_right_x2.pgm", &image);
+}
+
+#[test]
+fn corners_place_the_box() {
+    let (w, h) = (200, 100);
+    for corner in [
+        Corner::TopLeft,
+        Corner::TopRight,
+        Corner::BottomLeft,
+        Corner::BottomRight,
+    ] {
+        let mut config = OverlayConfig::new("", corner);
+        config.scale = 1;
+        let mut image = gray(w, h, 128);
+        Overlay::new(config).draw(&mut image, "X");
+
+        // The box starts 2px in from each edge, the opposite corner is untouched
+        let (x, y, far_x, far_y) = match corner {
+            Corner::TopLeft => (2, 2, w - 1, h - 1),
+            Corner::TopRight => (w - 3, 2, 0, h - 1),
+            Corner::BottomLeft => (2, h - 3, w - 1, 0),
+            Corner::BottomRight => (w - 3, h - 3, 0, 0),
+        };
+        assert_eq!(image.row(y)[x as usize], 0, "{:?}", corner);
+        assert_eq!(image.row(far_y)[far_x as usize], 128, "{:?}", corner);
+    }
+}
+
+#[test]
+fn settings_choose_the_corner_and_what_gets_stamped() {
+    let defaults = OverlayConfig::from_settings("CAM", &Settings::default().overlay);
+    assert_eq!(defaults.corner, Corner::BottomLeft);
+    assert!(defaults.snapshots_only);
+
+    let settings =
+        Settings::from_json(br#"{"overlay": {"corner": "top_right", "snapshots_only": false}}"#)
+            .unwrap();
+    let config = OverlayConfig::from_settings("CAM", &settings.overlay);
+    assert_eq!(
+        (config.name.as_str(), config.corner),
+        ("CAM", Corner::TopRight)
+    );
+    assert!(!config.snapshots_only);
+    assert_eq!(config.scale, OverlayConfig::default().scale);
+
+    assert!(Settings::from_json(br#"{"overlay": {"corner": "middle"}}"#).is_err());
+}
+
+#[test]
+fn text_is_clipped_at_the_edge() {
+    let mut image = gray(20, 5, 128);
+    overlay::draw_text(&mut image, 10, 0, "LONG TEXT", 2);
+    assert!(image.row(0)[..10].iter().all(|&p| p == 128));
+}
+
+#[test]
+fn rgb_frames_are_drawn_in_place() {
+    let mut config = OverlayConfig::new("", Corner::TopLeft);
+    config.scale = 1;
+    let mut frame = FrameBuffer {
+        data: vec![100; 64 * 32 * 3],
+        width: 64,
+        height: 32,
+        format: FrameFormat::Rgb888,
+        timestamp: Duration::from_secs(5),
+        wall_time: None,
+    };
+
+    Overlay::new(config).apply(&mut frame).unwrap();
+
+    assert_eq!(frame.data.len(), 64 * 32 * 3);
+    assert_eq!(&frame.data[(2 * 64 + 2) * 3..][..3], &[0, 0, 0]);
+    assert!(frame.data.contains(&255));
+    assert_eq!(&frame.data[frame.data.len() - 3..], &[100, 100, 100]);
+}
+
+#[test]
+fn jpeg_frames_are_reencoded_with_the_label() {
+    let jpeg = codec::encode_jpeg(&gray(160, 120, 128), 90).unwrap();
+    let mut frame = FrameBuffer {
+        data: jpeg.clone(),
+        width: 160,
+        height: 120,
+        format: FrameFormat::Jpeg,
+        timestamp: Duration::from_secs(5),
+        wall_time: None,
+    };
+
+    Overlay::new(OverlayConfig::new("CAM", Corner::TopLeft))
+        .apply(&mut frame)
+        .unwrap();
+
+    assert_ne!(frame.data, jpeg);
+    let image = codec::decode_jpeg(&frame.data).unwrap();
+    assert_eq!((image.width, image.height), (160, 120));
+    // Inside the black box, and well away from it
+    assert!(image.row(5)[5] < 40);
+    assert!(image.row(100)[150].abs_diff(128) < 8);
+}
+
+#[test]
+fn time_formatting() {
+    assert_eq!(clock::format_datetime(0), "1970-01-01 00:00:00");
+    assert_eq!(clock::format_datetime(951_782_400), "2000-02-29 00:00:00");
+    assert_eq!(clock::format_datetime(1_760_875_200), "2025-10-19 12:00:00");
+    assert_eq!(clock::format_datetime(-1), "1969-12-31 23:59:59");
+
+    assert_eq!(
+        clock::format_uptime(Duration::from_secs(3_723)),
+        "UP 01:02:03"
+    );
+    assert_eq!(
+        clock::format_uptime(Duration::from_secs(90_061)),
+        "UP 1D 01:01:01"
+    );
+}
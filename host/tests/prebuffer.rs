@@ -0,0 +1,285 @@
+//! Pre-event ring buffer: eviction, freezing on a trigger and topping up after it.
+
+mod common;
+
+use std::fs;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::{temp_dir, wait_for};
+use wrover::avi;
+use wrover::camera::{Camera, FrameBuffer, FrameFormat, FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::prebuffer::{AviDirSink, ClipSink, EventBuffer, EventClip, Prebuffer, PrebufferConfig};
+use wrover::settings::PrebufferSettings;
+
+fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// A frame of `size` bytes taken at `at_ms`; the buffer never looks inside
+fn frame(size: usize, at_ms: u64) -> FrameBuffer {
+    FrameBuffer {
+        data: vec![0xab; size],
+        width: 160,
+        height: 120,
+        format: FrameFormat::Jpeg,
+        timestamp: millis(at_ms),
+        wall_time: None,
+    }
+}
+
+fn config(budget_bytes: usize, pre_ms: u64, post_ms: u64, max_post_ms: u64) -> PrebufferConfig {
+    PrebufferConfig {
+        budget_bytes,
+        pre: millis(pre_ms),
+        post: millis(post_ms),
+        max_post: millis(max_post_ms),
+    }
+}
+
+fn timestamps(frames: &[FrameBuffer]) -> Vec<u64> {
+    frames
+        .iter()
+        .map(|frame| frame.timestamp.as_millis() as u64)
+        .collect()
+}
+
+#[test]
+fn ring_keeps_the_last_pre_seconds() {
+    let mut buffer = Prebuffer::new(config(1_000_000, 1000, 500, 5000));
+    for i in 0..10 {
+        assert!(buffer.push(frame(100, i * 250)).is_none());
+    }
+    // Newest is 2250 ms, so everything from 1250 ms on is within a second of it
+    assert_eq!(
+        buffer.timestamps(),
+        [
+            millis(1250),
+            millis(1500),
+            millis(1750),
+            millis(2000),
+            millis(2250)
+        ]
+    );
+    assert_eq!(buffer.len(), 5);
+    assert_eq!(buffer.bytes(), 500);
+    assert!(!buffer.is_capturing());
+}
+
+#[test]
+fn ring_evicts_the_oldest_over_budget() {
+    let mut buffer = Prebuffer::new(config(1000, 10_000, 500, 5000));
+    for i in 0..6 {
+        buffer.push(frame(300, i * 100));
+    }
+    assert_eq!(buffer.timestamps(), [millis(300), millis(400), millis(500)]);
+    assert_eq!(buffer.bytes(), 900);
+
+    // A big frame pushes out as many as it needs to
+    buffer.push(frame(800, 600));
+    assert_eq!(buffer.timestamps(), [millis(600)]);
+    assert_eq!(buffer.bytes(), 800);
+
+    // One that could never fit is dropped, and the ring is left alone
+    buffer.push(frame(1001, 700));
+    assert_eq!(buffer.timestamps(), [millis(600)]);
+}
+
+#[test]
+fn only_jpeg_frames_are_kept() {
+    let mut buffer = Prebuffer::new(config(1_000_000, 1000, 500, 5000));
+    let mut raw = frame(100, 0);
+    raw.format = FrameFormat::Grayscale;
+    buffer.push(raw);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn trigger_freezes_the_ring_and_collects_post_frames() {
+    let mut buffer = Prebuffer::new(config(1_000_000, 1000, 500, 5000));
+    for i in 0..=8 {
+        buffer.push(frame(100, i * 250));
+    }
+    buffer.trigger("motion", millis(2000));
+    assert!(buffer.is_capturing());
+    // The ring is now part of the clip
+    assert!(buffer.timestamps().is_empty());
+    assert_eq!(buffer.len(), 5);
+
+    assert!(buffer.push(frame(100, 2250)).is_none());
+    let clip = buffer
+        .push(frame(100, 2500))
+        .expect("clip ends at trigger + post");
+    assert!(!buffer.is_capturing());
+    assert_eq!(clip.reason, "motion");
+    assert_eq!(clip.triggered_at, millis(2000));
+    assert_eq!(clip.pre_frames, 5);
+    assert_eq!(
+        timestamps(&clip.frames),
+        [1000, 1250, 1500, 1750, 2000, 2250, 2500]
+    );
+    assert_eq!(clip.duration(), millis(1500));
+    assert_eq!(clip.bytes(), 700);
+
+    // Afterwards the ring fills up again from scratch
+    buffer.push(frame(100, 2750));
+    assert_eq!(buffer.timestamps(), [millis(2750)]);
+}
+
+#[test]
+fn retriggers_extend_the_clip_up_to_max_post() {
+    let mut buffer = Prebuffer::new(config(1_000_000, 0, 500, 1200));
+    buffer.trigger("motion", millis(0));
+    for at in (100..=800).step_by(100) {
+        buffer.trigger("motion", millis(at));
+        assert!(buffer.push(frame(10, at)).is_none());
+    }
+    // Triggers at 900 ms and later would run past 1200 ms, so the clip stops there
+    let mut end = None;
+    for at in (900..2000).step_by(100) {
+        buffer.trigger("motion", millis(at));
+        if let Some(clip) = buffer.push(frame(10, at)) {
+            end = Some(clip);
+            break;
+        }
+    }
+    let clip = end.expect("max_post ends the clip");
+    assert_eq!(clip.frames.last().unwrap().timestamp, millis(1200));
+    assert_eq!(clip.triggered_at, millis(0));
+
+    // A later trigger can't shorten a clip either
+    buffer.trigger("button", millis(5000));
+    buffer.trigger("button", millis(4000));
+    assert!(buffer.push(frame(10, 5400)).is_none());
+    assert!(buffer.push(frame(10, 5500)).is_some());
+}
+
+#[test]
+fn clips_are_cut_short_at_the_budget() {
+    let mut buffer = Prebuffer::new(config(1000, 10_000, 10_000, 10_000));
+    buffer.push(frame(300, 0));
+    buffer.push(frame(300, 100));
+    buffer.trigger("motion", millis(100));
+    assert!(buffer.push(frame(300, 200)).is_none());
+    assert_eq!(buffer.bytes(), 900);
+
+    // The next frame would take it to 1200 bytes: the clip ends without it, and it starts
+    // the ring for the next one
+    let clip = buffer.push(frame(300, 300)).expect("budget ends the clip");
+    assert_eq!(timestamps(&clip.frames), [0, 100, 200]);
+    assert_eq!(buffer.timestamps(), [millis(300)]);
+}
+
+#[test]
+fn finish_hands_over_what_there_is() {
+    let mut buffer = Prebuffer::new(config(1_000_000, 1000, 5000, 5000));
+    assert!(buffer.finish().is_none());
+    buffer.push(frame(100, 0));
+    buffer.trigger("shutdown", millis(0));
+    let clip = buffer.finish().unwrap();
+    assert_eq!(clip.frames.len(), 1);
+    assert!(!buffer.is_capturing());
+}
+
+#[test]
+fn config_from_settings() {
+    let config = PrebufferConfig::from_settings(&PrebufferSettings::default());
+    assert_eq!(config.budget_bytes, 1536 * 1024);
+    assert_eq!(config.pre, Duration::from_secs(5));
+    assert_eq!(config.post, Duration::from_secs(10));
+    assert_eq!(config.max_post, Duration::from_secs(60));
+}
+
+fn real_clip(count: usize) -> EventClip {
+    let mut camera = TestPattern::new(FrameSize::Qqvga, 1000.0);
+    let frames: Vec<_> = (0..count)
+        .map(|i| {
+            let mut frame = camera.capture().unwrap();
+            frame.timestamp = millis(i as u64 * 200);
+            frame
+        })
+        .collect();
+    EventClip {
+        reason: "motion".to_owned(),
+        triggered_at: millis(400),
+        wall_time: None,
+        pre_frames: 2,
+        frames,
+    }
+}
+
+#[test]
+fn clips_become_avi_files() {
+    let clip = real_clip(5);
+    let bytes = clip.to_avi(5).unwrap();
+    let info = avi::parse(&bytes).unwrap();
+    assert_eq!((info.width, info.height), (160, 120));
+    assert_eq!(info.total_frames, 5);
+    assert_eq!(info.us_per_frame, 200_000);
+    let first = &info.frames[0];
+    assert_eq!(&bytes[first.clone()], clip.frames[0].data.as_slice());
+
+    let empty = EventClip {
+        frames: Vec::new(),
+        ..clip
+    };
+    assert!(empty.to_avi(5).is_err());
+}
+
+#[test]
+fn avi_dir_sink_names_clips_by_time() {
+    let dir = temp_dir("prebuffer-sink");
+    let mut sink = AviDirSink::new(dir.join("events"), 5);
+
+    let mut clip = real_clip(3);
+    assert_eq!(sink.path_for(&clip), dir.join("events/400_motion.avi"));
+    clip.wall_time = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let path = sink.path_for(&clip);
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(
+        name.ends_with("_motion.avi") && !name.starts_with("400_"),
+        "{}",
+        name
+    );
+
+    sink.store(&clip).unwrap();
+    let info = avi::parse(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(info.total_frames, 3);
+}
+
+#[test]
+fn event_buffer_saves_clips_from_the_camera() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let (tx, rx) = mpsc::channel();
+    let events = EventBuffer::spawn(
+        Arc::downgrade(&app),
+        Prebuffer::new(config(1_000_000, 300, 200, 1000)),
+        20.0,
+        move |clip: &EventClip| {
+            tx.send(clip.clone())?;
+            Ok(())
+        },
+    );
+
+    // Let the ring fill up, then trigger
+    thread::sleep(Duration::from_millis(600));
+    let status: serde_json::Value = serde_json::from_str(&events.status_json()).unwrap();
+    assert!(status["frames"].as_u64().unwrap() > 0);
+    events.trigger("test");
+    assert!(events.is_capturing());
+
+    let clip = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(clip.reason, "test");
+    assert!(clip.pre_frames > 0);
+    assert!(clip.frames.len() > clip.pre_frames);
+    let first = clip.frames.first().unwrap().timestamp;
+    let last = clip.frames.last().unwrap().timestamp;
+    assert!(first <= clip.triggered_at && clip.triggered_at - first <= millis(300));
+    assert!(last >= clip.triggered_at + millis(200));
+
+    let status = || -> serde_json::Value { serde_json::from_str(&events.status_json()).unwrap() };
+    wait_for("the clip to be counted", || status()["clips"] == 1);
+    assert_eq!(status()["failures"], 0);
+}
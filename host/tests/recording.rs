@@ -0,0 +1,328 @@
+//! AVI muxer and SD recorder, checked by parsing what they write.
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{temp_dir, wait_for};
+use wrover::avi::{self, AviWriter};
+use wrover::camera::{Camera, FrameBuffer, FrameFormat, FrameSize, TestPattern};
+use wrover::codec;
+use wrover::http::App;
+use wrover::recorder::{self, Recorder, RecorderConfig};
+
+fn jpegs(count: usize) -> Vec<Vec<u8>> {
+    let mut camera = TestPattern::new(FrameSize::Qqvga, 1000.0);
+    (0..count).map(|_| camera.capture().unwrap().data).collect()
+}
+
+fn frame(data: Vec<u8>, width: u32, height: u32, at_ms: u64) -> FrameBuffer {
+    FrameBuffer {
+        data,
+        width,
+        height,
+        format: FrameFormat::Jpeg,
+        timestamp: Duration::from_millis(at_ms),
+        wall_time: None,
+    }
+}
+
+fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn writer_output_parses_back() {
+    let frames = jpegs(5);
+    let mut writer = AviWriter::new(Cursor::new(Vec::new()), 160, 120, 10).unwrap();
+    for (i, jpeg) in frames.iter().enumerate() {
+        writer.write_frame(jpeg, millis(i as u64 * 200)).unwrap();
+    }
+    assert_eq!(writer.frames(), 5);
+    assert_eq!(writer.duration(), millis(800));
+    let avi_bytes = writer.finish().unwrap().into_inner();
+
+    let info = avi::parse(&avi_bytes).unwrap();
+    assert_eq!((info.width, info.height), (160, 120));
+    assert_eq!(info.total_frames, 5);
+    // Frames came in at 5 fps, not the 10 we guessed up front
+    assert_eq!(info.us_per_frame, 200_000);
+    assert_eq!(info.fps(), 5.0);
+
+    assert_eq!(info.frames.len(), 5);
+    for (range, jpeg) in info.frames.iter().zip(&frames) {
+        assert_eq!(&avi_bytes[range.clone()], jpeg.as_slice());
+        let image = codec::decode_jpeg(&avi_bytes[range.clone()]).unwrap();
+        assert_eq!((image.width, image.height), (160, 120));
+    }
+}
+
+#[test]
+fn odd_sized_frames_are_padded() {
+    let mut writer = AviWriter::new(Cursor::new(Vec::new()), 2, 2, 1).unwrap();
+    writer.write_frame(&[1, 2, 3], millis(0)).unwrap();
+    writer.write_frame(&[4, 5, 6, 7], millis(1000)).unwrap();
+    let avi_bytes = writer.finish().unwrap().into_inner();
+
+    let info = avi::parse(&avi_bytes).unwrap();
+    assert_eq!(info.frames[0].len(), 3);
+    assert_eq!(
+        info.frames[1].start % 2,
+        0,
+        "chunks must start on even offsets"
+    );
+    assert_eq!(&avi_bytes[info.frames[1].clone()], &[4, 5, 6, 7]);
+}
+
+#[test]
+fn empty_clip_is_still_valid() {
+    let writer = AviWriter::new(Cursor::new(Vec::new()), 320, 240, 5).unwrap();
+    let avi_bytes = writer.finish().unwrap().into_inner();
+
+    let info = avi::parse(&avi_bytes).unwrap();
+    assert_eq!(info.total_frames, 0);
+    assert!(info.frames.is_empty());
+    assert_eq!(info.us_per_frame, 200_000);
+}
+
+#[test]
+fn unfinished_files_dont_parse() {
+    let mut writer = AviWriter::new(Cursor::new(Vec::new()), 160, 120, 10).unwrap();
+    writer.write_frame(&jpegs(1)[0], millis(0)).unwrap();
+    let partial = writer.get_mut().get_ref().clone();
+    assert!(avi::parse(&partial).is_err());
+}
+
+#[test]
+fn recover_keeps_complete_frames() {
+    let dir = temp_dir("recover");
+    let path = dir.join("cut.avi");
+    let frames = jpegs(4);
+
+    // Write four frames, "lose power" halfway through the fourth
+    let mut writer = AviWriter::new(File::create(&path).unwrap(), 160, 120, 10).unwrap();
+    for (i, jpeg) in frames.iter().enumerate() {
+        writer.write_frame(jpeg, millis(i as u64 * 100)).unwrap();
+    }
+    writer.get_mut().flush().unwrap();
+    drop(writer);
+    let full = fs::metadata(&path).unwrap().len();
+    let file = File::options().write(true).open(&path).unwrap();
+    file.set_len(full - frames[3].len() as u64 / 2).unwrap();
+    drop(file);
+
+    assert_eq!(recorder::recover_file(&path).unwrap(), Some(3));
+
+    let avi_bytes = fs::read(&path).unwrap();
+    let info = avi::parse(&avi_bytes).unwrap();
+    assert_eq!(info.total_frames, 3);
+    assert_eq!(info.us_per_frame, 100_000);
+    for (range, jpeg) in info.frames.iter().zip(&frames) {
+        assert_eq!(&avi_bytes[range.clone()], jpeg.as_slice());
+    }
+
+    // Already repaired, so nothing to do the second time
+    assert_eq!(recorder::recover_file(&path).unwrap(), None);
+}
+
+#[test]
+fn recover_handles_a_cut_inside_a_chunk_header() {
+    let dir = temp_dir("recover-header");
+    let path = dir.join("cut.avi");
+
+    let mut writer = AviWriter::new(File::create(&path).unwrap(), 2, 2, 10).unwrap();
+    writer
+        .write_frame(&[0xff, 0xd8, 0xff, 0xd9], millis(0))
+        .unwrap();
+    writer.get_mut().write_all(b"00d").unwrap();
+    drop(writer);
+
+    assert_eq!(recorder::recover_file(&path).unwrap(), Some(1));
+    let info = avi::parse(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(info.frames.len(), 1);
+}
+
+fn config(dir: &Path) -> RecorderConfig {
+    RecorderConfig {
+        dir: dir.to_owned(),
+        max_clip_bytes: u64::MAX,
+        max_clip_duration: Duration::from_secs(3600),
+        max_total_bytes: 0,
+        fps: 10,
+    }
+}
+
+fn frame_counts(dir: &Path) -> Vec<usize> {
+    recorder::clips(dir)
+        .unwrap()
+        .iter()
+        .map(|path| avi::parse(&fs::read(path).unwrap()).unwrap().frames.len())
+        .collect()
+}
+
+#[test]
+fn clips_rotate_by_duration() {
+    let dir = temp_dir("rotate-duration");
+    let mut recorder = Recorder::new(RecorderConfig {
+        max_clip_duration: Duration::from_secs(1),
+        ..config(&dir)
+    })
+    .unwrap();
+
+    let jpeg = jpegs(1).remove(0);
+    for i in 0..25 {
+        recorder
+            .write(&frame(jpeg.clone(), 160, 120, i * 100))
+            .unwrap();
+    }
+    recorder.finish().unwrap();
+
+    // 0-1000ms, 1100-2100ms, 2200-2400ms
+    assert_eq!(frame_counts(&dir), [11, 11, 3]);
+}
+
+#[test]
+fn clips_rotate_by_size() {
+    let dir = temp_dir("rotate-size");
+    let mut recorder = Recorder::new(RecorderConfig {
+        max_clip_bytes: avi::HEADER_LEN as u64 + 3 * (8 + 1000),
+        ..config(&dir)
+    })
+    .unwrap();
+
+    for i in 0..7 {
+        recorder.write(&frame(vec![0; 1000], 2, 2, i)).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    assert_eq!(frame_counts(&dir), [3, 3, 1]);
+    for clip in recorder::clips(&dir).unwrap() {
+        assert!(fs::metadata(&clip).unwrap().len() <= 4096);
+    }
+}
+
+#[test]
+fn resolution_change_starts_a_new_clip() {
+    let dir = temp_dir("resolution");
+    let mut recorder = Recorder::new(config(&dir)).unwrap();
+    recorder.write(&frame(vec![1; 10], 320, 240, 0)).unwrap();
+    recorder.write(&frame(vec![2; 10], 320, 240, 100)).unwrap();
+    recorder.write(&frame(vec![3; 10], 640, 480, 200)).unwrap();
+    recorder.finish().unwrap();
+
+    let sizes: Vec<_> = recorder::clips(&dir)
+        .unwrap()
+        .iter()
+        .map(|path| {
+            let info = avi::parse(&fs::read(path).unwrap()).unwrap();
+            (info.width, info.height, info.frames.len())
+        })
+        .collect();
+    assert_eq!(sizes, [(320, 240, 2), (640, 480, 1)]);
+}
+
+#[test]
+fn oldest_clips_are_deleted_past_the_budget() {
+    let dir = temp_dir("prune");
+    let clip_len = avi::HEADER_LEN as u64 + 2 * (8 + 1000) + 8 + 2 * 16;
+    let mut recorder = Recorder::new(RecorderConfig {
+        max_clip_bytes: avi::HEADER_LEN as u64 + 2 * (8 + 1000),
+        max_total_bytes: clip_len * 2,
+        ..config(&dir)
+    })
+    .unwrap();
+
+    for i in 0..8 {
+        recorder.write(&frame(vec![0; 1000], 2, 2, i)).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let names: Vec<_> = recorder::clips(&dir)
+        .unwrap()
+        .iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(names, ["00002.avi", "00003.avi"]);
+}
+
+#[test]
+fn restart_repairs_and_keeps_numbering() {
+    let dir = temp_dir("restart");
+    let jpeg = jpegs(1).remove(0);
+
+    // A recorder that "loses power": its clip is never finished
+    let mut recorder = Recorder::new(config(&dir)).unwrap();
+    for i in 0..3 {
+        recorder
+            .write(&frame(jpeg.clone(), 160, 120, i * 100))
+            .unwrap();
+    }
+    let cut = recorder.current().unwrap().to_owned();
+    std::mem::forget(recorder);
+    assert!(avi::parse(&fs::read(&cut).unwrap()).is_err());
+
+    let mut recorder = Recorder::new(config(&dir)).unwrap();
+    // Only the first frame was synced to disk, that one survives
+    let info = avi::parse(&fs::read(&cut).unwrap()).unwrap();
+    assert_eq!(info.frames.len(), 1);
+
+    let wall_time = UNIX_EPOCH + Duration::from_secs(1_907_755_200);
+    let mut synced = frame(jpeg, 160, 120, 1000);
+    synced.wall_time = Some(wall_time);
+    recorder.write(&synced).unwrap();
+    let next = recorder.finish().unwrap().unwrap();
+
+    let name = next.file_name().unwrap().to_str().unwrap();
+    assert!(name.starts_with("00001_2030"), "{}", name);
+    assert!(SystemTime::now() < wall_time);
+}
+
+#[test]
+fn headerless_clips_are_deleted() {
+    let dir = temp_dir("headerless");
+    fs::write(dir.join("00000.avi"), b"RIFF").unwrap();
+    fs::write(dir.join("notes.txt"), b"not ours").unwrap();
+
+    let mut recorder = Recorder::new(config(&dir)).unwrap();
+    assert!(recorder::clips(&dir).unwrap().is_empty());
+    assert!(dir.join("notes.txt").exists());
+
+    recorder.write(&frame(vec![1; 10], 2, 2, 0)).unwrap();
+    let next = recorder.finish().unwrap().unwrap();
+    assert_eq!(next.file_name().unwrap(), "00001.avi");
+}
+
+#[test]
+fn only_jpeg_frames_are_recorded() {
+    let dir = temp_dir("raw");
+    let mut recorder = Recorder::new(config(&dir)).unwrap();
+    let mut raw = frame(vec![0; 4], 2, 2, 0);
+    raw.format = FrameFormat::Grayscale;
+    assert!(recorder.write(&raw).is_err());
+    assert!(recorder.finish().unwrap().is_none());
+}
+
+#[test]
+fn recording_ends_with_the_app() {
+    let dir = temp_dir("app-gone");
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let recorder = Recorder::new(config(&dir)).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = recorder::spawn(Arc::downgrade(&app), recorder, 20, stop);
+    wait_for("a clip", || {
+        recorder::clips(&dir).is_ok_and(|clips| !clips.is_empty())
+    });
+    std::thread::sleep(millis(200));
+
+    // Never stopped, but nothing to record from any more: the clip is finished anyway
+    drop(app);
+    thread.join().unwrap();
+    let frames = frame_counts(&dir);
+    assert_eq!(frames.len(), 1);
+    assert!(frames[0] > 0);
+}
@@ -0,0 +1,767 @@
+//! RTSP: the request parser and RTP/JPEG packetizer on their own, then whole sessions over
+//! interleaved TCP and UDP against the test pattern. Also runs `ffprobe` against it, if
+//! it's installed.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::wait_for;
+use wrover::auth::{Change, Scope};
+use wrover::camera::{Camera, FrameSize, TestPattern};
+use wrover::client::base64;
+use wrover::codec::{self, Image, PixelFormat};
+use wrover::http::App;
+use wrover::rtsp::message::{self, Incoming, Response, Transport};
+use wrover::rtsp::rtp::{self, JpegParts, Packetizer};
+use wrover::rtsp::{Rtsp, RtspConfig};
+use wrover::settings::{AuthSettings, Settings};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn pattern_jpeg() -> Vec<u8> {
+    TestPattern::new(FrameSize::Qqvga, 100.0)
+        .capture()
+        .unwrap()
+        .data
+}
+
+fn request(text: &str) -> message::Request {
+    match message::parse(text.as_bytes()).unwrap() {
+        Some((Incoming::Request(request), len)) => {
+            assert_eq!(len, text.len());
+            request
+        }
+        other => panic!("Expected a request, got {:?}", other),
+    }
+}
+
+#[test]
+fn requests_parse() {
+    let setup = request(
+        "SETUP rtsp://cam/stream/track1 RTSP/1.0\r\nCSeq: 3\r\n\
+         Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\nsession:  1F2E3D;timeout=60\r\n\r\n",
+    );
+    assert_eq!(setup.method, "SETUP");
+    assert_eq!(setup.url, "rtsp://cam/stream/track1");
+    assert_eq!(setup.header("cseq"), Some("3"));
+    assert_eq!(setup.session(), Some("1F2E3D"));
+    assert_eq!(
+        setup.header("Transport"),
+        Some("RTP/AVP/TCP;unicast;interleaved=0-1")
+    );
+
+    let with_body =
+        "SET_PARAMETER rtsp://cam RTSP/1.0\r\nCSeq: 9\r\nContent-Length: 5\r\n\r\nhello";
+    assert_eq!(request(with_body).body, b"hello");
+    // Not all there yet
+    assert_eq!(message::parse(&with_body.as_bytes()[..40]).unwrap(), None);
+    assert_eq!(
+        message::parse(b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n").unwrap(),
+        None
+    );
+
+    assert!(message::parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    assert!(message::parse(b"OPTIONS\r\n\r\n").is_err());
+    assert!(message::parse(&[b'A'; message::MAX_REQUEST + 1]).is_err());
+}
+
+#[test]
+fn interleaved_data_is_skipped_over() {
+    let mut stream = message::interleave(1, &[7, 8, 9]);
+    assert_eq!(stream, [b'$', 1, 0, 3, 7, 8, 9]);
+    stream.extend_from_slice(b"GET_PARAMETER rtsp://cam RTSP/1.0\r\nCSeq: 5\r\n\r\n");
+
+    assert_eq!(message::parse(&stream[..5]).unwrap(), None);
+    let (incoming, used) = message::parse(&stream).unwrap().unwrap();
+    assert_eq!(
+        incoming,
+        Incoming::Interleaved {
+            channel: 1,
+            data: vec![7, 8, 9]
+        }
+    );
+    let Some((Incoming::Request(keepalive), _)) = message::parse(&stream[used..]).unwrap() else {
+        panic!("Expected the request after the packet");
+    };
+    assert_eq!(keepalive.method, "GET_PARAMETER");
+}
+
+#[test]
+fn transports() {
+    assert_eq!(
+        Transport::parse("RTP/AVP;unicast;client_port=5000-5001").unwrap(),
+        Transport::Udp {
+            rtp: 5000,
+            rtcp: 5001
+        }
+    );
+    assert_eq!(
+        Transport::parse("RTP/AVP/UDP;unicast;client_port=6970").unwrap(),
+        Transport::Udp {
+            rtp: 6970,
+            rtcp: 6971
+        }
+    );
+    assert_eq!(
+        Transport::parse("RTP/AVP/TCP;unicast;interleaved=2-3").unwrap(),
+        Transport::Interleaved { rtp: 2, rtcp: 3 }
+    );
+    assert_eq!(
+        Transport::parse("RTP/AVP/TCP;unicast").unwrap(),
+        Transport::Interleaved { rtp: 0, rtcp: 1 }
+    );
+    // Multicast is skipped in favour of the next option
+    assert_eq!(
+        Transport::parse("RTP/AVP;multicast, RTP/AVP/TCP;interleaved=0-1").unwrap(),
+        Transport::Interleaved { rtp: 0, rtcp: 1 }
+    );
+    assert!(Transport::parse("RTP/AVP;multicast;port=5000-5001").is_err());
+    assert!(Transport::parse("RTP/AVP;unicast").is_err());
+    assert!(Transport::parse("RTP/SAVP;unicast;client_port=1-2").is_err());
+    assert!(Transport::parse("RTP/AVP/TCP;interleaved=0-256").is_err());
+
+    let udp = Transport::Udp {
+        rtp: 5000,
+        rtcp: 5001,
+    };
+    assert_eq!(
+        udp.reply((40000, 40001), 0xdeadbeef),
+        "RTP/AVP;unicast;client_port=5000-5001;server_port=40000-40001;ssrc=DEADBEEF"
+    );
+    let tcp = Transport::Interleaved { rtp: 0, rtcp: 1 };
+    assert_eq!(
+        tcp.reply((0, 0), 1),
+        "RTP/AVP/TCP;unicast;interleaved=0-1;ssrc=00000001"
+    );
+}
+
+#[test]
+fn responses() {
+    let response = Response::new(200)
+        .with_header("Public", "OPTIONS")
+        .with_body("application/sdp", "v=0\r\n");
+    assert_eq!(
+        String::from_utf8(response.to_bytes(Some("2"))).unwrap(),
+        "RTSP/1.0 200 OK\r\nCSeq: 2\r\nPublic: OPTIONS\r\nContent-Type: application/sdp\r\n\
+         Content-Length: 5\r\n\r\nv=0\r\n"
+    );
+    assert_eq!(
+        Response::new(454).to_bytes(None),
+        b"RTSP/1.0 454 Session Not Found\r\n\r\n"
+    );
+    assert_eq!(
+        message::content_base("rtsp://cam:554/stream"),
+        "rtsp://cam:554/stream/"
+    );
+    assert_eq!(message::content_base("rtsp://cam/"), "rtsp://cam/");
+
+    let sdp = message::sdp("porch", "192.168.1.20".parse().unwrap(), 42, 10.0);
+    assert!(sdp.starts_with("v=0\r\no=- 42 1 IN IP4 192.168.1.20\r\ns=porch\r\n"));
+    assert!(sdp.contains("m=video 0 RTP/AVP 26\r\na=rtpmap:26 JPEG/90000\r\n"));
+    assert!(sdp.contains("a=control:track1\r\n"));
+}
+
+#[test]
+fn jpeg_parts() {
+    let jpeg = pattern_jpeg();
+    let parts = JpegParts::parse(&jpeg).unwrap();
+    assert_eq!(parts.kind, 0, "4:2:2");
+    assert_eq!((parts.width, parts.height), (160, 120));
+    assert_eq!(parts.restart_interval, 0);
+    assert_ne!(parts.tables[0], parts.tables[1]);
+    assert!(!parts.scan.is_empty());
+    // Everything from the scan to the end, EOI aside
+    let mut tail = parts.scan.to_vec();
+    tail.extend_from_slice(&[0xff, 0xd9]);
+    assert!(jpeg.ends_with(&tail));
+
+    // Camera buffers can have junk after the EOI
+    let mut padded = jpeg.clone();
+    padded.extend_from_slice(&[0; 32]);
+    assert_eq!(JpegParts::parse(&padded).unwrap(), parts);
+
+    // A restart interval, just before the scan
+    let sos = jpeg.len() - tail.len() - 14;
+    assert_eq!(jpeg[sos..sos + 2], [0xff, 0xda]);
+    let mut restarts = jpeg[..sos].to_vec();
+    restarts.extend_from_slice(&[0xff, 0xdd, 0, 4, 0, 16]);
+    restarts.extend_from_slice(&jpeg[sos..]);
+    assert_eq!(JpegParts::parse(&restarts).unwrap().restart_interval, 16);
+}
+
+#[test]
+fn unsupported_jpegs() {
+    assert!(JpegParts::parse(b"not a jpeg").is_err());
+    let jpeg = pattern_jpeg();
+    assert!(JpegParts::parse(&jpeg[..100]).is_err());
+
+    let gray = codec::encode_jpeg(&Image::new(64, 64, PixelFormat::Grayscale), 80).unwrap();
+    let error = JpegParts::parse(&gray).unwrap_err().to_string();
+    assert!(error.contains("3-component"), "{}", error);
+
+    let big = codec::encode_jpeg(&Image::new(2048, 16, PixelFormat::Rgb888), 80).unwrap();
+    let error = JpegParts::parse(&big).unwrap_err().to_string();
+    assert!(error.contains("too big"), "{}", error);
+}
+
+/// Header fields of one RTP/JPEG packet, and its scan data
+struct Packet<'a> {
+    marker: bool,
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+    offset: usize,
+    kind: u8,
+    q: u8,
+    width: u16,
+    height: u16,
+    restart: Option<u16>,
+    tables: Option<&'a [u8]>,
+    scan: &'a [u8],
+}
+
+fn unpack(packet: &[u8]) -> Packet<'_> {
+    assert_eq!(packet[0], 0x80, "RTP version 2, nothing extra");
+    assert_eq!(packet[1] & 0x7f, rtp::PAYLOAD_TYPE);
+    let be16 = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+    let be32 = |at: usize| u32::from_be_bytes(packet[at..at + 4].try_into().unwrap());
+    let jpeg = &packet[rtp::RTP_HEADER_LEN..];
+    let offset = be32(rtp::RTP_HEADER_LEN) as usize & 0xff_ffff;
+    let (kind, q) = (jpeg[4], jpeg[5]);
+
+    let mut rest = &jpeg[8..];
+    let restart = (kind >= 64).then(|| {
+        assert_eq!(rest[2..4], [0xff, 0xff]);
+        let interval = u16::from_be_bytes([rest[0], rest[1]]);
+        rest = &rest[4..];
+        interval
+    });
+    let tables = (offset == 0 && q >= 128).then(|| {
+        assert_eq!(rest[..2], [0, 0]);
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        let tables = &rest[4..4 + len];
+        rest = &rest[4 + len..];
+        tables
+    });
+
+    Packet {
+        marker: packet[1] & 0x80 != 0,
+        sequence: be16(2),
+        timestamp: be32(4),
+        ssrc: be32(8),
+        offset,
+        kind,
+        q,
+        width: jpeg[6] as u16 * 8,
+        height: jpeg[7] as u16 * 8,
+        restart,
+        tables,
+        scan: rest,
+    }
+}
+
+/// What a receiver gets back out of one frame's packets
+#[derive(Debug)]
+struct Received {
+    width: u16,
+    height: u16,
+    tables: Vec<u8>,
+    scan: Vec<u8>,
+}
+
+/// Puts a frame back together, checking the packets agree with each other
+fn reassemble(packets: &[Vec<u8>]) -> Received {
+    let first = unpack(&packets[0]);
+    let mut received = Received {
+        width: first.width,
+        height: first.height,
+        tables: first.tables.expect("Tables in the first packet").to_vec(),
+        scan: Vec::new(),
+    };
+    for (i, packet) in packets.iter().enumerate() {
+        let packet = unpack(packet);
+        assert_eq!(packet.offset, received.scan.len(), "Packets are contiguous");
+        assert_eq!(packet.marker, i == packets.len() - 1);
+        assert_eq!(packet.timestamp, first.timestamp);
+        assert_eq!(packet.ssrc, first.ssrc);
+        assert_eq!(packet.sequence, first.sequence.wrapping_add(i as u16));
+        assert_eq!((packet.kind, packet.q), (first.kind, 255));
+        received.scan.extend_from_slice(packet.scan);
+    }
+    received
+}
+
+#[test]
+fn packetizes_a_frame() {
+    let jpeg = pattern_jpeg();
+    let parts = JpegParts::parse(&jpeg).unwrap();
+    let mut packetizer = Packetizer::new(0x1234_5678, 65534, 300);
+    let packets = packetizer.packetize(&parts, 90_000);
+    assert!(
+        packets.len() > 3,
+        "{} bytes of scan in 300 byte packets",
+        parts.scan.len()
+    );
+    assert!(packets.iter().all(|packet| packet.len() <= 300));
+    assert_eq!(
+        packetizer.sequence(),
+        65534u16.wrapping_add(packets.len() as u16)
+    );
+
+    let first = unpack(&packets[0]);
+    assert_eq!(first.ssrc, 0x1234_5678);
+    assert_eq!(first.timestamp, 90_000);
+    assert_eq!(first.sequence, 65534);
+    assert_eq!(unpack(&packets[2]).sequence, 0, "Wraps");
+    assert_eq!((first.width, first.height, first.kind), (160, 120, 0));
+    assert_eq!(first.restart, None);
+    assert!(unpack(&packets[1]).tables.is_none(), "Tables only go once");
+
+    let received = reassemble(&packets);
+    assert_eq!(received.tables, [parts.tables[0], parts.tables[1]].concat());
+    assert_eq!(received.scan, parts.scan);
+
+    // The next frame carries on numbering
+    let next = packetizer.packetize(&parts, 99_000);
+    assert_eq!(
+        unpack(&next[0]).sequence,
+        packetizer.sequence() - next.len() as u16
+    );
+}
+
+#[test]
+fn packetizes_restart_markers() {
+    let jpeg = pattern_jpeg();
+    let parts = JpegParts {
+        restart_interval: 8,
+        ..JpegParts::parse(&jpeg).unwrap()
+    };
+    let packets = Packetizer::new(1, 0, 1400).packetize(&parts, 0);
+    for packet in &packets {
+        let packet = unpack(packet);
+        assert_eq!(packet.kind, 64);
+        assert_eq!(packet.restart, Some(8));
+    }
+    assert_eq!(reassemble(&packets).scan, parts.scan);
+}
+
+#[test]
+fn timestamps() {
+    assert_eq!(rtp::timestamp(Duration::from_secs(1)), 90_000);
+    assert_eq!(rtp::timestamp(Duration::from_millis(100)), 9_000);
+    // 2^32 ticks is about 13 hours
+    assert_eq!(
+        rtp::timestamp(Duration::from_secs((1 << 32) / 90_000 + 1)),
+        ((((1u64 << 32) / 90_000 + 1) * 90_000) % (1 << 32)) as u32
+    );
+}
+
+/// The headers of a `pattern_jpeg`, for making the frames that come over RTP whole again
+fn rebuild(received: &Received) -> Vec<u8> {
+    let reference = pattern_jpeg();
+    let parts = JpegParts::parse(&reference).unwrap();
+    assert_eq!(received.tables, [parts.tables[0], parts.tables[1]].concat());
+    let header_len = reference.len() - parts.scan.len() - 2;
+    let mut jpeg = reference[..header_len].to_vec();
+    jpeg.extend_from_slice(&received.scan);
+    jpeg.extend_from_slice(&[0xff, 0xd9]);
+    jpeg
+}
+
+fn start() -> (Arc<App>, Arc<Rtsp>) {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let config = RtspConfig {
+        port: 0,
+        fps: 10.0,
+        max_packet: 1400,
+    };
+    let rtsp = Rtsp::spawn(Arc::downgrade(&app), config).unwrap();
+    (app, rtsp)
+}
+
+struct RtspResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl RtspResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Just enough of a player
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    cseq: u32,
+    url: String,
+}
+
+impl Client {
+    fn connect(rtsp: &Rtsp) -> Self {
+        let addr = SocketAddr::from(([127, 0, 0, 1], rtsp.local_addr().port()));
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Self {
+            stream,
+            buf: Vec::new(),
+            cseq: 0,
+            url: format!("rtsp://{}/stream", addr),
+        }
+    }
+
+    fn fill(&mut self) {
+        let mut chunk = [0; 4096];
+        let len = self.stream.read(&mut chunk).unwrap();
+        assert!(len > 0, "Server hung up");
+        self.buf.extend_from_slice(&chunk[..len]);
+    }
+
+    fn request(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> RtspResponse {
+        self.cseq += 1;
+        let mut text = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, url, self.cseq);
+        for (name, value) in headers {
+            text += &format!("{}: {}\r\n", name, value);
+        }
+        text += "\r\n";
+        self.stream.write_all(text.as_bytes()).unwrap();
+
+        let response = self.response();
+        assert_eq!(
+            response.header("CSeq"),
+            Some(self.cseq.to_string().as_str())
+        );
+        response
+    }
+
+    /// The next response, skipping any RTP in front of it
+    fn response(&mut self) -> RtspResponse {
+        loop {
+            if self.buf.first() == Some(&b'$') {
+                self.packet();
+                continue;
+            }
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8(self.buf[..end].to_vec()).unwrap();
+                let mut lines = head.split("\r\n");
+                let status_line = lines.next().unwrap();
+                assert!(status_line.starts_with("RTSP/1.0 "), "{}", status_line);
+                let headers: Vec<(String, String)> = lines
+                    .map(|line| {
+                        let (k, v) = line.split_once(':').unwrap();
+                        (k.trim().to_owned(), v.trim().to_owned())
+                    })
+                    .collect();
+                let len: usize = headers
+                    .iter()
+                    .find(|(k, _)| k == "Content-Length")
+                    .map_or(0, |(_, v)| v.parse().unwrap());
+                while self.buf.len() < end + 4 + len {
+                    self.fill();
+                }
+                let body = String::from_utf8(self.buf[end + 4..end + 4 + len].to_vec()).unwrap();
+                self.buf.drain(..end + 4 + len);
+                return RtspResponse {
+                    status: status_line[9..12].parse().unwrap(),
+                    headers,
+                    body,
+                };
+            }
+            self.fill();
+        }
+    }
+
+    /// The next interleaved packet, with its channel
+    fn packet(&mut self) -> (u8, Vec<u8>) {
+        while self.buf.len() < 4 {
+            self.fill();
+        }
+        assert_eq!(self.buf[0], b'$', "Expected interleaved data");
+        let len = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+        while self.buf.len() < 4 + len {
+            self.fill();
+        }
+        let channel = self.buf[1];
+        let packet = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        (channel, packet)
+    }
+
+    /// DESCRIBE and SETUP, returning the session id
+    fn setup(&mut self, transport: &str) -> (String, RtspResponse) {
+        let url = self.url.clone();
+        let describe = self.request("DESCRIBE", &url, &[("Accept", "application/sdp")]);
+        assert_eq!(describe.status, 200);
+        let track = format!("{}track1", describe.header("Content-Base").unwrap());
+        let setup = self.request("SETUP", &track, &[("Transport", transport)]);
+        assert_eq!(setup.status, 200);
+        let session = setup.header("Session").unwrap();
+        assert!(session.ends_with(";timeout=60"), "{}", session);
+        let id = session.split(';').next().unwrap().to_owned();
+        (id, setup)
+    }
+}
+
+/// Collects packets until it has one whole frame, from its first packet to its marker
+fn next_frame(mut next: impl FnMut() -> Vec<u8>) -> Vec<Vec<u8>> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    loop {
+        assert!(Instant::now() < deadline, "No whole frame");
+        let packet = next();
+        let unpacked = unpack(&packet);
+        if unpacked.offset == 0 {
+            packets.clear();
+        }
+        let marker = unpacked.marker;
+        if unpacked.offset == 0 || !packets.is_empty() {
+            packets.push(packet);
+        }
+        if marker && !packets.is_empty() {
+            return packets;
+        }
+    }
+}
+
+#[test]
+fn plays_over_tcp() {
+    let (_app, rtsp) = start();
+    let mut client = Client::connect(&rtsp);
+    let url = client.url.clone();
+
+    let options = client.request("OPTIONS", &url, &[]);
+    assert_eq!(options.status, 200);
+    assert!(options.header("Public").unwrap().contains("DESCRIBE"));
+
+    let describe = client.request("DESCRIBE", &url, &[]);
+    assert_eq!(describe.header("Content-Type"), Some("application/sdp"));
+    assert_eq!(
+        describe.header("Content-Base"),
+        Some(format!("{}/", url).as_str())
+    );
+    assert!(describe.body.contains("s=wrover\r\n"));
+    assert!(describe.body.contains("m=video 0 RTP/AVP 26\r\n"));
+
+    let (session, setup) = client.setup("RTP/AVP/TCP;unicast;interleaved=0-1");
+    assert!(setup
+        .header("Transport")
+        .unwrap()
+        .starts_with("RTP/AVP/TCP;unicast;interleaved=0-1;ssrc="));
+
+    let play = client.request("PLAY", &url, &[("Session", &session)]);
+    assert_eq!(play.status, 200);
+    assert!(play
+        .header("RTP-Info")
+        .unwrap()
+        .starts_with(&format!("url={};seq=", url)));
+
+    let packets = next_frame(|| {
+        let (channel, packet) = client.packet();
+        assert_eq!(channel, 0);
+        packet
+    });
+    let received = reassemble(&packets);
+    assert_eq!((received.width, received.height), (160, 120));
+    let image = codec::decode_jpeg(&rebuild(&received)).unwrap();
+    assert_eq!((image.width, image.height), (160, 120));
+    assert_eq!(rtsp.playing(), 1);
+
+    // Keep-alives work while playing
+    let keepalive = client.request("GET_PARAMETER", &url, &[("Session", &session)]);
+    assert_eq!(keepalive.status, 200);
+
+    let teardown = client.request("TEARDOWN", &url, &[("Session", &session)]);
+    assert_eq!(teardown.status, 200);
+    assert_eq!(rtsp.playing(), 0);
+    let status: serde_json::Value = serde_json::from_str(&rtsp.status_json()).unwrap();
+    assert!(status["frames_sent"].as_u64().unwrap() >= 1);
+    assert_eq!(status["connections"], 1);
+}
+
+#[test]
+fn plays_over_udp() {
+    let (_app, rtsp) = start();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let mut client = Client::connect(&rtsp);
+    let url = client.url.clone();
+    let transport = format!("RTP/AVP;unicast;client_port={}-{}", port, port + 1);
+    let (session, setup) = client.setup(&transport);
+    let reply = setup.header("Transport").unwrap();
+    assert!(
+        reply.starts_with(&format!("{};server_port=", transport)),
+        "{}",
+        reply
+    );
+
+    let play = client.request("PLAY", &url, &[("Session", &session)]);
+    assert_eq!(play.status, 200);
+
+    let packets = next_frame(|| {
+        let mut packet = vec![0; 2048];
+        let (len, _) = socket.recv_from(&mut packet).unwrap();
+        packet.truncate(len);
+        packet
+    });
+    let image = codec::decode_jpeg(&rebuild(&reassemble(&packets))).unwrap();
+    assert_eq!((image.width, image.height), (160, 120));
+
+    // Hanging up ends the session
+    drop(client);
+    wait_for("the player to stop", || rtsp.playing() == 0);
+}
+
+#[test]
+fn bad_requests() {
+    let (_app, rtsp) = start();
+    let mut client = Client::connect(&rtsp);
+    let url = client.url.clone();
+
+    assert_eq!(client.request("PLAY", &url, &[]).status, 454);
+    assert_eq!(client.request("RECORD", &url, &[]).status, 501);
+    let multicast = [("Transport", "RTP/AVP;multicast")];
+    assert_eq!(client.request("SETUP", &url, &multicast).status, 461);
+
+    let (session, _) = client.setup("RTP/AVP/TCP;interleaved=0-1");
+    let wrong = [("Session", "0123456789ABCDEF")];
+    assert_eq!(client.request("PLAY", &url, &wrong).status, 454);
+    assert_eq!(client.request("TEARDOWN", &url, &wrong).status, 454);
+    assert_eq!(rtsp.playing(), 0);
+    assert_eq!(
+        client
+            .request("TEARDOWN", &url, &[("Session", &session)])
+            .status,
+        200
+    );
+
+    // No CSeq
+    client
+        .stream
+        .write_all(b"OPTIONS * RTSP/1.0\r\n\r\n")
+        .unwrap();
+    assert_eq!(client.response().status, 400);
+}
+
+#[test]
+fn needs_read_access_with_auth_on() {
+    let (app, rtsp) = start();
+    let mut auth = AuthSettings {
+        max_failures: 10,
+        ..Default::default()
+    };
+    for (name, scope) in [("ann", Scope::Admin), ("guest", Scope::Read)] {
+        Change::SetUser {
+            name: name.into(),
+            password: "pw".into(),
+            scope,
+        }
+        .apply(&mut auth)
+        .unwrap();
+    }
+    let token = Change::CreateToken {
+        name: "nvr".into(),
+        scope: Scope::Read,
+    }
+    .apply(&mut auth)
+    .unwrap()
+    .unwrap();
+    Change::Enable.apply(&mut auth).unwrap();
+    app.set_settings(
+        Settings {
+            camera_name: "porch".into(),
+            auth,
+            ..Default::default()
+        },
+        None,
+    );
+
+    let mut client = Client::connect(&rtsp);
+    let url = client.url.clone();
+    let track = format!("{}/track1", url);
+    let tcp = [("Transport", "RTP/AVP/TCP;interleaved=0-1")];
+    assert_eq!(client.request("OPTIONS", &url, &[]).status, 200);
+    let challenge = client.request("DESCRIBE", &url, &[]);
+    assert_eq!(challenge.status, 401);
+    assert_eq!(
+        challenge.header("WWW-Authenticate"),
+        Some("Basic realm=\"porch\", charset=\"UTF-8\"")
+    );
+    assert_eq!(client.request("SETUP", &track, &tcp).status, 401);
+    let wrong = format!("Basic {}", base64(b"guest:nope"));
+    let wrong = [("Authorization", wrong.as_str())];
+    assert_eq!(client.request("DESCRIBE", &url, &wrong).status, 401);
+
+    let guest = format!("Basic {}", base64(b"guest:pw"));
+    let guest = [("Authorization", guest.as_str())];
+    assert_eq!(client.request("DESCRIBE", &url, &guest).status, 200);
+    let setup = client.request("SETUP", &track, &[tcp[0], guest[0]]);
+    assert_eq!(setup.status, 200);
+    let session = setup.header("Session").unwrap().split(';').next().unwrap();
+    let session = [("Session", session)];
+    assert_eq!(client.request("PLAY", &url, &session).status, 200);
+    client.packet();
+    drop(client);
+
+    // Players that can't send a header put the token in the URL
+    let mut client = Client::connect(&rtsp);
+    let with_token = format!("{}?token={}", url, token);
+    let describe = client.request("DESCRIBE", &with_token, &[]);
+    assert_eq!(describe.status, 200);
+    assert!(!describe.header("Content-Base").unwrap().contains("token"));
+    let bearer = format!("Bearer {}", token);
+    assert_eq!(
+        client
+            .request("SETUP", &track, &[tcp[0], ("Authorization", &bearer)])
+            .status,
+        200
+    );
+    let revoked = format!("{}?token=wr_{}", url, "0".repeat(32));
+    assert_eq!(client.request("DESCRIBE", &revoked, &[]).status, 401);
+}
+
+#[test]
+fn stops_with_the_app() {
+    let (app, rtsp) = start();
+    let mut client = Client::connect(&rtsp);
+    let url = client.url.clone();
+    let (session, _) = client.setup("RTP/AVP/TCP;interleaved=0-1");
+    client.request("PLAY", &url, &[("Session", &session)]);
+    client.packet();
+
+    drop(app);
+    wait_for("the player to stop", || rtsp.playing() == 0);
+}
+
+#[test]
+fn ffprobe_reads_the_stream() {
+    let (_app, rtsp) = start();
+    let url = format!("rtsp://127.0.0.1:{}/stream", rtsp.local_addr().port());
+    for transport in ["tcp", "udp"] {
+        let output = Command::new("ffprobe")
+            .args(["-v", "error", "-rtsp_transport", transport])
+            .args(["-select_streams", "v:0", "-show_entries"])
+            .args(["stream=codec_name,width,height", "-of", "csv=p=0", &url])
+            .output();
+        let Ok(output) = output else {
+            eprintln!("ffprobe isn't installed, skipping");
+            return;
+        };
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "mjpeg,160,120"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! `/settings`: reading every setting back, and changing them with one `POST` that is
+//! checked, saved, and applied live or by a restart.
+
+mod common;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use common::{get, get_json, ms, request, temp_dir, wait_for};
+use serde_json::json;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::overlay::Corner;
+use wrover::settings::{FileSettings, Settings};
+use wrover_host::server::HostServer;
+
+#[test]
+fn settings_over_http() {
+    let dir = temp_dir("settings");
+    let path = dir.join("settings.json");
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    app.set_settings(
+        Settings::default(),
+        Some(Box::new(FileSettings::new(&path))),
+    );
+    let restarts = Arc::new(AtomicU32::new(0));
+    let counter = restarts.clone();
+    app.on_restart(move || {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    let addr = HostServer::bind("127.0.0.1:0", app.clone())
+        .unwrap()
+        .spawn();
+
+    let mut settings = get_json(addr, "/settings");
+    assert_eq!(settings["camera_name"], "wrover");
+    assert_eq!(settings["overlay"]["corner"], "bottom_left");
+    // Hashes stay behind /auth
+    assert!(settings.get("auth").is_none());
+
+    // Masks apply live, nothing to restart for
+    settings["masks"]["privacy"] =
+        json!([{"rect": {"x": 0.0, "y": 0.0, "width": 0.5, "height": 1.0}}]);
+    let saved = request(
+        addr,
+        "POST",
+        "/settings",
+        &[],
+        settings.to_string().as_bytes(),
+    );
+    assert_eq!(saved.status, 200, "{}", saved.text());
+    assert_eq!(app.settings().masks.privacy.len(), 1);
+
+    // The rest needs a restart
+    settings["camera_name"] = json!("porch");
+    settings["overlay"]["corner"] = json!("top_right");
+    let saved = request(
+        addr,
+        "POST",
+        "/settings",
+        &[],
+        settings.to_string().as_bytes(),
+    );
+    assert_eq!(saved.status, 202, "{}", saved.text());
+    wait_for("the restart", || restarts.load(Ordering::Relaxed) == 1);
+
+    // Read back, and saved for the next boot
+    let read = get_json(addr, "/settings");
+    assert_eq!(read, settings);
+    let stored = FileSettings::new(&path).load().unwrap();
+    assert_eq!(stored.camera_name, "porch");
+    assert_eq!(stored.overlay.corner, Corner::TopRight);
+    assert_eq!(stored.masks.privacy.len(), 1);
+    assert_eq!(stored, app.settings());
+
+    // Rejected settings change nothing
+    let bad = request(addr, "POST", "/settings", &[], b"{\"camera_name\": 3}");
+    assert_eq!(bad.status, 400);
+    let mut clash = settings.clone();
+    clash["triggers"] = json!([{"name": "a", "gpio": 33}, {"name": "b", "gpio": 33}]);
+    let bad = request(addr, "POST", "/settings", &[], clash.to_string().as_bytes());
+    assert_eq!(bad.status, 400);
+    assert!(
+        bad.text().contains("more than one trigger"),
+        "{}",
+        bad.text()
+    );
+    let mut dim = settings.clone();
+    dim["flash"]["brightness"] = json!(0);
+    let bad = request(addr, "POST", "/settings", &[], dim.to_string().as_bytes());
+    assert_eq!(bad.status, 400);
+    assert_eq!(FileSettings::new(&path).load().unwrap(), stored);
+
+    // `auth` in the body is ignored, it can't be turned on without an admin this way
+    settings["auth"] = json!({"enabled": true});
+    let saved = request(
+        addr,
+        "POST",
+        "/settings",
+        &[],
+        settings.to_string().as_bytes(),
+    );
+    assert_eq!(saved.status, 200, "{}", saved.text());
+    assert!(!app.settings().auth.enabled);
+    assert_eq!(get(addr, "/settings").status, 200);
+
+    std::thread::sleep(ms(1200));
+    assert_eq!(restarts.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,254 @@
+//! Battery mode: exposure settling, the RTC counters, which trigger woke it, a whole cycle
+//! against the test pattern and the wake log.
+
+use std::fs;
+use std::time::Duration;
+
+use serde_json::Value;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::settings::{Settings, SleepSettings, TriggerAction, TriggerSettings};
+use wrover::sleep::{self, Counters, CycleReport, Settle, WakeReason, WakeRecord};
+
+fn input(name: &str, gpio: i32, actions: &[TriggerAction]) -> TriggerSettings {
+    TriggerSettings {
+        name: name.into(),
+        gpio,
+        actions: actions.to_vec(),
+        ..Default::default()
+    }
+}
+
+fn report(stored: u32, failed: u32) -> CycleReport {
+    CycleReport {
+        settle_frames: 4,
+        settled: true,
+        stored,
+        failed,
+        took_ms: 900,
+    }
+}
+
+#[test]
+fn waits_for_exposure() {
+    // Brightening as auto exposure catches up, then steady
+    let mut settle = Settle::new(2.0, 30);
+    let done: Vec<_> = [40.0, 80.0, 110.0, 125.0, 126.5]
+        .into_iter()
+        .map(|brightness| settle.feed(brightness))
+        .collect();
+    assert_eq!(done, [false, false, false, false, true]);
+    assert!(settle.is_steady());
+    assert_eq!(settle.frames(), 5);
+
+    // Never steady: gives up after max_frames
+    let mut settle = Settle::new(2.0, 3);
+    assert!(!settle.feed(0.0));
+    assert!(!settle.feed(50.0));
+    assert!(settle.feed(100.0));
+    assert!(!settle.is_steady());
+}
+
+#[test]
+fn counts_wakes() {
+    let mut counters = Counters::new();
+    assert_eq!(counters, Counters::default());
+    counters.woke(WakeReason::PowerOn);
+    assert_eq!(counters.wakes, 0);
+
+    counters.woke(WakeReason::Timer);
+    counters.woke(WakeReason::Ext0);
+    counters.woke(WakeReason::Ext1(1 << 33));
+    counters.woke(WakeReason::Other(7));
+    assert_eq!(counters.wakes, 4);
+    assert_eq!(counters.timer_wakes, 1);
+    assert_eq!(counters.trigger_wakes, 2);
+
+    counters.cycle_done(Some(&report(3, 0)), Duration::from_millis(4200));
+    assert_eq!(counters.frames, 3);
+    assert_eq!(counters.failed_cycles, 0);
+    assert_eq!(counters.last_awake_ms, 4200);
+    counters.cycle_done(Some(&report(1, 2)), Duration::from_secs(1));
+    counters.cycle_done(None, Duration::from_secs(2));
+    assert_eq!(counters.frames, 4);
+    assert_eq!(counters.failed_cycles, 2);
+    assert_eq!(counters.last_awake_ms, 2000);
+}
+
+#[test]
+fn knows_which_trigger_woke_it() {
+    let inputs = [
+        input("doorbell", 4, &[TriggerAction::Publish]),
+        input("button", 0, &[TriggerAction::Wake]),
+        input("pir", 33, &[TriggerAction::Snapshot, TriggerAction::Wake]),
+    ];
+    let names = |reason| -> Vec<String> {
+        sleep::woken_by(reason, &inputs)
+            .into_iter()
+            .map(|input| input.name.clone())
+            .collect()
+    };
+    // The first that wakes is on EXT0, the rest on EXT1
+    assert_eq!(names(WakeReason::Ext0), ["button"]);
+    assert_eq!(names(WakeReason::Ext1(1 << 33)), ["pir"]);
+    assert!(names(WakeReason::Ext1(1 << 0)).is_empty());
+    assert!(names(WakeReason::Timer).is_empty());
+    assert!(names(WakeReason::PowerOn).is_empty());
+}
+
+#[test]
+fn power_on_and_settings_triggers_open_a_window() {
+    let mut settings = Settings {
+        triggers: vec![
+            input("button", 0, &[TriggerAction::Wake, TriggerAction::Settings]),
+            input("pir", 33, &[TriggerAction::Wake]),
+        ],
+        ..Default::default()
+    };
+    let window = |reason| sleep::settings_window(reason, &settings);
+    assert_eq!(window(WakeReason::PowerOn), Some(Duration::from_secs(120)));
+    assert_eq!(window(WakeReason::Ext0), Some(Duration::from_secs(120)));
+    // Timer and PIR wakes capture as before
+    assert_eq!(window(WakeReason::Timer), None);
+    assert_eq!(window(WakeReason::Ext1(1 << 33)), None);
+
+    settings.sleep.settings_secs = 0;
+    assert_eq!(sleep::settings_window(WakeReason::PowerOn, &settings), None);
+
+    let parsed = Settings::from_json(br#"{"triggers": [{"actions": ["wake", "settings"]}]}"#);
+    assert_eq!(
+        parsed.unwrap().triggers[0].actions,
+        [TriggerAction::Wake, TriggerAction::Settings]
+    );
+}
+
+#[test]
+fn settings_are_checked() {
+    let mut settings = Settings::default();
+    assert!(sleep::validate(&settings).is_ok());
+
+    // Nothing to wake it
+    settings.sleep.interval_secs = 0;
+    assert!(sleep::validate(&settings).is_err());
+    settings.triggers = vec![input("button", 0, &[TriggerAction::Publish])];
+    assert!(sleep::validate(&settings).is_err());
+    settings.triggers[0].actions.push(TriggerAction::Wake);
+    assert!(sleep::validate(&settings).is_ok());
+
+    for bad in [
+        SleepSettings {
+            frames: 0,
+            ..Default::default()
+        },
+        SleepSettings {
+            max_settle_frames: 101,
+            ..Default::default()
+        },
+        SleepSettings {
+            settle_tolerance: 0.0,
+            ..Default::default()
+        },
+        SleepSettings {
+            max_awake_secs: 5,
+            ..Default::default()
+        },
+        SleepSettings {
+            settings_secs: 3601,
+            ..Default::default()
+        },
+    ] {
+        assert!(bad.validate().is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn runs_a_cycle() {
+    let app = App::new(TestPattern::new(FrameSize::Qvga, 30.0));
+    let brightness = sleep::brightness(&app.capture_frame().unwrap()).unwrap();
+    assert!((1.0..255.0).contains(&brightness), "{}", brightness);
+
+    let settings = SleepSettings {
+        frames: 3,
+        max_settle_frames: 10,
+        settle_tolerance: 5.0,
+        ..Default::default()
+    };
+    let mut stored = Vec::new();
+    let report = sleep::run_cycle(&app, &settings, &mut |frame: &_| {
+        stored.push(sleep::brightness(frame)?);
+        Ok(())
+    })
+    .unwrap();
+    // The test pattern doesn't change much, so it settles straight away
+    assert!(report.settled);
+    assert_eq!(report.settle_frames, 2);
+    assert_eq!((report.stored, report.failed), (3, 0));
+    assert_eq!(stored.len(), 3);
+
+    // A sink that fails counts against the cycle, not the camera
+    let mut calls = 0;
+    let report = sleep::run_cycle(
+        &app,
+        &SleepSettings {
+            max_settle_frames: 0,
+            ..settings
+        },
+        &mut |_: &_| {
+            calls += 1;
+            match calls {
+                2 => anyhow::bail!("card full"),
+                _ => Ok(()),
+            }
+        },
+    )
+    .unwrap();
+    assert_eq!(report.settle_frames, 0);
+    assert!(!report.settled);
+    assert_eq!((report.stored, report.failed), (2, 1));
+}
+
+#[test]
+fn logs_wakes() {
+    let dir = std::env::temp_dir().join(format!("wrover-wakes-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("sleep/wakes.jsonl");
+
+    let mut counters = Counters::new();
+    counters.woke(WakeReason::Timer);
+    let done = report(3, 0);
+    WakeRecord {
+        reason: WakeReason::Timer,
+        woken_by: Vec::new(),
+        report: Some(&done),
+        error: None,
+        counters,
+    }
+    .append_to(&path)
+    .unwrap();
+    counters.woke(WakeReason::Ext1(1 << 33));
+    WakeRecord {
+        reason: WakeReason::Ext1(1 << 33),
+        woken_by: vec!["pir"],
+        report: None,
+        error: Some("Camera: not found".into()),
+        counters,
+    }
+    .append_to(&path)
+    .unwrap();
+
+    let lines: Vec<Value> = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["reason"], "timer");
+    assert_eq!(lines[0]["report"]["stored"], 3);
+    assert_eq!(lines[0]["counters"]["wakes"], 1);
+    assert_eq!(lines[1]["reason"]["ext1"], 1u64 << 33);
+    assert_eq!(lines[1]["woken_by"][0], "pir");
+    assert_eq!(lines[1]["report"], Value::Null);
+    assert_eq!(lines[1]["counters"]["trigger_wakes"], 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,225 @@
+//! Status LED patterns: what each status looks like, which one wins, stepping through a
+//! pattern with made-up times, then the real thread driving a fake pin.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use common::{ms, wait_for};
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::status_led::{Engine, Report, Status, StatusLed, StatusPin};
+
+#[derive(Default)]
+struct Pin {
+    levels: Vec<(Instant, bool)>,
+    dropped: bool,
+}
+
+#[derive(Clone, Default)]
+struct FakePin(Arc<Mutex<Pin>>);
+
+impl StatusPin for FakePin {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        self.0.lock().unwrap().levels.push((Instant::now(), on));
+        Ok(())
+    }
+}
+
+impl Drop for FakePin {
+    fn drop(&mut self) {
+        if let Ok(mut pin) = self.0.lock() {
+            pin.dropped = true;
+        }
+    }
+}
+
+impl FakePin {
+    fn levels(&self) -> Vec<bool> {
+        self.0
+            .lock()
+            .unwrap()
+            .levels
+            .iter()
+            .map(|&(_, on)| on)
+            .collect()
+    }
+}
+
+#[test]
+fn errors_blink_their_code() {
+    let blinks = |code| {
+        Status::Error(code)
+            .pattern()
+            .iter()
+            .filter(|&&(on, _)| on)
+            .count()
+    };
+    assert_eq!(blinks(1), 1);
+    assert_eq!(blinks(3), 3);
+    // Out of range codes still blink something countable
+    assert_eq!(blinks(0), 1);
+    assert_eq!(blinks(200), 9);
+
+    let pattern = Status::Error(2).pattern();
+    assert_eq!(
+        pattern,
+        [
+            (true, ms(200)),
+            (false, ms(300)),
+            (true, ms(200)),
+            (false, ms(1500))
+        ]
+    );
+
+    // Everything starts lit, so a change shows straight away
+    for status in [
+        Status::Ready,
+        Status::Streaming,
+        Status::Booting,
+        Status::Connecting,
+        Status::Provisioning,
+        Status::Updating,
+    ] {
+        assert!(status.pattern()[0].0, "{:?}", status);
+    }
+}
+
+#[test]
+fn most_important_status_wins() {
+    let now = Instant::now();
+    let mut engine = Engine::new();
+    assert_eq!(engine.shown(), None);
+    assert!(!engine.level());
+    assert_eq!(engine.next_change(), None);
+
+    engine.report(Report::Raise(Status::Ready), now);
+    engine.report(Report::Raise(Status::Streaming), now);
+    assert_eq!(engine.shown(), Some(Status::Streaming));
+    engine.report(Report::Raise(Status::Updating), now);
+    engine.report(Report::Raise(Status::Ready), now);
+    assert_eq!(engine.shown(), Some(Status::Updating));
+    engine.report(Report::Raise(Status::Error(2)), now);
+    assert_eq!(engine.shown(), Some(Status::Error(2)));
+
+    // Clearing what isn't raised changes nothing
+    engine.report(Report::Clear(Status::Error(3)), now);
+    assert_eq!(engine.shown(), Some(Status::Error(2)));
+    engine.report(Report::Clear(Status::Error(2)), now);
+    engine.report(Report::Clear(Status::Updating), now);
+    assert_eq!(engine.shown(), Some(Status::Streaming));
+    engine.report(Report::Clear(Status::Streaming), now);
+    assert_eq!(engine.shown(), Some(Status::Ready));
+    engine.report(Report::Clear(Status::Ready), now);
+    assert_eq!(engine.shown(), None);
+    assert!(!engine.level());
+    assert_eq!(engine.next_change(), None);
+}
+
+#[test]
+fn steps_through_the_pattern() {
+    let start = Instant::now();
+    let mut engine = Engine::new();
+    engine.report(Report::Raise(Status::Error(2)), start);
+    let level_at = |engine: &mut Engine, at: u64| {
+        engine.advance(start + ms(at));
+        engine.level()
+    };
+
+    // 200 on, 300 off, 200 on, 1500 off, and round again
+    assert!(level_at(&mut engine, 0));
+    assert!(level_at(&mut engine, 199));
+    assert!(!level_at(&mut engine, 200));
+    assert!(level_at(&mut engine, 500));
+    assert!(!level_at(&mut engine, 700));
+    assert_eq!(engine.next_change(), Some(start + ms(2200)));
+    assert!(level_at(&mut engine, 2200));
+    // Late by many cycles, still in step
+    assert!(!level_at(&mut engine, 10 * 2200 + 250));
+    assert!(level_at(&mut engine, 10 * 2200 + 600));
+
+    // Raising something less important, or the same again, doesn't restart it
+    engine.report(Report::Raise(Status::Ready), start + ms(22_600));
+    engine.report(Report::Raise(Status::Error(2)), start + ms(22_600));
+    assert_eq!(engine.next_change(), Some(start + ms(22_700)));
+
+    // Back to a blip every 3 s once the error clears, starting from now
+    let cleared = start + ms(30_000);
+    engine.report(Report::Clear(Status::Error(2)), cleared);
+    assert!(engine.level());
+    assert_eq!(engine.next_change(), Some(cleared + ms(50)));
+    engine.advance(cleared + ms(50));
+    assert!(!engine.level());
+    assert_eq!(engine.next_change(), Some(cleared + ms(3000)));
+}
+
+#[test]
+fn drives_a_pin() {
+    let pin = FakePin::default();
+    let status_led = StatusLed::spawn(pin.clone()).unwrap();
+    wait_for("the LED to start off", || pin.levels() == [false]);
+
+    // Booting is 100 on, 100 off
+    status_led.raise(Status::Booting);
+    thread::sleep(ms(1000));
+    let levels = pin.levels();
+    assert!((8..=13).contains(&levels.len()), "{:?}", levels);
+    assert!(
+        levels.windows(2).all(|pair| pair[0] != pair[1]),
+        "{:?}",
+        levels
+    );
+
+    // Steady while streaming, once booting's over
+    status_led.raise(Status::Streaming);
+    status_led.clear(Status::Booting);
+    thread::sleep(ms(100));
+    let before = pin.levels().len();
+    thread::sleep(ms(1500));
+    assert_eq!(pin.levels().len(), before);
+    assert_eq!(pin.levels().last(), Some(&true));
+
+    // Off and gone once the last handle is
+    let copy = status_led.clone();
+    drop(status_led);
+    copy.raise(Status::Error(1));
+    drop(copy);
+    wait_for("the thread to stop", || pin.0.lock().unwrap().dropped);
+    assert_eq!(pin.levels().last(), Some(&false));
+
+    // Nothing to drive, nothing goes wrong
+    let none = StatusLed::none();
+    none.raise(Status::Booting);
+    none.clear(Status::Booting);
+}
+
+#[test]
+fn streaming_is_reported() {
+    let pin = FakePin::default();
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    app.set_status_led(Some(StatusLed::spawn(pin.clone()).unwrap()));
+
+    // Lit from the first client until the last one goes
+    let watching = Arc::new(Mutex::new(true));
+    let viewers: Vec<_> = (0..2)
+        .map(|_| {
+            let (app, watching) = (app.clone(), watching.clone());
+            thread::spawn(move || {
+                app.stream(|_| match *watching.lock().unwrap() {
+                    true => Ok(()),
+                    false => anyhow::bail!("gone"),
+                })
+            })
+        })
+        .collect();
+    wait_for("both viewers", || app.client_count() == 2);
+    wait_for("the LED to come on", || pin.levels().last() == Some(&true));
+    *watching.lock().unwrap() = false;
+    for viewer in viewers {
+        viewer.join().unwrap();
+    }
+    wait_for("the LED to go off", || pin.levels().last() == Some(&false));
+    assert_eq!(pin.levels(), [false, true, false]);
+}
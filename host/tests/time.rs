@@ -0,0 +1,159 @@
+//! Time zones, the SNTP client and wall-clock stamps on frames, synced against a local
+//! stand-in NTP server.
+
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{get, get_json};
+use wrover::camera::{Camera, FrameSize, TestPattern};
+use wrover::clock::{self, TimeZone};
+use wrover::http::App;
+use wrover::sntp;
+use wrover_host::ntp::NtpServer;
+use wrover_host::server::HostServer;
+
+fn unix(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// 2030-06-15T12:00:00Z
+const FUTURE: u64 = 1_907_755_200;
+
+#[test]
+fn parses_zones_without_dst() {
+    let utc = TimeZone::parse("UTC0").unwrap();
+    assert_eq!(utc.offset, 0);
+    assert!(utc.dst.is_none());
+
+    let india = TimeZone::parse("<+0530>-5:30").unwrap();
+    assert_eq!(india.name, "+0530");
+    assert_eq!(india.offset, 5 * 3600 + 30 * 60);
+
+    let new_york_std = TimeZone::parse("EST5").unwrap();
+    assert_eq!(new_york_std.offset, -5 * 3600);
+}
+
+#[test]
+fn central_european_summer_time() {
+    let tz = TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+    let dst = tz.dst.as_ref().unwrap();
+    assert_eq!(dst.offset, 7200);
+
+    // Clocks go forward at 2025-03-30 01:00 UTC and back at 2025-10-26 01:00 UTC
+    let spring = 1_743_296_400;
+    let autumn = 1_761_440_400;
+    assert_eq!(tz.offset_at(spring - 1), 3600);
+    assert_eq!(tz.offset_at(spring), 7200);
+    assert_eq!(tz.offset_at(autumn - 1), 7200);
+    assert_eq!(tz.offset_at(autumn), 3600);
+    assert_eq!(tz.name_at(spring), "CEST");
+    assert_eq!(tz.name_at(autumn), "CET");
+}
+
+#[test]
+fn southern_hemisphere_dst_wraps_the_new_year() {
+    let tz = TimeZone::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+    // 2025-01-15 and 2025-07-15, both at noon UTC
+    assert_eq!(tz.offset_at(1_736_942_400), 11 * 3600);
+    assert_eq!(tz.offset_at(1_752_580_800), 10 * 3600);
+}
+
+#[test]
+fn rejects_bad_zones() {
+    for bad in [
+        "",
+        "5",
+        "CET-1CEST,M3.5.0",
+        "CET-1CEST,J60,J300",
+        "UTC0 junk",
+        "CET-1CEST,M13.1.0,M10.5.0",
+    ] {
+        assert!(TimeZone::parse(bad).is_err(), "{:?} parsed", bad);
+    }
+}
+
+#[test]
+fn ntp_timestamps_round_trip() {
+    let time = UNIX_EPOCH + Duration::new(FUTURE, 123_456_000);
+    let back = sntp::from_ntp(sntp::to_ntp(time));
+    let error = back.duration_since(time).unwrap_or_else(|e| e.duration());
+    assert!(error < Duration::from_micros(1), "off by {:?}", error);
+}
+
+#[test]
+fn server_reply_echoes_the_request() {
+    let sent = unix(FUTURE);
+    let request = sntp::request(sent);
+    let reply = sntp::reply(&request, unix(FUTURE + 5), 2).unwrap();
+    let parsed = sntp::parse_reply(&reply).unwrap();
+
+    assert_eq!(parsed.stratum, 2);
+    assert_eq!(parsed.originate, sntp::to_ntp(sent));
+    assert_eq!(sntp::from_ntp(parsed.transmit), unix(FUTURE + 5));
+
+    // Replies aren't requests and short packets are neither
+    assert!(sntp::reply(&reply, unix(FUTURE), 2).is_err());
+    assert!(sntp::parse_reply(&reply[..20]).is_err());
+}
+
+#[test]
+fn query_measures_the_offset() {
+    let ahead = Duration::from_secs(3600);
+    let addr = NtpServer::bind("127.0.0.1:0", SystemTime::now() + ahead)
+        .unwrap()
+        .spawn();
+
+    let offset = sntp::query(addr, Duration::from_secs(2)).unwrap();
+    let error = (offset - ahead.as_micros() as i64).abs();
+    assert!(error < 100_000, "offset {}us", offset);
+}
+
+#[test]
+fn query_times_out_without_a_server() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let result = sntp::query(silent.local_addr().unwrap(), Duration::from_millis(200));
+    assert!(result.is_err());
+}
+
+/// The only test that touches the global clock, so the others don't race with it
+#[test]
+fn synced_time_reaches_frames_and_the_api() {
+    let ntp = NtpServer::bind("127.0.0.1:0", unix(FUTURE))
+        .unwrap()
+        .spawn();
+    sntp::sync(&[ntp.to_string()]).unwrap();
+    sntp::apply_timezone("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+
+    let now = clock::wall_clock().unwrap();
+    let drift = clock::unix_seconds(now) - FUTURE as i64;
+    assert!((0..5).contains(&drift), "clock is {}s off", drift);
+
+    let mut camera = TestPattern::new(FrameSize::Qqvga, 30.0);
+    let frame = camera.capture().unwrap();
+    let stamped = clock::unix_seconds(frame.wall_time.unwrap());
+    assert!((FUTURE as i64..FUTURE as i64 + 5).contains(&stamped));
+
+    let addr = HostServer::bind("127.0.0.1:0", App::new(camera))
+        .unwrap()
+        .spawn();
+
+    // Summer, so two hours ahead of UTC
+    let capture = get(addr, "/capture");
+    let filename = capture.header("Content-Disposition").unwrap();
+    assert!(
+        filename.starts_with("inline; filename=capture_20300615_1400"),
+        "{}",
+        filename
+    );
+    let timestamp: f64 = capture.header("X-Timestamp").unwrap().parse().unwrap();
+    assert!((timestamp - FUTURE as f64).abs() < 5.0, "{}", timestamp);
+
+    let status = get_json(addr, "/status");
+    let time = status["time"].as_str().unwrap();
+    assert!(
+        time.starts_with("2030-06-15T14:0") && time.ends_with("+02:00"),
+        "{}",
+        time
+    );
+}
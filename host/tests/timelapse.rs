@@ -0,0 +1,346 @@
+//! Time-lapse schedule evaluation, plus the scheduler driven over HTTP.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{get, get_json, wait_for};
+use wrover::camera::{Control, FrameBuffer, FrameSize, TestPattern};
+use wrover::clock::{self, TimeZone};
+use wrover::http::App;
+use wrover::timelapse::{sun_times, Schedule, Sun, Timelapse, TimelapseConfig, Window};
+use wrover_host::server::HostServer;
+
+fn utc(year: i64, month: u32, day: u32, hour: u32, minute: u32) -> SystemTime {
+    let secs =
+        clock::days_from_civil(year, month, day) * 86_400 + hour as i64 * 3600 + minute as i64 * 60;
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+fn show(time: Option<SystemTime>) -> String {
+    time.map_or("never".into(), |t| {
+        clock::format_datetime(clock::unix_seconds(t))
+    })
+}
+
+fn cet() -> TimeZone {
+    TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap()
+}
+
+fn next(schedule: &str, window: &Window, tz: &TimeZone, after: SystemTime) -> String {
+    let schedule = Schedule::parse(schedule).unwrap();
+    show(schedule.next_after(after, window, tz))
+}
+
+#[test]
+fn parses_intervals() {
+    let every = |text| match Schedule::parse(text).unwrap() {
+        Schedule::Every(interval) => interval,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(every("every 30s"), Duration::from_secs(30));
+    assert_eq!(every("every 5m"), Duration::from_secs(300));
+    assert_eq!(every("every 2h"), Duration::from_secs(7200));
+    assert_eq!(every("every 250ms"), Duration::from_millis(250));
+    assert_eq!(every("every 45"), Duration::from_secs(45));
+
+    for bad in ["every", "every 5 fortnights", "every 10ms", "every -1s"] {
+        assert!(Schedule::parse(bad).is_err(), "{:?} parsed", bad);
+    }
+
+    // Would overflow as seconds, instead of panicking or wrapping
+    for long in ["every 18446744073709551615m", "every 5124095576030432h"] {
+        let error = Schedule::parse(long).unwrap_err();
+        assert_eq!(error.to_string(), "Interval too long");
+    }
+}
+
+#[test]
+fn rejects_bad_cron() {
+    for bad in [
+        "* * * *",
+        "60 * * * *",
+        "* 24 * * *",
+        "* * 0 * *",
+        "* * * 13 *",
+        "* * * * 8",
+        "*/0 * * * *",
+        "5-1 * * * *",
+        "a * * * *",
+    ] {
+        assert!(Schedule::parse(bad).is_err(), "{:?} parsed", bad);
+    }
+}
+
+#[test]
+fn intervals_line_up_with_local_time() {
+    let tz = cet();
+    // 2025-07-01 08:20 UTC is 10:20 CEST
+    let after = utc(2025, 7, 1, 8, 20);
+    assert_eq!(
+        next("every 1h", &Window::Always, &tz, after),
+        "2025-07-01 09:00:00"
+    );
+    assert_eq!(
+        next("every 15m", &Window::Always, &tz, after),
+        "2025-07-01 08:30:00"
+    );
+    // India is +5:30, so hourly shots land on :30 UTC
+    let india = TimeZone::parse("<+0530>-5:30").unwrap();
+    assert_eq!(
+        next("every 1h", &Window::Always, &india, after),
+        "2025-07-01 08:30:00"
+    );
+}
+
+#[test]
+fn cron_finds_the_next_match() {
+    let tz = TimeZone::utc();
+    let wed = utc(2025, 10, 15, 10, 7); // a Wednesday
+    let window = Window::Always;
+
+    assert_eq!(
+        next("*/15 * * * *", &window, &tz, wed),
+        "2025-10-15 10:15:00"
+    );
+    assert_eq!(next("0 * * * *", &window, &tz, wed), "2025-10-15 11:00:00");
+    assert_eq!(
+        next("5,50 9-17 * * *", &window, &tz, wed),
+        "2025-10-15 10:50:00"
+    );
+    // Weekends only: Saturday morning
+    assert_eq!(
+        next("0 8 * * 6,0", &window, &tz, wed),
+        "2025-10-18 08:00:00"
+    );
+    // 7 is Sunday too
+    assert_eq!(next("0 8 * * 7", &window, &tz, wed), "2025-10-19 08:00:00");
+    // Next leap day
+    assert_eq!(next("0 0 29 2 *", &window, &tz, wed), "2028-02-29 00:00:00");
+    // Both day fields set: the 1st OR a Monday, whichever comes first
+    assert_eq!(next("0 0 1 * 1", &window, &tz, wed), "2025-10-20 00:00:00");
+    // Exactly on a match means the next one
+    let on = utc(2025, 10, 15, 10, 15);
+    assert_eq!(
+        next("*/15 * * * *", &window, &tz, on),
+        "2025-10-15 10:30:00"
+    );
+}
+
+#[test]
+fn cron_follows_the_local_zone() {
+    let tz = cet();
+    // Noon local is 10:00 UTC in summer and 11:00 UTC in winter
+    let summer = utc(2025, 7, 1, 0, 0);
+    let winter = utc(2025, 12, 1, 0, 0);
+    assert_eq!(
+        next("0 12 * * *", &Window::Always, &tz, summer),
+        "2025-07-01 10:00:00"
+    );
+    assert_eq!(
+        next("0 12 * * *", &Window::Always, &tz, winter),
+        "2025-12-01 11:00:00"
+    );
+}
+
+#[test]
+fn impossible_cron_never_fires() {
+    let schedule = Schedule::parse("0 0 31 2 *").unwrap();
+    let after = utc(2025, 1, 1, 0, 0);
+    assert_eq!(
+        schedule.next_after(after, &Window::Always, &TimeZone::utc()),
+        None
+    );
+}
+
+#[test]
+fn hour_windows_skip_ahead() {
+    let tz = TimeZone::utc();
+    let day = Window::parse("07:30-19:00", 0.0, 0.0).unwrap();
+    assert_eq!(
+        day,
+        Window::Hours {
+            start: 450,
+            end: 1140
+        }
+    );
+
+    let evening = utc(2025, 10, 15, 19, 10);
+    assert_eq!(next("every 1h", &day, &tz, evening), "2025-10-16 08:00:00");
+    assert_eq!(next("every 30m", &day, &tz, evening), "2025-10-16 07:30:00");
+    let morning = utc(2025, 10, 15, 9, 10);
+    assert_eq!(next("every 1h", &day, &tz, morning), "2025-10-15 10:00:00");
+
+    // Night window, wrapping past midnight
+    let night = Window::parse("22:00-06:00", 0.0, 0.0).unwrap();
+    assert_eq!(
+        next("every 1h", &night, &tz, morning),
+        "2025-10-15 22:00:00"
+    );
+    let late = utc(2025, 10, 15, 23, 10);
+    assert_eq!(next("every 1h", &night, &tz, late), "2025-10-16 00:00:00");
+}
+
+#[test]
+fn rejects_bad_windows() {
+    for bad in ["7-19", "25:00-26:00", "07:00", "daylight"] {
+        // `daylight` is fine in itself, but not at an impossible location
+        assert!(Window::parse(bad, 95.0, 0.0).is_err(), "{:?} parsed", bad);
+    }
+}
+
+#[test]
+fn sunrise_and_sunset() {
+    // London on the 2025 summer solstice: 03:43 and 20:21 UTC
+    let day = clock::days_from_civil(2025, 6, 21);
+    let Sun::Rises(rise, set) = sun_times(day, 51.5074, -0.1278) else {
+        panic!("The sun does rise in London");
+    };
+    let expect_rise = clock::unix_seconds(utc(2025, 6, 21, 3, 43));
+    let expect_set = clock::unix_seconds(utc(2025, 6, 21, 20, 21));
+    assert!(
+        (rise - expect_rise).abs() < 180,
+        "sunrise {}",
+        show(Some(UNIX_EPOCH + Duration::from_secs(rise as u64)))
+    );
+    assert!(
+        (set - expect_set).abs() < 180,
+        "sunset {}",
+        show(Some(UNIX_EPOCH + Duration::from_secs(set as u64)))
+    );
+
+    // Tromsø: midnight sun in June, polar night in December
+    assert_eq!(sun_times(day, 69.65, 18.96), Sun::AlwaysUp);
+    let december = clock::days_from_civil(2025, 12, 21);
+    assert_eq!(sun_times(december, 69.65, 18.96), Sun::AlwaysDown);
+}
+
+#[test]
+fn daylight_window_waits_for_sunrise() {
+    let tz = TimeZone::utc();
+    let london = Window::parse("daylight", 51.5074, -0.1278).unwrap();
+    let night = utc(2025, 6, 21, 23, 0);
+
+    // Sunrise on the 22nd is just after 03:43, so the first half-hour slot is 04:00
+    assert_eq!(
+        next("every 30m", &london, &tz, night),
+        "2025-06-22 04:00:00"
+    );
+    assert!(london.contains(utc(2025, 6, 21, 12, 0), &tz));
+    assert!(!london.contains(night, &tz));
+
+    // A margin widens the window on both sides
+    let early = Window::parse("daylight+30", 51.5074, -0.1278).unwrap();
+    assert_eq!(next("every 30m", &early, &tz, night), "2025-06-22 03:30:00");
+
+    // Polar night: nothing to shoot until the sun comes back in January
+    let tromso = Window::parse("daylight", 69.65, 18.96).unwrap();
+    let december = utc(2025, 12, 10, 12, 0);
+    let first = Schedule::parse("every 1h")
+        .unwrap()
+        .next_after(december, &tromso, &tz)
+        .unwrap();
+    assert!(first > utc(2026, 1, 10, 0, 0), "{}", show(Some(first)));
+    assert!(first < utc(2026, 1, 25, 0, 0), "{}", show(Some(first)));
+}
+
+fn config(schedule: &str) -> TimelapseConfig {
+    TimelapseConfig {
+        schedule: Schedule::parse(schedule).unwrap(),
+        window: Window::Always,
+        shot_controls: Vec::new(),
+        description: (schedule.into(), String::new()),
+    }
+}
+
+#[test]
+fn start_and_stop_over_http() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let shots = Arc::new(Mutex::new(Vec::<FrameBuffer>::new()));
+    let sink = {
+        let shots = shots.clone();
+        move |frame: &FrameBuffer| {
+            shots.lock().unwrap().push(frame.clone());
+            Ok(())
+        }
+    };
+    let timelapse = Timelapse::spawn(Arc::downgrade(&app), config("every 100ms"), sink, false);
+    app.set_timelapse(Some(timelapse));
+    let addr = HostServer::bind("127.0.0.1:0", app).unwrap().spawn();
+
+    let status = get_json(addr, "/timelapse");
+    assert_eq!(status["running"], false);
+    assert_eq!(status["schedule"], "every 100ms");
+    assert_eq!(status["shots"], 0);
+
+    let status = get_json(addr, "/timelapse?action=start");
+    assert_eq!(status["running"], true);
+    wait_for("three shots", || shots.lock().unwrap().len() >= 3);
+
+    let status = get_json(addr, "/timelapse");
+    assert!(status["next_shot"].is_string());
+    assert!(status["last_shot"].is_string());
+
+    get_json(addr, "/timelapse?action=stop");
+    thread::sleep(Duration::from_millis(150));
+    let taken = shots.lock().unwrap().len();
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(shots.lock().unwrap().len(), taken, "shots after stop");
+
+    let status = get_json(addr, "/timelapse");
+    assert_eq!(status["running"], false);
+    assert_eq!(status["shots"].as_u64().unwrap() as usize, taken);
+    assert!(status["next_shot"].is_null());
+
+    // Shots are the real thing
+    let frame = &shots.lock().unwrap()[0];
+    assert_eq!((frame.width, frame.height), (160, 120));
+    assert!(wrover::codec::decode_jpeg(&frame.data).is_ok());
+
+    let status = get(addr, "/timelapse?action=pause").status;
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn failed_shots_are_reported() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let sink = |_: &FrameBuffer| -> anyhow::Result<()> { anyhow::bail!("card full") };
+    let timelapse = Timelapse::spawn(Arc::downgrade(&app), config("every 100ms"), sink, true);
+    app.set_timelapse(Some(timelapse.clone()));
+    let addr = HostServer::bind("127.0.0.1:0", app).unwrap().spawn();
+
+    wait_for("a failure", || {
+        get_json(addr, "/timelapse")["failures"].as_u64() > Some(0)
+    });
+    let status = get_json(addr, "/timelapse");
+    assert_eq!(status["last_error"], "card full");
+    assert_eq!(status["shots"], 0);
+    timelapse.stop();
+}
+
+#[test]
+fn timelapse_is_404_when_not_set_up() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let addr = HostServer::bind("127.0.0.1:0", app).unwrap().spawn();
+    assert_eq!(get(addr, "/timelapse").status, 404);
+}
+
+#[test]
+fn shot_controls_are_temporary() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let vga = FrameSize::Vga.index();
+    let frame = app
+        .capture_with(&[(Control::FrameSize, vga), (Control::Quality, 10)])
+        .unwrap();
+    assert_eq!((frame.width, frame.height), (640, 480));
+
+    // Back to what it was for everyone else
+    let frame = app.capture_frame().unwrap();
+    assert_eq!((frame.width, frame.height), (160, 120));
+
+    // Bad values fail the shot but still leave the camera as it was
+    assert!(app.capture_with(&[(Control::Quality, 99)]).is_err());
+    assert_eq!(app.capture_frame().unwrap().width, 160);
+}
@@ -0,0 +1,314 @@
+//! Trigger inputs: debouncing with made-up times, settings checks, then the real thread
+//! watching simulated pins and running handlers.
+
+mod common;
+
+use std::fs;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{json_of, ms, temp_dir, wait_for};
+use serde_json::Value;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::http::App;
+use wrover::recorder::{self, RecorderConfig};
+use wrover::settings::{Edge, Pull, RecordingSettings, TriggerAction, TriggerSettings};
+use wrover::trigger::{
+    self, Debouncer, RecordOnTrigger, SnapshotOnTrigger, TriggerEvent, TriggerPins, Triggers,
+};
+
+/// Pins the test sets, each change waking `wait` like an interrupt would
+struct FakePins {
+    levels: Arc<Mutex<Vec<bool>>>,
+    changes: Receiver<usize>,
+}
+
+#[derive(Clone)]
+struct Hand {
+    levels: Arc<Mutex<Vec<bool>>>,
+    changes: Sender<usize>,
+}
+
+impl Hand {
+    fn set(&self, input: usize, level: bool) {
+        self.levels.lock().unwrap()[input] = level;
+        let _ = self.changes.send(input);
+    }
+
+    /// Bounces a few times on the way to `level`
+    fn bounce_to(&self, input: usize, level: bool) {
+        for _ in 0..3 {
+            self.set(input, level);
+            thread::sleep(ms(2));
+            self.set(input, !level);
+            thread::sleep(ms(2));
+        }
+        self.set(input, level);
+    }
+}
+
+fn fake_pins(levels: Vec<bool>) -> (FakePins, Hand) {
+    let levels = Arc::new(Mutex::new(levels));
+    let (changes, rx) = mpsc::channel();
+    let pins = FakePins {
+        levels: levels.clone(),
+        changes: rx,
+    };
+    (pins, Hand { levels, changes })
+}
+
+impl TriggerPins for FakePins {
+    fn wait(&mut self, timeout: Duration) -> Vec<usize> {
+        let mut changed: Vec<_> = self.changes.recv_timeout(timeout).into_iter().collect();
+        changed.extend(self.changes.try_iter());
+        changed.sort_unstable();
+        changed.dedup();
+        changed
+    }
+
+    fn level(&self, input: usize) -> bool {
+        self.levels.lock().unwrap()[input]
+    }
+}
+
+fn button(gpio: i32) -> TriggerSettings {
+    TriggerSettings {
+        gpio,
+        ..Default::default()
+    }
+}
+
+fn pir(gpio: i32) -> TriggerSettings {
+    TriggerSettings {
+        name: "pir".into(),
+        gpio,
+        pull: Pull::Down,
+        edge: Edge::Rising,
+        debounce_ms: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn debounces_a_bouncy_button() {
+    let start = Instant::now();
+    let at = |t| start + ms(t);
+    let mut button = Debouncer::new(Edge::Falling, ms(50), true);
+    assert_eq!(button.due(), None);
+    assert_eq!(button.settle(false, at(0)), None);
+
+    // Pressed, bouncing for 10 ms: only the last change counts
+    for t in [0, 3, 6, 10] {
+        button.changed(at(t));
+    }
+    assert_eq!(button.due(), Some(at(60)));
+    assert_eq!(button.settle(false, at(59)), None);
+    assert!(button.level());
+    assert_eq!(button.settle(false, at(60)), Some(Edge::Falling));
+    assert!(!button.level());
+    assert_eq!(button.due(), None);
+
+    // Released: settles, but it's the wrong way to fire
+    button.changed(at(500));
+    assert_eq!(button.settle(true, at(550)), None);
+    assert!(button.level());
+
+    // A glitch that comes back before the wait is up does nothing
+    button.changed(at(1000));
+    button.changed(at(1001));
+    assert_eq!(button.settle(true, at(1051)), None);
+    assert!(button.level());
+    assert_eq!(button.due(), None);
+}
+
+#[test]
+fn fires_on_chosen_edges() {
+    let now = Instant::now();
+    let edges = |edge, levels: &[bool]| {
+        let mut debouncer = Debouncer::new(edge, Duration::ZERO, false);
+        levels
+            .iter()
+            .filter_map(|&level| {
+                debouncer.changed(now);
+                debouncer.settle(level, now)
+            })
+            .collect::<Vec<_>>()
+    };
+    let levels = [true, false, true, true, false];
+    assert_eq!(edges(Edge::Rising, &levels), [Edge::Rising, Edge::Rising]);
+    assert_eq!(
+        edges(Edge::Falling, &levels),
+        [Edge::Falling, Edge::Falling]
+    );
+    assert_eq!(
+        edges(Edge::Both, &levels),
+        [Edge::Rising, Edge::Falling, Edge::Rising, Edge::Falling]
+    );
+}
+
+#[test]
+fn settings_are_checked() {
+    // The host builds for the Freenove: GPIO0 and 33
+    assert!(TriggerSettings::default().validate().is_err());
+    assert!(button(0).validate().is_ok());
+    assert!(pir(33).validate().is_ok());
+    for bad in [
+        button(4),
+        TriggerSettings {
+            name: String::new(),
+            ..button(0)
+        },
+        TriggerSettings {
+            debounce_ms: 1001,
+            ..button(0)
+        },
+        TriggerSettings {
+            actions: Vec::new(),
+            ..button(0)
+        },
+        TriggerSettings {
+            record_secs: 0,
+            ..button(0)
+        },
+    ] {
+        assert!(bad.validate().is_err(), "{:?}", bad);
+    }
+    assert!(trigger::validate(&[button(0), pir(33)]).is_ok());
+    assert!(trigger::validate(&[button(0), pir(0)]).is_err());
+
+    assert!(!button(0).active_level());
+    assert!(pir(33).active_level());
+    let both = |pull| TriggerSettings {
+        edge: Edge::Both,
+        pull,
+        ..button(0)
+    };
+    assert!(!both(Pull::Up).active_level());
+    assert!(both(Pull::None).active_level());
+
+    let parsed: TriggerSettings = serde_json::from_str(
+        r#"{"name": "door", "gpio": 33, "edge": "both", "pull": "none",
+            "actions": ["snapshot", "wake", "record"]}"#,
+    )
+    .unwrap();
+    assert_eq!(parsed.edge, Edge::Both);
+    assert_eq!(parsed.pull, Pull::None);
+    assert_eq!(
+        parsed.actions,
+        [
+            TriggerAction::Snapshot,
+            TriggerAction::Wake,
+            TriggerAction::Record
+        ]
+    );
+    assert_eq!(parsed.debounce_ms, 50);
+}
+
+#[test]
+fn fires_handlers() {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let (pins, hand) = fake_pins(vec![true, false]);
+    assert!(Triggers::spawn(
+        Arc::downgrade(&app),
+        vec![button(0), button(0)],
+        fake_pins(vec![true, true]).0
+    )
+    .is_err());
+    let triggers = Triggers::spawn(Arc::downgrade(&app), vec![button(0), pir(33)], pins).unwrap();
+    let events = Arc::new(Mutex::new(Vec::<TriggerEvent>::new()));
+    let seen = events.clone();
+    triggers.add_handler(move |event: &TriggerEvent, _: &Arc<App>| {
+        seen.lock().unwrap().push(event.clone());
+    });
+    let fired = |name: &str, edge| {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.input.name == name && event.edge == edge)
+            .count()
+    };
+
+    // One press, however much it bounces
+    hand.bounce_to(0, false);
+    wait_for("the press", || triggers.fired("button") == Some(1));
+    hand.bounce_to(0, true);
+    thread::sleep(ms(200));
+    assert_eq!(triggers.fired("button"), Some(1));
+    assert_eq!(fired("button", Edge::Falling), 1);
+
+    // The PIR fires each time it goes high
+    for _ in 0..2 {
+        hand.set(1, true);
+        thread::sleep(ms(100));
+        hand.set(1, false);
+        thread::sleep(ms(100));
+    }
+    wait_for("both PIR events", || fired("pir", Edge::Rising) == 2);
+    assert_eq!(fired("pir", Edge::Falling), 0);
+    assert_eq!(triggers.fired("nothing"), None);
+
+    let status: Value = serde_json::from_str(&triggers.status_json()).unwrap();
+    assert_eq!(status["inputs"][0]["name"], "button");
+    assert_eq!(status["inputs"][0]["fired"], 1);
+    assert_eq!(status["inputs"][1]["gpio"], 33);
+    assert_eq!(status["inputs"][1]["fired"], 2);
+    assert_eq!(status["inputs"][1]["actions"][0], "publish");
+    assert!(status["inputs"][1]["last_fired_ms"].is_u64());
+
+    // And the same under `/status` once the app has them
+    assert_eq!(json_of(app.route("/status", ""))["triggers"], Value::Null);
+    app.set_triggers(Some(triggers.clone()));
+    assert_eq!(json_of(app.route("/status", ""))["triggers"], status);
+}
+
+#[test]
+fn snapshots_and_recordings() {
+    let dir = temp_dir("trigger-clips");
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 30.0));
+    let (pins, hand) = fake_pins(vec![false, true]);
+    let recorder = TriggerSettings {
+        actions: vec![TriggerAction::Record],
+        record_secs: 1,
+        ..pir(33)
+    };
+    let snapshot = TriggerSettings {
+        actions: vec![TriggerAction::Snapshot],
+        ..button(0)
+    };
+    let triggers = Triggers::spawn(Arc::downgrade(&app), vec![recorder, snapshot], pins).unwrap();
+
+    let snapshots = Arc::new(Mutex::new(0));
+    let stored = snapshots.clone();
+    triggers.add_handler(SnapshotOnTrigger(move |_: &_| {
+        *stored.lock().unwrap() += 1;
+        Ok(())
+    }));
+    let settings = RecordingSettings {
+        fps: 10,
+        ..Default::default()
+    };
+    triggers.add_handler(RecordOnTrigger::new(RecorderConfig::new(&dir, &settings)));
+
+    // Recording only for the input that asks for it
+    hand.set(0, true);
+    wait_for("a clip", || recorder::clips(&dir).unwrap().len() == 1);
+    assert_eq!(*snapshots.lock().unwrap(), 0);
+    let clip = recorder::clips(&dir).unwrap().remove(0);
+    let size = || fs::metadata(&clip).unwrap().len();
+    wait_for("the clip to stop growing", || {
+        let before = size();
+        thread::sleep(ms(300));
+        size() == before
+    });
+    assert!(size() > 0);
+
+    // And a snapshot for the one that asks for that
+    hand.bounce_to(1, false);
+    wait_for("a snapshot", || *snapshots.lock().unwrap() == 1);
+    assert_eq!(recorder::clips(&dir).unwrap().len(), 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
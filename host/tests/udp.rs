@@ -0,0 +1,296 @@
+//! The UDP stream: fragment headers, reassembly with loss, reordering and wrapping ids, then
+//! frames from the test pattern over loopback.
+
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::codec;
+use wrover::http::App;
+use wrover::settings::UdpSettings;
+use wrover::udp::{self, Header, Reassembler, UdpConfig, UdpStream, HEADER_LEN};
+
+fn frame(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7) as u8).collect()
+}
+
+#[test]
+fn headers_round_trip() {
+    let header = Header {
+        frame_id: 0xdead_beef,
+        index: 2,
+        count: 3,
+        frame_len: 3000,
+        offset: 2744,
+        timestamp_us: 1_700_000_000_123_456,
+    };
+    let mut packet = Vec::new();
+    header.write(&mut packet);
+    assert_eq!(packet.len(), HEADER_LEN);
+    assert_eq!(&packet[..4], b"WU\x01\x00");
+    packet.extend_from_slice(&[1; 256]);
+
+    let (parsed, data) = Header::parse(&packet).unwrap();
+    assert_eq!(parsed, header);
+    assert_eq!(data, [1; 256]);
+}
+
+#[test]
+fn bad_fragments() {
+    let good = udp::fragment(1, 0, &frame(100), 1400).unwrap().remove(0);
+    assert!(Header::parse(&good).is_ok());
+    assert!(Header::parse(&good[..HEADER_LEN - 1]).is_err());
+
+    let mut bad_magic = good.clone();
+    bad_magic[0] = b'X';
+    assert!(Header::parse(&bad_magic).is_err());
+
+    let mut bad_version = good.clone();
+    bad_version[2] = 2;
+    assert!(Header::parse(&bad_version).is_err());
+
+    // Fragment 1 of 1
+    let mut bad_index = good.clone();
+    bad_index[9] = 1;
+    assert!(Header::parse(&bad_index).is_err());
+
+    // 100 bytes at offset 1 of a 100 byte frame
+    let mut overrun = good.clone();
+    overrun[19] = 1;
+    assert!(Header::parse(&overrun).is_err());
+}
+
+#[test]
+fn fragments_fit_and_cover_the_frame() {
+    let data = frame(5000);
+    let packets = udp::fragment(42, 99, &data, 1000).unwrap();
+    assert_eq!(packets.len(), 6);
+
+    let mut rebuilt = Vec::new();
+    for (i, packet) in packets.iter().enumerate() {
+        assert!(packet.len() <= 1000);
+        let (header, part) = Header::parse(packet).unwrap();
+        assert_eq!(header.frame_id, 42);
+        assert_eq!(header.timestamp_us, 99);
+        assert_eq!((header.index as usize, header.count), (i, 6));
+        assert_eq!(header.frame_len, 5000);
+        assert_eq!(header.offset as usize, rebuilt.len());
+        rebuilt.extend_from_slice(part);
+    }
+    assert_eq!(rebuilt, data);
+
+    // Exactly full packets, and an empty frame still gets one
+    assert_eq!(udp::fragment(1, 0, &frame(972 * 2), 1000).unwrap().len(), 2);
+    assert_eq!(udp::fragment(1, 0, &[], 1000).unwrap().len(), 1);
+    assert!(udp::fragment(1, 0, &data, HEADER_LEN + 10).is_err());
+}
+
+#[test]
+fn reassembles_out_of_order() {
+    let data = frame(3000);
+    let mut packets = udp::fragment(7, 5, &data, 500).unwrap();
+    packets.reverse();
+    let last = packets.pop().unwrap();
+
+    let mut reassembler = Reassembler::default();
+    for packet in &packets {
+        assert_eq!(reassembler.push(packet), None);
+    }
+    // A duplicate changes nothing
+    assert_eq!(reassembler.push(&packets[0]), None);
+    let frame = reassembler.push(&last).unwrap();
+    assert_eq!((frame.id, frame.timestamp_us), (7, 5));
+    assert_eq!(frame.data, data);
+
+    // It's done, stragglers are late
+    assert_eq!(reassembler.push(&packets[1]), None);
+    let stats = reassembler.stats();
+    assert_eq!(stats.frames, 1);
+    assert_eq!(stats.lost, 0);
+    assert_eq!(stats.late, 2);
+    assert_eq!(stats.fragments, packets.len() as u64 + 3);
+}
+
+#[test]
+fn counts_lost_frames() {
+    let mut reassembler = Reassembler::default();
+    let mut send = |id: u32, skip: Option<usize>| {
+        let mut delivered = Vec::new();
+        for (i, packet) in udp::fragment(id, 0, &frame(2000), 600)
+            .unwrap()
+            .iter()
+            .enumerate()
+        {
+            if Some(i) != skip {
+                delivered.extend(reassembler.push(packet).map(|frame| frame.id));
+            }
+        }
+        (delivered, reassembler.stats())
+    };
+
+    assert_eq!(send(10, None).0, [10]);
+    // Missing a fragment, so not delivered
+    assert!(send(11, Some(1)).0.is_empty());
+    // Never sent at all
+    let (delivered, stats) = send(13, None);
+    assert_eq!(delivered, [13]);
+    assert_eq!((stats.frames, stats.lost), (2, 2));
+    // 11 and 12 are given up on, bits of them arriving now are late
+    let (delivered, stats) = send(12, None);
+    assert!(delivered.is_empty());
+    assert_eq!(stats.late, 4);
+}
+
+#[test]
+fn frame_ids_wrap() {
+    let mut reassembler = Reassembler::default();
+    for id in [u32::MAX - 1, u32::MAX, 0, 1] {
+        let packets = udp::fragment(id, 0, &frame(100), 1400).unwrap();
+        assert_eq!(reassembler.push(&packets[0]).unwrap().id, id);
+    }
+    assert_eq!(reassembler.stats().lost, 0);
+    assert_eq!(reassembler.stats().late, 0);
+}
+
+#[test]
+fn interleaved_frames_both_complete() {
+    let first = udp::fragment(1, 0, &frame(1000), 300).unwrap();
+    let second = udp::fragment(2, 0, &frame(1000), 300).unwrap();
+
+    let mut reassembler = Reassembler::default();
+    let mut delivered = Vec::new();
+    for (a, b) in first.iter().zip(&second) {
+        delivered.extend(reassembler.push(a).map(|frame| frame.id));
+        delivered.extend(reassembler.push(b).map(|frame| frame.id));
+    }
+    assert_eq!(delivered, [1, 2]);
+}
+
+#[test]
+fn garbage_is_counted() {
+    let mut reassembler = Reassembler::default();
+    assert_eq!(reassembler.push(b"hello"), None);
+    assert_eq!(reassembler.push(&[0; 64]), None);
+    assert_eq!(reassembler.stats().invalid, 2);
+    assert_eq!(reassembler.stats().fragments, 0);
+}
+
+#[test]
+fn oversized_frames_are_dropped() {
+    // A header alone can claim a frame of up to 4 GiB
+    let mut huge = Vec::new();
+    Header {
+        frame_id: 1,
+        index: 0,
+        count: 2,
+        frame_len: u32::MAX,
+        offset: 0,
+        timestamp_us: 0,
+    }
+    .write(&mut huge);
+    huge.extend_from_slice(&[0; 100]);
+    let mut reassembler = Reassembler::default();
+    assert_eq!(reassembler.push(&huge), None);
+    assert_eq!(reassembler.stats().invalid, 1);
+
+    let mut reassembler = Reassembler::default().with_max_frame(1000);
+    for packet in udp::fragment(2, 0, &frame(1001), 500).unwrap() {
+        assert_eq!(reassembler.push(&packet), None);
+    }
+    let packets = udp::fragment(3, 0, &frame(1000), 500).unwrap();
+    let delivered: Vec<_> = packets.iter().filter_map(|p| reassembler.push(p)).collect();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(reassembler.stats().invalid, 3);
+    assert_eq!(reassembler.stats().lost, 0);
+}
+
+#[test]
+fn targets_resolve() {
+    let config = |target: &str| {
+        UdpConfig::from_settings(&UdpSettings {
+            target: target.into(),
+            ..Default::default()
+        })
+    };
+    assert_eq!(
+        config("127.0.0.1:6000").unwrap().target,
+        "127.0.0.1:6000".parse().unwrap()
+    );
+    assert_eq!(
+        config("239.1.2.3").unwrap().target,
+        "239.1.2.3:5000".parse().unwrap()
+    );
+    assert_eq!(config("localhost:7000").unwrap().target.port(), 7000);
+    assert!(config("").is_err());
+
+    let tiny = UdpConfig::from_settings(&UdpSettings {
+        target: "127.0.0.1".into(),
+        max_packet: 10,
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(tiny.max_packet > HEADER_LEN);
+}
+
+fn start(socket: &UdpSocket) -> (Arc<App>, Arc<UdpStream>) {
+    let app = App::new(TestPattern::new(FrameSize::Qvga, 30.0));
+    let config = UdpConfig {
+        target: socket.local_addr().unwrap(),
+        fps: 20.0,
+        max_packet: 1000,
+        ttl: 1,
+    };
+    let stream = UdpStream::spawn(Arc::downgrade(&app), config).unwrap();
+    (app, stream)
+}
+
+#[test]
+fn streams_over_loopback() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let (_app, stream) = start(&socket);
+
+    let mut reassembler = Reassembler::default();
+    let mut buf = [0; 2048];
+    let mut frames = Vec::new();
+    while frames.len() < 3 {
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(from.port(), stream.local_addr().port());
+        assert!(len <= 1000);
+        frames.extend(reassembler.push(&buf[..len]));
+    }
+
+    for pair in frames.windows(2) {
+        assert!(pair[1].id.wrapping_sub(pair[0].id) >= 1);
+        assert!(pair[1].timestamp_us > pair[0].timestamp_us);
+    }
+    let image = codec::decode_jpeg(&frames[2].data).unwrap();
+    assert_eq!((image.width, image.height), (320, 240));
+
+    let status: Value = serde_json::from_str(&stream.status_json()).unwrap();
+    assert_eq!(status["target"], socket.local_addr().unwrap().to_string());
+    assert!(status["frames"].as_u64().unwrap() >= 3);
+    assert!(status["packets"].as_u64().unwrap() > status["frames"].as_u64().unwrap());
+    assert_eq!(status["errors"], 0);
+}
+
+#[test]
+fn stops_with_the_app() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let (app, _stream) = start(&socket);
+    let mut buf = [0; 2048];
+    socket.recv(&mut buf).unwrap();
+
+    drop(app);
+    std::thread::sleep(Duration::from_millis(200));
+    // Drain what was already in flight, then nothing more comes
+    while socket.recv(&mut buf).is_ok() {}
+    assert!(socket.recv(&mut buf).is_err());
+}
@@ -0,0 +1,337 @@
+//! PUT uploads: path templates, the retry queue, the SD spool, and uploads against a local
+//! stand-in server
+
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use common::{temp_dir, wait_for};
+use serde_json::Value;
+use wrover::camera::{FrameBuffer, FrameFormat, FrameSize, TestPattern};
+use wrover::client::Auth;
+use wrover::http::App;
+use wrover::settings::UploadSettings;
+use wrover::sink::FrameSink;
+use wrover::upload::{
+    url_for, PathTemplate, RetryQueue, Spool, Upload, UploadConfig, UploadSink, Uploader,
+};
+use wrover_host::catcher::Catcher;
+use wrover_host::client::TcpHttpClient;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 2024-03-05 06:07:08.009 UTC, 42 s after boot
+fn frame() -> FrameBuffer {
+    FrameBuffer {
+        data: vec![0xff, 0xd8, 1, 2, 3, 0xff, 0xd9],
+        width: 160,
+        height: 120,
+        format: FrameFormat::Jpeg,
+        timestamp: Duration::from_millis(42_000),
+        wall_time: Some(UNIX_EPOCH + Duration::from_millis(1_709_618_828_009)),
+    }
+}
+
+fn config(url: &str) -> UploadConfig {
+    let settings = UploadSettings {
+        url: url.into(),
+        username: "cam".into(),
+        password: "secret".into(),
+        ..Default::default()
+    };
+    let mut config = UploadConfig::from_settings(&settings, "wrover-a1b2c3").unwrap();
+    config.backoff = Duration::from_millis(10);
+    config.replay_interval = Duration::from_millis(100);
+    config
+}
+
+fn app() -> Arc<App> {
+    App::new(TestPattern::new(FrameSize::Qqvga, 30.0))
+}
+
+fn status(uploader: &Uploader) -> Value {
+    serde_json::from_str(&uploader.status_json()).unwrap()
+}
+
+#[test]
+fn templates_render() {
+    let template = PathTemplate::parse("{camera}/{date}/{time}.jpg").unwrap();
+    assert_eq!(
+        template.render("porch", "wrover-a1b2c3", &frame()),
+        "porch/20240305/060708_009.jpg"
+    );
+    // No slashes sneak in from the name, and no leading one either
+    assert_eq!(
+        template.render("front/back", "x", &frame()),
+        "front_back/20240305/060708_009.jpg"
+    );
+    let template = PathTemplate::parse("/cams/{device}-{uptime}.jpg").unwrap();
+    assert_eq!(
+        template.render("porch", "wrover-a1b2c3", &frame()),
+        "cams/wrover-a1b2c3-42000.jpg"
+    );
+
+    // Before the clock is synced
+    let unsynced = FrameBuffer {
+        wall_time: None,
+        ..frame()
+    };
+    let template = PathTemplate::parse("{camera}/{date}/{time}.jpg").unwrap();
+    assert_eq!(
+        template.render("porch", "x", &unsynced),
+        "porch/boot/42000.jpg"
+    );
+}
+
+#[test]
+fn bad_templates_are_rejected() {
+    assert!(PathTemplate::parse("{camera}/{nope}.jpg").is_err());
+    assert!(PathTemplate::parse("{camera/x.jpg").is_err());
+    assert!(PathTemplate::parse("camera}/x.jpg").is_err());
+    assert!(PathTemplate::parse("").is_err());
+}
+
+#[test]
+fn urls_are_encoded() {
+    assert_eq!(
+        url_for("http://nas/dav/", "front door/20240305/x.jpg"),
+        "http://nas/dav/front%20door/20240305/x.jpg"
+    );
+    assert_eq!(url_for("http://nas", "a+b.jpg"), "http://nas/a%2Bb.jpg");
+}
+
+#[test]
+fn auth_from_settings() {
+    let config = config("http://nas/dav");
+    assert_eq!(
+        config.auth,
+        Auth::Basic {
+            username: "cam".into(),
+            password: "secret".into()
+        }
+    );
+    let basic = Auth::Basic {
+        username: "Aladdin".into(),
+        password: "open sesame".into(),
+    };
+    assert_eq!(
+        basic.header().unwrap(),
+        "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+    );
+
+    let settings = UploadSettings {
+        url: "https://bucket.example.com".into(),
+        username: "ignored".into(),
+        token: "t0k3n".into(),
+        ..Default::default()
+    };
+    let config = UploadConfig::from_settings(&settings, "x").unwrap();
+    assert_eq!(config.auth.header().unwrap(), "Bearer t0k3n");
+
+    let settings = UploadSettings {
+        url: "https://nas".into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        UploadConfig::from_settings(&settings, "x").unwrap().auth,
+        Auth::None
+    );
+    assert!(UploadConfig::from_settings(&UploadSettings::default(), "x").is_err());
+}
+
+#[test]
+fn retry_queue_backs_off() {
+    let start = Instant::now();
+    let ms = |n| start + Duration::from_millis(n);
+    let mut queue = RetryQueue::new(4, 2, Duration::from_millis(100));
+    assert_eq!(queue.push(Upload::new("a", vec![]), start), None);
+    assert_eq!(queue.push(Upload::new("b", vec![]), start), None);
+
+    let a = queue.pop_due(start).unwrap();
+    assert_eq!(a.path, "a");
+    // First retry 100 ms later
+    assert_eq!(queue.retry(a, start), None);
+    assert_eq!(queue.pop_due(start).unwrap().path, "b");
+    assert_eq!(queue.pop_due(ms(99)), None);
+    assert_eq!(queue.next_due(), Some(ms(100)));
+    let a = queue.pop_due(ms(100)).unwrap();
+    assert_eq!(a.attempts, 1);
+
+    // Second one twice as long after that
+    assert_eq!(queue.retry(a, ms(100)), None);
+    assert_eq!(queue.pop_due(ms(299)), None);
+    let a = queue.pop_due(ms(300)).unwrap();
+    assert_eq!(a.attempts, 2);
+
+    // Out of retries: handed back
+    let a = queue.retry(a, ms(300)).unwrap();
+    assert_eq!(a.attempts, 3);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn retry_queue_is_bounded() {
+    let now = Instant::now();
+    let mut queue = RetryQueue::new(2, 3, Duration::from_millis(100));
+    assert_eq!(queue.push(Upload::new("a", vec![]), now), None);
+    assert_eq!(queue.push(Upload::new("b", vec![]), now), None);
+    assert_eq!(queue.push(Upload::new("c", vec![]), now).unwrap().path, "a");
+    assert_eq!(queue.len(), 2);
+
+    // Whatever has waited longest goes first
+    let later = now + Duration::from_millis(500);
+    let b = queue.pop_due(now).unwrap();
+    assert_eq!(queue.retry(b, now), None);
+    assert_eq!(
+        queue.push(Upload::new("d", vec![]), later),
+        Some(Upload::new("c", vec![]))
+    );
+    assert_eq!(queue.pop_due(later).unwrap().path, "b");
+    assert_eq!(queue.pop_due(later).unwrap().path, "d");
+}
+
+#[test]
+fn spool_keeps_paths_in_order() {
+    let dir = temp_dir("upload-spool");
+    let spool = Spool::new(&dir);
+    assert_eq!(spool.next().unwrap(), None);
+
+    spool
+        .store(&Upload::new("porch/20240305/060709_000.jpg", vec![2]))
+        .unwrap();
+    spool
+        .store(&Upload::new("porch/20240305/060708_000.jpg", vec![1]))
+        .unwrap();
+    spool
+        .store(&Upload::new("porch/20240306/000000_000.jpg", vec![3]))
+        .unwrap();
+    assert_eq!(
+        spool.paths(),
+        [
+            "porch/20240305/060708_000.jpg",
+            "porch/20240305/060709_000.jpg",
+            "porch/20240306/000000_000.jpg",
+        ]
+    );
+
+    let first = spool.next().unwrap().unwrap();
+    assert_eq!(first.path, "porch/20240305/060708_000.jpg");
+    assert_eq!(first.jpeg, [1]);
+    spool.remove(&first.path).unwrap();
+    spool.remove("porch/20240305/060709_000.jpg").unwrap();
+    // Emptied directories go too, but not the spool itself
+    assert!(!dir.join("porch/20240305").exists());
+    spool.remove("porch/20240306/000000_000.jpg").unwrap();
+    assert!(!dir.join("porch").exists());
+    assert!(dir.exists());
+    assert_eq!(spool.next().unwrap(), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn uploads_to_the_server() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let uploader = Uploader::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/dav/")),
+        TcpHttpClient::new(TIMEOUT),
+        None,
+    );
+
+    let mut sink = UploadSink(uploader.clone());
+    sink.store(&frame()).unwrap();
+    let received = catcher.wait_for(1, TIMEOUT);
+    assert_eq!(received[0].method, "PUT");
+    assert_eq!(received[0].path, "/dav/wrover/20240305/060708_009.jpg");
+    assert_eq!(received[0].header("Content-Type"), Some("image/jpeg"));
+    assert_eq!(
+        received[0].header("Authorization"),
+        Some("Basic Y2FtOnNlY3JldA==")
+    );
+    assert_eq!(received[0].body, frame().data);
+
+    wait_for("upload", || status(&uploader)["uploaded"] == 1);
+    assert!(uploader.is_idle());
+
+    let raw = FrameBuffer {
+        format: FrameFormat::Grayscale,
+        ..frame()
+    };
+    assert!(uploader.upload(&raw).is_err());
+}
+
+#[test]
+fn failed_uploads_are_spooled_and_replayed() {
+    let dir = temp_dir("upload-replay");
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    // The first upload and its three retries
+    catcher.reply_with(&[503, 503, 503, 503]);
+    let app = app();
+    let uploader = Uploader::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/dav")),
+        TcpHttpClient::new(TIMEOUT),
+        Some(Spool::new(&dir)),
+    );
+
+    uploader.upload(&frame()).unwrap();
+    wait_for("spooling", || status(&uploader)["spooled"] == 1);
+
+    // The server's back: the spooled one goes on the next replay
+    wait_for("replay", || status(&uploader)["replayed"] == 1);
+    let received = catcher.received();
+    assert_eq!(received.len(), 5);
+    assert_eq!(received[4].path, "/dav/wrover/20240305/060708_009.jpg");
+    assert_eq!(received[4].body, frame().data);
+    assert_eq!(status(&uploader)["spool"], 0);
+    assert_eq!(status(&uploader)["failed"], 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn leftovers_from_before_a_reboot_go_first() {
+    let dir = temp_dir("upload-leftovers");
+    Spool::new(&dir)
+        .store(&Upload::new("wrover/boot/1000.jpg", vec![9]))
+        .unwrap();
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let uploader = Uploader::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/dav")),
+        TcpHttpClient::new(TIMEOUT),
+        Some(Spool::new(&dir)),
+    );
+
+    let received = catcher.wait_for(1, TIMEOUT);
+    assert_eq!(received[0].path, "/dav/wrover/boot/1000.jpg");
+    wait_for("replay", || status(&uploader)["replayed"] == 1);
+    assert_eq!(Spool::new(&dir).paths(), Vec::<String>::new());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rejected_uploads_are_not_retried() {
+    let dir = temp_dir("upload-rejected");
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    catcher.reply_with(&[403]);
+    let app = app();
+    let uploader = Uploader::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/dav")),
+        TcpHttpClient::new(TIMEOUT),
+        Some(Spool::new(&dir)),
+    );
+
+    uploader.upload(&frame()).unwrap();
+    wait_for("failure", || status(&uploader)["failed"] == 1);
+    assert_eq!(catcher.received().len(), 1);
+    assert_eq!(status(&uploader)["last_error"], "HTTP 403");
+    assert_eq!(status(&uploader)["spooled"], 0);
+}
@@ -0,0 +1,423 @@
+//! Webhook bodies and signing, and deliveries against a local stand-in server
+
+mod common;
+
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{get_json, request, wait_for};
+use serde_json::Value;
+use wrover::camera::{FrameSize, TestPattern};
+use wrover::client::{Method, Request};
+use wrover::http::App;
+use wrover::motion::{MotionEvent, MotionHandler, PostOnMotion};
+use wrover::settings::{Settings, WebhookSettings};
+use wrover::webhook::{
+    self, BodyFormat, Delivery, Webhook, WebhookConfig, METADATA_HEADER, SIGNATURE_HEADER,
+};
+use wrover_host::catcher::Catcher;
+use wrover_host::client::{split_url, TcpHttpClient};
+use wrover_host::server::HostServer;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn config(url: &str) -> WebhookConfig {
+    let settings = WebhookSettings {
+        url: url.into(),
+        secret: "hunter2".into(),
+        ..Default::default()
+    };
+    let mut config = WebhookConfig::from_settings(&settings, "wrover-a1b2c3").unwrap();
+    config.backoff = Duration::from_millis(10);
+    config
+}
+
+fn app() -> Arc<App> {
+    App::new(TestPattern::new(FrameSize::Qqvga, 30.0))
+}
+
+fn delivery() -> Delivery {
+    let frame = app().capture_frame().unwrap();
+    Delivery::new(
+        "motion",
+        "porch",
+        "wrover-a1b2c3",
+        &frame,
+        serde_json::json!({ "area": 0.25 }),
+    )
+}
+
+/// Parts of a `multipart/form-data` body: (headers, content)
+fn parts(content_type: &str, body: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let boundary = content_type.split_once("boundary=").unwrap().1;
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // The first delimiter has no CRLF in front of it
+    let body = [b"\r\n".as_slice(), body].concat();
+    let mut at = Vec::new();
+    while let Some(next) = find(&body[at.last().map_or(0, |&last| last + 1)..], &delimiter) {
+        at.push(next + at.last().map_or(0, |&last| last + 1));
+    }
+    assert!(body[at.last().unwrap() + delimiter.len()..].starts_with(b"--\r\n"));
+    at.windows(2)
+        .map(|pair| {
+            let part = &body[pair[0] + delimiter.len()..pair[1]];
+            let headers_end = find(part, b"\r\n\r\n").unwrap();
+            let headers = String::from_utf8(part[2..headers_end].to_vec()).unwrap();
+            (headers, part[headers_end + 4..].to_vec())
+        })
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn status(webhook: &Webhook) -> Value {
+    serde_json::from_str(&webhook.status_json()).unwrap()
+}
+
+#[test]
+fn config_from_settings() {
+    assert!(WebhookConfig::from_settings(&WebhookSettings::default(), "x").is_err());
+    let settings = WebhookSettings {
+        url: "https://example.com/hook".into(),
+        format: "raw".into(),
+        interval_secs: 300,
+        ..Default::default()
+    };
+    let config = WebhookConfig::from_settings(&settings, "x").unwrap();
+    assert_eq!(config.format, BodyFormat::Raw);
+    assert_eq!(config.secret, None);
+    assert_eq!(config.interval, Some(Duration::from_secs(300)));
+    assert_eq!(config.retries, 3);
+    assert_eq!(config.queue_len, 4);
+
+    let settings = WebhookSettings {
+        format: "xml".into(),
+        ..settings
+    };
+    assert!(WebhookConfig::from_settings(&settings, "x").is_err());
+}
+
+#[test]
+fn signature_is_hmac_sha256() {
+    // RFC 4231-style vector, as printed by `openssl dgst -sha256 -hmac key`
+    assert_eq!(
+        webhook::sign(b"key", b"The quick brown fox jumps over the lazy dog"),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+}
+
+#[test]
+fn multipart_body() {
+    let delivery = delivery();
+    let request = webhook::build_request(&config("http://example.com/hook"), &delivery);
+    assert_eq!(request.method, Method::Post);
+    assert_eq!(request.url, "http://example.com/hook");
+    assert_eq!(
+        request.header(SIGNATURE_HEADER).unwrap(),
+        webhook::sign(b"hunter2", &request.body)
+    );
+
+    let parts = parts(request.header("Content-Type").unwrap(), &request.body);
+    assert_eq!(parts.len(), 2);
+    assert!(parts[0].0.contains("name=\"metadata\""));
+    let metadata: Value = serde_json::from_slice(&parts[0].1).unwrap();
+    assert_eq!(metadata["event"], "motion");
+    assert_eq!(metadata["camera"], "porch");
+    assert_eq!(metadata["device"], "wrover-a1b2c3");
+    assert_eq!(metadata["width"], 160);
+    assert_eq!(metadata["area"], 0.25);
+    assert_eq!(metadata["bytes"], delivery.jpeg.len());
+    assert!(parts[1].0.contains("name=\"image\""));
+    assert!(parts[1].0.contains("Content-Type: image/jpeg"));
+    assert_eq!(parts[1].1, delivery.jpeg);
+}
+
+#[test]
+fn raw_body() {
+    let delivery = delivery();
+    let mut config = config("http://example.com/hook");
+    config.format = BodyFormat::Raw;
+    config.secret = None;
+    let request = webhook::build_request(&config, &delivery);
+    assert_eq!(request.header("Content-Type"), Some("image/jpeg"));
+    assert_eq!(request.body, delivery.jpeg);
+    assert_eq!(request.header(SIGNATURE_HEADER), None);
+    let metadata: Value = serde_json::from_str(request.header(METADATA_HEADER).unwrap()).unwrap();
+    assert_eq!(metadata, delivery.metadata);
+}
+
+#[test]
+fn urls_split() {
+    assert_eq!(
+        split_url("http://10.0.0.2:8080/a/b?c=d").unwrap(),
+        ("10.0.0.2".to_owned(), 8080, "/a/b?c=d".to_owned())
+    );
+    assert_eq!(
+        split_url("http://example.com").unwrap(),
+        ("example.com".to_owned(), 80, "/".to_owned())
+    );
+    assert!(split_url("https://example.com").is_err());
+}
+
+#[test]
+fn delivered_to_the_server() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let webhook = Webhook::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/hook?camera=1")),
+        TcpHttpClient::new(TIMEOUT),
+    );
+
+    let frame = app.capture_frame().unwrap();
+    webhook.post("test", &frame, Value::Null).unwrap();
+    let received = catcher.wait_for(1, TIMEOUT);
+    let request = &received[0];
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/hook?camera=1");
+    assert_eq!(
+        request.header(SIGNATURE_HEADER).unwrap(),
+        webhook::sign(b"hunter2", &request.body)
+    );
+    let parts = parts(request.header("Content-Type").unwrap(), &request.body);
+    let metadata: Value = serde_json::from_slice(&parts[0].1).unwrap();
+    assert_eq!(metadata["event"], "test");
+    assert_eq!(metadata["camera"], "wrover");
+    assert_eq!(parts[1].1, frame.data);
+
+    wait_for("delivery", || status(&webhook)["delivered"] == 1);
+    let status = status(&webhook);
+    assert_eq!(status["last_status"], 200);
+    assert_eq!(status["signed"], true);
+    assert!(webhook.is_idle());
+}
+
+#[test]
+fn server_errors_are_retried() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    catcher.reply_with(&[500, 503]);
+    let app = app();
+    let webhook = Webhook::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/hook")),
+        TcpHttpClient::new(TIMEOUT),
+    );
+
+    webhook.capture("test");
+    wait_for("delivery", || status(&webhook)["delivered"] == 1);
+    let received = catcher.received();
+    assert_eq!(received.len(), 3);
+    // The same request every time
+    assert_eq!(received[0].body, received[2].body);
+    assert_eq!(status(&webhook)["failed"], 0);
+}
+
+#[test]
+fn gives_up_after_the_retries() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    catcher.reply_with(&[500, 500, 500, 500, 400]);
+    let app = app();
+    let webhook = Webhook::spawn(
+        Arc::downgrade(&app),
+        config(&catcher.url("/hook")),
+        TcpHttpClient::new(TIMEOUT),
+    );
+
+    webhook.capture("test");
+    wait_for("failure", || status(&webhook)["failed"] == 1);
+    assert_eq!(catcher.received().len(), 4);
+    assert_eq!(status(&webhook)["last_error"], "HTTP 500");
+
+    // A client error won't get better by asking again
+    webhook.capture("test");
+    wait_for("failure", || status(&webhook)["failed"] == 2);
+    assert_eq!(catcher.received().len(), 5);
+    assert_eq!(status(&webhook)["last_status"], 400);
+}
+
+#[test]
+fn unreachable_server_counts_as_failed() {
+    // Bind and drop to find a port nobody listens on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let app = app();
+    let mut config = config(&format!("http://{}/hook", addr));
+    config.retries = 1;
+    let webhook = Webhook::spawn(Arc::downgrade(&app), config, TcpHttpClient::new(TIMEOUT));
+
+    webhook.capture("test");
+    wait_for("failure", || status(&webhook)["failed"] == 1);
+    assert!(status(&webhook)["last_error"].is_string());
+}
+
+#[test]
+fn full_queue_drops_the_oldest() {
+    // A client that holds the first request until told to go on
+    let (release, held) = mpsc::channel::<()>();
+    let held = Mutex::new(held);
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let log = sent.clone();
+    let client = move |request: &Request| {
+        if log.lock().unwrap().is_empty() {
+            held.lock().unwrap().recv().unwrap();
+        }
+        let metadata: Value =
+            serde_json::from_str(request.header(METADATA_HEADER).unwrap()).unwrap();
+        log.lock()
+            .unwrap()
+            .push(metadata["event"].as_str().unwrap().to_owned());
+        Ok(200)
+    };
+
+    let app = app();
+    let mut config = config("http://example.com/hook");
+    config.format = BodyFormat::Raw;
+    config.queue_len = 2;
+    let webhook = Webhook::spawn(Arc::downgrade(&app), config, client);
+
+    let frame = app.capture_frame().unwrap();
+    webhook.post("first", &frame, Value::Null).unwrap();
+    wait_for("first send", || status(&webhook)["queued"] == 0);
+    for event in ["a", "b", "c", "d"] {
+        webhook.post(event, &frame, Value::Null).unwrap();
+    }
+    assert_eq!(status(&webhook)["queued"], 2);
+    assert_eq!(status(&webhook)["dropped"], 2);
+
+    release.send(()).unwrap();
+    wait_for("queue to drain", || webhook.is_idle());
+    assert_eq!(*sent.lock().unwrap(), ["first", "c", "d"]);
+    assert_eq!(status(&webhook)["delivered"], 3);
+}
+
+#[test]
+fn boot_and_timer_posts() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let mut config = config(&catcher.url("/hook"));
+    config.format = BodyFormat::Raw;
+    config.on_boot = true;
+    config.interval = Some(Duration::from_millis(100));
+    let _webhook = Webhook::spawn(Arc::downgrade(&app), config, TcpHttpClient::new(TIMEOUT));
+
+    let received = catcher.wait_for(3, TIMEOUT);
+    let events: Vec<String> = received
+        .iter()
+        .map(|request| {
+            let metadata: Value =
+                serde_json::from_str(request.header(METADATA_HEADER).unwrap()).unwrap();
+            metadata["event"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(events[..3], ["boot", "timer", "timer"]);
+}
+
+#[test]
+fn motion_starts_are_posted() {
+    let catcher = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let mut config = config(&catcher.url("/hook"));
+    config.format = BodyFormat::Raw;
+    let webhook = Webhook::spawn(Arc::downgrade(&app), config, TcpHttpClient::new(TIMEOUT));
+    let mut handler = PostOnMotion(webhook.clone());
+
+    let frame = app.capture_frame().unwrap();
+    let started = MotionEvent::Started {
+        at: Duration::from_secs(3),
+        area: 0.5,
+    };
+    let stopped = MotionEvent::Stopped {
+        at: Duration::from_secs(9),
+        duration: Duration::from_secs(6),
+    };
+    handler.event(&started, &frame);
+    handler.event(&stopped, &frame);
+
+    let received = catcher.wait_for(1, TIMEOUT);
+    let metadata: Value =
+        serde_json::from_str(received[0].header(METADATA_HEADER).unwrap()).unwrap();
+    assert_eq!(metadata["event"], "motion");
+    assert_eq!(metadata["area"], 0.5);
+    wait_for("delivery", || webhook.is_idle());
+    // Nothing for the stop
+    assert_eq!(catcher.received().len(), 1);
+}
+
+#[test]
+fn settings_move_the_webhook() {
+    let old = Catcher::spawn("127.0.0.1:0").unwrap();
+    let new = Catcher::spawn("127.0.0.1:0").unwrap();
+    let app = app();
+    let settings = Settings {
+        webhook: WebhookSettings {
+            enabled: true,
+            url: old.url("/hook"),
+            secret: "hunter2".into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let config = WebhookConfig::from_settings(&settings.webhook, "wrover-a1b2c3").unwrap();
+    app.set_settings(settings, None);
+    app.on_restart(|| panic!("The webhook should change without a restart"));
+    let webhook = Webhook::spawn(Arc::downgrade(&app), config, TcpHttpClient::new(TIMEOUT));
+    app.set_webhook(Some(webhook.clone()));
+    let addr = HostServer::bind("127.0.0.1:0", app.clone())
+        .unwrap()
+        .spawn();
+
+    webhook.capture("test");
+    old.wait_for(1, TIMEOUT);
+
+    let mut settings = get_json(addr, "/settings");
+    assert_eq!(settings["webhook"]["url"], old.url("/hook"));
+    settings["webhook"]["url"] = new.url("/moved").into();
+    settings["webhook"]["secret"] = "swordfish".into();
+    settings["webhook"]["on_motion"] = false.into();
+    let saved = request(
+        addr,
+        "POST",
+        "/settings",
+        &[],
+        settings.to_string().as_bytes(),
+    );
+    assert_eq!(saved.status, 200, "{}", saved.text());
+    assert_eq!(status(&webhook)["url"], new.url("/moved"));
+
+    // The next one goes to the new URL, signed with the new secret
+    webhook.capture("test");
+    let received = new.wait_for(1, TIMEOUT);
+    assert_eq!(received[0].path, "/moved");
+    assert_eq!(
+        received[0].header(SIGNATURE_HEADER).unwrap(),
+        webhook::sign(b"swordfish", &received[0].body)
+    );
+
+    // And motion isn't posted any more
+    let frame = app.capture_frame().unwrap();
+    let started = MotionEvent::Started {
+        at: Duration::from_secs(3),
+        area: 0.5,
+    };
+    PostOnMotion(webhook.clone()).event(&started, &frame);
+    wait_for("delivery", || webhook.is_idle());
+    assert_eq!(old.received().len(), 1);
+    assert_eq!(new.received().len(), 1);
+
+    // A URL that isn't HTTP is refused and changes nothing
+    settings["webhook"]["url"] = "ftp://example.com".into();
+    let bad = request(
+        addr,
+        "POST",
+        "/settings",
+        &[],
+        settings.to_string().as_bytes(),
+    );
+    assert_eq!(bad.status, 400);
+    assert_eq!(webhook.config().url, new.url("/moved"));
+}
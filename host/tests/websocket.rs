@@ -0,0 +1,455 @@
+//! `/ws`: commands and per-client sending against closure senders, then the whole thing over
+//! a real WebSocket through `HostServer`.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use common::wait_for;
+use serde_json::{json, Value};
+use wrover::auth::Scope;
+use wrover::camera::{Camera, Control, FrameSize, TestPattern};
+use wrover::codec;
+use wrover::flash::Flash;
+use wrover::http::App;
+use wrover::ws::{self, Command, Message, WsConfig, WsStream};
+use wrover_host::server::HostServer;
+use wrover_host::ws::{accept_key, read_message, write_frame, OP_BINARY, OP_CLOSE, OP_TEXT};
+
+#[derive(Default)]
+struct FakeFlash(bool);
+
+impl Flash for FakeFlash {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        self.0 = on;
+        Ok(())
+    }
+
+    fn is_on(&self) -> bool {
+        self.0
+    }
+}
+
+fn start(max_clients: usize) -> (Arc<App>, Arc<WsStream>) {
+    let app = App::new(TestPattern::new(FrameSize::Qqvga, 100.0));
+    let config = WsConfig {
+        fps: 20.0,
+        max_clients,
+    };
+    let ws = WsStream::spawn(Arc::downgrade(&app), config);
+    (app, ws)
+}
+
+/// What a closure sender got
+#[derive(Debug, Clone, PartialEq)]
+enum Sent {
+    Text(Value),
+    Binary(Vec<u8>),
+}
+
+fn recorder() -> (
+    Arc<Mutex<Vec<Sent>>>,
+    impl FnMut(Message<'_>) -> anyhow::Result<()> + Send + 'static,
+) {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sending = sent.clone();
+    let sender = move |message: Message<'_>| {
+        let message = match message {
+            Message::Text(text) => Sent::Text(serde_json::from_str(text).unwrap()),
+            Message::Binary(data) => Sent::Binary(data.to_vec()),
+        };
+        sending.lock().unwrap().push(message);
+        Ok(())
+    };
+    (sent, sender)
+}
+
+fn frames(sent: &Mutex<Vec<Sent>>) -> usize {
+    sent.lock()
+        .unwrap()
+        .iter()
+        .filter(|s| matches!(s, Sent::Binary(_)))
+        .count()
+}
+
+fn replies(sent: &Mutex<Vec<Sent>>) -> Vec<Value> {
+    sent.lock()
+        .unwrap()
+        .iter()
+        .filter_map(|s| match s {
+            Sent::Text(json) if json["type"] != "frame" => Some(json.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn commands_parse() {
+    let parse = |json: Value| Command::parse(&json);
+    assert_eq!(parse(json!({"cmd": "pause"})).unwrap(), Command::Pause);
+    assert_eq!(parse(json!({"cmd": "resume"})).unwrap(), Command::Resume);
+    assert_eq!(
+        parse(json!({"cmd": "fps", "value": 2.5})).unwrap(),
+        Command::Fps(Some(2.5))
+    );
+    assert_eq!(
+        parse(json!({"cmd": "fps", "value": 0})).unwrap(),
+        Command::Fps(None)
+    );
+    assert_eq!(
+        parse(json!({"cmd": "control", "var": "quality", "val": 10})).unwrap(),
+        Command::Control(Control::Quality, 10)
+    );
+    assert_eq!(
+        parse(json!({"cmd": "flash", "on": true})).unwrap(),
+        Command::Flash(true)
+    );
+
+    assert!(parse(json!({})).is_err());
+    assert!(parse(json!({"cmd": "reboot"})).is_err());
+    assert!(parse(json!({"cmd": "fps", "value": -1})).is_err());
+    assert!(parse(json!({"cmd": "fps", "value": 1e-30})).is_err());
+    assert!(parse(json!({"cmd": "fps", "value": 0.05})).is_err());
+    assert!(parse(json!({"cmd": "fps", "value": 31})).is_err());
+    assert!(parse(json!({"cmd": "fps"})).is_err());
+    assert_eq!(
+        parse(json!({"cmd": "fps", "value": 0.1})).unwrap(),
+        Command::Fps(Some(0.1))
+    );
+    assert!(parse(json!({"cmd": "control", "var": "zoom", "val": 1})).is_err());
+    assert!(parse(json!({"cmd": "control", "var": "quality", "val": 1.5})).is_err());
+    assert!(parse(json!({"cmd": "flash", "on": "yes"})).is_err());
+}
+
+#[test]
+fn metadata_describes_the_frame() {
+    let frame = TestPattern::new(FrameSize::Qqvga, 100.0).capture().unwrap();
+    let json: Value = serde_json::from_str(&ws::metadata(&frame, 7, 2)).unwrap();
+    assert_eq!(json["type"], "frame");
+    assert_eq!(json["seq"], 7);
+    assert_eq!(json["width"], 160);
+    assert_eq!(json["height"], 120);
+    assert_eq!(json["format"], "jpeg");
+    assert_eq!(json["bytes"], frame.data.len());
+    assert_eq!(json["dropped"], 2);
+    assert!(json.get("timestamp").is_some());
+    assert!(json.get("time").is_some());
+}
+
+#[test]
+fn frames_follow_their_metadata() {
+    let (_app, ws) = start(4);
+    let (sent, sender) = recorder();
+    let _client = ws.connect(sender).unwrap();
+    wait_for("frames", || frames(&sent) >= 3);
+
+    let sent = sent.lock().unwrap().clone();
+    let mut last_seq = 0;
+    for pair in sent.chunks_exact(2) {
+        let (Sent::Text(meta), Sent::Binary(jpeg)) = (&pair[0], &pair[1]) else {
+            panic!("Expected metadata then a JPEG, got {:?}", pair);
+        };
+        let seq = meta["seq"].as_u64().unwrap();
+        assert!(seq > last_seq);
+        last_seq = seq;
+        assert_eq!(meta["bytes"], jpeg.len());
+        let image = codec::decode_jpeg(jpeg).unwrap();
+        assert_eq!((image.width, image.height), (160, 120));
+    }
+}
+
+#[test]
+fn pause_and_resume() {
+    let (_app, ws) = start(4);
+    let (sent, sender) = recorder();
+    let client = ws.connect(sender).unwrap();
+    wait_for("frames", || frames(&sent) >= 1);
+
+    client.receive(r#"{"cmd":"pause"}"#);
+    wait_for("the ack", || !replies(&sent).is_empty());
+    assert_eq!(replies(&sent)[0], json!({"type": "ok", "cmd": "pause"}));
+    // At most one frame was already on its way
+    thread::sleep(Duration::from_millis(100));
+    let paused = frames(&sent);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(frames(&sent), paused);
+
+    client.receive(r#"{"cmd":"resume"}"#);
+    wait_for("frames again", || frames(&sent) >= paused + 2);
+}
+
+#[test]
+fn fps_limits_one_client() {
+    let (_app, ws) = start(4);
+    let (slow, slow_sender) = recorder();
+    let (fast, fast_sender) = recorder();
+    let slow_client = ws.connect(slow_sender).unwrap();
+    let _fast_client = ws.connect(fast_sender).unwrap();
+    slow_client.receive(r#"{"cmd":"fps","value":2}"#);
+    wait_for("the ack", || !replies(&slow).is_empty());
+
+    let (slow_before, fast_before) = (frames(&slow), frames(&fast));
+    thread::sleep(Duration::from_millis(1000));
+    let slow_frames = frames(&slow) - slow_before;
+    let fast_frames = frames(&fast) - fast_before;
+    assert!(slow_frames <= 3, "{} frames at 2 fps", slow_frames);
+    assert!(
+        fast_frames > slow_frames * 2,
+        "{} vs {}",
+        fast_frames,
+        slow_frames
+    );
+}
+
+#[test]
+fn commands_reach_the_app() {
+    let (app, ws) = start(4);
+    app.set_flash(Some(Box::<FakeFlash>::default()));
+    let (sent, sender) = recorder();
+    let client = ws.connect(sender).unwrap();
+    client.receive(r#"{"cmd":"pause"}"#);
+
+    // Read only until the server says otherwise
+    client.receive(r#"{"cmd":"control","var":"quality","val":33}"#);
+    wait_for("the refusal", || replies(&sent).len() == 2);
+    assert_eq!(replies(&sent)[1]["type"], "error");
+    assert_ne!(app.control_value(Control::Quality), 33);
+
+    let client = client.with_scope(Scope::Admin);
+    sent.lock().unwrap().clear();
+    client.receive(r#"{"cmd":"control","var":"quality","val":33}"#);
+    client.receive(r#"{"cmd":"flash","on":true}"#);
+    wait_for("acks", || replies(&sent).len() == 2);
+    assert_eq!(app.control_value(Control::Quality), 33);
+    assert_eq!(app.flash_state(), Some(true));
+    assert_eq!(
+        replies(&sent),
+        [
+            json!({"type": "ok", "cmd": "control"}),
+            json!({"type": "ok", "cmd": "flash"}),
+        ]
+    );
+}
+
+#[test]
+fn bad_commands_get_errors() {
+    let (_app, ws) = start(4);
+    let (sent, sender) = recorder();
+    let client = ws.connect(sender).unwrap();
+    client.receive(r#"{"cmd":"pause"}"#);
+
+    client.receive(r#"{"cmd":"reboot"}"#);
+    client.receive("pause please");
+    client.receive(r#"{"cmd":"fps","value":1e-30}"#);
+    wait_for("errors", || replies(&sent).len() == 4);
+    let replies = replies(&sent);
+    assert_eq!(replies[1]["type"], "error");
+    assert_eq!(replies[1]["cmd"], "reboot");
+    assert!(replies[1]["error"].as_str().unwrap().contains("reboot"));
+    assert_eq!(replies[2]["type"], "error");
+    assert_eq!(replies[2]["cmd"], Value::Null);
+    assert_eq!(replies[3]["type"], "error");
+    assert!(replies[3]["error"].as_str().unwrap().contains("0.1-30"));
+}
+
+#[test]
+fn slow_clients_drop_frames() {
+    let (_app, ws) = start(4);
+    let (fast, fast_sender) = recorder();
+    let slow = Arc::new(Mutex::new(Vec::new()));
+    let slow_sent = slow.clone();
+    let slow_sender = move |message: Message<'_>| {
+        if let Message::Text(text) = message {
+            slow_sent
+                .lock()
+                .unwrap()
+                .push(serde_json::from_str::<Value>(text).unwrap());
+        } else {
+            thread::sleep(Duration::from_millis(300));
+        }
+        Ok(())
+    };
+    let _slow_client = ws.connect(slow_sender).unwrap();
+    let _fast_client = ws.connect(fast_sender).unwrap();
+
+    wait_for("the fast client", || frames(&fast) >= 20);
+    let slow = slow.lock().unwrap().clone();
+    assert!(slow.len() < 10, "slow client got {} frames", slow.len());
+    // Frames it missed show up as dropped, and its sequence numbers skip
+    let last = slow.last().unwrap();
+    assert!(last["dropped"].as_u64().unwrap() > 0);
+    assert!(last["seq"].as_u64().unwrap() > slow.len() as u64);
+
+    let status: Value = serde_json::from_str(&ws.status_json()).unwrap();
+    assert_eq!(status["clients"], 2);
+    assert!(status["dropped"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn failed_sends_disconnect() {
+    let (_app, ws) = start(4);
+    let _client = ws
+        .connect(|_: Message<'_>| anyhow::bail!("Connection reset"))
+        .unwrap();
+    wait_for("the client to go", || ws.client_count() == 0);
+}
+
+#[test]
+fn clients_are_limited() {
+    let (_app, ws) = start(2);
+    let first = ws.connect(|_: Message<'_>| Ok(())).unwrap();
+    let _second = ws.connect(|_: Message<'_>| Ok(())).unwrap();
+    assert!(ws.connect(|_: Message<'_>| Ok(())).is_err());
+
+    drop(first);
+    assert_eq!(ws.client_count(), 1);
+    assert!(ws.connect(|_: Message<'_>| Ok(())).is_ok());
+}
+
+#[test]
+fn stops_with_the_app() {
+    let (app, ws) = start(4);
+    let (_sent, sender) = recorder();
+    let _client = ws.connect(sender).unwrap();
+    drop(app);
+    wait_for("the client to go", || ws.client_count() == 0);
+}
+
+#[test]
+fn accept_keys() {
+    // The example in RFC 6455
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[test]
+fn frames_round_trip() {
+    for len in [0, 125, 126, 65535, 65536] {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut wire = Vec::new();
+        write_frame(&mut wire, OP_BINARY, &payload, Some([1, 2, 3, 4])).unwrap();
+        assert_eq!(
+            read_message(&mut wire.as_slice()).unwrap(),
+            (OP_BINARY, payload)
+        );
+    }
+
+    // Fragments are joined
+    let wire = [0x01, 2, b'a', b'b', 0x80, 1, b'c'];
+    assert_eq!(
+        read_message(&mut &wire[..]).unwrap(),
+        (OP_TEXT, b"abc".to_vec())
+    );
+}
+
+/// Upgrades a connection to `/ws`
+fn open(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /ws HTTP/1.1\r\nHost: cam\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        head.push(line.trim().to_owned());
+    }
+    assert_eq!(head[0], "HTTP/1.1 101 Switching Protocols");
+    assert!(head.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned()));
+    (stream, reader)
+}
+
+fn serve(max_clients: usize) -> SocketAddr {
+    let (app, ws) = start(max_clients);
+    app.set_websocket(Some(ws));
+    HostServer::bind("127.0.0.1:0", app).unwrap().spawn()
+}
+
+#[test]
+fn streams_over_a_websocket() {
+    let addr = serve(4);
+    let (mut stream, mut reader) = open(addr);
+
+    let (opcode, meta) = read_message(&mut reader).unwrap();
+    assert_eq!(opcode, OP_TEXT);
+    let meta: Value = serde_json::from_slice(&meta).unwrap();
+    assert_eq!(meta["type"], "frame");
+    let (opcode, jpeg) = read_message(&mut reader).unwrap();
+    assert_eq!(opcode, OP_BINARY);
+    assert_eq!(meta["bytes"], jpeg.len());
+    codec::decode_jpeg(&jpeg).unwrap();
+
+    // Commands from clients are masked
+    write_frame(
+        &mut stream,
+        OP_TEXT,
+        br#"{"cmd":"pause"}"#,
+        Some([9, 8, 7, 6]),
+    )
+    .unwrap();
+    loop {
+        let (opcode, data) = read_message(&mut reader).unwrap();
+        if opcode != OP_TEXT {
+            continue;
+        }
+        let json: Value = serde_json::from_slice(&data).unwrap();
+        if json["type"] != "frame" {
+            assert_eq!(json, json!({"type": "ok", "cmd": "pause"}));
+            break;
+        }
+    }
+
+    write_frame(
+        &mut stream,
+        OP_CLOSE,
+        &1000u16.to_be_bytes(),
+        Some([1, 1, 1, 1]),
+    )
+    .unwrap();
+    loop {
+        let (opcode, data) = read_message(&mut reader).unwrap();
+        if opcode == OP_CLOSE {
+            assert_eq!(data, 1000u16.to_be_bytes());
+            break;
+        }
+    }
+}
+
+#[test]
+fn too_many_websockets_are_closed() {
+    let addr = serve(1);
+    let (_first, mut first_reader) = open(addr);
+    read_message(&mut first_reader).unwrap();
+
+    let (_second, mut second_reader) = open(addr);
+    let (opcode, data) = read_message(&mut second_reader).unwrap();
+    assert_eq!(opcode, OP_CLOSE);
+    assert_eq!(data, 1013u16.to_be_bytes());
+}
+
+#[test]
+fn plain_requests_to_ws_are_refused() {
+    let addr = serve(4);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /ws HTTP/1.1\r\nHost: cam\r\n\r\n").unwrap();
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).unwrap();
+    assert!(status.starts_with("HTTP/1.1 400"), "{}", status);
+}
@@ -1,33 +1,41 @@
-use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::hal::gpio::PinDriver;
-use std::thread;
-use std::time::Duration;
-
-fn main() -> anyhow::Result<()> {
-    // Basic ESP-IDF setup
-    esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
-
-    // 1. Take peripherals
-    let peripherals = Peripherals::take().unwrap();
-
-    // 2. Configure LED Pin
-    // On ESP32-CAM, gpio4 is the flashlight. 
-    // On standard ESP32 DevKits, gpio2 is usually the blue onboard LED.
-    let mut led = PinDriver::output(peripherals.pins.gpio4)?;
-
-    println!("Blinky started!");
-
-    // 3. Blink Loop
-    loop {
-        // Turn LED On
-        led.set_high()?;
-        println!("LED ON");
-        thread::sleep(Duration::from_millis(100));
-
-        // Turn LED Off
-        led.set_low()?;
-        println!("LED OFF");
-        thread::sleep(Duration::from_millis(100));
-    }
-}
\ No newline at end of file
+use std::thread;
+use std::time::Duration;
+
+use wrover::board;
+use wrover::status_led::{GpioStatusPin, Status, StatusLed};
+
+/// How long each pattern is shown for
+const SHOW_FOR: Duration = Duration::from_secs(6);
+
+fn main() -> anyhow::Result<()> {
+    // Basic ESP-IDF setup
+    esp_idf_svc::sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    // 1. The status LED from the board profile (GPIO2 on the Freenove, GPIO33 on the
+    // AI-Thinker), driven from its own thread
+    let Some(pin) = board::current().status_led else {
+        anyhow::bail!("{} has no status LED", board::current().name);
+    };
+    let status_led = StatusLed::spawn(GpioStatusPin::new(pin)?)?;
+
+    println!("Blinky started!");
+
+    // 2. Every pattern in turn
+    loop {
+        for status in [
+            Status::Booting,
+            Status::Connecting,
+            Status::Provisioning,
+            Status::Ready,
+            Status::Streaming,
+            Status::Updating,
+            Status::Error(3),
+        ] {
+            println!("Showing {:?}", status);
+            status_led.raise(status);
+            thread::sleep(SHOW_FOR);
+            status_led.clear(status);
+        }
+    }
+}
@@ -1,19 +1,46 @@
 use esp_idf_svc::hal::peripherals::Peripherals;
 
-
-use esp_idf_svc::sys::camera::{
-    esp_camera_fb_get, esp_camera_fb_return,
-
-};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::http::server::{Configuration, EspHttpServer};
-use esp_idf_svc::io::Write;
+use esp_idf_svc::hal::modem::Modem;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration; 
+use std::time::{Duration, Instant};
 
-mod ov3660; 
+use wrover::battery::{self, esp::EspBatteryAdc, Battery};
+use wrover::board;
+use wrover::camera::{EspCamera, FrameBuffer};
+use wrover::client::esp::EspClient;
+use wrover::flash::FlashLed;
+use wrover::http::App;
+use wrover::motion::{
+    ClipOnMotion, Motion, MotionConfig, MotionDetector, PostOnMotion, PublishOnMotion,
+    RecordOnMotion, SnapshotOnMotion,
+};
+use wrover::mqtt::{self, MqttConfig};
+use wrover::ota::{esp::EspSlot, Ota};
+use wrover::ov3660::OV3660Config;
+use wrover::overlay::{Overlay, OverlayConfig};
+use wrover::prebuffer::{self, AviDirSink, EventBuffer, Prebuffer, PrebufferConfig};
+use wrover::recorder::{self, Recorder, RecorderConfig};
+use wrover::rtsp::{Rtsp, RtspConfig};
+use wrover::sdcard::{self, SdCard};
+use wrover::sink::{DirSink, FrameSink};
+use wrover::sleep::{self, WakeRecord};
+use wrover::status_led::{errors, GpioStatusPin, Status, StatusLed};
+use wrover::system;
+use wrover::timelapse::{Timelapse, TimelapseConfig};
+use wrover::tls::{self, esp::NvsCerts};
+use wrover::trigger::{self, esp::GpioTriggerPins, PublishOnTrigger, RecordOnTrigger, SnapshotOnTrigger, Triggers};
+use wrover::udp::{UdpConfig, UdpStream};
+use wrover::upload::{Spool, UploadConfig, UploadSink, Uploader};
+use wrover::webhook::{Webhook, WebhookConfig};
+use wrover::ws::{WsConfig, WsStream};
+use wrover::settings::{NvsSettings, Settings, TriggerAction};
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -23,11 +50,353 @@ fn main() -> anyhow::Result<()> {
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
+    // What's going on, as blink patterns on the board's little LED
+    let status_led = match board::current().status_led {
+        Some(pin) => StatusLed::spawn(GpioStatusPin::new(pin)?)?,
+        None => StatusLed::none(),
+    };
+    status_led.raise(Status::Booting);
+
+    // Firmware updates land in the other OTA slot. A freshly updated image only stays if
+    // Wi-Fi and the camera come up, otherwise the previous one boots again.
+    let mut slot = EspSlot::new()?;
+
+    // Saved settings, defaults the first time
+    let store = NvsSettings::new(nvs.clone())?;
+    let settings = store.load();
+
+    // Battery mode: a round of captures each wake, then deep sleep again. Settings that
+    // would never wake it up leave the camera running as usual instead. A power on, or a
+    // trigger with `settings`, runs as usual for a while first so /settings can be changed.
+    let booted = Instant::now();
+    let mut settings_window = None;
+    if settings.sleep.enabled {
+        match sleep::validate(&settings) {
+            Ok(()) => match sleep::settings_window(sleep::esp::wake_reason(), &settings) {
+                Some(window) => settings_window = Some(window),
+                None => battery_cycle(peripherals, sys_loop, nvs, &settings, slot),
+            },
+            Err(e) => log::error!("Battery mode is off: {:#}", e),
+        }
+    }
+
     // 1. SETUP WIFI
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
-    )?;
+    status_led.raise(Status::Connecting);
+    let wifi = connect_wifi(peripherals.modem, sys_loop, nvs.clone());
+    let wifi = slot
+        .require("Wi-Fi", wifi)
+        .inspect_err(|_| blink_error(&status_led, errors::WIFI))?;
+    status_led.clear(Status::Connecting);
+
+    println!("Wifi connected! IP: {:?}", wifi.wifi().sta_netif().get_ip_info()?.ip);
+
+    // Time from NTP, so frames get real timestamps. Has to outlive everything below.
+    let _sntp = wrover::sntp::start(&settings.time)?;
+
+    // 2. SETUP CAMERA
+    let camera = slot
+        .require("Camera", EspCamera::new(OV3660Config::high_quality()))
+        .inspect_err(|_| blink_error(&status_led, errors::CAMERA))?;
+    slot.mark_valid()?;
+    let app = App::new(camera);
+    app.set_status_led(Some(status_led.clone()));
+
+    // /ota takes uploads and pulls images from a URL
+    let fetch = EspClient::new(Duration::from_secs(30));
+    let ota = Ota::new(slot, Some(Box::new(fetch)));
+    ota.set_status_led(Some(status_led.clone()));
+    app.set_ota(Some(ota));
+
+    // Changes made over HTTP (masks) are saved back to NVS
+    app.set_settings(settings.clone(), Some(Box::new(store)));
+
+    // Flash LED, on boards where it has a pin of its own. Dimmed with LEDC channel 1 and
+    // timer 1, the camera clock has 0.
+    if let Some(pin) = board::current().flash {
+        let led = FlashLed::new(peripherals.ledc.channel1, peripherals.ledc.timer1, pin)?;
+        app.set_flash(Some(Box::new(led)));
+    }
+
+    // Name + time in a corner of /capture snapshots, and of streams unless `snapshots_only`
+    let overlay = OverlayConfig::from_settings(settings.camera_name.clone(), &settings.overlay);
+    app.set_overlay(Some(Overlay::new(overlay)));
+
+    // SD card, for local recording (so a flaky network doesn't lose footage), time-lapse,
+    // motion and trigger clips, and uploads waiting for the server
+    let motion_to_sd =
+        settings.motion.snapshot || settings.motion.record || settings.prebuffer.enabled;
+    let wants_sd = settings.recording.enabled
+        || settings.timelapse.enabled
+        || (settings.motion.enabled && motion_to_sd)
+        || (settings.upload.enabled && settings.upload.spool)
+        || settings.triggers.iter().any(|t| {
+            t.actions.contains(&TriggerAction::Snapshot) || t.actions.contains(&TriggerAction::Record)
+        });
+    let sd_card = match board::current().sd {
+        Some(pins) if wants_sd => Some(
+            SdCard::mount(pins, peripherals.sdmmc1, peripherals.spi2)
+                .inspect_err(|_| blink_error(&status_led, errors::SD_CARD))?,
+        ),
+        _ => None,
+    };
+
+    if sd_card.is_some() && settings.recording.enabled {
+        let dir = format!("{}/rec", sdcard::MOUNT_POINT);
+        let recorder = Recorder::new(RecorderConfig::new(dir, &settings.recording))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        recorder::spawn(Arc::downgrade(&app), recorder, settings.recording.fps, stop);
+    }
+
+    // Captures PUT to a file server, spooled on the card while it can't be reached
+    let uploader = if settings.upload.enabled {
+        let config = UploadConfig::from_settings(&settings.upload, &system::device_id())?;
+        let spool = sd_card
+            .as_ref()
+            .filter(|_| settings.upload.spool)
+            .map(|_| Spool::new(format!("{}/spool", sdcard::MOUNT_POINT)));
+        let client = EspClient::new(Duration::from_secs(30));
+        Some(Uploader::spawn(Arc::downgrade(&app), config, client, spool))
+    } else {
+        None
+    };
+
+    // Time-lapse shots are uploaded or go to the card, /timelapse starts and stops them
+    match &uploader {
+        Some(uploader) if settings.upload.timelapse => {
+            let config = TimelapseConfig::from_settings(&settings.timelapse)?;
+            let sink = UploadSink(uploader.clone());
+            let timelapse = Timelapse::spawn(
+                Arc::downgrade(&app),
+                config,
+                sink,
+                settings.timelapse.enabled,
+            );
+            app.set_timelapse(Some(timelapse));
+        }
+        _ if sd_card.is_some() => {
+            let config = TimelapseConfig::from_settings(&settings.timelapse)?;
+            let sink = DirSink::new(format!("{}/timelapse", sdcard::MOUNT_POINT));
+            let timelapse = Timelapse::spawn(
+                Arc::downgrade(&app),
+                config,
+                sink,
+                settings.timelapse.enabled,
+            );
+            app.set_timelapse(Some(timelapse));
+        }
+        _ => {}
+    }
+
+    // Motion detection, /motion turns it on and off
+    let detector = MotionDetector::new(MotionConfig::from_settings(&settings.motion))?;
+    let motion = Motion::spawn(
+        Arc::downgrade(&app),
+        detector,
+        settings.motion.fps,
+        settings.motion.enabled,
+    );
+    if sd_card.is_some() && settings.motion.snapshot {
+        let sink = DirSink::new(format!("{}/motion", sdcard::MOUNT_POINT));
+        motion.add_handler(SnapshotOnMotion(sink));
+    }
+    if let Some(uploader) = uploader.as_ref().filter(|_| settings.upload.motion) {
+        motion.add_handler(SnapshotOnMotion(UploadSink(uploader.clone())));
+    }
+    if sd_card.is_some() && settings.motion.record {
+        let dir = format!("{}/motion", sdcard::MOUNT_POINT);
+        let recorder = Recorder::new(RecorderConfig::new(dir, &settings.recording))?;
+        motion.add_handler(RecordOnMotion(recorder));
+    }
+    // Motion clips that start a few seconds before the motion did, buffered in PSRAM
+    if sd_card.is_some() && settings.prebuffer.enabled {
+        let mut config = PrebufferConfig::from_settings(&settings.prebuffer);
+        config.budget_bytes = config.budget_bytes.min(prebuffer::free_psram() / 2);
+        if config.budget_bytes == 0 {
+            log::warn!("No PSRAM free for the pre-event buffer, leaving it off");
+        } else {
+            let fps = settings.prebuffer.fps;
+            let sink = AviDirSink::new(format!("{}/events", sdcard::MOUNT_POINT), fps);
+            let events = EventBuffer::spawn(
+                Arc::downgrade(&app),
+                Prebuffer::new(config),
+                fps as f32,
+                sink,
+            );
+            motion.add_handler(ClipOnMotion(events));
+        }
+    }
+
+    // Battery voltage, and slower streams, no streams or deep sleep as it runs down. Before
+    // MQTT, so discovery knows there's a battery.
+    if settings.battery.enabled {
+        let adc = battery::validate(&settings).and_then(|()| {
+            let pin = board::current()
+                .battery
+                .expect("validate checks there's a pin");
+            Ok((EspBatteryAdc::new(pin.gpio)?, pin.divider))
+        });
+        match adc {
+            Ok((adc, divider)) => {
+                let (sleep_settings, triggers) =
+                    (settings.sleep.clone(), settings.triggers.clone());
+                let battery = Battery::spawn(
+                    Arc::downgrade(&app),
+                    adc,
+                    divider,
+                    settings.battery.clone(),
+                    move || sleep::esp::deep_sleep(&sleep_settings, &triggers),
+                );
+                app.set_battery(Some(battery));
+            }
+            Err(e) => log::error!("The battery monitor is off: {:#}", e),
+        }
+    }
+
+    // Status, motion events and commands over MQTT
+    let mqtt = if settings.mqtt.enabled {
+        let config = MqttConfig::from_settings(&settings.mqtt, &system::device_id());
+        let mqtt = mqtt::esp::start(Arc::downgrade(&app), config)?;
+        motion.add_handler(PublishOnMotion(mqtt.clone()));
+        Some(mqtt)
+    } else {
+        None
+    };
+
+    // Snapshots POSTed to a webhook: on motion, at boot and/or on a timer
+    if settings.webhook.enabled {
+        let config = WebhookConfig::from_settings(&settings.webhook, &system::device_id())?;
+        let client = EspClient::new(Duration::from_secs(10));
+        let webhook = Webhook::spawn(Arc::downgrade(&app), config, client);
+        // Always added, so /settings can turn on_motion on and off
+        motion.add_handler(PostOnMotion(webhook.clone()));
+        app.set_webhook(Some(webhook));
+    }
+    app.set_motion(Some(motion));
+
+    // PIR sensors and buttons. Bad settings leave them off rather than stop the camera.
+    if !settings.triggers.is_empty() {
+        let triggers = trigger::validate(&settings.triggers)
+            .and_then(|()| GpioTriggerPins::new(&settings.triggers))
+            .and_then(|pins| Triggers::spawn(Arc::downgrade(&app), settings.triggers.clone(), pins));
+        match triggers {
+            Ok(triggers) => {
+                if sd_card.is_some() {
+                    let sink = DirSink::new(format!("{}/triggers", sdcard::MOUNT_POINT));
+                    triggers.add_handler(SnapshotOnTrigger(sink));
+                    let dir = format!("{}/triggers", sdcard::MOUNT_POINT);
+                    triggers.add_handler(RecordOnTrigger::new(RecorderConfig::new(dir, &settings.recording)));
+                }
+                if let Some(uploader) = &uploader {
+                    triggers.add_handler(SnapshotOnTrigger(UploadSink(uploader.clone())));
+                }
+                if let Some(mqtt) = &mqtt {
+                    triggers.add_handler(PublishOnTrigger(mqtt.clone()));
+                }
+                app.set_triggers(Some(triggers));
+            }
+            Err(e) => log::error!("Triggers are off: {:#}", e),
+        }
+    }
+
+    // RTSP for NVRs and players: rtsp://<ip>/stream, RTP/JPEG over UDP or TCP
+    if settings.rtsp.enabled {
+        let config = RtspConfig::from_settings(&settings.rtsp);
+        Rtsp::spawn(Arc::downgrade(&app), config)?;
+    }
+
+    // Low-latency frames for robots, fragmented over UDP to one host or a multicast group
+    if settings.udp.enabled {
+        let config = UdpConfig::from_settings(&settings.udp)?;
+        UdpStream::spawn(Arc::downgrade(&app), config)?;
+    }
+
+    // Frames and metadata for browser viewers, with commands coming back on the same socket
+    if settings.websocket.enabled {
+        let config = WsConfig::from_settings(&settings.websocket);
+        app.set_websocket(Some(WsStream::spawn(Arc::downgrade(&app), config)));
+    }
+
+    // 3. START WEB SERVER (/, /stream, /capture, /status, /control, /timelapse, /motion,
+    // /masks, /masks/edit, /flash, /auth, /tls, /ota, /ota/pull, /settings, /ws). The
+    // default only has room for 8 handlers.
+    let base = || Configuration {
+        max_uri_handlers: 24,
+        ..Default::default()
+    };
+
+    // HTTPS with the stored certificate, or a self-signed one made now on first boot. If
+    // that doesn't work out it's plain HTTP, rather than a camera nobody can reach.
+    let mut certs = NvsCerts::new(nvs)?;
+    let https = if settings.tls.enabled {
+        match tls::esp::load_or_generate(&mut certs, &settings.camera_name) {
+            Ok(pair) => {
+                let mut config = base();
+                tls::esp::configure(&mut config, &pair, settings.tls.port);
+                match EspHttpServer::new(&config) {
+                    Ok(server) => Some((server, pair.fingerprint())),
+                    Err(e) => {
+                        log::warn!("HTTPS didn't start, serving plain HTTP: {}", e);
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("No certificate, serving plain HTTP: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (mut server, serving) = match https {
+        Some((server, fingerprint)) => (server, Some(fingerprint)),
+        None => (EspHttpServer::new(&base())?, None),
+    };
+
+    // Plain HTTP on port 80 then only sends browsers on to HTTPS. A control port of its
+    // own, the HTTPS server already has both defaults.
+    let _redirect = if serving.is_some() && settings.tls.redirect {
+        let mut redirect = EspHttpServer::new(&Configuration {
+            ctrl_port: 32770,
+            uri_match_wildcard: true,
+            max_uri_handlers: 1,
+            max_open_sockets: 2,
+            ..Default::default()
+        })?;
+        wrover::http::esp::register_redirect(&mut redirect, settings.tls.port)?;
+        Some(redirect)
+    } else {
+        None
+    };
+
+    app.set_tls(serving, Some(Box::new(certs)));
+    wrover::http::esp::register(&mut server, app.clone())?;
+
+    println!("Server ready!");
+    status_led.raise(Status::Ready);
+    status_led.clear(Status::Booting);
+    if let Some(window) = settings_window {
+        log::info!("Battery mode in {:?}, unless /settings turns it off", window);
+    }
+
+    // Keep main thread alive. After a settings window it's battery mode again, as the
+    // settings are now: a /settings that turned it off has restarted the camera already.
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        if settings_window.is_some_and(|window| booted.elapsed() >= window) {
+            let settings = app.settings();
+            sleep::esp::deep_sleep(&settings.sleep, &settings.triggers);
+        }
+    }
+}
+
+/// Joins the network, blocking until there's an IP
+fn connect_wifi(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+) -> anyhow::Result<BlockingWifi<EspWifi<'static>>> {
+    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), Some(nvs))?, sys_loop)?;
 
     wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
         esp_idf_svc::wifi::ClientConfiguration {
@@ -41,71 +410,110 @@ fn main() -> anyhow::Result<()> {
     wifi.start()?;
     wifi.connect()?;
     wifi.wait_netif_up()?;
+    Ok(wifi)
+}
 
-    println!("Wifi connected! IP: {:?}", wifi.wifi().sta_netif().get_ip_info()?.ip);
+/// One wake in battery mode, see `wrover::sleep`: settle exposure, capture, store and/or
+/// upload, log the wake on the card, then deep sleep. Every way out ends asleep, a camera
+/// left awake would flatten the battery.
+fn battery_cycle(
+    peripherals: Peripherals,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    settings: &Settings,
+    mut slot: EspSlot,
+) -> ! {
+    let awake = Instant::now();
+    let reason = sleep::esp::wake_reason();
+    let counters = sleep::esp::update_counters(|counters| counters.woke(reason));
+    let woken_by: Vec<_> = sleep::woken_by(reason, &settings.triggers)
+        .into_iter()
+        .map(|input| input.name.as_str())
+        .collect();
+    log::info!("Awake: {:?} {:?}, {:?}", reason, woken_by, counters);
 
-    // 2. SETUP CAMERA
-    let _ = ov3660::start_ov3660(ov3660::OV3660Config::high_quality());
-
-    // 3. START MJPEG STREAM SERVER
-    let mut server = EspHttpServer::new(&Configuration::default())?;
-
-    server.fn_handler("/", esp_idf_svc::http::Method::Get, |request| {
-        // Send Multipart Header
-        let mut response = request.into_response(
-            200, 
-            Some("OK"), 
-            &[("Content-Type", "multipart/x-mixed-replace; boundary=123456789000000000000987654321")]
-        )?;
-
-        println!("Client connected to stream.");
-
-        loop {
-            unsafe {
-                let fb = esp_camera_fb_get();
-                if !fb.is_null() {
-                    // Fix: Unaligned Read for Timestamp field (prevents panic)
-                    let buf_ptr = std::ptr::addr_of!((*fb).buf);
-                    let len_ptr = std::ptr::addr_of!((*fb).len);
-                    let buf = buf_ptr.read_unaligned();
-                    let len = len_ptr.read_unaligned();
-                    
-                    let data = std::slice::from_raw_parts(buf, len as usize);
-
-                    // MJPEG Frame Header
-                    let header = format!(
-                        "\r\n--123456789000000000000987654321\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
-                        len
-                    );
-
-                    // Send Header
-                    if response.write_all(header.as_bytes()).is_err() {
-                        esp_camera_fb_return(fb);
-                        println!("Client disconnected.");
-                        break;
-                    }
+    let sd_card = match board::current().sd {
+        Some(pins) => SdCard::mount(pins, peripherals.sdmmc1, peripherals.spi2)
+            .inspect_err(|e| log::warn!("No SD card: {}", e))
+            .ok(),
+        None => None,
+    };
 
-                    // Send Image
-                    if response.write_all(data).is_err() {
-                        esp_camera_fb_return(fb);
-                        break;
-                    }
+    let report = (|| -> anyhow::Result<_> {
+        let camera = slot.require("Camera", EspCamera::new(OV3660Config::high_quality()))?;
+        slot.mark_valid()?;
+        let app = App::new(camera);
+        let overlay = OverlayConfig::from_settings(settings.camera_name.clone(), &settings.overlay);
+        app.set_overlay(Some(Overlay::new(overlay)));
 
-                    esp_camera_fb_return(fb);
-                }
-            }
-            // Small delay to let the CPU breathe
-            // Remove this for maximum FPS, but keep it if the board gets too hot
-            thread::sleep(Duration::from_millis(20)); 
+        // A folder for each wake: without Wi-Fi there's no clock to name frames by
+        let mut card = sd_card
+            .as_ref()
+            .map(|_| DirSink::new(format!("{}/sleep/{:06}", sdcard::MOUNT_POINT, counters.wakes)));
+
+        // Wi-Fi only comes up if there's a server to upload to
+        let (_wifi, _sntp);
+        let uploader = if settings.upload.enabled {
+            _wifi = connect_wifi(peripherals.modem, sys_loop, nvs)?;
+            _sntp = wrover::sntp::start(&settings.time)?;
+            let config = UploadConfig::from_settings(&settings.upload, &system::device_id())?;
+            let spool = sd_card
+                .as_ref()
+                .filter(|_| settings.upload.spool)
+                .map(|_| Spool::new(format!("{}/spool", sdcard::MOUNT_POINT)));
+            let client = EspClient::new(Duration::from_secs(30));
+            Some(Uploader::spawn(Arc::downgrade(&app), config, client, spool))
+        } else {
+            None
+        };
+        if card.is_none() && uploader.is_none() {
+            anyhow::bail!("Nowhere to put frames: no SD card and uploads are off");
         }
-        
-        Ok::<(), anyhow::Error>(())
-    })?;
 
-    println!("Server ready!");
+        let mut sink = |frame: &FrameBuffer| -> anyhow::Result<()> {
+            if let Some(card) = &mut card {
+                card.store(frame)?;
+            }
+            if let Some(uploader) = &uploader {
+                uploader.upload(frame)?;
+            }
+            Ok(())
+        };
+        let report = sleep::run_cycle(&app, &settings.sleep, &mut sink)?;
 
-    // Keep main thread alive
-    loop {
-        thread::sleep(Duration::from_secs(1));
+        // Uploads get until max_awake_secs, failed ones are in the spool for next time
+        let deadline = awake + Duration::from_secs(settings.sleep.max_awake_secs as u64);
+        while uploader.as_ref().is_some_and(|uploader| !uploader.is_idle()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(report)
+    })();
+
+    if let Err(e) = &report {
+        log::error!("Wake cycle failed: {:#}", e);
     }
-}
\ No newline at end of file
+    let counters = sleep::esp::update_counters(|counters| {
+        counters.cycle_done(report.as_ref().ok(), awake.elapsed())
+    });
+    if sd_card.is_some() {
+        let record = WakeRecord {
+            reason,
+            woken_by,
+            report: report.as_ref().ok(),
+            error: report.as_ref().err().map(|e| format!("{:#}", e)),
+            counters,
+        };
+        let path = format!("{}/sleep/wakes.jsonl", sdcard::MOUNT_POINT);
+        if let Err(e) = record.append_to(Path::new(&path)) {
+            log::warn!("Couldn't log the wake: {}", e);
+        }
+    }
+    drop(sd_card);
+    sleep::esp::deep_sleep(&settings.sleep, &settings.triggers)
+}
+
+/// Blinks `code` out for good: the LED keeps going after `main` gives up
+fn blink_error(status_led: &StatusLed, code: u8) {
+    status_led.raise(Status::Error(code));
+    std::mem::forget(status_led.clone());
+}
@@ -0,0 +1,423 @@
+//! Who may use the API: basic auth users for the UI (browsers prompt for it), bearer tokens
+//! for scripts, each either read-only or admin.
+//!
+//! Passwords and tokens are only kept as salted SHA-256 hashes, in the settings (so NVS on
+//! the board). Tokens are shown once, when they're made. A peer that keeps getting it wrong
+//! is locked out for a while, without its guesses being checked at all.
+//!
+//! `App::authorize` puts this in front of every route; server adapters pass it the
+//! `Authorization` header and the peer's address.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hmac_sha256::Hash;
+use serde::{Deserialize, Serialize};
+
+use crate::client::base64_decode;
+use crate::http::Response;
+use crate::random;
+use crate::settings::{AuthSettings, TokenSettings, UserSettings};
+
+/// Hashing rounds for passwords, which are easier to guess than tokens
+pub const PASSWORD_ROUNDS: u32 = 1000;
+
+/// Peers whose failures are remembered, the stalest is forgotten past this
+const MAX_PEERS: usize = 16;
+
+/// What a user or token may do. Admin includes read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Watch and read status
+    Read,
+    /// Change settings too
+    Admin,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// An `Authorization` header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    pub fn parse(header: &str) -> anyhow::Result<Self> {
+        let header = header.trim();
+        let (scheme, value) = header.split_once(' ').unwrap_or((header, ""));
+        let value = value.trim();
+        if value.is_empty() {
+            anyhow::bail!("No credentials after {:?}", scheme);
+        }
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(base64_decode(value)?)
+                .map_err(|_| anyhow::anyhow!("Basic credentials aren't UTF-8"))?;
+            let (username, password) = decoded
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Basic credentials without a ':'"))?;
+            Ok(Credentials::Basic {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            })
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            Ok(Credentials::Bearer(value.to_owned()))
+        } else {
+            anyhow::bail!("Unsupported authorization scheme {:?}", scheme)
+        }
+    }
+}
+
+/// A salted, iterated SHA-256 of a password or token: `sha256$<rounds>$<salt>$<hash>`, hex
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecretHash {
+    rounds: u32,
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SecretHash {
+    /// Hashes `secret` with a fresh salt
+    pub fn new(secret: &str, rounds: u32) -> Self {
+        let mut salt = [0; 16];
+        random::fill(&mut salt);
+        let rounds = rounds.max(1);
+        Self {
+            rounds,
+            salt,
+            hash: stretch(secret, &salt, rounds),
+        }
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        let hash = stretch(secret, &self.salt, self.rounds);
+        // Same time whichever byte differs
+        hash.iter()
+            .zip(&self.hash)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Display for SecretHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sha256${}${}${}",
+            self.rounds,
+            hex(&self.salt),
+            hex(&self.hash)
+        )
+    }
+}
+
+impl FromStr for SecretHash {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = text.split('$').collect();
+        let ["sha256", rounds, salt, hash] = parts[..] else {
+            anyhow::bail!("Not a sha256$rounds$salt$hash: {:?}", text);
+        };
+        let rounds: u32 = rounds
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Bad rounds {:?}", rounds))?;
+        if rounds == 0 {
+            anyhow::bail!("Zero rounds");
+        }
+        Ok(Self {
+            rounds,
+            salt: unhex(salt)?,
+            hash: unhex(hash)?,
+        })
+    }
+}
+
+fn stretch(secret: &str, salt: &[u8; 16], rounds: u32) -> [u8; 32] {
+    let mut data = salt.to_vec();
+    data.extend_from_slice(secret.as_bytes());
+    let mut hash = Hash::hash(&data);
+    for _ in 1..rounds {
+        let mut round = Hash::new();
+        round.update(hash);
+        round.update(secret.as_bytes());
+        hash = round.finalize();
+    }
+    hash
+}
+
+/// A new random API token, `wr_` and 32 hex digits
+pub fn generate_token() -> String {
+    let mut bytes = [0; 16];
+    random::fill(&mut bytes);
+    format!("wr_{}", hex(&bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex<const N: usize>(text: &str) -> anyhow::Result<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        anyhow::bail!("Expected {} hex digits, got {:?}", N * 2, text);
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow::anyhow!("Not hex: {:?}", text))?;
+    }
+    Ok(out)
+}
+
+/// A change to users and tokens, as `POST /auth` takes it:
+/// - `{"action":"set_user","name":"ann","password":"...","scope":"admin"}`, adds or replaces
+/// - `{"action":"remove_user","name":"ann"}`
+/// - `{"action":"create_token","name":"ci","scope":"read"}`
+/// - `{"action":"revoke_token","name":"ci"}`
+/// - `{"action":"enable"}`, `{"action":"disable"}`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Change {
+    SetUser {
+        name: String,
+        password: String,
+        scope: Scope,
+    },
+    RemoveUser {
+        name: String,
+    },
+    CreateToken {
+        name: String,
+        scope: Scope,
+    },
+    RevokeToken {
+        name: String,
+    },
+    Enable,
+    Disable,
+}
+
+impl Change {
+    /// Makes the change, returning the new token if there is one. Won't leave auth on
+    /// without an admin, nobody could turn it off again.
+    pub fn apply(self, settings: &mut AuthSettings) -> anyhow::Result<Option<String>> {
+        let mut token = None;
+        match self {
+            Change::SetUser {
+                name,
+                password,
+                scope,
+            } => {
+                if name.is_empty() || name.contains(':') {
+                    anyhow::bail!("User names can't be empty or have a ':'");
+                }
+                if password.is_empty() {
+                    anyhow::bail!("Empty password");
+                }
+                let hash = SecretHash::new(&password, PASSWORD_ROUNDS).to_string();
+                settings.users.retain(|user| user.name != name);
+                settings.users.push(UserSettings { name, hash, scope });
+            }
+            Change::RemoveUser { name } => {
+                let before = settings.users.len();
+                settings.users.retain(|user| user.name != name);
+                if settings.users.len() == before {
+                    anyhow::bail!("No user {:?}", name);
+                }
+            }
+            Change::CreateToken { name, scope } => {
+                if name.is_empty() || settings.tokens.iter().any(|t| t.name == name) {
+                    anyhow::bail!("Token names have to be unique and not empty");
+                }
+                let new = generate_token();
+                // Random enough that stretching wouldn't add anything
+                let hash = SecretHash::new(&new, 1).to_string();
+                settings.tokens.push(TokenSettings { name, hash, scope });
+                token = Some(new);
+            }
+            Change::RevokeToken { name } => {
+                let before = settings.tokens.len();
+                settings.tokens.retain(|t| t.name != name);
+                if settings.tokens.len() == before {
+                    anyhow::bail!("No token {:?}", name);
+                }
+            }
+            Change::Enable => settings.enabled = true,
+            Change::Disable => settings.enabled = false,
+        }
+
+        let has_admin = settings.users.iter().any(|u| u.scope == Scope::Admin)
+            || settings.tokens.iter().any(|t| t.scope == Scope::Admin);
+        if settings.enabled && !has_admin {
+            anyhow::bail!("Auth needs an admin user or token while it's on");
+        }
+        Ok(token)
+    }
+}
+
+/// Users and tokens without their hashes, as JSON. `token` is a newly made one, to show once.
+pub fn summary_json(settings: &AuthSettings, token: Option<&str>) -> String {
+    let list = |items: Vec<(&str, Scope)>| {
+        items
+            .into_iter()
+            .map(|(name, scope)| serde_json::json!({ "name": name, "scope": scope }))
+            .collect::<Vec<_>>()
+    };
+    let mut json = serde_json::json!({
+        "enabled": settings.enabled,
+        "users": list(settings.users.iter().map(|u| (u.name.as_str(), u.scope)).collect()),
+        "tokens": list(settings.tokens.iter().map(|t| (t.name.as_str(), t.scope)).collect()),
+        "max_failures": settings.max_failures,
+        "lockout_secs": settings.lockout_secs,
+    });
+    if let Some(token) = token {
+        json["token"] = token.into();
+    }
+    json.to_string()
+}
+
+#[derive(Debug)]
+struct Failures {
+    peer: Option<IpAddr>,
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Checks credentials against the settings, and counts failures per peer
+#[derive(Default)]
+pub struct Authenticator {
+    failures: Mutex<Vec<Failures>>,
+}
+
+impl Authenticator {
+    /// What the holder of `header` may do, if they may do `needed`. Otherwise the response
+    /// turning them away: 401 asking for credentials, 403 if theirs aren't enough, 429 while
+    /// locked out. `realm` is shown in the browser's login prompt.
+    pub fn check(
+        &self,
+        settings: &AuthSettings,
+        header: Option<&str>,
+        peer: Option<IpAddr>,
+        needed: Scope,
+        realm: &str,
+    ) -> Result<Scope, Response> {
+        if let Some(wait) = self.locked_out(peer) {
+            return Err(
+                Response::text(429, "Too many failed logins, try again later")
+                    .with_header("Retry-After", wait.as_secs().max(1).to_string()),
+            );
+        }
+
+        let challenge = |bearer: bool| {
+            let value = if bearer {
+                format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm)
+            } else {
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm)
+            };
+            Response::text(401, "Authentication required").with_header("WWW-Authenticate", value)
+        };
+        let Some(header) = header else {
+            return Err(challenge(false));
+        };
+        let credentials = Credentials::parse(header).ok();
+        let bearer = matches!(credentials, Some(Credentials::Bearer(_)));
+        let Some(scope) = credentials.and_then(|credentials| verify(settings, &credentials)) else {
+            self.fail(peer, settings);
+            return Err(challenge(bearer));
+        };
+
+        self.succeed(peer);
+        if scope < needed {
+            return Err(Response::text(
+                403,
+                format!("Needs {} access", needed.name()),
+            ));
+        }
+        Ok(scope)
+    }
+
+    fn locked_out(&self, peer: Option<IpAddr>) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let until = failures.iter().find(|f| f.peer == peer)?.locked_until?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    fn fail(&self, peer: Option<IpAddr>, settings: &AuthSettings) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let lockout = Duration::from_secs(settings.lockout_secs as u64);
+        let position = match failures.iter().position(|f| f.peer == peer) {
+            Some(position) => position,
+            None => {
+                if failures.len() >= MAX_PEERS {
+                    if let Some(stalest) = (0..failures.len()).min_by_key(|&i| failures[i].last) {
+                        failures.swap_remove(stalest);
+                    }
+                }
+                failures.push(Failures {
+                    peer,
+                    count: 0,
+                    last: now,
+                    locked_until: None,
+                });
+                failures.len() - 1
+            }
+        };
+
+        let entry = &mut failures[position];
+        // Failures long ago, or from before the last lockout, don't count
+        if now.duration_since(entry.last) > lockout || entry.locked_until.is_some() {
+            entry.count = 0;
+            entry.locked_until = None;
+        }
+        entry.count += 1;
+        entry.last = now;
+        if entry.count >= settings.max_failures.max(1) {
+            log::warn!("Locking out {:?} after {} failed logins", peer, entry.count);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    fn succeed(&self, peer: Option<IpAddr>) {
+        self.failures.lock().unwrap().retain(|f| f.peer != peer);
+    }
+}
+
+/// The scope `credentials` grant, if they're right
+fn verify(settings: &AuthSettings, credentials: &Credentials) -> Option<Scope> {
+    match credentials {
+        Credentials::Basic { username, password } => settings
+            .users
+            .iter()
+            .find(|user| user.name == *username)
+            .filter(|user| {
+                user.hash
+                    .parse::<SecretHash>()
+                    .is_ok_and(|hash| hash.verify(password))
+            })
+            .map(|user| user.scope),
+        Credentials::Bearer(token) => settings
+            .tokens
+            .iter()
+            .find(|t| {
+                t.hash
+                    .parse::<SecretHash>()
+                    .is_ok_and(|hash| hash.verify(token))
+            })
+            .map(|t| t.scope),
+    }
+}
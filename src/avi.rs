@@ -0,0 +1,369 @@
+//! Minimal AVI (RIFF) muxer for MJPEG clips: one video stream, one `00dc` chunk per JPEG,
+//! and an `idx1` index at the end so players can seek.
+//!
+//! The header has a fixed size, so the sizes and frame counts are written as zeros up front
+//! and patched in by `finish`. A file that never got finished (power cut, card pulled) can
+//! be repaired with `recover`, which keeps every complete frame and rebuilds the index.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+/// Everything before the first frame chunk
+pub const HEADER_LEN: usize = 224;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// Offsets of the fields `finish` patches
+const RIFF_SIZE: u64 = 4;
+const AVIH_US_PER_FRAME: u64 = 32;
+const AVIH_MAX_BYTES_PER_SEC: u64 = 36;
+const AVIH_TOTAL_FRAMES: u64 = 48;
+const AVIH_SUGGESTED_BUFFER: u64 = 60;
+const STRH_SCALE: u64 = 128;
+const STRH_RATE: u64 = 132;
+const STRH_LENGTH: u64 = 140;
+const STRH_SUGGESTED_BUFFER: u64 = 144;
+const MOVI_SIZE: u64 = 216;
+/// Where `idx1` offsets count from: the `movi` fourcc
+const MOVI_START: u64 = 220;
+
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    fps: u32,
+    /// (offset from `movi`, size) per frame
+    index: Vec<(u32, u32)>,
+    /// Bytes after the `movi` fourcc
+    movi_len: u32,
+    largest_frame: u32,
+    first: Option<Duration>,
+    last: Option<Duration>,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Writes the header. `fps` is only a guess until `finish` works out the real rate
+    /// from the frame timestamps.
+    pub fn new(mut out: W, width: u32, height: u32, fps: u32) -> anyhow::Result<Self> {
+        let fps = fps.max(1);
+        out.write_all(&header(width, height, fps))?;
+        Ok(Self {
+            out,
+            fps,
+            index: Vec::new(),
+            movi_len: 4,
+            largest_frame: 0,
+            first: None,
+            last: None,
+        })
+    }
+
+    /// Appends one JPEG. `timestamp` is the capture time, any clock as long as it's the same
+    /// one for every frame.
+    pub fn write_frame(&mut self, jpeg: &[u8], timestamp: Duration) -> anyhow::Result<()> {
+        let len = u32::try_from(jpeg.len())?;
+        let padded = len + (len & 1);
+        if self.bytes_written() + 8 + padded as u64 + 16 * (self.index.len() as u64 + 1)
+            > u32::MAX as u64
+        {
+            anyhow::bail!("AVI file would be over 4GB");
+        }
+
+        self.out.write_all(b"00dc")?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(jpeg)?;
+        if len & 1 == 1 {
+            self.out.write_all(&[0])?;
+        }
+
+        self.index.push((self.movi_len, len));
+        self.movi_len += 8 + padded;
+        self.largest_frame = self.largest_frame.max(len);
+        self.first.get_or_insert(timestamp);
+        self.last = Some(timestamp);
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Size of the file so far, not counting the index `finish` will add
+    pub fn bytes_written(&self) -> u64 {
+        MOVI_START + self.movi_len as u64
+    }
+
+    /// Time between the first and last frame
+    pub fn duration(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => last.saturating_sub(first),
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Writes the index, fixes up the header and hands back the output
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        // Average frame interval from the timestamps, or the nominal rate for 0-1 frames
+        let intervals = self.index.len().saturating_sub(1) as u32;
+        let us_per_frame = match intervals {
+            0 => 1_000_000 / self.fps,
+            n => (self.duration().as_micros() / n as u128).clamp(1, u32::MAX as u128) as u32,
+        };
+
+        let index = self.index.clone();
+        finalize(
+            &mut self.out,
+            &index,
+            us_per_frame,
+            self.largest_frame,
+            self.movi_len,
+        )?;
+        Ok(self.out)
+    }
+}
+
+fn header(width: u32, height: u32, fps: u32) -> Vec<u8> {
+    let mut h = Vec::with_capacity(HEADER_LEN);
+    let chunk = |h: &mut Vec<u8>, fourcc: &[u8; 4], size: u32| {
+        h.extend_from_slice(fourcc);
+        h.extend_from_slice(&size.to_le_bytes());
+    };
+    let u32s = |h: &mut Vec<u8>, values: &[u32]| {
+        for v in values {
+            h.extend_from_slice(&v.to_le_bytes());
+        }
+    };
+
+    chunk(&mut h, b"RIFF", 0);
+    h.extend_from_slice(b"AVI ");
+    chunk(&mut h, b"LIST", 192);
+    h.extend_from_slice(b"hdrl");
+
+    chunk(&mut h, b"avih", 56);
+    u32s(
+        &mut h,
+        &[
+            1_000_000 / fps,
+            0,
+            0,
+            AVIF_HASINDEX,
+            0,
+            0,
+            1,
+            0,
+            width,
+            height,
+        ],
+    );
+    u32s(&mut h, &[0; 4]);
+
+    chunk(&mut h, b"LIST", 116);
+    h.extend_from_slice(b"strl");
+
+    chunk(&mut h, b"strh", 56);
+    h.extend_from_slice(b"vids");
+    h.extend_from_slice(b"MJPG");
+    // flags, priority + language, initial frames, scale, rate, start, length,
+    // suggested buffer size, quality (-1 = default), sample size (0 = varies)
+    u32s(&mut h, &[0, 0, 0, 1, fps, 0, 0, 0, u32::MAX, 0]);
+    for v in [0u16, 0, width as u16, height as u16] {
+        h.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // BITMAPINFOHEADER
+    chunk(&mut h, b"strf", 40);
+    u32s(&mut h, &[40, width, height]);
+    h.extend_from_slice(&1u16.to_le_bytes()); // planes
+    h.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+    h.extend_from_slice(b"MJPG");
+    u32s(&mut h, &[width * height * 3, 0, 0, 0, 0]);
+
+    chunk(&mut h, b"LIST", 0);
+    h.extend_from_slice(b"movi");
+
+    debug_assert_eq!(h.len(), HEADER_LEN);
+    h
+}
+
+/// Appends `idx1` right after the last frame and patches every size and count in the header
+fn finalize<W: Write + Seek>(
+    out: &mut W,
+    index: &[(u32, u32)],
+    us_per_frame: u32,
+    largest_frame: u32,
+    movi_len: u32,
+) -> anyhow::Result<()> {
+    out.seek(SeekFrom::Start(MOVI_START + movi_len as u64))?;
+
+    let mut idx1 = Vec::with_capacity(8 + index.len() * 16);
+    idx1.extend_from_slice(b"idx1");
+    idx1.extend_from_slice(&(index.len() as u32 * 16).to_le_bytes());
+    for &(offset, size) in index {
+        idx1.extend_from_slice(b"00dc");
+        idx1.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+        idx1.extend_from_slice(&offset.to_le_bytes());
+        idx1.extend_from_slice(&size.to_le_bytes());
+    }
+    out.write_all(&idx1)?;
+    let end = out.stream_position()?;
+
+    let frames = index.len() as u32;
+    let bytes_per_sec = (largest_frame as u64 * 1_000_000 / us_per_frame as u64) as u32;
+    let patches = [
+        (RIFF_SIZE, end as u32 - 8),
+        (AVIH_US_PER_FRAME, us_per_frame),
+        (AVIH_MAX_BYTES_PER_SEC, bytes_per_sec),
+        (AVIH_TOTAL_FRAMES, frames),
+        (AVIH_SUGGESTED_BUFFER, largest_frame),
+        (STRH_SCALE, us_per_frame),
+        (STRH_RATE, 1_000_000),
+        (STRH_LENGTH, frames),
+        (STRH_SUGGESTED_BUFFER, largest_frame),
+        (MOVI_SIZE, movi_len),
+    ];
+    for (at, value) in patches {
+        out.seek(SeekFrom::Start(at))?;
+        out.write_all(&value.to_le_bytes())?;
+    }
+
+    out.seek(SeekFrom::Start(end))?;
+    out.flush()?;
+    Ok(())
+}
+
+/// What `parse` found in a finished file
+#[derive(Clone, Debug)]
+pub struct AviInfo {
+    pub width: u32,
+    pub height: u32,
+    pub us_per_frame: u32,
+    /// `dwTotalFrames` from the main header
+    pub total_frames: u32,
+    /// Byte range of each JPEG in the file, from `idx1`
+    pub frames: Vec<Range<usize>>,
+}
+
+impl AviInfo {
+    pub fn fps(&self) -> f32 {
+        1_000_000.0 / self.us_per_frame.max(1) as f32
+    }
+}
+
+/// Reads back a finished file. Strict about the layout `AviWriter` produces, so it doubles
+/// as a check on the writer.
+pub fn parse(avi: &[u8]) -> anyhow::Result<AviInfo> {
+    let u32_at = |at: usize| -> anyhow::Result<u32> {
+        let bytes = avi
+            .get(at..at + 4)
+            .ok_or_else(|| anyhow::anyhow!("Truncated at byte {}", at))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let expect = |at: usize, fourcc: &[u8; 4]| -> anyhow::Result<()> {
+        if avi.get(at..at + 4) != Some(fourcc) {
+            anyhow::bail!(
+                "Expected {} at byte {}",
+                String::from_utf8_lossy(fourcc),
+                at
+            );
+        }
+        Ok(())
+    };
+
+    expect(0, b"RIFF")?;
+    expect(8, b"AVI ")?;
+    if u32_at(4)? as usize + 8 != avi.len() {
+        anyhow::bail!("RIFF size doesn't match the file, not finished?");
+    }
+    expect(12, b"LIST")?;
+    expect(20, b"hdrl")?;
+    expect(24, b"avih")?;
+    expect(112, b"MJPG")?;
+    expect(212, b"LIST")?;
+    expect(220, b"movi")?;
+
+    let movi_end = MOVI_START as usize + u32_at(216)? as usize;
+    expect(movi_end, b"idx1")?;
+    let entries = u32_at(movi_end + 4)? as usize / 16;
+
+    let mut frames = Vec::with_capacity(entries);
+    for i in 0..entries {
+        let entry = movi_end + 8 + i * 16;
+        expect(entry, b"00dc")?;
+        let chunk = MOVI_START as usize + u32_at(entry + 8)? as usize;
+        let size = u32_at(entry + 12)? as usize;
+
+        expect(chunk, b"00dc")?;
+        if u32_at(chunk + 4)? as usize != size {
+            anyhow::bail!("Index entry {} doesn't match its chunk", i);
+        }
+        let start = chunk + 8;
+        if start + size > movi_end {
+            anyhow::bail!("Frame {} runs past the end of movi", i);
+        }
+        frames.push(start..start + size);
+    }
+
+    Ok(AviInfo {
+        width: u32_at(64)?,
+        height: u32_at(68)?,
+        us_per_frame: u32_at(32)?,
+        total_frames: u32_at(48)?,
+        frames,
+    })
+}
+
+/// True if `file` looks like one of ours that never got its index
+pub fn is_unfinished(file: &mut File) -> anyhow::Result<bool> {
+    let mut head = [0; 12];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut head).is_err() {
+        return Ok(false);
+    }
+    Ok(&head[0..4] == b"RIFF" && &head[8..12] == b"AVI " && head[4..8] == [0; 4])
+}
+
+/// Repairs a file that was cut off mid-recording: drops any partial frame at the end,
+/// then writes the index and header like `finish` would. The frame rate stays at whatever
+/// the header was created with. Returns how many frames were saved.
+pub fn recover(file: &mut File) -> anyhow::Result<usize> {
+    let len = file.metadata()?.len();
+    let mut header = vec![0; HEADER_LEN];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)
+        .map_err(|_| anyhow::anyhow!("Too short to be an AVI file"))?;
+    if &header[0..4] != b"RIFF" || &header[108..116] != b"vidsMJPG" {
+        anyhow::bail!("Not an MJPEG AVI file");
+    }
+    let us_per_frame = u32::from_le_bytes(header[32..36].try_into().unwrap()).max(1);
+
+    // Walk the chunks until one doesn't fit in what made it to disk
+    let mut index = Vec::new();
+    let mut largest_frame = 0;
+    let mut pos = HEADER_LEN as u64;
+    let mut chunk = [0; 8];
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        let size = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+        let end = pos + 8 + size as u64 + (size & 1) as u64;
+        if &chunk[0..4] != b"00dc" || end > len {
+            break;
+        }
+
+        index.push(((pos - MOVI_START) as u32, size));
+        largest_frame = largest_frame.max(size);
+        pos = end;
+    }
+
+    file.set_len(pos)?;
+    let movi_len = (pos - MOVI_START) as u32;
+    finalize(file, &index, us_per_frame, largest_frame, movi_len)?;
+    file.sync_all()?;
+
+    Ok(index.len())
+}
@@ -0,0 +1,361 @@
+//! The battery monitor: cell voltage through a divider on an ADC pin (`Board::battery`).
+//!
+//! A thread reads the pin every `interval_secs`, smooths it a little (the camera and Wi-Fi
+//! make it sag in bursts), and maps volts to percent along `curve`. Readings show up in
+//! `/status`, `/metrics` and MQTT telemetry. Each threshold's action applies while the
+//! charge is below it and is undone once it's `hysteresis_percent` above: slower streams
+//! and paused streams go through `App::set_stream_limits`, deep sleep through a hook the
+//! firmware passes in.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::board;
+use crate::http::App;
+use crate::settings::{BatteryAction, BatterySettings, BatteryThreshold, Settings};
+use crate::sleep;
+
+/// Weight of each new reading against the ones before
+const SMOOTHING: f32 = 0.3;
+
+/// How often paused streams look again
+pub const PAUSED_CHECK: Duration = Duration::from_secs(1);
+
+/// Percent charge at `volts`, along a curve from full to empty (see `BatterySettings`),
+/// straight lines between the points
+pub fn percent(curve: &[(f32, u8)], volts: f32) -> f32 {
+    let Some((&(full_volts, full), &(empty_volts, empty))) = curve.first().zip(curve.last()) else {
+        return 0.0;
+    };
+    if volts >= full_volts {
+        return full as f32;
+    }
+    if volts <= empty_volts {
+        return empty as f32;
+    }
+    for pair in curve.windows(2) {
+        let ((high_volts, high), (low_volts, low)) = (pair[0], pair[1]);
+        if volts >= low_volts {
+            let along = (volts - low_volts) / (high_volts - low_volts);
+            return low as f32 + along * (high as f32 - low as f32);
+        }
+    }
+    empty as f32
+}
+
+/// Which thresholds' actions apply, with hysteresis
+#[derive(Clone, Debug)]
+pub struct Thresholds {
+    thresholds: Vec<BatteryThreshold>,
+    hysteresis: f32,
+    active: Vec<bool>,
+}
+
+impl Thresholds {
+    pub fn new(thresholds: Vec<BatteryThreshold>, hysteresis_percent: u8) -> Self {
+        Self {
+            active: vec![false; thresholds.len()],
+            thresholds,
+            hysteresis: hysteresis_percent as f32,
+        }
+    }
+
+    /// Takes a reading in, returning the actions that started (`true`) or stopped applying
+    pub fn update(&mut self, percent: f32) -> Vec<(BatteryAction, bool)> {
+        let mut changes = Vec::new();
+        for (threshold, active) in self.thresholds.iter().zip(&mut self.active) {
+            let below = threshold.below_percent as f32;
+            let now = if *active {
+                percent < below + self.hysteresis
+            } else {
+                percent < below
+            };
+            if now != *active {
+                *active = now;
+                changes.push((threshold.action, now));
+            }
+        }
+        changes
+    }
+
+    /// The actions that apply, each once
+    pub fn active(&self) -> Vec<BatteryAction> {
+        let mut actions = Vec::new();
+        for (threshold, &active) in self.thresholds.iter().zip(&self.active) {
+            if active && !actions.contains(&threshold.action) {
+                actions.push(threshold.action);
+            }
+        }
+        actions
+    }
+}
+
+/// What live streams (`/stream`, `/ws`, RTSP, UDP) are held to, see `App::set_stream_limits`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StreamLimits {
+    pub max_fps: Option<f32>,
+    pub paused: bool,
+}
+
+impl StreamLimits {
+    /// From the actions that apply
+    pub fn for_actions(actions: &[BatteryAction], low_fps: f32) -> Self {
+        Self {
+            max_fps: actions
+                .contains(&BatteryAction::ReduceFps)
+                .then_some(low_fps),
+            paused: actions.contains(&BatteryAction::StopStreaming),
+        }
+    }
+
+    /// Time between frames for a stream that would like `interval`, `None` while paused
+    pub fn interval(&self, interval: Duration) -> Option<Duration> {
+        if self.paused {
+            return None;
+        }
+        let slowest = self.max_fps.map(|fps| Duration::from_secs_f32(1.0 / fps));
+        Some(slowest.map_or(interval, |slowest| interval.max(slowest)))
+    }
+}
+
+/// Checks the battery settings, that the pin isn't taken by a trigger, and that something
+/// wakes the chip if a threshold puts it to sleep
+pub fn validate(settings: &Settings) -> anyhow::Result<()> {
+    settings.battery.validate()?;
+    let board = board::current();
+    let Some(pin) = board.battery else {
+        anyhow::bail!("The {} has no pin to read the battery on", board.name);
+    };
+    if settings.triggers.iter().any(|input| input.gpio == pin.gpio) {
+        anyhow::bail!(
+            "GPIO{} reads the battery, it can't be a trigger too",
+            pin.gpio
+        );
+    }
+    let sleeps = settings
+        .battery
+        .thresholds
+        .iter()
+        .any(|threshold| threshold.action == BatteryAction::DeepSleep);
+    if sleeps {
+        sleep::validate_wakes(settings)?;
+    }
+    Ok(())
+}
+
+/// The pin's ADC channel
+pub trait BatteryAdc: Send {
+    /// Volts at the pin, calibrated
+    fn read_volts(&mut self) -> anyhow::Result<f32>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Reading {
+    /// At the cell, smoothed
+    pub volts: f32,
+    pub percent: f32,
+}
+
+#[derive(Default)]
+struct State {
+    reading: Option<Reading>,
+    actions: Vec<BatteryAction>,
+    last_error: Option<String>,
+}
+
+/// The monitor, see the module docs
+pub struct Battery {
+    settings: BatterySettings,
+    divider: f32,
+    state: Mutex<State>,
+}
+
+impl Battery {
+    /// Starts reading on a background thread, which stops once the app is gone. `deep_sleep`
+    /// is called when the `DeepSleep` action starts applying.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        mut adc: impl BatteryAdc + 'static,
+        divider: f32,
+        settings: BatterySettings,
+        mut deep_sleep: impl FnMut() + Send + 'static,
+    ) -> Arc<Self> {
+        let battery = Arc::new(Self {
+            divider,
+            state: Mutex::new(State::default()),
+            settings,
+        });
+
+        let this = battery.clone();
+        thread::spawn(move || {
+            let interval = Duration::from_secs(this.settings.interval_secs as u64);
+            let mut thresholds = Thresholds::new(
+                this.settings.thresholds.clone(),
+                this.settings.hysteresis_percent,
+            );
+            loop {
+                let Some(app) = weak_app.upgrade() else {
+                    break;
+                };
+                match adc.read_volts() {
+                    Ok(volts) => {
+                        let reading = this.smooth(volts * this.divider);
+                        let changes = thresholds.update(reading.percent);
+                        for &(action, active) in &changes {
+                            log::info!(
+                                "Battery at {:.0}% ({:.2} V): {:?} {}",
+                                reading.percent,
+                                reading.volts,
+                                action,
+                                if active { "on" } else { "off" }
+                            );
+                        }
+                        let actions = thresholds.active();
+                        this.state.lock().unwrap().actions = actions.clone();
+                        if !changes.is_empty() {
+                            let limits = StreamLimits::for_actions(&actions, this.settings.low_fps);
+                            app.set_stream_limits(limits);
+                        }
+                        if changes.contains(&(BatteryAction::DeepSleep, true)) {
+                            deep_sleep();
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Couldn't read the battery: {}", e);
+                        this.state.lock().unwrap().last_error = Some(e.to_string());
+                    }
+                }
+                drop(app);
+                thread::sleep(interval);
+            }
+        });
+
+        battery
+    }
+
+    /// The latest reading, `None` before the first
+    pub fn reading(&self) -> Option<Reading> {
+        self.state.lock().unwrap().reading
+    }
+
+    /// The actions that apply now
+    pub fn actions(&self) -> Vec<BatteryAction> {
+        self.state.lock().unwrap().actions.clone()
+    }
+
+    /// The `battery` part of `/status`
+    pub fn status_json(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        serde_json::json!({
+            "volts": state.reading.map(|reading| reading.volts),
+            "percent": state.reading.map(|reading| reading.percent.round()),
+            "actions": state.actions,
+            "last_error": state.last_error,
+        })
+    }
+
+    /// Smooths a new reading in
+    fn smooth(&self, volts: f32) -> Reading {
+        let mut state = self.state.lock().unwrap();
+        let volts = match state.reading {
+            Some(last) => last.volts + (volts - last.volts) * SMOOTHING,
+            None => volts,
+        };
+        let reading = Reading {
+            volts,
+            percent: percent(&self.settings.curve, volts),
+        };
+        state.reading = Some(reading);
+        state.last_error = None;
+        reading
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub mod esp {
+    use std::ptr;
+
+    use esp_idf_svc::sys::{self, esp};
+
+    use super::BatteryAdc;
+
+    /// Raw readings averaged for each one reported
+    const SAMPLES: u32 = 16;
+
+    /// ADC1 oneshot on the battery pin, calibrated with the eFuse line-fitting values
+    pub struct EspBatteryAdc {
+        unit: sys::adc_oneshot_unit_handle_t,
+        channel: sys::adc_channel_t,
+        cali: sys::adc_cali_handle_t,
+    }
+
+    // SAFETY: the handles are only used through `&mut self`
+    unsafe impl Send for EspBatteryAdc {}
+
+    impl EspBatteryAdc {
+        /// Takes over ADC1 for `gpio`, which has to be one of its pins
+        pub fn new(gpio: i32) -> anyhow::Result<Self> {
+            let (mut unit_id, mut channel) = (0, 0);
+            esp!(unsafe { sys::adc_oneshot_io_to_channel(gpio, &mut unit_id, &mut channel) })?;
+            if unit_id != sys::adc_unit_t_ADC_UNIT_1 {
+                anyhow::bail!(
+                    "GPIO{} isn't on ADC1, which is the one that works with Wi-Fi",
+                    gpio
+                );
+            }
+
+            let mut unit = ptr::null_mut();
+            let unit_config = sys::adc_oneshot_unit_init_cfg_t {
+                unit_id,
+                ..Default::default()
+            };
+            esp!(unsafe { sys::adc_oneshot_new_unit(&unit_config, &mut unit) })?;
+            // 12 dB reads up to ~3.1 V, so the divider has to bring a full cell below that
+            let channel_config = sys::adc_oneshot_chan_cfg_t {
+                atten: sys::adc_atten_t_ADC_ATTEN_DB_12,
+                bitwidth: sys::adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+            };
+            esp!(unsafe { sys::adc_oneshot_config_channel(unit, channel, &channel_config) })?;
+
+            let mut cali = ptr::null_mut();
+            let cali_config = sys::adc_cali_line_fitting_config_t {
+                unit_id,
+                atten: channel_config.atten,
+                bitwidth: channel_config.bitwidth,
+                ..Default::default()
+            };
+            esp!(unsafe { sys::adc_cali_create_scheme_line_fitting(&cali_config, &mut cali) })?;
+
+            Ok(Self {
+                unit,
+                channel,
+                cali,
+            })
+        }
+    }
+
+    impl BatteryAdc for EspBatteryAdc {
+        fn read_volts(&mut self) -> anyhow::Result<f32> {
+            let mut total = 0;
+            for _ in 0..SAMPLES {
+                let (mut raw, mut millivolts) = (0, 0);
+                esp!(unsafe { sys::adc_oneshot_read(self.unit, self.channel, &mut raw) })?;
+                esp!(unsafe { sys::adc_cali_raw_to_voltage(self.cali, raw, &mut millivolts) })?;
+                total += millivolts;
+            }
+            Ok(total as f32 / SAMPLES as f32 / 1000.0)
+        }
+    }
+
+    impl Drop for EspBatteryAdc {
+        fn drop(&mut self) {
+            unsafe {
+                sys::adc_cali_delete_scheme_line_fitting(self.cali);
+                sys::adc_oneshot_del_unit(self.unit);
+            }
+        }
+    }
+}
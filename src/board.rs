@@ -0,0 +1,141 @@
+//! Pin assignments for the boards we run on.
+//!
+//! The Freenove WROVER is the default. Build with `--features ai-thinker` for an AI-Thinker
+//! ESP32-CAM. Pins are plain GPIO numbers (-1 = not connected) so they can go straight into
+//! the C drivers.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Board {
+    pub name: &'static str,
+    pub camera: CameraPins,
+    /// `None` if there's no card slot
+    pub sd: Option<SdPins>,
+    /// White flash LED, `None` if the board has none (or its pin is taken)
+    pub flash: Option<i32>,
+    /// Small indicator LED for `status_led`, `None` if there's none free
+    pub status_led: Option<LedPin>,
+    /// Free for `trigger` inputs. All RTC GPIOs, so any of them can wake the chip.
+    pub trigger_pins: &'static [i32],
+    /// Battery voltage through a divider, for `battery`. `None` if there's no ADC1 pin
+    /// free (ADC2 can't be read while Wi-Fi is on).
+    pub battery: Option<BatteryPin>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BatteryPin {
+    pub gpio: i32,
+    /// Battery volts per volt at the pin
+    pub divider: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LedPin {
+    pub gpio: i32,
+    /// Lit by pulling the pin low
+    pub active_low: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CameraPins {
+    pub pwdn: i32,
+    pub reset: i32,
+    pub xclk: i32,
+    pub siod: i32,
+    pub sioc: i32,
+    /// D0-D7, a.k.a. Y2-Y9 on most schematics
+    pub data: [i32; 8],
+    pub vsync: i32,
+    pub href: i32,
+    pub pclk: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SdPins {
+    /// SDMMC host in 1-bit mode. On the ESP32 these are fixed by the IO MUX (14/15/2).
+    Mmc1Bit { clk: i32, cmd: i32, d0: i32 },
+    Spi {
+        sclk: i32,
+        mosi: i32,
+        miso: i32,
+        cs: i32,
+    },
+}
+
+/// Freenove ESP32-WROVER-E CAM (matches the standard WROVER-KIT camera pinout)
+pub const FREENOVE_WROVER: Board = Board {
+    name: "Freenove WROVER",
+    camera: CameraPins {
+        pwdn: -1,
+        reset: -1,
+        xclk: 21,
+        siod: 26,
+        sioc: 27,
+        data: [4, 5, 18, 19, 36, 39, 34, 35],
+        vsync: 25,
+        href: 23,
+        pclk: 22,
+    },
+    // No slot on the board, this is an SPI breakout on the free header pins
+    sd: Some(SdPins::Spi {
+        sclk: 14,
+        mosi: 13,
+        miso: 15,
+        cs: 32,
+    }),
+    // GPIO4 is the flash on the AI-Thinker, but camera Y2 here
+    flash: None,
+    // The blue LED next to the USB port
+    status_led: Some(LedPin {
+        gpio: 2,
+        active_low: false,
+    }),
+    // GPIO0 is the BOOT button, 33 is on the header
+    trigger_pins: &[0, 33],
+    // The only ADC1 pin left, so it's a trigger or the battery, not both. Two equal
+    // resistors from the cell.
+    battery: Some(BatteryPin {
+        gpio: 33,
+        divider: 2.0,
+    }),
+};
+
+/// AI-Thinker ESP32-CAM
+pub const AI_THINKER: Board = Board {
+    name: "AI-Thinker ESP32-CAM",
+    camera: CameraPins {
+        pwdn: 32,
+        reset: -1,
+        xclk: 0,
+        siod: 26,
+        sioc: 27,
+        data: [5, 18, 19, 21, 36, 39, 34, 35],
+        vsync: 25,
+        href: 23,
+        pclk: 22,
+    },
+    sd: Some(SdPins::Mmc1Bit {
+        clk: 14,
+        cmd: 15,
+        d0: 2,
+    }),
+    flash: Some(4),
+    // The red LED on the back
+    status_led: Some(LedPin {
+        gpio: 33,
+        active_low: true,
+    }),
+    // The only one left with the card in 1-bit mode. 12 is free too but sets the flash
+    // voltage at boot, a sensor holding it high would brick the boot.
+    trigger_pins: &[13],
+    // ADC1 is all camera, PWDN and the LED
+    battery: None,
+};
+
+/// The board this firmware was built for
+pub fn current() -> &'static Board {
+    if cfg!(feature = "ai-thinker") {
+        &AI_THINKER
+    } else {
+        &FREENOVE_WROVER
+    }
+}
@@ -0,0 +1,309 @@
+//! Camera abstraction so the streaming stack doesn't care where frames come from.
+//!
+//! `EspCamera` wraps the esp32-camera driver. `PlaybackCamera` and `TestPattern` produce
+//! frames on a PC, so everything above the camera can run without a board.
+
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(target_os = "espidf")]
+mod esp;
+mod pattern;
+mod playback;
+
+#[cfg(target_os = "espidf")]
+pub use esp::EspCamera;
+pub use pattern::TestPattern;
+pub use playback::PlaybackCamera;
+
+use crate::codec::{self, Image, PixelFormat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameFormat {
+    Jpeg,
+    Grayscale,
+    Rgb888,
+}
+
+impl FrameFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "jpeg",
+            FrameFormat::Grayscale => "grayscale",
+            FrameFormat::Rgb888 => "rgb888",
+        }
+    }
+}
+
+/// One captured frame. The ESP driver buffer is copied out and handed back straight away,
+/// so holding on to a `FrameBuffer` never starves the sensor.
+#[derive(Clone, Debug)]
+pub struct FrameBuffer {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub format: FrameFormat,
+    /// Capture time, relative to boot (or to camera creation on the host)
+    pub timestamp: Duration,
+    /// Capture time on the wall clock, `None` until the time has been synced
+    pub wall_time: Option<SystemTime>,
+}
+
+impl FrameBuffer {
+    /// Decoded pixels. Raw frames are just wrapped, JPEGs go through the software decoder.
+    pub fn to_image(&self) -> anyhow::Result<Image> {
+        let format = match self.format {
+            FrameFormat::Jpeg => return codec::decode_jpeg(&self.data),
+            FrameFormat::Grayscale => PixelFormat::Grayscale,
+            FrameFormat::Rgb888 => PixelFormat::Rgb888,
+        };
+        let stride = self.width as usize * format.bytes_per_pixel();
+        Image::from_raw(self.width, self.height, stride, format, self.data.clone())
+    }
+
+    /// Runs `f` on the pixels and puts the result back. Raw frames are edited in place,
+    /// JPEGs are decoded and re-encoded at `jpeg_quality` (1-100).
+    pub fn edit(&mut self, jpeg_quality: u8, f: impl FnOnce(&mut Image)) -> anyhow::Result<()> {
+        if self.format == FrameFormat::Jpeg {
+            let mut image = codec::decode_jpeg(&self.data)?;
+            f(&mut image);
+            self.data = codec::encode_jpeg(&image, jpeg_quality)?;
+            return Ok(());
+        }
+
+        let format = match self.format {
+            FrameFormat::Grayscale => PixelFormat::Grayscale,
+            _ => PixelFormat::Rgb888,
+        };
+        let stride = self.width as usize * format.bytes_per_pixel();
+        let data = std::mem::take(&mut self.data);
+        let mut image = Image::from_raw(self.width, self.height, stride, format, data)?;
+        f(&mut image);
+        self.data = image.data;
+        Ok(())
+    }
+}
+
+/// Resolutions from `framesize.csv`, in order. The index is what `/control?var=framesize`
+/// takes, so it stays the same whatever esp32-camera version is linked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameSize {
+    P96x96,
+    Qqvga,
+    Qcif,
+    Hqvga,
+    Qvga,
+    Cif,
+    Vga,
+    Svga,
+    Xga,
+    Sxga,
+    Uxga,
+    Qxga,
+}
+
+impl FrameSize {
+    pub const ALL: [FrameSize; 12] = [
+        FrameSize::P96x96,
+        FrameSize::Qqvga,
+        FrameSize::Qcif,
+        FrameSize::Hqvga,
+        FrameSize::Qvga,
+        FrameSize::Cif,
+        FrameSize::Vga,
+        FrameSize::Svga,
+        FrameSize::Xga,
+        FrameSize::Sxga,
+        FrameSize::Uxga,
+        FrameSize::Qxga,
+    ];
+
+    pub fn from_index(index: i32) -> Option<Self> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| Self::ALL.get(i).copied())
+    }
+
+    pub fn index(self) -> i32 {
+        Self::ALL.iter().position(|&s| s == self).unwrap() as i32
+    }
+
+    pub fn dimensions(self) -> (u32, u32) {
+        match self {
+            FrameSize::P96x96 => (96, 96),
+            FrameSize::Qqvga => (160, 120),
+            FrameSize::Qcif => (176, 144),
+            FrameSize::Hqvga => (240, 176),
+            FrameSize::Qvga => (320, 240),
+            FrameSize::Cif => (400, 296),
+            FrameSize::Vga => (640, 480),
+            FrameSize::Svga => (800, 600),
+            FrameSize::Xga => (1024, 768),
+            FrameSize::Sxga => (1280, 1024),
+            FrameSize::Uxga => (1600, 1200),
+            FrameSize::Qxga => (2048, 1536),
+        }
+    }
+
+    /// The `FRAMESIZE_` suffix, e.g. `VGA`
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameSize::P96x96 => "96X96",
+            FrameSize::Qqvga => "QQVGA",
+            FrameSize::Qcif => "QCIF",
+            FrameSize::Hqvga => "HQVGA",
+            FrameSize::Qvga => "QVGA",
+            FrameSize::Cif => "CIF",
+            FrameSize::Vga => "VGA",
+            FrameSize::Svga => "SVGA",
+            FrameSize::Xga => "XGA",
+            FrameSize::Sxga => "SXGA",
+            FrameSize::Uxga => "UXGA",
+            FrameSize::Qxga => "QXGA",
+        }
+    }
+
+    /// Any case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|size| size.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Sensor settings exposed through `/control` and `/status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    FrameSize,
+    /// JPEG quality, 4-63, lower is better
+    Quality,
+    /// -2 to 2
+    Brightness,
+    /// -2 to 2
+    Contrast,
+    /// -2 to 2
+    Saturation,
+    HMirror,
+    VFlip,
+}
+
+impl Control {
+    pub const ALL: [Control; 7] = [
+        Control::FrameSize,
+        Control::Quality,
+        Control::Brightness,
+        Control::Contrast,
+        Control::Saturation,
+        Control::HMirror,
+        Control::VFlip,
+    ];
+
+    /// Same names as the esp32-camera CameraWebServer example
+    pub fn name(self) -> &'static str {
+        match self {
+            Control::FrameSize => "framesize",
+            Control::Quality => "quality",
+            Control::Brightness => "brightness",
+            Control::Contrast => "contrast",
+            Control::Saturation => "saturation",
+            Control::HMirror => "hmirror",
+            Control::VFlip => "vflip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    pub fn range(self) -> (i32, i32) {
+        match self {
+            Control::FrameSize => (0, FrameSize::ALL.len() as i32 - 1),
+            Control::Quality => (4, 63),
+            Control::Brightness | Control::Contrast | Control::Saturation => (-2, 2),
+            Control::HMirror | Control::VFlip => (0, 1),
+        }
+    }
+
+    /// Checks `value` is in range for this control
+    pub fn validate(self, value: i32) -> anyhow::Result<i32> {
+        let (min, max) = self.range();
+        if value < min || value > max {
+            anyhow::bail!("{} must be between {} and {}", self.name(), min, max);
+        }
+        Ok(value)
+    }
+}
+
+pub trait Camera: Send {
+    /// Blocks until the next frame is ready
+    fn capture(&mut self) -> anyhow::Result<FrameBuffer>;
+
+    fn set_control(&mut self, control: Control, value: i32) -> anyhow::Result<()>;
+
+    fn control(&self, control: Control) -> i32;
+}
+
+impl<C: Camera + ?Sized> Camera for Box<C> {
+    fn capture(&mut self) -> anyhow::Result<FrameBuffer> {
+        (**self).capture()
+    }
+
+    fn set_control(&mut self, control: Control, value: i32) -> anyhow::Result<()> {
+        (**self).set_control(control, value)
+    }
+
+    fn control(&self, control: Control) -> i32 {
+        (**self).control(control)
+    }
+}
+
+/// Control values for cameras without a real sensor behind them
+#[derive(Clone, Copy, Debug)]
+pub struct ControlValues([i32; Control::ALL.len()]);
+
+impl ControlValues {
+    pub fn new(frame_size: FrameSize) -> Self {
+        let mut values = [0; Control::ALL.len()];
+        values[Control::FrameSize as usize] = frame_size.index();
+        values[Control::Quality as usize] = 12;
+        Self(values)
+    }
+
+    pub fn get(&self, control: Control) -> i32 {
+        self.0[control as usize]
+    }
+
+    pub fn set(&mut self, control: Control, value: i32) -> anyhow::Result<()> {
+        self.0[control as usize] = control.validate(value)?;
+        Ok(())
+    }
+
+    pub fn frame_size(&self) -> FrameSize {
+        FrameSize::from_index(self.get(Control::FrameSize)).unwrap_or(FrameSize::Svga)
+    }
+}
+
+/// Sleeps just enough to hold a fixed frame rate
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(fps: f32) -> Self {
+        Self {
+            interval: Duration::from_secs_f32(1.0 / fps.max(0.01)),
+            next: None,
+        }
+    }
+
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let due = self.next.unwrap_or(now);
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        // If we fell behind, restart from now instead of bursting to catch up
+        self.next = Some(due.max(now) + self.interval);
+    }
+}
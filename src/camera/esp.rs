@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use esp_idf_svc::sys::camera::*;
+
+use super::{Camera, Control, FrameBuffer, FrameFormat, FrameSize};
+use crate::clock;
+use crate::ov3660::{start_ov3660, OV3660Config};
+
+/// The esp32-camera driver. There is only one sensor, so only create one of these.
+pub struct EspCamera {
+    _private: (),
+}
+
+impl EspCamera {
+    pub fn new(config: OV3660Config) -> anyhow::Result<Self> {
+        start_ov3660(config)?;
+        Ok(Self { _private: () })
+    }
+
+    fn sensor() -> anyhow::Result<*mut sensor_t> {
+        let sensor = unsafe { esp_camera_sensor_get() };
+        if sensor.is_null() {
+            anyhow::bail!("Camera sensor not initialised");
+        }
+        Ok(sensor)
+    }
+}
+
+impl Camera for EspCamera {
+    fn capture(&mut self) -> anyhow::Result<FrameBuffer> {
+        unsafe {
+            let fb = esp_camera_fb_get();
+            if fb.is_null() {
+                anyhow::bail!("Camera capture failed");
+            }
+
+            // Unaligned reads, the timestamp field leaves camera_fb_t packed
+            let buf = std::ptr::addr_of!((*fb).buf).read_unaligned();
+            let len = std::ptr::addr_of!((*fb).len).read_unaligned();
+            let width = std::ptr::addr_of!((*fb).width).read_unaligned();
+            let height = std::ptr::addr_of!((*fb).height).read_unaligned();
+            let format = std::ptr::addr_of!((*fb).format).read_unaligned();
+            let timestamp = std::ptr::addr_of!((*fb).timestamp).read_unaligned();
+
+            let data = std::slice::from_raw_parts(buf, len).to_vec();
+            esp_camera_fb_return(fb);
+
+            #[allow(non_upper_case_globals)]
+            let format = match format {
+                pixformat_t_PIXFORMAT_JPEG => FrameFormat::Jpeg,
+                pixformat_t_PIXFORMAT_GRAYSCALE => FrameFormat::Grayscale,
+                pixformat_t_PIXFORMAT_RGB888 => FrameFormat::Rgb888,
+                other => anyhow::bail!("Unsupported pixel format {}", other),
+            };
+
+            // The driver stamps frames with time since boot, work back from how old it is
+            let timestamp = Duration::new(timestamp.tv_sec as u64, timestamp.tv_usec as u32 * 1000);
+            let uptime = Duration::from_micros(esp_idf_svc::sys::esp_timer_get_time() as u64);
+            let wall_time = clock::wall_clock().map(|now| now - uptime.saturating_sub(timestamp));
+
+            Ok(FrameBuffer {
+                data,
+                width: width as u32,
+                height: height as u32,
+                format,
+                timestamp,
+                wall_time,
+            })
+        }
+    }
+
+    fn set_control(&mut self, control: Control, value: i32) -> anyhow::Result<()> {
+        let value = control.validate(value)?;
+        let sensor = Self::sensor()?;
+
+        let result = unsafe {
+            let s = &*sensor;
+            match control {
+                Control::FrameSize => {
+                    let size = FrameSize::from_index(value).unwrap();
+                    s.set_framesize.map(|f| f(sensor, to_framesize_t(size)))
+                }
+                Control::Quality => s.set_quality.map(|f| f(sensor, value)),
+                Control::Brightness => s.set_brightness.map(|f| f(sensor, value)),
+                Control::Contrast => s.set_contrast.map(|f| f(sensor, value)),
+                Control::Saturation => s.set_saturation.map(|f| f(sensor, value)),
+                Control::HMirror => s.set_hmirror.map(|f| f(sensor, value)),
+                Control::VFlip => s.set_vflip.map(|f| f(sensor, value)),
+            }
+        };
+
+        match result {
+            Some(0) => Ok(()),
+            Some(err) => anyhow::bail!("Setting {} failed with error: {}", control.name(), err),
+            None => anyhow::bail!("Sensor does not support {}", control.name()),
+        }
+    }
+
+    fn control(&self, control: Control) -> i32 {
+        let Ok(sensor) = Self::sensor() else {
+            return 0;
+        };
+
+        let status = unsafe { &(*sensor).status };
+        match control {
+            Control::FrameSize => from_framesize_t(status.framesize).map_or(-1, FrameSize::index),
+            Control::Quality => status.quality as i32,
+            Control::Brightness => status.brightness as i32,
+            Control::Contrast => status.contrast as i32,
+            Control::Saturation => status.saturation as i32,
+            Control::HMirror => status.hmirror as i32,
+            Control::VFlip => status.vflip as i32,
+        }
+    }
+}
+
+const FRAMESIZES: [(FrameSize, framesize_t); 12] = [
+    (FrameSize::P96x96, framesize_t_FRAMESIZE_96X96),
+    (FrameSize::Qqvga, framesize_t_FRAMESIZE_QQVGA),
+    (FrameSize::Qcif, framesize_t_FRAMESIZE_QCIF),
+    (FrameSize::Hqvga, framesize_t_FRAMESIZE_HQVGA),
+    (FrameSize::Qvga, framesize_t_FRAMESIZE_QVGA),
+    (FrameSize::Cif, framesize_t_FRAMESIZE_CIF),
+    (FrameSize::Vga, framesize_t_FRAMESIZE_VGA),
+    (FrameSize::Svga, framesize_t_FRAMESIZE_SVGA),
+    (FrameSize::Xga, framesize_t_FRAMESIZE_XGA),
+    (FrameSize::Sxga, framesize_t_FRAMESIZE_SXGA),
+    (FrameSize::Uxga, framesize_t_FRAMESIZE_UXGA),
+    (FrameSize::Qxga, framesize_t_FRAMESIZE_QXGA),
+];
+
+pub(crate) fn to_framesize_t(size: FrameSize) -> framesize_t {
+    FRAMESIZES.iter().find(|(s, _)| *s == size).unwrap().1
+}
+
+fn from_framesize_t(raw: framesize_t) -> Option<FrameSize> {
+    FRAMESIZES.iter().find(|(_, r)| *r == raw).map(|(s, _)| *s)
+}
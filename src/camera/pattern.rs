@@ -0,0 +1,92 @@
+use std::time::Instant;
+
+use super::{Camera, Control, ControlValues, FrameBuffer, FrameFormat, FrameSize, Pacer};
+use crate::clock;
+use crate::codec::{self, Image, PixelFormat};
+
+const BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+/// Synthetic colour bars with a bar sweeping across, so a frozen stream is obvious.
+/// Honours `framesize`, `quality`, `hmirror` and `vflip`.
+pub struct TestPattern {
+    pacer: Pacer,
+    started: Instant,
+    frame: u32,
+    controls: ControlValues,
+}
+
+impl TestPattern {
+    pub fn new(frame_size: FrameSize, fps: f32) -> Self {
+        Self {
+            pacer: Pacer::new(fps),
+            started: Instant::now(),
+            frame: 0,
+            controls: ControlValues::new(frame_size),
+        }
+    }
+
+    fn render(&self) -> Image {
+        let (width, height) = self.controls.frame_size().dimensions();
+        let mut image = Image::new(width, height, PixelFormat::Rgb888);
+        let sweep = (self.frame * 8) % width;
+        let hmirror = self.controls.get(Control::HMirror) != 0;
+        let vflip = self.controls.get(Control::VFlip) != 0;
+
+        // Bars on top, a grey ramp along the bottom quarter
+        for y in 0..height {
+            let src_y = if vflip { height - 1 - y } else { y };
+            let row = image.row_mut(y);
+            for x in 0..width {
+                let src_x = if hmirror { width - 1 - x } else { x };
+                let rgb = if src_x.abs_diff(sweep) < 4 {
+                    [128, 128, 128]
+                } else if src_y < height * 3 / 4 {
+                    BARS[(src_x * 8 / width) as usize]
+                } else {
+                    let level = (src_x * 255 / width) as u8;
+                    [level; 3]
+                };
+                row[x as usize * 3..x as usize * 3 + 3].copy_from_slice(&rgb);
+            }
+        }
+        image
+    }
+}
+
+impl Camera for TestPattern {
+    fn capture(&mut self) -> anyhow::Result<FrameBuffer> {
+        self.pacer.wait();
+
+        let image = self.render();
+        // Sensor quality is 4 (best) to 63 (worst), the encoder wants 1-100
+        let quality = 100 - self.controls.get(Control::Quality) * 3 / 2;
+        let data = codec::encode_jpeg(&image, quality as u8)?;
+        self.frame = self.frame.wrapping_add(1);
+
+        Ok(FrameBuffer {
+            data,
+            width: image.width,
+            height: image.height,
+            format: FrameFormat::Jpeg,
+            timestamp: self.started.elapsed(),
+            wall_time: clock::wall_clock(),
+        })
+    }
+
+    fn set_control(&mut self, control: Control, value: i32) -> anyhow::Result<()> {
+        self.controls.set(control, value)
+    }
+
+    fn control(&self, control: Control) -> i32 {
+        self.controls.get(control)
+    }
+}
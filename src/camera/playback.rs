@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+
+use super::{Camera, Control, ControlValues, FrameBuffer, FrameFormat, FrameSize, Pacer};
+use crate::clock;
+use crate::codec;
+
+/// Plays a directory of JPEGs back in name order, looping forever
+pub struct PlaybackCamera {
+    files: Vec<PathBuf>,
+    next: usize,
+    pacer: Pacer,
+    started: Instant,
+    controls: ControlValues,
+}
+
+impl PlaybackCamera {
+    pub fn new(dir: impl AsRef<Path>, fps: f32) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let mut files = Vec::new();
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("Can't read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            let is_jpeg = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg")
+                });
+            if is_jpeg {
+                files.push(path);
+            }
+        }
+
+        if files.is_empty() {
+            anyhow::bail!("No .jpg files in {}", dir.display());
+        }
+        files.sort();
+
+        Ok(Self {
+            files,
+            next: 0,
+            pacer: Pacer::new(fps),
+            started: Instant::now(),
+            controls: ControlValues::new(FrameSize::Svga),
+        })
+    }
+}
+
+impl Camera for PlaybackCamera {
+    fn capture(&mut self) -> anyhow::Result<FrameBuffer> {
+        self.pacer.wait();
+
+        let path = &self.files[self.next];
+        self.next = (self.next + 1) % self.files.len();
+
+        let data = std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+        let (width, height) = codec::jpeg_dimensions(&data)
+            .with_context(|| format!("{} is not a valid JPEG", path.display()))?;
+
+        Ok(FrameBuffer {
+            data,
+            width,
+            height,
+            format: FrameFormat::Jpeg,
+            timestamp: self.started.elapsed(),
+            wall_time: clock::wall_clock(),
+        })
+    }
+
+    /// Recorded frames can't be changed, but the values are kept so `/status` reflects them
+    fn set_control(&mut self, control: Control, value: i32) -> anyhow::Result<()> {
+        self.controls.set(control, value)
+    }
+
+    fn control(&self, control: Control) -> i32 {
+        self.controls.get(control)
+    }
+}
@@ -0,0 +1,152 @@
+//! Outgoing HTTP requests, for webhooks and uploads.
+//!
+//! Senders only see `HttpClient`: `esp::EspClient` goes through the ESP-IDF client (HTTPS
+//! included), the host crate has a plain TCP one, and a closure will do in a test.
+
+#[cfg(target_os = "espidf")]
+pub mod esp;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Post,
+    Put,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Post => "POST",
+            Method::Put => "PUT",
+        }
+    }
+}
+
+/// Credentials for servers that want them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Auth {
+    None,
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl Auth {
+    /// Value for the `Authorization` header
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Auth::None => None,
+            Auth::Basic { username, password } => Some(format!(
+                "Basic {}",
+                base64(format!("{}:{}", username, password).as_bytes())
+            )),
+            Auth::Bearer(token) => Some(format!("Bearer {}", token)),
+        }
+    }
+}
+
+/// Standard base64, with padding
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Standard base64 back to bytes. Padding is optional, whitespace isn't allowed.
+pub fn base64_decode(text: &str) -> anyhow::Result<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => anyhow::bail!("Not base64: {:?}", c as char),
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    if count >= 6 {
+        anyhow::bail!("Truncated base64");
+    }
+    Ok(out)
+}
+
+pub fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+/// Whether a failed attempt is worth repeating: no answer, overload or a server error.
+/// Anything else (bad credentials, a missing path) won't get better by asking again.
+pub fn should_retry(result: &anyhow::Result<u16>) -> bool {
+    match result {
+        Ok(status) => *status == 408 || *status == 429 || *status >= 500,
+        Err(_) => true,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub url: String,
+    /// Anything besides Content-Length, which clients add themselves
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn new(method: Method, url: impl Into<String>) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub trait HttpClient: Send {
+    /// The response status. Only failing to get one at all is an error.
+    fn send(&mut self, request: &Request) -> anyhow::Result<u16>;
+}
+
+impl<F: FnMut(&Request) -> anyhow::Result<u16> + Send> HttpClient for F {
+    fn send(&mut self, request: &Request) -> anyhow::Result<u16> {
+        self(request)
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method as EspMethod;
+use esp_idf_svc::io::{Read, Write};
+
+use super::{is_success, HttpClient, Method, Request};
+use crate::ota::{Fetch, Receive};
+
+impl From<Method> for EspMethod {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Post => EspMethod::Post,
+            Method::Put => EspMethod::Put,
+        }
+    }
+}
+
+/// A fresh connection per request, which is plenty for a few a minute. HTTPS servers are
+/// checked against the ESP-IDF certificate bundle. Redirects aren't followed.
+pub struct EspClient {
+    timeout: Duration,
+}
+
+impl EspClient {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl EspClient {
+    fn connect(&self) -> anyhow::Result<EspHttpConnection> {
+        Ok(EspHttpConnection::new(&Configuration {
+            timeout: Some(self.timeout),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?)
+    }
+}
+
+impl HttpClient for EspClient {
+    fn send(&mut self, request: &Request) -> anyhow::Result<u16> {
+        let mut connection = self.connect()?;
+
+        let len = request.body.len().to_string();
+        let mut headers: Vec<_> = request
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        headers.push(("Content-Length", &len));
+
+        connection.initiate_request(request.method.into(), &request.url, &headers)?;
+        connection.write_all(&request.body)?;
+        connection.initiate_response()?;
+        Ok(connection.status())
+    }
+}
+
+impl Fetch for EspClient {
+    fn fetch(&mut self, url: &str, receive: &mut Receive) -> anyhow::Result<()> {
+        let mut connection = self.connect()?;
+        connection.initiate_request(EspMethod::Get, url, &[])?;
+        connection.initiate_response()?;
+        let status = connection.status();
+        if !is_success(status) {
+            anyhow::bail!("GET {} answered {}", url, status);
+        }
+        let len = connection
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        receive(len, &mut |buf| Ok(connection.read(buf)?))
+    }
+}
@@ -0,0 +1,167 @@
+//! Wall-clock helpers.
+//!
+//! Until something sets the system time the ESP32 thinks it is 1970, so anything before
+//! 2024 counts as "not set" and callers fall back to uptime.
+//!
+//! On the board SNTP sets the system clock itself. On the host we can't (and shouldn't)
+//! touch it, so `sntp::sync` stores an offset here instead.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod tz;
+
+pub use tz::{Dst, Rule, TimeZone};
+
+/// 2024-01-01T00:00:00Z
+const VALID_AFTER: u64 = 1_704_067_200;
+
+/// Correction applied on top of the system clock, in microseconds
+static OFFSET_MICROS: AtomicI64 = AtomicI64::new(0);
+
+static TIMEZONE: RwLock<Option<TimeZone>> = RwLock::new(None);
+
+/// The current time, if it has been set
+pub fn wall_clock() -> Option<SystemTime> {
+    let now = now();
+    is_valid(now).then_some(now)
+}
+
+/// System time plus any offset from `set_offset`, valid or not
+pub fn now() -> SystemTime {
+    let offset = OFFSET_MICROS.load(Ordering::Relaxed);
+    let magnitude = Duration::from_micros(offset.unsigned_abs());
+    if offset >= 0 {
+        SystemTime::now() + magnitude
+    } else {
+        SystemTime::now() - magnitude
+    }
+}
+
+/// Shifts `now()` by `offset` microseconds relative to the system clock
+pub fn set_offset(offset_micros: i64) {
+    OFFSET_MICROS.store(offset_micros, Ordering::Relaxed);
+}
+
+/// Zone used by the `*_local` formatters. Starts out as UTC.
+pub fn set_timezone(tz: TimeZone) {
+    *TIMEZONE.write().unwrap() = Some(tz);
+}
+
+pub fn timezone() -> TimeZone {
+    TIMEZONE
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(TimeZone::utc)
+}
+
+/// `time` shifted into the configured zone, as seconds since the (local) epoch
+pub fn local_seconds(time: SystemTime) -> i64 {
+    let secs = unix_seconds(time);
+    secs + timezone().offset_at(secs) as i64
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in the configured zone
+pub fn format_local(time: SystemTime) -> String {
+    format_datetime(local_seconds(time))
+}
+
+/// `YYYYMMDD_HHMMSS` in the configured zone, for file names
+pub fn format_compact(time: SystemTime) -> String {
+    let (date, time) = split(local_seconds(time));
+    format!(
+        "{:04}{:02}{:02}_{:02}{:02}{:02}",
+        date.0, date.1, date.2, time.0, time.1, time.2
+    )
+}
+
+/// RFC 3339 with the zone offset, e.g. `2025-10-19T14:00:00+02:00`
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let secs = unix_seconds(time);
+    let offset = timezone().offset_at(secs);
+    let (date, hms) = split(secs + offset as i64);
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.unsigned_abs();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+        date.0,
+        date.1,
+        date.2,
+        hms.0,
+        hms.1,
+        hms.2,
+        sign,
+        offset / 3600,
+        offset / 60 % 60
+    )
+}
+
+pub fn is_valid(time: SystemTime) -> bool {
+    time.duration_since(UNIX_EPOCH)
+        .is_ok_and(|since| since.as_secs() >= VALID_AFTER)
+}
+
+pub fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS`
+pub fn format_datetime(unix_seconds: i64) -> String {
+    let (date, time) = split(unix_seconds);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        date.0, date.1, date.2, time.0, time.1, time.2
+    )
+}
+
+/// `UP HH:MM:SS`, with a day count once it gets that far
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    let hms = format!("{:02}:{:02}:{:02}", rest / 3600, rest / 60 % 60, rest % 60);
+    if days > 0 {
+        format!("UP {}D {}", days, hms)
+    } else {
+        format!("UP {}", hms)
+    }
+}
+
+/// ((year, month, day), (hour, minute, second))
+pub fn split(unix_seconds: i64) -> ((i64, u32, u32), (u32, u32, u32)) {
+    let days = unix_seconds.div_euclid(86_400);
+    let secs = unix_seconds.rem_euclid(86_400) as u32;
+    (
+        civil_from_days(days),
+        (secs / 3600, secs / 60 % 60, secs % 60),
+    )
+}
+
+/// Proleptic Gregorian date to days since 1970-01-01
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's algorithm)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,227 @@
+//! POSIX `TZ` strings, e.g. `CET-1CEST,M3.5.0,M10.5.0/3` or `<+0530>-5:30`.
+//!
+//! Same strings ESP-IDF's `tzset()` takes, so one setting drives both. Only the `Mm.w.d`
+//! rule form is supported, which is what every zone in practice uses.
+
+use anyhow::Context;
+
+use super::{civil_from_days, days_from_civil};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeZone {
+    pub name: String,
+    /// Seconds east of UTC
+    pub offset: i32,
+    pub dst: Option<Dst>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dst {
+    pub name: String,
+    /// Seconds east of UTC while DST is in effect
+    pub offset: i32,
+    pub start: Rule,
+    pub end: Rule,
+}
+
+/// Day `weekday` (0 = Sunday) of week `week` (1-5, 5 = last) of `month`, at `time`
+/// seconds past local midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub month: u32,
+    pub week: u32,
+    pub weekday: u32,
+    pub time: i32,
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            name: "UTC".into(),
+            offset: 0,
+            dst: None,
+        }
+    }
+
+    pub fn parse(tz: &str) -> anyhow::Result<Self> {
+        let mut p = Parser { rest: tz.trim() };
+
+        let name = p.name().context("Missing zone name")?;
+        let offset = -p.offset().context("Missing UTC offset")?;
+
+        let dst = if p.rest.is_empty() {
+            None
+        } else {
+            let dst_name = p.name().context("Bad DST name")?;
+            // DST defaults to one hour ahead of standard time
+            let dst_offset = if p.rest.is_empty() || p.rest.starts_with(',') {
+                offset + 3600
+            } else {
+                -p.offset().context("Bad DST offset")?
+            };
+
+            let (Some(start), Some(end)) = (p.rule(), p.rule()) else {
+                anyhow::bail!("DST needs start and end rules (,Mm.w.d/time,Mm.w.d/time)");
+            };
+            Some(Dst {
+                name: dst_name,
+                offset: dst_offset,
+                start,
+                end,
+            })
+        };
+
+        if !p.rest.is_empty() {
+            anyhow::bail!("Unexpected '{}' in time zone", p.rest);
+        }
+
+        Ok(Self { name, offset, dst })
+    }
+
+    /// Seconds east of UTC at `unix_seconds`
+    pub fn offset_at(&self, unix_seconds: i64) -> i32 {
+        let Some(dst) = &self.dst else {
+            return self.offset;
+        };
+
+        let (year, _, _) = civil_from_days((unix_seconds + self.offset as i64).div_euclid(86_400));
+        // Start is given in standard time, end in daylight time
+        let start = dst.start.unix_seconds(year) - self.offset as i64;
+        let end = dst.end.unix_seconds(year) - dst.offset as i64;
+
+        let in_dst = if start < end {
+            unix_seconds >= start && unix_seconds < end
+        } else {
+            // Southern hemisphere, DST spans new year
+            unix_seconds >= start || unix_seconds < end
+        };
+
+        if in_dst {
+            dst.offset
+        } else {
+            self.offset
+        }
+    }
+
+    /// Zone abbreviation at `unix_seconds`
+    pub fn name_at(&self, unix_seconds: i64) -> &str {
+        match &self.dst {
+            Some(dst)
+                if self.offset_at(unix_seconds) == dst.offset && dst.offset != self.offset =>
+            {
+                &dst.name
+            }
+            _ => &self.name,
+        }
+    }
+}
+
+impl Rule {
+    /// Local (zone-less) seconds of this rule's moment in `year`
+    fn unix_seconds(&self, year: i64) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        // 1970-01-01 was a Thursday
+        let first_weekday = (first + 4).rem_euclid(7) as u32;
+        let mut day = 1 + (self.weekday + 7 - first_weekday) % 7 + (self.week - 1) * 7;
+
+        let next_month = if self.month == 12 {
+            days_from_civil(year + 1, 1, 1)
+        } else {
+            days_from_civil(year, self.month + 1, 1)
+        };
+        let month_len = (next_month - first) as u32;
+        while day > month_len {
+            day -= 7;
+        }
+
+        (first + day as i64 - 1) * 86_400 + self.time as i64
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// `CET` or `<+0530>`
+    fn name(&mut self) -> Option<String> {
+        let (name, rest) = if let Some(quoted) = self.rest.strip_prefix('<') {
+            let end = quoted.find('>')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len());
+            (&self.rest[..end], &self.rest[end..])
+        };
+
+        if name.len() < 3 {
+            return None;
+        }
+        self.rest = rest;
+        Some(name.to_owned())
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, POSIX sign (positive is west of UTC)
+    fn offset(&mut self) -> Option<i32> {
+        let (sign, rest) = match self.rest.as_bytes().first() {
+            Some(b'-') => (-1, &self.rest[1..]),
+            Some(b'+') => (1, &self.rest[1..]),
+            _ => (1, self.rest),
+        };
+        self.rest = rest;
+        Some(sign * self.time()?)
+    }
+
+    /// `hh[:mm[:ss]]` in seconds
+    fn time(&mut self) -> Option<i32> {
+        let mut total = 0;
+        for (i, scale) in [3600, 60, 1].into_iter().enumerate() {
+            if i > 0 {
+                match self.rest.strip_prefix(':') {
+                    Some(rest) => self.rest = rest,
+                    None => break,
+                }
+            }
+            total += self.number()? as i32 * scale;
+        }
+        Some(total)
+    }
+
+    /// `,Mm.w.d[/time]`
+    fn rule(&mut self) -> Option<Rule> {
+        self.rest = self.rest.strip_prefix(",M")?;
+        let month = self.number()?;
+        self.rest = self.rest.strip_prefix('.')?;
+        let week = self.number()?;
+        self.rest = self.rest.strip_prefix('.')?;
+        let weekday = self.number()?;
+
+        let time = match self.rest.strip_prefix('/') {
+            Some(rest) => {
+                self.rest = rest;
+                self.offset()?
+            }
+            None => 2 * 3600,
+        };
+
+        let valid = (1..=12).contains(&month) && (1..=5).contains(&week) && weekday <= 6;
+        valid.then_some(Rule {
+            month,
+            week,
+            weekday,
+            time,
+        })
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let value = self.rest[..end].parse().ok()?;
+        self.rest = &self.rest[end..];
+        Some(value)
+    }
+}
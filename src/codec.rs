@@ -0,0 +1,206 @@
+//! Software JPEG encode/decode for processing pipelines.
+//!
+//! Everything here is pure Rust (`jpeg-decoder` / `jpeg-encoder`), so it runs the same on
+//! the board and on the host. Decoding a full QXGA frame needs ~9MB, so on the ESP32 always
+//! decode with a size limit (see `decode_jpeg_scaled`).
+
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8-bit luminance, 1 byte per pixel
+    Grayscale,
+    /// 24-bit RGB, 3 bytes per pixel
+    Rgb888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Grayscale => 1,
+            PixelFormat::Rgb888 => 3,
+        }
+    }
+}
+
+/// A decoded image. Rows are `stride` bytes apart, which can be more than
+/// `width * bytes_per_pixel` if the buffer came from somewhere with padding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Black image with tightly packed rows
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let stride = width as usize * format.bytes_per_pixel();
+        Self {
+            width,
+            height,
+            stride,
+            format,
+            data: vec![0; stride * height as usize],
+        }
+    }
+
+    /// Wraps an existing buffer, checking that it is big enough
+    pub fn from_raw(
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+        data: Vec<u8>,
+    ) -> anyhow::Result<Self> {
+        let row_len = width as usize * format.bytes_per_pixel();
+        if stride < row_len {
+            anyhow::bail!(
+                "Stride {} is smaller than a row ({} bytes)",
+                stride,
+                row_len
+            );
+        }
+        if height > 0 && data.len() < stride * (height as usize - 1) + row_len {
+            anyhow::bail!(
+                "Buffer of {} bytes is too small for {}x{}",
+                data.len(),
+                width,
+                height
+            );
+        }
+
+        Ok(Self {
+            width,
+            height,
+            stride,
+            format,
+            data,
+        })
+    }
+
+    /// Pixel bytes of row `y`, without padding
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.width as usize * self.format.bytes_per_pixel()]
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> &mut [u8] {
+        let start = y as usize * self.stride;
+        let len = self.width as usize * self.format.bytes_per_pixel();
+        &mut self.data[start..start + len]
+    }
+
+    /// Luminance copy of the image (ITU-R BT.601 weights)
+    pub fn to_grayscale(&self) -> Image {
+        if self.format == PixelFormat::Grayscale {
+            return self.packed();
+        }
+
+        let mut gray = Image::new(self.width, self.height, PixelFormat::Grayscale);
+        for y in 0..self.height {
+            let src = self.row(y);
+            let dst = gray.row_mut(y);
+            for (out, px) in dst.iter_mut().zip(src.chunks_exact(3)) {
+                let luma = 77 * px[0] as u32 + 150 * px[1] as u32 + 29 * px[2] as u32;
+                *out = (luma >> 8) as u8;
+            }
+        }
+        gray
+    }
+
+    /// Copy with `stride == width * bytes_per_pixel`
+    fn packed(&self) -> Image {
+        let row_len = self.width as usize * self.format.bytes_per_pixel();
+        if self.stride == row_len {
+            return self.clone();
+        }
+
+        let mut out = Image::new(self.width, self.height, self.format);
+        for y in 0..self.height {
+            out.row_mut(y).copy_from_slice(self.row(y));
+        }
+        out
+    }
+}
+
+/// Decodes a JPEG at full size
+pub fn decode_jpeg(jpeg: &[u8]) -> anyhow::Result<Image> {
+    decode(jpeg, None)
+}
+
+/// Decodes a JPEG, letting the IDCT scale it down (1/2, 1/4 or 1/8) to the smallest size
+/// that still covers `max_width` x `max_height`. Much cheaper than decoding then resizing.
+pub fn decode_jpeg_scaled(jpeg: &[u8], max_width: u16, max_height: u16) -> anyhow::Result<Image> {
+    decode(jpeg, Some((max_width, max_height)))
+}
+
+/// Reads width and height from the JPEG header without decoding anything
+pub fn jpeg_dimensions(jpeg: &[u8]) -> anyhow::Result<(u32, u32)> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+    decoder.read_info().context("JPEG header is invalid")?;
+    let info = decoder.info().context("JPEG has no frame header")?;
+    Ok((info.width as u32, info.height as u32))
+}
+
+fn decode(jpeg: &[u8], scale: Option<(u16, u16)>) -> anyhow::Result<Image> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+
+    if let Some((width, height)) = scale {
+        decoder
+            .scale(width, height)
+            .context("JPEG header is invalid")?;
+    }
+
+    let pixels = decoder.decode().context("JPEG decode failed")?;
+    let info = decoder.info().context("JPEG has no frame header")?;
+    let (width, height) = (info.width as u32, info.height as u32);
+
+    let (format, data) = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => (PixelFormat::Grayscale, pixels),
+        // Big endian samples, keep the high byte
+        jpeg_decoder::PixelFormat::L16 => (
+            PixelFormat::Grayscale,
+            pixels.chunks_exact(2).map(|px| px[0]).collect(),
+        ),
+        jpeg_decoder::PixelFormat::RGB24 => (PixelFormat::Rgb888, pixels),
+        jpeg_decoder::PixelFormat::CMYK32 => anyhow::bail!("CMYK JPEGs are not supported"),
+    };
+
+    let stride = width as usize * format.bytes_per_pixel();
+    Image::from_raw(width, height, stride, format, data)
+}
+
+/// Compresses an image to a baseline JPEG. `quality` is 1-100, higher is better
+/// (the opposite of the sensor's `jpeg_quality`). Colour comes out 4:2:2 like the sensor's
+/// own JPEGs, which is also one of the two layouts RTP/JPEG can carry.
+pub fn encode_jpeg(image: &Image, quality: u8) -> anyhow::Result<Vec<u8>> {
+    let width = u16::try_from(image.width).context("Image too wide for JPEG")?;
+    let height = u16::try_from(image.height).context("Image too tall for JPEG")?;
+
+    let color_type = match image.format {
+        PixelFormat::Grayscale => jpeg_encoder::ColorType::Luma,
+        PixelFormat::Rgb888 => jpeg_encoder::ColorType::Rgb,
+    };
+
+    // The encoder wants tightly packed rows
+    let row_len = image.width as usize * image.format.bytes_per_pixel();
+    let packed;
+    let data = if image.stride == row_len {
+        &image.data[..row_len * image.height as usize]
+    } else {
+        packed = image.packed();
+        &packed.data
+    };
+
+    let mut out = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality.clamp(1, 100));
+    encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_2);
+    encoder
+        .encode(data, width, height, color_type)
+        .map_err(|e| anyhow::anyhow!("JPEG encode failed: {}", e))?;
+
+    Ok(out)
+}
@@ -0,0 +1,235 @@
+//! The white flash LED, on boards that have one (`Board::flash`).
+//!
+//! `FlashControl` sits between the LED and everything that switches it: it keeps the
+//! brightness under `max_brightness`, turns the LED off once it's been on for `max_on_secs`
+//! (it gets hot enough to hurt itself), and lights it for `/capture` if `on_capture` is set.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::settings::FlashSettings;
+
+/// How often the max-on timer is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Something that can be switched on and off as the flash
+pub trait Flash: Send {
+    fn set(&mut self, on: bool) -> anyhow::Result<()>;
+
+    fn is_on(&self) -> bool;
+
+    /// How bright it is when on, in percent of full power. LEDs that are all or nothing
+    /// ignore it.
+    fn set_brightness(&mut self, _percent: u8) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+struct Led {
+    flash: Box<dyn Flash>,
+    settings: FlashSettings,
+    /// When it was last switched on, while it's on
+    on_since: Option<Instant>,
+}
+
+/// The flash with its limits applied, see the module docs
+pub struct FlashControl {
+    led: Mutex<Led>,
+}
+
+impl FlashControl {
+    /// Starts the max-on timer, which stops once the control is dropped
+    pub fn spawn(flash: Box<dyn Flash>, settings: FlashSettings) -> Arc<Self> {
+        let control = Arc::new(Self {
+            led: Mutex::new(Led {
+                flash,
+                settings: settings.clone(),
+                on_since: None,
+            }),
+        });
+        if let Err(e) = control.configure(settings) {
+            log::warn!("Couldn't set the flash brightness: {}", e);
+        }
+
+        let weak = Arc::downgrade(&control);
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            let Some(control) = weak.upgrade() else {
+                break;
+            };
+            control.enforce_max_on();
+        });
+
+        control
+    }
+
+    /// New settings, applied straight away
+    pub fn configure(&self, settings: FlashSettings) -> anyhow::Result<()> {
+        let mut led = self.led.lock().unwrap();
+        led.flash.set_brightness(settings.duty())?;
+        led.settings = settings;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> FlashSettings {
+        self.led.lock().unwrap().settings.clone()
+    }
+
+    pub fn set(&self, on: bool) -> anyhow::Result<()> {
+        let mut led = self.led.lock().unwrap();
+        led.flash.set(on)?;
+        if !on {
+            led.on_since = None;
+        } else if led.on_since.is_none() {
+            led.on_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.led.lock().unwrap().flash.is_on()
+    }
+
+    /// Runs `capture` with the LED on, and switches it back off after unless it was
+    /// already on
+    pub fn lit<T>(&self, capture: impl FnOnce() -> T) -> anyhow::Result<T> {
+        let was_on = self.is_on();
+        if !was_on {
+            self.set(true)?;
+        }
+        let result = capture();
+        if !was_on {
+            self.set(false)?;
+        }
+        Ok(result)
+    }
+
+    /// What `GET /flash` answers: settings, whether it's on and for how much longer
+    pub fn status_json(&self) -> String {
+        let led = self.led.lock().unwrap();
+        let off_in = led.on_since.map(|since| {
+            Duration::from_secs(led.settings.max_on_secs as u64).saturating_sub(since.elapsed())
+        });
+        serde_json::json!({
+            "on": led.flash.is_on(),
+            "duty": led.settings.duty(),
+            "off_in_ms": off_in.map(|off_in| off_in.as_millis() as u64),
+            "settings": led.settings,
+        })
+        .to_string()
+    }
+
+    fn enforce_max_on(&self) {
+        let mut led = self.led.lock().unwrap();
+        let max_on = Duration::from_secs(led.settings.max_on_secs as u64);
+        if !led.on_since.is_some_and(|since| since.elapsed() >= max_on) {
+            return;
+        }
+        log::info!("Flash was on for {:?}, turning it off", max_on);
+        match led.flash.set(false) {
+            Ok(()) => led.on_since = None,
+            Err(e) => log::warn!("Couldn't turn the flash off: {}", e),
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use esp::{FlashLed, GpioFlash};
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+    use esp_idf_svc::hal::ledc::config::TimerConfig;
+    use esp_idf_svc::hal::ledc::{LedcDriver, LedcTimerDriver, Resolution, CHANNEL1, TIMER1};
+    use esp_idf_svc::hal::units::FromValueType;
+
+    use super::Flash;
+
+    /// High enough that the sensor's rolling shutter doesn't turn the PWM into bands
+    const PWM_FREQUENCY_KHZ: u32 = 20;
+
+    /// The LED on a plain GPIO, fully on or off
+    pub struct GpioFlash {
+        pin: PinDriver<'static, AnyOutputPin, Output>,
+        on: bool,
+    }
+
+    impl GpioFlash {
+        /// Takes over `gpio`. Starts off.
+        pub fn new(gpio: i32) -> anyhow::Result<Self> {
+            // SAFETY: the board profile reserves this pin for the flash, nothing else
+            // drives it
+            let pin = unsafe { AnyOutputPin::new(gpio) };
+            let mut pin = PinDriver::output(pin)?;
+            pin.set_low()?;
+            Ok(Self { pin, on: false })
+        }
+    }
+
+    impl Flash for GpioFlash {
+        fn set(&mut self, on: bool) -> anyhow::Result<()> {
+            if on {
+                self.pin.set_high()?;
+            } else {
+                self.pin.set_low()?;
+            }
+            self.on = on;
+            Ok(())
+        }
+
+        fn is_on(&self) -> bool {
+            self.on
+        }
+    }
+
+    /// The LED dimmed with LEDC PWM. Channel 1 and timer 1, the camera's XCLK has channel 0
+    /// and timer 0.
+    pub struct FlashLed {
+        driver: LedcDriver<'static>,
+        /// Used while it's on
+        duty: u32,
+        on: bool,
+    }
+
+    impl FlashLed {
+        /// Takes over `gpio`. Starts off, at half brightness.
+        pub fn new(channel: CHANNEL1, timer: TIMER1, gpio: i32) -> anyhow::Result<Self> {
+            let config = TimerConfig::new()
+                .frequency(PWM_FREQUENCY_KHZ.kHz().into())
+                .resolution(Resolution::Bits10);
+            let timer = LedcTimerDriver::new(timer, &config)?;
+            // SAFETY: the board profile reserves this pin for the flash, nothing else
+            // drives it
+            let pin = unsafe { AnyOutputPin::new(gpio) };
+            let mut driver = LedcDriver::new(channel, timer, pin)?;
+            driver.set_duty(0)?;
+            let duty = driver.get_max_duty() / 2;
+            Ok(Self {
+                driver,
+                duty,
+                on: false,
+            })
+        }
+    }
+
+    impl Flash for FlashLed {
+        fn set(&mut self, on: bool) -> anyhow::Result<()> {
+            self.driver.set_duty(if on { self.duty } else { 0 })?;
+            self.on = on;
+            Ok(())
+        }
+
+        fn is_on(&self) -> bool {
+            self.on
+        }
+
+        fn set_brightness(&mut self, percent: u8) -> anyhow::Result<()> {
+            self.duty = self.driver.get_max_duty() * percent.min(100) as u32 / 100;
+            if self.on {
+                self.driver.set_duty(self.duty)?;
+            }
+            Ok(())
+        }
+    }
+}
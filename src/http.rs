@@ -0,0 +1,1049 @@
+//! The HTTP API, independent of which server is carrying it.
+//!
+//! Routes (same shape as the esp32-camera CameraWebServer example):
+//! - `/` and `/stream`: MJPEG stream (`multipart/x-mixed-replace`)
+//! - `/capture`: a single JPEG
+//! - `/status`: JSON with uptime, wall-clock time, counters, the certificate fingerprint,
+//!   the battery, trigger inputs and every camera control
+//! - `/metrics`: the same counters for Prometheus
+//! - `/control?var=<name>&val=<int>`: change a camera control
+//! - `/timelapse[?action=start|stop]`: time-lapse status as JSON, or start/stop it
+//! - `/motion[?action=enable|disable]`: motion detection status as JSON, or turn it on/off
+//! - `/masks`: privacy and motion masks as JSON, `POST` the same JSON to change them
+//! - `/masks/edit`: a page for drawing the masks over a snapshot
+//! - `/flash[?action=on|off|toggle]`: flash LED state and settings as JSON, or switch it;
+//!   `POST` the settings to change them
+//! - `/ws`: the stream over a WebSocket, with metadata and commands (see `ws`)
+//! - `/auth`: users and tokens as JSON (never their hashes), `POST` changes to them
+//! - `/tls`: HTTPS settings and certificate fingerprints, `POST` a certificate or changes
+//! - `/ota`: firmware update progress, `POST` an image to install it (see `ota`)
+//! - `/ota/pull`: `POST {"url":"..."}` to have the camera download and install an image
+//! - `/settings`: every setting but `auth` as JSON, `POST` the same JSON to change them
+//!
+//! `App::route` handles every `GET` but the streams and `App::post` every `POST`, so a server
+//! adapter only needs to special-case `/stream`, `/ws` and the upload to `/ota` (which is
+//! streamed, being far bigger than `MAX_BODY`, through `App::upload_ota`). Before any of them it asks
+//! `App::authorize`, which lets everything through until auth is turned on (see `auth`).
+//! `esp::register` hooks these into `EspHttpServer`; the host crate has its own adapter.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth::{self, Authenticator, Scope};
+use crate::battery::{Battery, Reading, StreamLimits};
+use crate::camera::{Camera, Control, FrameBuffer, FrameFormat};
+use crate::clock;
+use crate::flash::{Flash, FlashControl};
+use crate::mask::{PrivacyMask, Shape};
+use crate::motion::Motion;
+use crate::ota::{self, Ota};
+use crate::overlay::Overlay;
+use crate::settings::{FlashSettings, MaskSettings, Settings, SettingsStore};
+use crate::status_led::{Status, StatusLed};
+use crate::system;
+use crate::timelapse::Timelapse;
+use crate::tls::{self, CertStore};
+use crate::trigger::Triggers;
+use crate::webhook::{Webhook, WebhookConfig};
+use crate::ws::WsStream;
+
+#[cfg(target_os = "espidf")]
+pub mod esp;
+
+pub const STREAM_BOUNDARY: &str = "123456789000000000000987654321";
+
+/// Small delay between stream frames to let the CPU breathe.
+/// Remove this for maximum FPS, but keep it if the board gets too hot.
+const STREAM_FRAME_DELAY: Duration = Duration::from_millis(20);
+
+/// Frames thrown away after changing resolution or quality, the driver has this many
+/// already queued with the old settings
+const SETTLE_FRAMES: usize = 2;
+
+/// Frames thrown away after lighting the flash for `/capture`, for auto exposure to adjust
+const FLASH_SETTLE_FRAMES: usize = 4;
+
+/// Re-encode quality for frames that only get privacy masks, no overlay
+const PRIVACY_JPEG_QUALITY: u8 = 85;
+
+/// Time for the `/settings` response to get out before restarting into the new settings
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Everything `App::route` answers, for servers that register paths one by one
+pub const ROUTES: [&str; 13] = [
+    "/capture",
+    "/status",
+    "/metrics",
+    "/control",
+    "/timelapse",
+    "/motion",
+    "/masks",
+    "/masks/edit",
+    "/flash",
+    "/auth",
+    "/tls",
+    "/ota",
+    "/settings",
+];
+
+/// Everything `App::post` answers
+pub const POST_ROUTES: [&str; 6] = [
+    "/masks",
+    "/flash",
+    "/auth",
+    "/tls",
+    "/ota/pull",
+    "/settings",
+];
+
+/// Biggest `POST` body servers should accept
+pub const MAX_BODY: usize = 64 * 1024;
+
+const MASK_EDITOR: &str = include_str!("http/mask_editor.html");
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    /// Anything besides Content-Type
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain", body.into())
+    }
+
+    pub fn json(body: String) -> Self {
+        Self::new(200, "application/json", body)
+    }
+}
+
+/// Shared state behind every handler
+pub struct App {
+    camera: Mutex<Box<dyn Camera>>,
+    overlay: Mutex<Option<Overlay>>,
+    timelapse: Mutex<Option<Arc<Timelapse>>>,
+    motion: Mutex<Option<Arc<Motion>>>,
+    flash: Mutex<Option<Arc<FlashControl>>>,
+    websocket: Mutex<Option<Arc<WsStream>>>,
+    ota: Mutex<Option<Arc<Ota>>>,
+    status_led: Mutex<Option<StatusLed>>,
+    battery: Mutex<Option<Arc<Battery>>>,
+    triggers: Mutex<Option<Arc<Triggers>>>,
+    webhook: Mutex<Option<Arc<Webhook>>>,
+    stream_limits: Mutex<StreamLimits>,
+    privacy: Mutex<PrivacyMask>,
+    settings: Mutex<Settings>,
+    store: Mutex<Option<Box<dyn SettingsStore>>>,
+    restart: Arc<Mutex<Box<dyn FnMut() + Send>>>,
+    /// Fingerprint of the certificate being served, if it's HTTPS
+    serving: Mutex<Option<String>>,
+    certs: Mutex<Option<Box<dyn CertStore>>>,
+    authenticator: Authenticator,
+    started: Instant,
+    frames: AtomicU64,
+    clients: AtomicU32,
+}
+
+impl App {
+    pub fn new(camera: impl Camera + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            camera: Mutex::new(Box::new(camera)),
+            overlay: Mutex::new(None),
+            timelapse: Mutex::new(None),
+            motion: Mutex::new(None),
+            flash: Mutex::new(None),
+            websocket: Mutex::new(None),
+            ota: Mutex::new(None),
+            status_led: Mutex::new(None),
+            battery: Mutex::new(None),
+            triggers: Mutex::new(None),
+            webhook: Mutex::new(None),
+            stream_limits: Mutex::new(StreamLimits::default()),
+            privacy: Mutex::new(PrivacyMask::default()),
+            settings: Mutex::new(Settings::default()),
+            store: Mutex::new(None),
+            restart: Arc::new(Mutex::new(Box::new(system::restart))),
+            serving: Mutex::new(None),
+            certs: Mutex::new(None),
+            authenticator: Authenticator::default(),
+            started: Instant::now(),
+            frames: AtomicU64::new(0),
+            clients: AtomicU32::new(0),
+        })
+    }
+
+    pub fn capture_frame(&self) -> anyhow::Result<FrameBuffer> {
+        let frame = self.camera.lock().unwrap().capture()?;
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        Ok(frame)
+    }
+
+    /// Captures one frame with `controls` applied, then puts the old values back. Holds
+    /// the camera the whole time so streams never see the temporary settings. The frame is
+    /// stamped like a `/capture` snapshot.
+    pub fn capture_with(&self, controls: &[(Control, i32)]) -> anyhow::Result<FrameBuffer> {
+        if controls.is_empty() {
+            let mut frame = self.capture_frame()?;
+            self.stamp(&mut frame, true)?;
+            return Ok(frame);
+        }
+
+        let mut camera = self.camera.lock().unwrap();
+        let previous: Vec<_> = controls
+            .iter()
+            .map(|&(control, _)| (control, camera.control(control)))
+            .collect();
+
+        let result = (|| {
+            for &(control, value) in controls {
+                camera.set_control(control, value)?;
+            }
+            for _ in 0..SETTLE_FRAMES {
+                camera.capture()?;
+            }
+            camera.capture()
+        })();
+
+        for (control, value) in previous {
+            if let Err(e) = camera.set_control(control, value) {
+                log::warn!("Couldn't restore {}: {}", control.name(), e);
+            }
+        }
+
+        drop(camera);
+
+        let mut frame = result?;
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.stamp(&mut frame, true)?;
+        Ok(frame)
+    }
+
+    /// A frame as `/stream` sends it: privacy masked, and stamped unless the overlay is
+    /// snapshots-only
+    pub fn stream_frame(&self) -> anyhow::Result<FrameBuffer> {
+        let mut frame = self.capture_frame()?;
+        self.stamp(&mut frame, false)?;
+        Ok(frame)
+    }
+
+    /// Changes a camera control, as `/control` does
+    pub fn set_control(&self, control: Control, value: i32) -> anyhow::Result<()> {
+        self.camera.lock().unwrap().set_control(control, value)
+    }
+
+    pub fn control_value(&self, control: Control) -> i32 {
+        self.camera.lock().unwrap().control(control)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Frames captured since boot
+    pub fn frame_count(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Clients watching `/stream` right now
+    pub fn client_count(&self) -> u32 {
+        self.clients.load(Ordering::Relaxed)
+    }
+
+    /// Text stamped onto frames, `None` to turn it off
+    pub fn set_overlay(&self, overlay: Option<Overlay>) {
+        *self.overlay.lock().unwrap() = overlay;
+    }
+
+    /// Blacks out privacy areas, and applies the overlay if it wants this kind of frame.
+    /// A failed overlay is logged and the frame goes out without it, but a frame that
+    /// needed masking never goes out unmasked.
+    pub(crate) fn stamp(&self, frame: &mut FrameBuffer, snapshot: bool) -> anyhow::Result<()> {
+        let overlay = self.overlay.lock().unwrap();
+        let overlay = overlay
+            .as_ref()
+            .filter(|overlay| snapshot || !overlay.config().snapshots_only);
+        let mut privacy = self.privacy.lock().unwrap();
+        if overlay.is_none() && privacy.is_empty() {
+            return Ok(());
+        }
+
+        let quality = overlay.map_or(PRIVACY_JPEG_QUALITY, |o| o.config().jpeg_quality);
+        let label = overlay.map(|o| (o, o.label(frame.wall_time, frame.timestamp)));
+        let result = frame.edit(quality, |image| {
+            privacy.apply(image);
+            if let Some((overlay, label)) = &label {
+                overlay.draw(image, label);
+            }
+        });
+
+        match result {
+            Err(e) if !privacy.is_empty() => Err(e.context("Privacy mask failed")),
+            Err(e) => {
+                log::warn!("Overlay failed: {}", e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// The scheduler behind `/timelapse`
+    pub fn set_timelapse(&self, timelapse: Option<Arc<Timelapse>>) {
+        *self.timelapse.lock().unwrap() = timelapse;
+    }
+
+    /// The detector behind `/motion`. It gets the current masks.
+    pub fn set_motion(&self, motion: Option<Arc<Motion>>) {
+        if let Some(motion) = &motion {
+            motion.set_exclusions(exclusions(&self.settings.lock().unwrap().masks));
+        }
+        *self.motion.lock().unwrap() = motion;
+    }
+
+    /// The flash LED, `None` on boards without one. It gets the current flash settings.
+    pub fn set_flash(&self, flash: Option<Box<dyn Flash>>) {
+        let settings = self.settings.lock().unwrap().flash.clone();
+        *self.flash.lock().unwrap() = flash.map(|flash| FlashControl::spawn(flash, settings));
+    }
+
+    /// What `/ws` serves, `None` to leave it off
+    pub fn set_websocket(&self, websocket: Option<Arc<WsStream>>) {
+        *self.websocket.lock().unwrap() = websocket;
+    }
+
+    pub fn websocket(&self) -> Option<Arc<WsStream>> {
+        self.websocket.lock().unwrap().clone()
+    }
+
+    /// What `/ota` updates, `None` where there's nothing to update
+    pub fn set_ota(&self, ota: Option<Arc<Ota>>) {
+        *self.ota.lock().unwrap() = ota;
+    }
+
+    /// Where `Streaming` is raised while anyone watches `/stream`
+    pub fn set_status_led(&self, status_led: Option<StatusLed>) {
+        *self.status_led.lock().unwrap() = status_led;
+    }
+
+    /// The monitor behind the battery in `/status`, `None` without one
+    pub fn set_battery(&self, battery: Option<Arc<Battery>>) {
+        *self.battery.lock().unwrap() = battery;
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery.lock().unwrap().is_some()
+    }
+
+    /// Trigger inputs, for `/status`
+    pub fn set_triggers(&self, triggers: Option<Arc<Triggers>>) {
+        *self.triggers.lock().unwrap() = triggers;
+    }
+
+    /// The webhook `/settings` reconfigures, `None` if it's off
+    pub fn set_webhook(&self, webhook: Option<Arc<Webhook>>) {
+        *self.webhook.lock().unwrap() = webhook;
+    }
+
+    /// The latest battery reading, `None` without a monitor or before its first reading
+    pub fn battery_reading(&self) -> Option<Reading> {
+        self.battery.lock().unwrap().as_ref()?.reading()
+    }
+
+    /// Slows or pauses the live streams, see `battery`
+    pub fn set_stream_limits(&self, limits: StreamLimits) {
+        *self.stream_limits.lock().unwrap() = limits;
+    }
+
+    pub fn stream_limits(&self) -> StreamLimits {
+        *self.stream_limits.lock().unwrap()
+    }
+
+    /// Whether the flash is on, `None` if there isn't one
+    pub fn flash_state(&self) -> Option<bool> {
+        self.flash
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|flash| flash.is_on())
+    }
+
+    /// The flash's settings, `None` if there isn't one
+    pub fn flash_settings(&self) -> Option<FlashSettings> {
+        self.flash
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|flash| flash.settings())
+    }
+
+    pub fn switch_flash(&self, on: bool) -> anyhow::Result<()> {
+        self.flash_control()?.set(on)
+    }
+
+    /// Changes the flash settings, applies them and saves them
+    pub fn configure_flash(
+        &self,
+        change: impl FnOnce(&mut FlashSettings),
+    ) -> anyhow::Result<FlashSettings> {
+        let flash = self.flash_control()?;
+        let mut settings = flash.settings();
+        change(&mut settings);
+        settings.validate()?;
+        flash.configure(settings.clone())?;
+        self.update_settings(|all| all.flash = settings.clone())?;
+        Ok(settings)
+    }
+
+    fn flash_control(&self) -> anyhow::Result<Arc<FlashControl>> {
+        match self.flash.lock().unwrap().clone() {
+            Some(flash) => Ok(flash),
+            None => anyhow::bail!("This board has no flash LED"),
+        }
+    }
+
+    /// Settings as loaded at boot, and where to save changes made over HTTP (`None` to
+    /// keep them until reboot). Applies the masks and the flash settings.
+    pub fn set_settings(&self, settings: Settings, store: Option<Box<dyn SettingsStore>>) {
+        self.apply_masks(&settings.masks);
+        if let Some(flash) = self.flash.lock().unwrap().as_ref() {
+            if let Err(e) = flash.configure(settings.flash.clone()) {
+                log::warn!("Couldn't apply the flash settings: {}", e);
+            }
+        }
+        *self.settings.lock().unwrap() = settings;
+        *self.store.lock().unwrap() = store;
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Changes the settings and saves them
+    pub fn update_settings(&self, change: impl FnOnce(&mut Settings)) -> anyhow::Result<()> {
+        let mut settings = self.settings.lock().unwrap();
+        change(&mut settings);
+        match self.store.lock().unwrap().as_mut() {
+            Some(store) => store.save(&settings),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces what `POST /settings` does to apply sections that only take effect at boot
+    /// (restart the board)
+    pub fn on_restart(&self, restart: impl FnMut() + Send + 'static) {
+        *self.restart.lock().unwrap() = Box::new(restart);
+    }
+
+    /// Fingerprint of the certificate the server started with (`None` over plain HTTP), and
+    /// where `/tls` keeps uploaded ones (`None` to refuse them)
+    pub fn set_tls(&self, serving: Option<String>, certs: Option<Box<dyn CertStore>>) {
+        *self.serving.lock().unwrap() = serving;
+        *self.certs.lock().unwrap() = certs;
+    }
+
+    fn apply_masks(&self, masks: &MaskSettings) {
+        *self.privacy.lock().unwrap() = PrivacyMask::new(masks.privacy.clone());
+        if let Some(motion) = self.motion.lock().unwrap().as_ref() {
+            motion.set_exclusions(exclusions(masks));
+        }
+    }
+
+    /// Whether a request may go ahead: `Ok` with what the caller may do, or the response to
+    /// send instead. `authorization` is the `Authorization` header.
+    pub fn authorize(
+        &self,
+        post: bool,
+        path: &str,
+        query: &str,
+        authorization: Option<&str>,
+        peer: Option<IpAddr>,
+    ) -> Result<Scope, Response> {
+        let (auth, realm) = {
+            let settings = self.settings.lock().unwrap();
+            if !settings.auth.enabled {
+                return Ok(Scope::Admin);
+            }
+            (settings.auth.clone(), settings.camera_name.clone())
+        };
+        let needed = required_scope(post, path, query);
+        self.authenticator
+            .check(&auth, authorization, peer, needed, &realm)
+    }
+
+    /// Any route but the stream. `query` is the part after `?`, possibly empty.
+    pub fn route(&self, path: &str, query: &str) -> Response {
+        match path {
+            "/capture" => self.capture(),
+            "/status" => self.status(),
+            "/metrics" => self.metrics(),
+            "/control" => self.control(query),
+            "/timelapse" => self.timelapse(query),
+            "/motion" => self.motion(query),
+            "/masks" => self.masks(),
+            "/masks/edit" => Response::new(200, "text/html", MASK_EDITOR),
+            "/flash" => self.flash(query),
+            "/auth" => self.auth(),
+            "/tls" => self.tls(),
+            "/ota" => self.ota(),
+            "/settings" => self.get_settings(),
+            _ => Response::text(404, "Not found"),
+        }
+    }
+
+    /// Any `POST` route
+    pub fn post(&self, path: &str, body: &[u8]) -> Response {
+        match path {
+            "/masks" => self.post_masks(body),
+            "/flash" => self.post_flash(body),
+            "/auth" => self.post_auth(body),
+            "/tls" => self.post_tls(body),
+            "/ota/pull" => self.post_ota_pull(body),
+            "/settings" => self.post_settings(body),
+            _ => Response::text(404, "Not found"),
+        }
+    }
+
+    /// `GET /capture`
+    pub fn capture(&self) -> Response {
+        match self.capture_lit() {
+            Ok(mut frame) if frame.format == FrameFormat::Jpeg => {
+                if let Err(e) = self.stamp(&mut frame, true) {
+                    return Response::text(500, format!("Camera Capture Failed: {}", e));
+                }
+                let filename = match frame.wall_time {
+                    Some(time) => format!("capture_{}.jpg", clock::format_compact(time)),
+                    None => "capture.jpg".to_owned(),
+                };
+                Response::new(200, "image/jpeg", std::mem::take(&mut frame.data))
+                    .with_header(
+                        "Content-Disposition",
+                        format!("inline; filename={}", filename),
+                    )
+                    .with_header("X-Timestamp", timestamp(&frame))
+            }
+            Ok(frame) => Response::text(
+                500,
+                format!("Camera is in {} mode, not JPEG", frame.format.name()),
+            ),
+            Err(e) => Response::text(500, format!("Camera Capture Failed: {}", e)),
+        }
+    }
+
+    /// A frame for `/capture`, with the flash lit for it if `on_capture` says so
+    fn capture_lit(&self) -> anyhow::Result<FrameBuffer> {
+        let flash = self.flash.lock().unwrap().clone();
+        let Some(flash) = flash.filter(|flash| flash.settings().on_capture) else {
+            return self.capture_frame();
+        };
+        flash.lit(|| {
+            for _ in 0..FLASH_SETTLE_FRAMES {
+                self.camera.lock().unwrap().capture()?;
+            }
+            self.capture_frame()
+        })?
+    }
+
+    /// `GET /status`
+    pub fn status(&self) -> Response {
+        let camera = self.camera.lock().unwrap();
+        let time = match clock::wall_clock() {
+            Some(now) => format!("\"{}\"", clock::format_rfc3339(now)),
+            None => "null".to_owned(),
+        };
+        let fingerprint = match self.serving.lock().unwrap().as_deref() {
+            Some(fingerprint) => format!("\"{}\"", fingerprint),
+            None => "null".to_owned(),
+        };
+        let mut json = format!(
+            "{{\"uptime_ms\":{},\"time\":{},\"frames\":{},\"clients\":{},\
+             \"tls_fingerprint\":{}",
+            self.started.elapsed().as_millis(),
+            time,
+            self.frames.load(Ordering::Relaxed),
+            self.clients.load(Ordering::Relaxed),
+            fingerprint,
+        );
+        let battery = match self.battery.lock().unwrap().as_ref() {
+            Some(battery) => battery.status_json().to_string(),
+            None => "null".to_owned(),
+        };
+        json += &format!(",\"battery\":{}", battery);
+        let triggers = match self.triggers.lock().unwrap().as_ref() {
+            Some(triggers) => triggers.status_json(),
+            None => "null".to_owned(),
+        };
+        json += &format!(",\"triggers\":{}", triggers);
+        for control in Control::ALL {
+            json += &format!(",\"{}\":{}", control.name(), camera.control(control));
+        }
+        json.push('}');
+
+        Response::json(json)
+    }
+
+    /// `GET /metrics`, in the Prometheus text format. Gauges without a value are left out.
+    pub fn metrics(&self) -> Response {
+        let reading = self.battery_reading();
+        let metrics = [
+            ("uptime_seconds", "gauge", Some(self.uptime().as_secs_f64())),
+            ("frames_total", "counter", Some(self.frame_count() as f64)),
+            ("stream_clients", "gauge", Some(self.client_count() as f64)),
+            ("wifi_rssi_dbm", "gauge", system::rssi().map(f64::from)),
+            (
+                "free_heap_bytes",
+                "gauge",
+                system::free_heap().map(f64::from),
+            ),
+            (
+                "battery_volts",
+                "gauge",
+                reading.map(|reading| reading.volts as f64),
+            ),
+            (
+                "battery_percent",
+                "gauge",
+                reading.map(|reading| reading.percent.round() as f64),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, value) in metrics {
+            if let Some(value) = value {
+                text += &format!("# TYPE wrover_{0} {1}\nwrover_{0} {2}\n", name, kind, value);
+            }
+        }
+        Response::new(200, "text/plain; version=0.0.4", text)
+    }
+
+    /// `GET /control?var=<name>&val=<int>`
+    pub fn control(&self, query: &str) -> Response {
+        let (Some(var), Some(val)) = (query_param(query, "var"), query_param(query, "val")) else {
+            return Response::text(400, "Expected ?var=<name>&val=<value>");
+        };
+        let Some(control) = Control::from_name(var) else {
+            return Response::text(400, format!("Unknown control: {}", var));
+        };
+        let Ok(value) = val.parse::<i32>() else {
+            return Response::text(400, format!("Not a number: {}", val));
+        };
+
+        match self.set_control(control, value) {
+            Ok(()) => Response::text(200, "OK"),
+            Err(e) => Response::text(400, e.to_string()),
+        }
+    }
+
+    /// `GET /timelapse[?action=start|stop]`
+    pub fn timelapse(&self, query: &str) -> Response {
+        let Some(timelapse) = self.timelapse.lock().unwrap().clone() else {
+            return Response::text(404, "Time-lapse is not set up");
+        };
+
+        match query_param(query, "action") {
+            None => {}
+            Some("start") => timelapse.start(),
+            Some("stop") => timelapse.stop(),
+            Some(other) => return Response::text(400, format!("Unknown action: {}", other)),
+        }
+        Response::json(timelapse.status_json())
+    }
+
+    /// `GET /motion[?action=enable|disable]`
+    pub fn motion(&self, query: &str) -> Response {
+        let Some(motion) = self.motion.lock().unwrap().clone() else {
+            return Response::text(404, "Motion detection is not set up");
+        };
+
+        match query_param(query, "action") {
+            None => {}
+            Some("enable") => motion.enable(),
+            Some("disable") => motion.disable(),
+            Some(other) => return Response::text(400, format!("Unknown action: {}", other)),
+        }
+        Response::json(motion.status_json())
+    }
+
+    /// `GET /masks`
+    pub fn masks(&self) -> Response {
+        let settings = self.settings.lock().unwrap();
+        Response::json(serde_json::to_string(&settings.masks).unwrap())
+    }
+
+    /// `POST /masks` with the JSON `GET /masks` returns. Applied straight away and saved.
+    pub fn post_masks(&self, body: &[u8]) -> Response {
+        let masks: MaskSettings = match serde_json::from_slice(body) {
+            Ok(masks) => masks,
+            Err(e) => return Response::text(400, format!("Invalid masks: {}", e)),
+        };
+        if let Err(e) = masks.validate() {
+            return Response::text(400, e.to_string());
+        }
+
+        self.apply_masks(&masks);
+        if let Err(e) = self.update_settings(|settings| settings.masks = masks) {
+            return Response::text(500, format!("Masks applied but not saved: {}", e));
+        }
+        self.masks()
+    }
+
+    /// `GET /flash[?action=on|off|toggle]`
+    pub fn flash(&self, query: &str) -> Response {
+        let Ok(flash) = self.flash_control() else {
+            return Response::text(404, "This board has no flash LED");
+        };
+
+        let on = match query_param(query, "action") {
+            None => None,
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            Some("toggle") => Some(!flash.is_on()),
+            Some(other) => return Response::text(400, format!("Unknown action: {}", other)),
+        };
+        if let Some(Err(e)) = on.map(|on| flash.set(on)) {
+            return Response::text(500, format!("Couldn't switch the flash: {}", e));
+        }
+        Response::json(flash.status_json())
+    }
+
+    /// `POST /flash` with the `settings` `GET /flash` returns. Applied straight away and
+    /// saved.
+    pub fn post_flash(&self, body: &[u8]) -> Response {
+        if self.flash_control().is_err() {
+            return Response::text(404, "This board has no flash LED");
+        }
+        let settings: FlashSettings = match serde_json::from_slice(body) {
+            Ok(settings) => settings,
+            Err(e) => return Response::text(400, format!("Invalid settings: {}", e)),
+        };
+        if let Err(e) = settings.validate() {
+            return Response::text(400, e.to_string());
+        }
+        match self.configure_flash(|current| *current = settings) {
+            Ok(_) => self.flash(""),
+            Err(e) => Response::text(500, format!("Not saved: {}", e)),
+        }
+    }
+
+    /// `GET /auth`
+    pub fn auth(&self) -> Response {
+        let settings = self.settings.lock().unwrap();
+        Response::json(auth::summary_json(&settings.auth, None))
+    }
+
+    /// `POST /auth` with one `auth::Change`. Answers like `GET /auth`, plus the token if one
+    /// was made.
+    pub fn post_auth(&self, body: &[u8]) -> Response {
+        let change: auth::Change = match serde_json::from_slice(body) {
+            Ok(change) => change,
+            Err(e) => return Response::text(400, format!("Invalid change: {}", e)),
+        };
+        let mut updated = self.settings().auth;
+        let token = match change.apply(&mut updated) {
+            Ok(token) => token,
+            Err(e) => return Response::text(400, e.to_string()),
+        };
+        let summary = auth::summary_json(&updated, token.as_deref());
+        if let Err(e) = self.update_settings(|settings| settings.auth = updated) {
+            return Response::text(500, format!("Changed but not saved: {}", e));
+        }
+        Response::json(summary)
+    }
+
+    /// `GET /settings`: everything but `auth`, whose hashes never leave the camera
+    pub fn get_settings(&self) -> Response {
+        let mut json = serde_json::to_value(self.settings()).unwrap();
+        json.as_object_mut().unwrap().remove("auth");
+        Response::json(json.to_string())
+    }
+
+    /// `POST /settings` with the JSON `GET /settings` returns, missing fields taking their
+    /// defaults and `auth` left to `POST /auth`. Checked and saved; masks, the flash and a
+    /// running webhook (all but its timer) apply straight away, and if anything else
+    /// changed the camera answers `202` and restarts into it.
+    pub fn post_settings(&self, body: &[u8]) -> Response {
+        let mut settings = match Settings::from_json(body) {
+            Ok(settings) => settings,
+            Err(e) => return Response::text(400, format!("Invalid settings: {}", e)),
+        };
+        let current = self.settings();
+        settings.auth = current.auth.clone();
+        if let Err(e) = settings.validate() {
+            return Response::text(400, e.to_string());
+        }
+
+        let webhook = self.webhook.lock().unwrap().clone().filter(|_| {
+            settings.webhook.enabled
+                && settings.webhook.interval_secs == current.webhook.interval_secs
+        });
+        let mut rest = settings.clone();
+        rest.masks = current.masks.clone();
+        rest.flash = current.flash.clone();
+        if webhook.is_some() {
+            rest.webhook = current.webhook.clone();
+        }
+        let restart = rest != current;
+
+        self.apply_masks(&settings.masks);
+        if let Some(flash) = self.flash.lock().unwrap().as_ref() {
+            if let Err(e) = flash.configure(settings.flash.clone()) {
+                log::warn!("Couldn't apply the flash settings: {}", e);
+            }
+        }
+        if let Some(webhook) = webhook {
+            let device_id = webhook.config().device_id;
+            match WebhookConfig::from_settings(&settings.webhook, &device_id) {
+                Ok(config) => webhook.configure(config),
+                Err(e) => return Response::text(400, e.to_string()),
+            }
+        }
+        if let Err(e) = self.update_settings(|all| *all = settings) {
+            return Response::text(500, format!("Not saved: {}", e));
+        }
+        let mut response = self.get_settings();
+        if restart {
+            log::info!("Settings changed, restarting");
+            let restart = self.restart.clone();
+            thread::spawn(move || {
+                thread::sleep(RESTART_DELAY);
+                (restart.lock().unwrap())();
+            });
+            response.status = 202;
+        }
+        response
+    }
+
+    /// `GET /tls`
+    pub fn tls(&self) -> Response {
+        let certs = self.certs.lock().unwrap();
+        let stored = match certs.as_ref().map(|certs| certs.load()) {
+            Some(Ok(pair)) => pair.map(|pair| pair.fingerprint()),
+            Some(Err(e)) => return Response::text(500, format!("Stored certificate: {}", e)),
+            None => None,
+        };
+        drop(certs);
+        let serving = self.serving.lock().unwrap().clone();
+        Response::json(tls::summary_json(
+            &self.settings().tls,
+            serving.as_deref(),
+            stored.as_deref(),
+        ))
+    }
+
+    /// `POST /tls` with one `tls::Change`. Takes effect at the next boot; answers like
+    /// `GET /tls`.
+    pub fn post_tls(&self, body: &[u8]) -> Response {
+        let change: tls::Change = match serde_json::from_slice(body) {
+            Ok(change) => change,
+            Err(e) => return Response::text(400, format!("Invalid change: {}", e)),
+        };
+        let result = match change {
+            tls::Change::Enable => self.update_settings(|settings| settings.tls.enabled = true),
+            tls::Change::Disable => self.update_settings(|settings| settings.tls.enabled = false),
+            tls::Change::Upload { cert, key } => {
+                let pair = match tls::CertPair::new(cert, key) {
+                    Ok(pair) => pair,
+                    Err(e) => return Response::text(400, e.to_string()),
+                };
+                match self.certs.lock().unwrap().as_mut() {
+                    Some(certs) => certs.save(&pair),
+                    None => return Response::text(404, "Certificate storage is not set up"),
+                }
+            }
+            tls::Change::Regenerate => match self.certs.lock().unwrap().as_mut() {
+                Some(certs) => certs.clear(),
+                None => return Response::text(404, "Certificate storage is not set up"),
+            },
+        };
+        if let Err(e) = result {
+            return Response::text(500, format!("Not saved: {}", e));
+        }
+        self.tls()
+    }
+
+    /// `GET /ota`
+    pub fn ota(&self) -> Response {
+        match self.ota.lock().unwrap().as_ref() {
+            Some(ota) => Response::json(ota.status_json()),
+            None => Response::text(404, "Updates are not set up"),
+        }
+    }
+
+    /// `POST /ota` with the image as the body, `len` bytes of it if the request says. Reads
+    /// it through `read` straight into flash, and restarts into it if it's good.
+    pub fn upload_ota(&self, len: Option<usize>, read: &mut ota::Body) -> Response {
+        let ota = match self.ota_for_update() {
+            Ok(ota) => ota,
+            Err(response) => return response,
+        };
+        match ota.update(len, read) {
+            Ok(_) => Response::json(ota.status_json()),
+            Err(e) => ota_error(e),
+        }
+    }
+
+    /// `POST /ota/pull` with `{"url":"..."}`. Answers straight away, `GET /ota` follows the
+    /// download.
+    pub fn post_ota_pull(&self, body: &[u8]) -> Response {
+        let ota = match self.ota_for_update() {
+            Ok(ota) => ota,
+            Err(response) => return response,
+        };
+        let pull: ota::Pull = match serde_json::from_slice(body) {
+            Ok(pull) => pull,
+            Err(e) => return Response::text(400, format!("Expected {{\"url\":...}}: {}", e)),
+        };
+        match ota.pull(&pull.url) {
+            Ok(()) => Response::new(202, "application/json", ota.status_json()),
+            Err(e) => ota_error(e),
+        }
+    }
+
+    /// The updater, if there is one and auth is on
+    fn ota_for_update(&self) -> Result<Arc<Ota>, Response> {
+        let Some(ota) = self.ota.lock().unwrap().clone() else {
+            return Err(Response::text(404, "Updates are not set up"));
+        };
+        if !self.settings.lock().unwrap().auth.enabled {
+            return Err(Response::text(
+                403,
+                "Turn on auth before updating over the air",
+            ));
+        }
+        Ok(ota)
+    }
+
+    /// `GET /stream`. Call after sending the `multipart/x-mixed-replace` headers; pushes
+    /// frames through `send` until it fails, which means the client went away.
+    pub fn stream(&self, mut send: impl FnMut(&[u8]) -> anyhow::Result<()>) {
+        let _client = ClientGuard::new(self);
+        log::info!("Client connected to stream.");
+
+        loop {
+            let Some(delay) = self.stream_limits().interval(STREAM_FRAME_DELAY) else {
+                log::info!("Streams are paused, letting the client go");
+                break;
+            };
+            match self.stream_frame() {
+                Ok(frame) => {
+                    if send(&part_header(&frame)).is_err() || send(&frame.data).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("{}", e),
+            }
+            thread::sleep(delay);
+        }
+
+        log::info!("Client disconnected.");
+    }
+}
+
+fn ota_error(e: anyhow::Error) -> Response {
+    if e.is::<ota::Busy>() {
+        Response::text(409, e.to_string())
+    } else {
+        Response::text(400, format!("Update failed: {:#}", e))
+    }
+}
+
+pub fn stream_content_type() -> String {
+    format!("multipart/x-mixed-replace; boundary={}", STREAM_BOUNDARY)
+}
+
+/// MJPEG frame header
+fn part_header(frame: &FrameBuffer) -> Vec<u8> {
+    format!(
+        "\r\n--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: {}\r\n\r\n",
+        STREAM_BOUNDARY,
+        frame.data.len(),
+        timestamp(frame)
+    )
+    .into_bytes()
+}
+
+/// `seconds.micros`: Unix time once the clock is synced, time since boot before that
+pub(crate) fn timestamp(frame: &FrameBuffer) -> String {
+    let since = match frame.wall_time {
+        Some(time) => time
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default(),
+        None => frame.timestamp,
+    };
+    format!("{}.{:06}", since.as_secs(), since.subsec_micros())
+}
+
+/// What a request needs: admin for anything that changes something, read for the rest
+pub fn required_scope(post: bool, path: &str, query: &str) -> Scope {
+    let changes = match path {
+        "/control" | "/auth" | "/settings" => true,
+        "/timelapse" | "/motion" | "/flash" => query_param(query, "action").is_some(),
+        _ => false,
+    };
+    if post || changes {
+        Scope::Admin
+    } else {
+        Scope::Read
+    }
+}
+
+/// Everything motion detection leaves out: privacy areas too, nobody can see them anyway
+fn exclusions(masks: &MaskSettings) -> Vec<Shape> {
+    masks.privacy.iter().chain(&masks.motion).cloned().collect()
+}
+
+/// Finds `key` in a `a=1&b=2` query string. Values are used as-is, no %-decoding.
+pub fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Counts a streaming client for as long as it is alive
+struct ClientGuard<'a>(&'a App);
+
+impl<'a> ClientGuard<'a> {
+    fn new(app: &'a App) -> Self {
+        if app.clients.fetch_add(1, Ordering::Relaxed) == 0 {
+            if let Some(led) = app.status_led.lock().unwrap().as_ref() {
+                led.raise(Status::Streaming);
+            }
+        }
+        Self(app)
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.clients.fetch_sub(1, Ordering::Relaxed) == 1 {
+            if let Some(led) = self.0.status_led.lock().unwrap().as_ref() {
+                led.clear(Status::Streaming);
+            }
+        }
+    }
+}
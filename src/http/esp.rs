@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, TcpStream};
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::ws::EspHttpWsConnection;
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::sys;
+use esp_idf_svc::ws::FrameType;
+
+use super::{stream_content_type, App, Response, MAX_BODY, POST_ROUTES, ROUTES};
+use crate::auth::Scope;
+use crate::tls;
+use crate::ws::{Message, WsClient, WsStream};
+
+/// Registers every API route on `server`
+pub fn register(server: &mut EspHttpServer, app: Arc<App>) -> anyhow::Result<()> {
+    for uri in ["/", "/stream"] {
+        let app = app.clone();
+        server.fn_handler(uri, Method::Get, move |mut request| {
+            if let Err(response) = authorize(&app, &mut request, false, uri) {
+                return send(request, response);
+            }
+            let content_type = stream_content_type();
+            let mut response = request.into_response(
+                200,
+                Some("OK"),
+                &[("Content-Type", content_type.as_str())],
+            )?;
+
+            app.stream(|chunk| response.write_all(chunk).map_err(Into::into));
+            Ok::<(), anyhow::Error>(())
+        })?;
+    }
+
+    for uri in ROUTES {
+        let app = app.clone();
+        server.fn_handler(uri, Method::Get, move |mut request| {
+            if let Err(response) = authorize(&app, &mut request, false, uri) {
+                return send(request, response);
+            }
+            let query = query(&request);
+            send(request, app.route(uri, &query))
+        })?;
+    }
+
+    for uri in POST_ROUTES {
+        let app = app.clone();
+        server.fn_handler(uri, Method::Post, move |mut request| {
+            if let Err(response) = authorize(&app, &mut request, true, uri) {
+                return send(request, response);
+            }
+            let len = request.content_len().unwrap_or(0) as usize;
+            if len > MAX_BODY {
+                return send(request, Response::text(413, "Body too large"));
+            }
+            let mut body = vec![0; len];
+            request
+                .read_exact(&mut body)
+                .map_err(|e| anyhow::anyhow!("Reading request body failed: {:?}", e))?;
+            send(request, app.post(uri, &body))
+        })?;
+    }
+
+    // Firmware is far bigger than `MAX_BODY`, so it goes to flash as it arrives
+    let ota_app = app.clone();
+    server.fn_handler("/ota", Method::Post, move |mut request| {
+        if let Err(response) = authorize(&ota_app, &mut request, true, "/ota") {
+            return send(request, response);
+        }
+        let len = request.content_len().map(|len| len as usize);
+        let response = ota_app.upload_ota(len, &mut |buf| {
+            request
+                .read(buf)
+                .map_err(|e| anyhow::anyhow!("Reading the image failed: {:?}", e))
+        });
+        send(request, response)
+    })?;
+
+    if let Some(websocket) = app.websocket() {
+        register_websocket(server, app.clone(), websocket)?;
+    }
+
+    Ok(())
+}
+
+/// The whole plain HTTP server when it only sends clients on to HTTPS on `https_port`. It
+/// needs `uri_match_wildcard` for the one handler to catch every path.
+pub fn register_redirect(server: &mut EspHttpServer, https_port: u16) -> anyhow::Result<()> {
+    server.fn_handler("/*", Method::Get, move |request| {
+        let response = tls::redirect(request.header("Host"), request.uri(), https_port);
+        send(request, response)
+    })?;
+    Ok(())
+}
+
+/// `/ws`. The server calls the handler once per incoming frame rather than once per
+/// connection, so clients are kept by session and sent to from their own threads through
+/// detached senders.
+fn register_websocket(
+    server: &mut EspHttpServer,
+    app: Arc<App>,
+    websocket: Arc<WsStream>,
+) -> anyhow::Result<()> {
+    let clients = Mutex::new(HashMap::<i32, WsClient>::new());
+    server.ws_handler("/ws", move |connection: &mut EspHttpWsConnection| {
+        let session = connection.session();
+        if let EspHttpWsConnection::New(_, raw) = connection {
+            // Already upgraded, so all that's left for the unauthorized is hanging up
+            let raw = *raw;
+            let header = raw_header(raw, "Authorization");
+            let scope = app
+                .authorize(false, "/ws", "", header.as_deref(), raw_peer(raw))
+                .map_err(|response| anyhow::anyhow!("/ws refused with {}", response.status))?;
+
+            let mut sender = connection.create_detached_sender()?;
+            let client = websocket.connect(move |message: Message<'_>| {
+                let result = match message {
+                    Message::Text(text) => sender.send(FrameType::Text(false), text.as_bytes()),
+                    Message::Binary(data) => sender.send(FrameType::Binary(false), data),
+                };
+                result.map_err(|e| anyhow::anyhow!("WebSocket send failed: {}", e))
+            });
+            match client {
+                Ok(client) => {
+                    clients
+                        .lock()
+                        .unwrap()
+                        .insert(session, client.with_scope(scope));
+                }
+                Err(e) => {
+                    log::warn!("Turned a WebSocket client away: {}", e);
+                    connection.send(FrameType::Close, &[])?;
+                }
+            }
+            return Ok::<(), anyhow::Error>(());
+        }
+        if connection.is_closed() {
+            clients.lock().unwrap().remove(&session);
+            return Ok(());
+        }
+
+        // Ask for the length first, then read the frame
+        let (frame_type, len) = connection.recv(&mut [])?;
+        if len > MAX_BODY {
+            anyhow::bail!("WebSocket frame of {} bytes", len);
+        }
+        let mut data = vec![0; len];
+        connection.recv(&mut data)?;
+        if let FrameType::Text(_) = frame_type {
+            // The server NUL-terminates text frames
+            let text = String::from_utf8_lossy(&data);
+            let text = text.trim_end_matches('\0');
+            if let Some(client) = clients.lock().unwrap().get(&session) {
+                client.receive(text);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// `App::authorize` for a request, with its `Authorization` header and peer
+fn authorize(
+    app: &App,
+    request: &mut Request<&mut EspHttpConnection>,
+    post: bool,
+    path: &str,
+) -> Result<Scope, Response> {
+    let query = query(request);
+    let header = request.header("Authorization").map(str::to_owned);
+    let peer = request
+        .connection()
+        .raw_connection()
+        .ok()
+        .and_then(|raw| raw_peer(raw));
+    app.authorize(post, path, &query, header.as_deref(), peer)
+}
+
+/// The address at the other end of a request's socket
+fn raw_peer(raw: *mut sys::httpd_req_t) -> Option<IpAddr> {
+    let fd = unsafe { sys::httpd_req_to_sockfd(raw) };
+    if fd < 0 {
+        return None;
+    }
+    // Only borrowed, the server closes it
+    let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    socket.peer_addr().ok().map(|addr| addr.ip().to_canonical())
+}
+
+/// A request header, for when there's only the raw request
+fn raw_header(raw: *mut sys::httpd_req_t, name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    let len = unsafe { sys::httpd_req_get_hdr_value_len(raw, name.as_ptr()) };
+    if len == 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; len + 1];
+    let err = unsafe {
+        sys::httpd_req_get_hdr_value_str(raw, name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    if err != sys::ESP_OK {
+        return None;
+    }
+    buf.truncate(len);
+    String::from_utf8(buf).ok()
+}
+
+pub(crate) fn query(request: &Request<&mut EspHttpConnection>) -> String {
+    request
+        .uri()
+        .split_once('?')
+        .map_or("", |(_, query)| query)
+        .to_owned()
+}
+
+pub(crate) fn send(
+    request: Request<&mut EspHttpConnection>,
+    response: Response,
+) -> anyhow::Result<()> {
+    let mut headers = vec![("Content-Type", response.content_type)];
+    headers.extend(response.headers.iter().map(|(k, v)| (*k, v.as_str())));
+
+    let mut out = request.into_response(response.status, None, &headers)?;
+    out.write_all(&response.body)?;
+    Ok(())
+}
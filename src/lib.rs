@@ -0,0 +1,40 @@
+//! Shared building blocks for the wrover firmware.
+//!
+//! ESP-IDF specific pieces are behind `cfg(target_os = "espidf")`, everything else also
+//! builds on a normal PC (see `host/`).
+
+pub mod auth;
+pub mod avi;
+pub mod battery;
+pub mod board;
+pub mod camera;
+pub mod client;
+pub mod clock;
+pub mod codec;
+pub mod flash;
+pub mod http;
+pub mod mask;
+pub mod motion;
+pub mod mqtt;
+pub mod ota;
+pub mod ov3660;
+pub mod overlay;
+pub mod prebuffer;
+pub mod random;
+pub mod recorder;
+pub mod rtsp;
+#[cfg(target_os = "espidf")]
+pub mod sdcard;
+pub mod settings;
+pub mod sink;
+pub mod sleep;
+pub mod sntp;
+pub mod status_led;
+pub mod system;
+pub mod timelapse;
+pub mod tls;
+pub mod trigger;
+pub mod udp;
+pub mod upload;
+pub mod webhook;
+pub mod ws;
@@ -1,28 +1,25 @@
-use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::hal::gpio::*;
 use std::thread;
 use std::time::Duration;
 
+use wrover::board;
+use wrover::status_led::{GpioStatusPin, Status, StatusLed};
+
 fn main() -> anyhow::Result<()> {
     // Basic ESP-IDF setup
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    // 1. Take peripherals
-    let peripherals = Peripherals::take().unwrap();
-
-    // 2. Configure LED Pin
-    // ESP32-C3 pins - adjust based on your board's LED connections
-    let mut led = PinDriver::output(peripherals.pins.gpio26)?;
+    // 1. The board's status LED, blinking away on a thread of its own
+    let Some(pin) = board::current().status_led else {
+        anyhow::bail!("{} has no status LED", board::current().name);
+    };
+    let status_led = StatusLed::spawn(GpioStatusPin::new(pin)?)?;
 
+    // 2. A blip every few seconds to show it's alive
+    status_led.raise(Status::Ready);
 
-    // 3. Blink Loop
+    // 3. Keep the handle, the LED goes off once it's dropped
     loop {
-        // Turn LED On
-        led.set_high()?; 
-        std::thread::sleep(Duration::from_millis(1000));
-        led.set_low()?;
-        std::thread::sleep(Duration::from_millis(1000));
-
-    };
-}
\ No newline at end of file
+        thread::sleep(Duration::from_secs(1));
+    }
+}
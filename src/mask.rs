@@ -0,0 +1,212 @@
+//! Region masks: areas left out of motion detection, and privacy areas blacked out of
+//! everything the camera sends or stores.
+//!
+//! Shapes are in fractions of the frame (0-1 from the top left), so they stay put when the
+//! resolution changes. A pixel belongs to a shape if its centre does. Blacking out a JPEG
+//! stream costs a decode and re-encode per frame, so keep masked streams small.
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Image;
+use crate::motion::BlockMask;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// Corners in order, closed automatically. Self-intersecting outlines use the even-odd
+    /// rule.
+    Polygon(Vec<[f32; 2]>),
+}
+
+impl Shape {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                if ![x, y, width, height].iter().all(|v| v.is_finite()) {
+                    anyhow::bail!("Rectangle has a non-numeric coordinate");
+                }
+                if *width <= 0.0 || *height <= 0.0 {
+                    anyhow::bail!("Rectangle must have a positive size");
+                }
+            }
+            Shape::Polygon(points) => {
+                if points.len() < 3 {
+                    anyhow::bail!("Polygon needs at least 3 points, got {}", points.len());
+                }
+                if !points.iter().flatten().all(|v| v.is_finite()) {
+                    anyhow::bail!("Polygon has a non-numeric coordinate");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the horizontal line at `y` is inside the shape, as `[start, end)` pairs
+    fn spans(&self, y: f32) -> Vec<(f32, f32)> {
+        match *self {
+            Shape::Rect {
+                x,
+                y: top,
+                width,
+                height,
+            } => {
+                if (top..top + height).contains(&y) {
+                    vec![(x, x + width)]
+                } else {
+                    Vec::new()
+                }
+            }
+            Shape::Polygon(ref points) => {
+                let mut crossings = Vec::new();
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    // Half-open, so a vertex exactly on the line is only counted once
+                    if (a[1] <= y) != (b[1] <= y) {
+                        crossings.push(a[0] + (y - a[1]) * (b[0] - a[0]) / (b[1] - a[1]));
+                    }
+                }
+                crossings.sort_by(f32::total_cmp);
+                crossings
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0], pair[1]))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// One bit per pixel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    bits: Vec<bool>,
+}
+
+impl Bitmap {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            bits: vec![false; (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        self.bits[(y * self.width + x) as usize]
+    }
+
+    pub fn row(&self, y: u32) -> &[bool] {
+        let start = (y * self.width) as usize;
+        &self.bits[start..start + self.width as usize]
+    }
+
+    /// Number of set pixels
+    pub fn count(&self) -> usize {
+        self.bits.iter().filter(|&&bit| bit).count()
+    }
+}
+
+/// Pixels of a `width` x `height` frame covered by any of `shapes`
+pub fn rasterize(shapes: &[Shape], width: u32, height: u32) -> Bitmap {
+    let mut bitmap = Bitmap::new(width, height);
+    for y in 0..height {
+        let centre = (y as f32 + 0.5) / height as f32;
+        let row = (y * width) as usize;
+        for shape in shapes {
+            for (start, end) in shape.spans(centre) {
+                // Pixel x is in if its centre (x + 0.5) / width is in [start, end)
+                let first = column(start, width);
+                let last = column(end, width);
+                if first < last {
+                    bitmap.bits[row + first as usize..row + last as usize].fill(true);
+                }
+            }
+        }
+    }
+    bitmap
+}
+
+/// First column whose centre is at or right of `x`
+fn column(x: f32, width: u32) -> u32 {
+    (x * width as f32 - 0.5).ceil().clamp(0.0, width as f32) as u32
+}
+
+/// Motion detector blocks that are mostly covered by `shapes`
+pub fn block_mask(shapes: &[Shape], cols: u32, rows: u32, block: u32) -> BlockMask {
+    let bitmap = rasterize(shapes, cols * block, rows * block);
+    let mut mask = BlockMask::new(cols, rows);
+    for row in 0..rows {
+        for col in 0..cols {
+            let covered: u32 = (row * block..(row + 1) * block)
+                .map(|y| {
+                    let start = (col * block) as usize;
+                    let pixels = &bitmap.row(y)[start..start + block as usize];
+                    pixels.iter().filter(|&&bit| bit).count() as u32
+                })
+                .sum();
+            mask.set_ignored(col, row, covered * 2 > block * block);
+        }
+    }
+    mask
+}
+
+/// Privacy shapes, with the rasterised bitmap kept for as long as the frame size holds
+#[derive(Debug, Default)]
+pub struct PrivacyMask {
+    shapes: Vec<Shape>,
+    bitmap: Option<Bitmap>,
+}
+
+impl PrivacyMask {
+    pub fn new(shapes: Vec<Shape>) -> Self {
+        Self {
+            shapes,
+            bitmap: None,
+        }
+    }
+
+    pub fn shapes(&self) -> &[Shape] {
+        &self.shapes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    /// Blacks out every covered pixel
+    pub fn apply(&mut self, image: &mut Image) {
+        if self.shapes.is_empty() {
+            return;
+        }
+        let stale = self
+            .bitmap
+            .as_ref()
+            .map_or(true, |b| (b.width, b.height) != (image.width, image.height));
+        if stale {
+            self.bitmap = Some(rasterize(&self.shapes, image.width, image.height));
+        }
+
+        let bitmap = self.bitmap.as_ref().unwrap();
+        let bpp = image.format.bytes_per_pixel();
+        for y in 0..image.height {
+            let pixels = image.row_mut(y);
+            for (px, &covered) in pixels.chunks_exact_mut(bpp).zip(bitmap.row(y)) {
+                if covered {
+                    px.fill(0);
+                }
+            }
+        }
+    }
+}
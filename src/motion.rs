@@ -0,0 +1,313 @@
+//! Motion detection: a background thread watching the camera at a low frame rate.
+//!
+//! Frames are shrunk to a small grayscale grid and compared block by block with a slowly
+//! adapting background (see `MotionDetector`). When motion starts or stops, every
+//! `MotionHandler` hears about it: save a snapshot, record a clip, send a notification.
+//! `/motion` reports the state and turns detection on and off. Areas to leave out come
+//! from `mask` shapes (`Motion::set_exclusions`).
+
+mod detector;
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub use detector::{shrink, BlockMask, MotionConfig, MotionDetector, MotionEvent};
+
+use crate::camera::{FrameBuffer, Pacer};
+use crate::clock;
+use crate::http::App;
+use crate::mask::{self, Shape};
+use crate::mqtt::Mqtt;
+use crate::prebuffer::EventBuffer;
+use crate::recorder::Recorder;
+use crate::settings::MotionSettings;
+use crate::sink::FrameSink;
+use crate::webhook::Webhook;
+
+impl MotionConfig {
+    pub fn from_settings(settings: &MotionSettings) -> Self {
+        Self {
+            sensitivity: settings.sensitivity,
+            min_area: settings.min_area_percent / 100.0,
+            hold: Duration::from_secs(settings.hold_secs as u64),
+            ..Default::default()
+        }
+    }
+}
+
+/// Something to do about motion. Frames are the ones streamed, i.e. with the overlay.
+pub trait MotionHandler: Send {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer);
+
+    /// Every frame in between `Started` and `Stopped`
+    fn frame(&mut self, _frame: &FrameBuffer) {}
+}
+
+impl<F: FnMut(&MotionEvent, &FrameBuffer) + Send> MotionHandler for F {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer) {
+        self(event, frame)
+    }
+}
+
+/// Stores the frame that started the motion
+pub struct SnapshotOnMotion<S>(pub S);
+
+impl<S: FrameSink> MotionHandler for SnapshotOnMotion<S> {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer) {
+        if let MotionEvent::Started { .. } = event {
+            if let Err(e) = self.0.store(frame) {
+                log::warn!("Couldn't save motion snapshot: {}", e);
+            }
+        }
+    }
+}
+
+/// Records a clip from start to stop
+pub struct RecordOnMotion(pub Recorder);
+
+impl MotionHandler for RecordOnMotion {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer) {
+        let result = match event {
+            MotionEvent::Started { .. } => self.0.write(frame),
+            MotionEvent::Stopped { .. } => {
+                self.0.write(frame).and_then(|()| self.0.finish().map(drop))
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("Motion recording failed: {}", e);
+        }
+    }
+
+    fn frame(&mut self, frame: &FrameBuffer) {
+        if let Err(e) = self.0.write(frame) {
+            log::warn!("Motion recording failed: {}", e);
+        }
+    }
+}
+
+/// Saves a clip with the seconds before the motion too, for as long as it lasts
+pub struct ClipOnMotion(pub Arc<EventBuffer>);
+
+impl MotionHandler for ClipOnMotion {
+    fn event(&mut self, event: &MotionEvent, _frame: &FrameBuffer) {
+        if let MotionEvent::Started { .. } = event {
+            self.0.trigger("motion");
+        }
+    }
+
+    fn frame(&mut self, _frame: &FrameBuffer) {
+        self.0.trigger("motion");
+    }
+}
+
+/// Tells the MQTT broker (and whoever listens there) when motion starts and stops
+pub struct PublishOnMotion(pub Arc<Mqtt>);
+
+impl MotionHandler for PublishOnMotion {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer) {
+        self.0.publish_motion(event, frame);
+    }
+}
+
+/// POSTs the frame that started the motion to the webhook, while its `on_motion` is set
+pub struct PostOnMotion(pub Arc<Webhook>);
+
+impl MotionHandler for PostOnMotion {
+    fn event(&mut self, event: &MotionEvent, frame: &FrameBuffer) {
+        if !self.0.config().on_motion {
+            return;
+        }
+        if let MotionEvent::Started { area, .. } = *event {
+            let extra = serde_json::json!({ "area": area });
+            if let Err(e) = self.0.post("motion", frame, extra) {
+                log::warn!("Couldn't queue motion webhook: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    enabled: bool,
+    active: bool,
+    area: f32,
+    events: u64,
+    last_start: Option<SystemTime>,
+    last_stop: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+pub struct Motion {
+    sensitivity: u8,
+    state: Mutex<State>,
+    changed: Condvar,
+    handlers: Mutex<Vec<Box<dyn MotionHandler>>>,
+    /// New areas to leave out, picked up before the next frame
+    exclusions: Mutex<Option<Vec<Shape>>>,
+}
+
+impl Motion {
+    /// Starts watching on a background thread at `fps`. Only holds a weak reference to the
+    /// app, so it can be stored in the app (`App::set_motion`) without keeping it alive.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        mut detector: MotionDetector,
+        fps: f32,
+        enabled: bool,
+    ) -> Arc<Self> {
+        let motion = Arc::new(Self {
+            sensitivity: detector.config().sensitivity,
+            state: Mutex::new(State {
+                enabled,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+            handlers: Mutex::new(Vec::new()),
+            exclusions: Mutex::new(None),
+        });
+
+        let this = motion.clone();
+        thread::spawn(move || {
+            let mut pacer = Pacer::new(fps);
+            loop {
+                if !this.is_enabled() {
+                    // Close off whatever the handlers started, and start afresh next time
+                    let event = detector.reset();
+                    let frame = weak_app.upgrade().and_then(|app| app.stream_frame().ok());
+                    if let (Some(event), Some(frame)) = (event, frame) {
+                        this.dispatch(Some(event), &frame, 0.0);
+                    }
+                    this.state.lock().unwrap().active = false;
+                    this.wait_for_enable();
+                    continue;
+                }
+
+                pacer.wait();
+                if let Some(shapes) = this.exclusions.lock().unwrap().take() {
+                    let config = detector.config();
+                    let mask = mask::block_mask(&shapes, config.cols, config.rows, config.block);
+                    detector.set_mask(Some(mask)).unwrap();
+                }
+                let Some(app) = weak_app.upgrade() else {
+                    break;
+                };
+                let mut frame = match app.capture_frame() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        this.failed(e);
+                        continue;
+                    }
+                };
+                // Detect on the raw frame, the overlay clock would look like motion
+                let event = match detector.feed(&frame) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        this.failed(e);
+                        continue;
+                    }
+                };
+                if let Err(e) = app.stamp(&mut frame, false) {
+                    this.failed(e);
+                    continue;
+                }
+                this.dispatch(event, &frame, detector.area());
+            }
+        });
+
+        motion
+    }
+
+    /// Adds something to do about motion
+    pub fn add_handler(&self, handler: impl MotionHandler + 'static) {
+        self.handlers.lock().unwrap().push(Box::new(handler));
+    }
+
+    /// Leaves whatever `shapes` cover out of detection from the next frame on
+    pub fn set_exclusions(&self, shapes: Vec<Shape>) {
+        *self.exclusions.lock().unwrap() = Some(shapes);
+    }
+
+    pub fn enable(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = true;
+        state.last_error = None;
+        self.changed.notify_all();
+    }
+
+    pub fn disable(&self) {
+        self.state.lock().unwrap().enabled = false;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    /// Whether there is motion right now
+    pub fn is_active(&self) -> bool {
+        self.state.lock().unwrap().active
+    }
+
+    /// Blocks while detection is off
+    fn wait_for_enable(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.enabled {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn dispatch(&self, event: Option<MotionEvent>, frame: &FrameBuffer, area: f32) {
+        let active = {
+            let mut state = self.state.lock().unwrap();
+            state.area = area;
+            state.last_error = None;
+            let now = frame.wall_time.or_else(clock::wall_clock);
+            match event {
+                Some(MotionEvent::Started { area, .. }) => {
+                    log::info!("Motion started ({:.1}% of the frame)", area * 100.0);
+                    state.active = true;
+                    state.events += 1;
+                    state.last_start = now;
+                }
+                Some(MotionEvent::Stopped { duration, .. }) => {
+                    log::info!("Motion stopped after {:.1}s", duration.as_secs_f32());
+                    state.active = false;
+                    state.last_stop = now;
+                }
+                None => {}
+            }
+            state.active
+        };
+
+        let mut handlers = self.handlers.lock().unwrap();
+        for handler in handlers.iter_mut() {
+            match &event {
+                Some(event) => handler.event(event, frame),
+                None if active => handler.frame(frame),
+                None => {}
+            }
+        }
+    }
+
+    fn failed(&self, e: anyhow::Error) {
+        log::warn!("Motion detection: {}", e);
+        self.state.lock().unwrap().last_error = Some(e.to_string());
+    }
+
+    /// What `/motion` reports
+    pub fn status_json(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let time = |t: Option<SystemTime>| t.map(clock::format_rfc3339);
+        serde_json::json!({
+            "enabled": state.enabled,
+            "active": state.active,
+            "area": state.area,
+            "sensitivity": self.sensitivity,
+            "events": state.events,
+            "last_start": time(state.last_start),
+            "last_stop": time(state.last_stop),
+            "last_error": state.last_error,
+        })
+        .to_string()
+    }
+}
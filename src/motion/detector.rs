@@ -0,0 +1,381 @@
+use std::time::Duration;
+
+use crate::camera::{FrameBuffer, FrameFormat};
+use crate::codec::{self, Image, PixelFormat};
+
+/// Per-pixel differences up to this much are sensor noise and JPEG artifacts
+const NOISE_FLOOR: u8 = 4;
+
+/// Changed blocks follow the scene this many times slower than still ones, so a moving
+/// object doesn't smear into the background but a parked car eventually does
+const CHANGED_LEARN_DIVISOR: f32 = 10.0;
+
+#[derive(Clone, Debug)]
+pub struct MotionConfig {
+    /// Size of the analysis grid in blocks
+    pub cols: u32,
+    pub rows: u32,
+    /// Block size in pixels, frames are shrunk to `cols * block` x `rows * block`
+    pub block: u32,
+    /// 1-100, higher reacts to smaller changes
+    pub sensitivity: u8,
+    /// Share of the looked-at blocks (0-1) that must change to count as motion
+    pub min_area: f32,
+    /// Frames in a row over `min_area` before motion starts
+    pub start_frames: u32,
+    /// Motion stops after this long without any
+    pub hold: Duration,
+    /// How fast the background follows the scene, 0-1 per frame
+    pub learn_rate: f32,
+    /// More than this share changing at once is a lighting change, not motion
+    pub lighting_area: f32,
+    /// Blocks to leave out, `None` to look everywhere
+    pub mask: Option<BlockMask>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            cols: 20,
+            rows: 15,
+            block: 4,
+            sensitivity: 60,
+            min_area: 0.01,
+            start_frames: 2,
+            hold: Duration::from_secs(5),
+            learn_rate: 0.05,
+            lighting_area: 0.8,
+            mask: None,
+        }
+    }
+}
+
+impl MotionConfig {
+    /// Size frames are shrunk to before comparing
+    pub fn analysis_size(&self) -> (u32, u32) {
+        (self.cols * self.block, self.rows * self.block)
+    }
+
+    /// Mean difference over a block that marks it as changed
+    pub fn block_threshold(&self) -> f32 {
+        let sensitivity = self.sensitivity.clamp(1, 100) as f32;
+        2.0 + (100.0 - sensitivity) * 30.0 / 99.0
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.cols == 0 || self.rows == 0 || self.block == 0 {
+            anyhow::bail!("Motion grid can't be empty");
+        }
+        if !(0.0..=1.0).contains(&self.min_area) || !(0.0..=1.0).contains(&self.learn_rate) {
+            anyhow::bail!("min_area and learn_rate must be between 0 and 1");
+        }
+        if let Some(mask) = &self.mask {
+            if (mask.cols, mask.rows) != (self.cols, self.rows) {
+                anyhow::bail!(
+                    "Mask is {}x{} blocks, the grid is {}x{}",
+                    mask.cols,
+                    mask.rows,
+                    self.cols,
+                    self.rows
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which blocks of the grid are left out of detection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMask {
+    pub cols: u32,
+    pub rows: u32,
+    ignored: Vec<bool>,
+}
+
+impl BlockMask {
+    /// Nothing ignored
+    pub fn new(cols: u32, rows: u32) -> Self {
+        Self {
+            cols,
+            rows,
+            ignored: vec![false; (cols * rows) as usize],
+        }
+    }
+
+    pub fn is_ignored(&self, col: u32, row: u32) -> bool {
+        self.ignored[(row * self.cols + col) as usize]
+    }
+
+    pub fn set_ignored(&mut self, col: u32, row: u32, ignored: bool) {
+        self.ignored[(row * self.cols + col) as usize] = ignored;
+    }
+
+    /// Ignores a rectangle of blocks, clipped to the grid
+    pub fn ignore_rect(&mut self, col: u32, row: u32, width: u32, height: u32) {
+        for r in row..(row + height).min(self.rows) {
+            for c in col..(col + width).min(self.cols) {
+                self.set_ignored(c, r, true);
+            }
+        }
+    }
+
+    pub fn ignored_count(&self) -> usize {
+        self.ignored.iter().filter(|&&ignored| ignored).count()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MotionEvent {
+    /// `area` is the share of blocks that changed in the frame that started it
+    Started {
+        at: Duration,
+        area: f32,
+    },
+    Stopped {
+        at: Duration,
+        duration: Duration,
+    },
+}
+
+impl MotionEvent {
+    pub fn at(&self) -> Duration {
+        match *self {
+            MotionEvent::Started { at, .. } | MotionEvent::Stopped { at, .. } => at,
+        }
+    }
+}
+
+/// Block-difference detector against an adaptive background. Feed it frames in order;
+/// it returns an event whenever motion starts or stops.
+pub struct MotionDetector {
+    config: MotionConfig,
+    /// Empty until the first frame
+    background: Vec<f32>,
+    changed: Vec<bool>,
+    area: f32,
+    frames_over: u32,
+    active_since: Option<Duration>,
+    last_motion: Duration,
+    last_frame: Duration,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let blocks = (config.cols * config.rows) as usize;
+        Ok(Self {
+            config,
+            background: Vec::new(),
+            changed: vec![false; blocks],
+            area: 0.0,
+            frames_over: 0,
+            active_since: None,
+            last_motion: Duration::ZERO,
+            last_frame: Duration::ZERO,
+        })
+    }
+
+    pub fn config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    /// Swaps the mask without forgetting the background
+    pub fn set_mask(&mut self, mask: Option<BlockMask>) -> anyhow::Result<()> {
+        let previous = std::mem::replace(&mut self.config.mask, mask);
+        if let Err(e) = self.config.validate() {
+            self.config.mask = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Decodes (at reduced size for JPEGs) and processes a camera frame
+    pub fn feed(&mut self, frame: &FrameBuffer) -> anyhow::Result<Option<MotionEvent>> {
+        let (width, height) = self.config.analysis_size();
+        let luma = match frame.format {
+            FrameFormat::Jpeg => {
+                codec::decode_jpeg_scaled(&frame.data, width as u16, height as u16)?.to_grayscale()
+            }
+            _ => frame.to_image()?.to_grayscale(),
+        };
+        Ok(self.process(&luma, frame.timestamp))
+    }
+
+    /// Processes a grayscale image of any size taken at `at`
+    pub fn process(&mut self, luma: &Image, at: Duration) -> Option<MotionEvent> {
+        let (width, height) = self.config.analysis_size();
+        let small = shrink(luma, width, height);
+        self.last_frame = at;
+
+        if self.background.is_empty() {
+            self.background = small.data.iter().map(|&v| v as f32).collect();
+            return None;
+        }
+
+        self.compare(&small);
+        if self.area > self.config.lighting_area {
+            log::debug!("Lighting change ({:.0}% of blocks)", self.area * 100.0);
+            self.background = small.data.iter().map(|&v| v as f32).collect();
+            self.changed.fill(false);
+            self.area = 0.0;
+        } else {
+            self.learn(&small);
+        }
+
+        let moving = self.area > 0.0 && self.area >= self.config.min_area;
+        if moving {
+            self.frames_over += 1;
+            self.last_motion = at;
+        } else {
+            self.frames_over = 0;
+        }
+
+        match self.active_since {
+            None if self.frames_over >= self.config.start_frames.max(1) => {
+                self.active_since = Some(at);
+                Some(MotionEvent::Started {
+                    at,
+                    area: self.area,
+                })
+            }
+            Some(since) if !moving && at.saturating_sub(self.last_motion) >= self.config.hold => {
+                self.active_since = None;
+                Some(MotionEvent::Stopped {
+                    at,
+                    duration: at.saturating_sub(since),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Forgets the background. Returns `Stopped` if motion was going on.
+    pub fn reset(&mut self) -> Option<MotionEvent> {
+        self.background.clear();
+        self.changed.fill(false);
+        self.area = 0.0;
+        self.frames_over = 0;
+        let since = self.active_since.take()?;
+        Some(MotionEvent::Stopped {
+            at: self.last_frame,
+            duration: self.last_frame.saturating_sub(since),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active_since.is_some()
+    }
+
+    /// Share of the looked-at blocks that changed in the last frame
+    pub fn area(&self) -> f32 {
+        self.area
+    }
+
+    /// Changed blocks of the last frame, row by row
+    pub fn changed_blocks(&self) -> &[bool] {
+        &self.changed
+    }
+
+    fn compare(&mut self, small: &Image) {
+        let MotionConfig {
+            cols, rows, block, ..
+        } = self.config;
+        let threshold = self.config.block_threshold();
+        let pixels = (block * block) as f32;
+        let width = (cols * block) as usize;
+
+        let mut looked_at = 0;
+        let mut changed = 0;
+        for row in 0..rows {
+            for col in 0..cols {
+                let index = (row * cols + col) as usize;
+                if let Some(mask) = &self.config.mask {
+                    if mask.is_ignored(col, row) {
+                        self.changed[index] = false;
+                        continue;
+                    }
+                }
+
+                let mut sum = 0u32;
+                for y in row * block..(row + 1) * block {
+                    let start = y as usize * width + (col * block) as usize;
+                    let current = &small.data[start..start + block as usize];
+                    let background = &self.background[start..start + block as usize];
+                    for (&c, &b) in current.iter().zip(background) {
+                        let diff = (c as f32 - b).abs() as u8;
+                        sum += diff.saturating_sub(NOISE_FLOOR) as u32;
+                    }
+                }
+
+                looked_at += 1;
+                self.changed[index] = sum as f32 / pixels > threshold;
+                changed += self.changed[index] as u32;
+            }
+        }
+
+        self.area = if looked_at == 0 {
+            0.0
+        } else {
+            changed as f32 / looked_at as f32
+        };
+    }
+
+    fn learn(&mut self, small: &Image) {
+        let MotionConfig {
+            cols,
+            block,
+            learn_rate,
+            ..
+        } = self.config;
+        let width = (cols * block) as usize;
+        for (i, (background, &current)) in self.background.iter_mut().zip(&small.data).enumerate() {
+            let (x, y) = (i % width, i / width);
+            let block_index = (y / block as usize) * cols as usize + x / block as usize;
+            let rate = if self.changed[block_index] {
+                learn_rate / CHANGED_LEARN_DIVISOR
+            } else {
+                learn_rate
+            };
+            *background += (current as f32 - *background) * rate;
+        }
+    }
+}
+
+/// Box-filters a grayscale image to exactly `width` x `height`
+pub fn shrink(image: &Image, width: u32, height: u32) -> Image {
+    let gray;
+    let image = if image.format == PixelFormat::Grayscale {
+        image
+    } else {
+        gray = image.to_grayscale();
+        &gray
+    };
+
+    let mut out = Image::new(width, height, PixelFormat::Grayscale);
+    if image.width == 0 || image.height == 0 {
+        return out;
+    }
+    for y in 0..height {
+        let (y0, y1) = span(y, height, image.height);
+        let row = out.row_mut(y);
+        for x in 0..width {
+            let (x0, x1) = span(x, width, image.width);
+            let mut sum = 0u32;
+            for sy in y0..y1 {
+                sum += image.row(sy)[x0 as usize..x1 as usize]
+                    .iter()
+                    .map(|&v| v as u32)
+                    .sum::<u32>();
+            }
+            row[x as usize] = (sum / ((y1 - y0) * (x1 - x0))) as u8;
+        }
+    }
+    out
+}
+
+/// Source pixels covered by output pixel `i` of `out`, at least one
+fn span(i: u32, out: u32, source: u32) -> (u32, u32) {
+    let start = (i as u64 * source as u64 / out as u64) as u32;
+    let end = ((i as u64 + 1) * source as u64 / out as u64) as u32;
+    (start.min(source - 1), end.max(start + 1).min(source))
+}
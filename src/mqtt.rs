@@ -0,0 +1,619 @@
+//! MQTT: status, telemetry and motion events out, commands in.
+//!
+//! Topics, under a base of `wrover/<device id>` unless set otherwise:
+//! - `<base>/status`: `online`, or `offline` once the board is gone (retained, and the
+//!   broker's last will)
+//! - `<base>/telemetry`: JSON with uptime, frames, RSSI and free heap, every
+//!   `telemetry_interval`
+//! - `<base>/motion`: `ON` or `OFF` (retained), plus JSON for each event on `<base>/events`
+//! - `<base>/snapshot`: JPEG bytes, on the `snapshot` command or when motion starts
+//! - `<base>/flash`: `ON` or `OFF` (retained), on boards with a flash LED, and its
+//!   brightness in percent on `<base>/flash/brightness`. Also sent with telemetry if they
+//!   changed some other way (HTTP, or the max-on timer).
+//! - `<base>/cmd/<command>`: `snapshot`, `resolution` (`VGA`, `SVGA`... or the index),
+//!   `flash` (`ON`, `OFF` or `TOGGLE`), `flash_brightness` (1-100), `reboot`. Each gets a
+//!   JSON reply on `<base>/result`.
+//!
+//! With discovery on, Home Assistant is told about all of these as well, see `discovery`.
+//!
+//! `Mqtt` doesn't own a socket: it sends through an `MqttClient` and is fed `MqttEvent`s by
+//! whatever holds the connection. `esp::start` uses the ESP-IDF client, the host crate has
+//! a plain TCP one.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::board;
+use crate::camera::{Control, FrameBuffer, FrameFormat, FrameSize};
+use crate::clock;
+use crate::http::App;
+use crate::motion::MotionEvent;
+use crate::settings::MqttSettings;
+use crate::system;
+use crate::trigger::TriggerEvent;
+
+pub mod discovery;
+#[cfg(target_os = "espidf")]
+pub mod esp;
+
+/// How long `reboot` waits for its reply and `offline` to go out
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+/// Published by the broker if we drop off without saying goodbye
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MqttEvent {
+    Connected,
+    Disconnected,
+    Message { topic: String, payload: Vec<u8> },
+}
+
+/// The sending half of a connection
+pub trait MqttClient: Send {
+    fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()>;
+
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> anyhow::Result<()>;
+}
+
+/// Every topic we use, under one base
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topics {
+    base: String,
+}
+
+impl Topics {
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            base: base.into().trim_end_matches('/').to_owned(),
+        }
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn status(&self) -> String {
+        format!("{}/status", self.base)
+    }
+
+    pub fn telemetry(&self) -> String {
+        format!("{}/telemetry", self.base)
+    }
+
+    pub fn motion(&self) -> String {
+        format!("{}/motion", self.base)
+    }
+
+    pub fn events(&self) -> String {
+        format!("{}/events", self.base)
+    }
+
+    pub fn snapshot(&self) -> String {
+        format!("{}/snapshot", self.base)
+    }
+
+    pub fn flash(&self) -> String {
+        format!("{}/flash", self.base)
+    }
+
+    pub fn flash_brightness(&self) -> String {
+        format!("{}/flash/brightness", self.base)
+    }
+
+    pub fn command(&self, name: &str) -> String {
+        format!("{}/cmd/{}", self.base, name)
+    }
+
+    /// Filter matching every command topic
+    pub fn commands(&self) -> String {
+        self.command("+")
+    }
+
+    pub fn result(&self) -> String {
+        format!("{}/result", self.base)
+    }
+
+    /// The command name, if `topic` is a command topic
+    pub fn command_name<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.base.as_str())?
+            .strip_prefix("/cmd/")
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    /// `mqtt://host:port`
+    pub url: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topics: Topics,
+    pub telemetry_interval: Duration,
+    /// Publish a JPEG whenever motion starts
+    pub motion_snapshots: bool,
+    /// Names the device in Home Assistant if there's no MAC to go by
+    pub device_id: String,
+    /// Home Assistant discovery prefix, `None` to stay quiet
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttConfig {
+    /// `device_id` fills in the client id and base topic if the settings leave them empty
+    pub fn from_settings(settings: &MqttSettings, device_id: &str) -> Self {
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+        let client_id = non_empty(&settings.client_id).unwrap_or_else(|| device_id.to_owned());
+        let base =
+            non_empty(&settings.base_topic).unwrap_or_else(|| format!("wrover/{}", client_id));
+        Self {
+            url: settings.url.clone(),
+            client_id,
+            username: non_empty(&settings.username),
+            password: non_empty(&settings.password),
+            topics: Topics::new(base),
+            telemetry_interval: Duration::from_secs(settings.telemetry_secs.max(1) as u64),
+            motion_snapshots: settings.motion_snapshots,
+            device_id: device_id.to_owned(),
+            discovery_prefix: (settings.discovery && !settings.discovery_prefix.is_empty())
+                .then(|| settings.discovery_prefix.clone()),
+        }
+    }
+
+    /// `offline` on the status topic, retained
+    pub fn will(&self) -> Will {
+        Will {
+            topic: self.topics.status(),
+            payload: b"offline".to_vec(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    }
+
+    /// Host and port from the URL, 1883 if it has none
+    pub fn host_port(&self) -> anyhow::Result<(String, u16)> {
+        let rest = self
+            .url
+            .strip_prefix("mqtt://")
+            .or_else(|| self.url.strip_prefix("tcp://"))
+            .unwrap_or(&self.url);
+        let rest = rest.trim_end_matches('/');
+        match rest.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Bad port in MQTT URL {}", self.url))?;
+                Ok((host.to_owned(), port))
+            }
+            None => Ok((rest.to_owned(), 1883)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Switch {
+    On,
+    Off,
+    Toggle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Snapshot,
+    Resolution(FrameSize),
+    Flash(Switch),
+    /// Percent
+    FlashBrightness(u8),
+    Reboot,
+}
+
+impl Command {
+    /// `name` is the last level of the command topic
+    pub fn parse(name: &str, payload: &[u8]) -> anyhow::Result<Self> {
+        let payload = std::str::from_utf8(payload)
+            .map_err(|_| anyhow::anyhow!("Payload isn't text"))?
+            .trim();
+        match name {
+            "snapshot" => Ok(Command::Snapshot),
+            "resolution" => FrameSize::from_name(payload)
+                .or_else(|| payload.parse().ok().and_then(FrameSize::from_index))
+                .map(Command::Resolution)
+                .ok_or_else(|| anyhow::anyhow!("Unknown resolution: {}", payload)),
+            "flash" => match payload.to_ascii_uppercase().as_str() {
+                "ON" => Ok(Command::Flash(Switch::On)),
+                "OFF" => Ok(Command::Flash(Switch::Off)),
+                "TOGGLE" | "" => Ok(Command::Flash(Switch::Toggle)),
+                _ => anyhow::bail!("Expected ON, OFF or TOGGLE, got {}", payload),
+            },
+            "flash_brightness" => match payload.parse() {
+                Ok(percent @ 1..=100) => Ok(Command::FlashBrightness(percent)),
+                _ => anyhow::bail!("Expected a brightness of 1-100, got {}", payload),
+            },
+            "reboot" => Ok(Command::Reboot),
+            _ => anyhow::bail!("Unknown command: {}", name),
+        }
+    }
+}
+
+fn on_off(on: bool) -> &'static [u8] {
+    if on {
+        b"ON"
+    } else {
+        b"OFF"
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    published: u64,
+    failures: u64,
+    commands: u64,
+    last_error: Option<String>,
+}
+
+/// Publishes what the camera is up to and runs the commands it's sent
+pub struct Mqtt {
+    app: Weak<App>,
+    config: MqttConfig,
+    client: Mutex<Box<dyn MqttClient>>,
+    connected: AtomicBool,
+    reboot: Mutex<Box<dyn FnMut() + Send>>,
+    stats: Mutex<Stats>,
+    /// Flash state and brightness as last published
+    flash: Mutex<Option<(bool, u8)>>,
+}
+
+impl Mqtt {
+    /// Starts publishing telemetry. Only holds a weak reference to the app.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        config: MqttConfig,
+        client: impl MqttClient + 'static,
+    ) -> Arc<Self> {
+        let mqtt = Arc::new(Self {
+            app: weak_app,
+            config,
+            client: Mutex::new(Box::new(client)),
+            connected: AtomicBool::new(false),
+            reboot: Mutex::new(Box::new(system::restart)),
+            stats: Mutex::new(Stats::default()),
+            flash: Mutex::new(None),
+        });
+
+        let this = mqtt.clone();
+        thread::spawn(move || loop {
+            thread::sleep(this.config.telemetry_interval);
+            if this.app.strong_count() == 0 {
+                break;
+            }
+            if this.is_connected() {
+                this.publish_telemetry();
+                this.publish_flash(false);
+            }
+        });
+
+        mqtt
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    pub fn topics(&self) -> &Topics {
+        &self.config.topics
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Replaces what `reboot` does (restart the board)
+    pub fn on_reboot(&self, reboot: impl FnMut() + Send + 'static) {
+        *self.reboot.lock().unwrap() = Box::new(reboot);
+    }
+
+    /// Call with everything the connection reports
+    pub fn handle(&self, event: MqttEvent) {
+        match event {
+            MqttEvent::Connected => {
+                log::info!("MQTT connected");
+                self.connected.store(true, Ordering::Relaxed);
+                self.announce();
+            }
+            MqttEvent::Disconnected => {
+                log::info!("MQTT disconnected");
+                self.connected.store(false, Ordering::Relaxed);
+            }
+            MqttEvent::Message { topic, payload } => {
+                if let Some(name) = self.topics().command_name(&topic) {
+                    self.command(name, &payload);
+                }
+            }
+        }
+    }
+
+    /// Subscribes to commands and publishes everything retained. Again on every reconnect,
+    /// the broker may have lost it all.
+    fn announce(&self) {
+        let topics = self.topics();
+        let subscribed = self
+            .client
+            .lock()
+            .unwrap()
+            .subscribe(&topics.commands(), QoS::AtLeastOnce);
+        if let Err(e) = subscribed {
+            self.failed("Couldn't subscribe to commands", &e);
+        }
+        self.publish_discovery();
+        let _ = self.publish(&topics.status(), QoS::AtLeastOnce, true, b"online");
+        self.publish_flash(true);
+        self.publish_telemetry();
+    }
+
+    /// Flash state and brightness, retained. Only if they changed since they were last
+    /// published, unless `force`.
+    fn publish_flash(&self, force: bool) {
+        let Some(app) = self.app.upgrade() else {
+            return;
+        };
+        let (Some(on), Some(settings)) = (app.flash_state(), app.flash_settings()) else {
+            return;
+        };
+        let state = (on, settings.brightness);
+        let mut last = self.flash.lock().unwrap();
+        if !force && *last == Some(state) {
+            return;
+        }
+        let topics = self.topics();
+        let brightness = settings.brightness.to_string();
+        let sent = self
+            .publish(&topics.flash(), QoS::AtLeastOnce, true, on_off(on))
+            .and_then(|()| {
+                let topic = topics.flash_brightness();
+                self.publish(&topic, QoS::AtLeastOnce, true, brightness.as_bytes())
+            });
+        if sent.is_ok() {
+            *last = Some(state);
+        }
+    }
+
+    /// Home Assistant discovery configs, if enabled
+    fn publish_discovery(&self) {
+        let (Some(prefix), Some(app)) = (&self.config.discovery_prefix, self.app.upgrade()) else {
+            return;
+        };
+        let device = discovery::Device::new(
+            &app.settings().camera_name,
+            board::current().name,
+            system::mac(),
+            &self.config.device_id,
+        );
+        let flash = app.flash_state().is_some();
+        let battery = app.has_battery();
+        for (topic, config) in discovery::messages(prefix, &device, self.topics(), flash, battery) {
+            let payload = config.to_string();
+            let _ = self.publish(&topic, QoS::AtLeastOnce, true, payload.as_bytes());
+        }
+    }
+
+    /// Sends one message. Fails straight away while disconnected.
+    pub fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let result = if self.is_connected() {
+            self.client
+                .lock()
+                .unwrap()
+                .publish(topic, qos, retain, payload)
+        } else {
+            Err(anyhow::anyhow!("MQTT is not connected"))
+        };
+        match &result {
+            Ok(()) => self.stats.lock().unwrap().published += 1,
+            Err(e) => self.failed(&format!("Couldn't publish to {}", topic), e),
+        }
+        result
+    }
+
+    fn failed(&self, what: &str, e: &anyhow::Error) {
+        log::warn!("{}: {}", what, e);
+        let mut stats = self.stats.lock().unwrap();
+        stats.failures += 1;
+        stats.last_error = Some(e.to_string());
+    }
+
+    /// Uptime, frames, clients, resolution, flash, RSSI, heap and battery, as JSON
+    pub fn telemetry_json(&self) -> Option<String> {
+        let app = self.app.upgrade()?;
+        let frame_size = FrameSize::from_index(app.control_value(Control::FrameSize));
+        let time = clock::wall_clock().map(clock::format_rfc3339);
+        let battery = app.battery_reading();
+        let json = serde_json::json!({
+            "uptime_s": app.uptime().as_secs(),
+            "time": time,
+            "frames": app.frame_count(),
+            "clients": app.client_count(),
+            "resolution": frame_size.map(FrameSize::name),
+            "flash": app.flash_state(),
+            "flash_brightness": app.flash_settings().map(|flash| flash.brightness),
+            "rssi": system::rssi(),
+            "free_heap": system::free_heap(),
+            "min_free_heap": system::min_free_heap(),
+            "battery_volts": battery.map(|reading| reading.volts),
+            "battery_percent": battery.map(|reading| reading.percent.round()),
+        });
+        Some(json.to_string())
+    }
+
+    pub fn publish_telemetry(&self) {
+        if let Some(json) = self.telemetry_json() {
+            let topic = self.topics().telemetry();
+            let _ = self.publish(&topic, QoS::AtMostOnce, false, json.as_bytes());
+        }
+    }
+
+    /// The frame as-is on the snapshot topic
+    pub fn publish_snapshot(&self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        if frame.format != FrameFormat::Jpeg {
+            anyhow::bail!("Snapshots have to be JPEG, not {}", frame.format.name());
+        }
+        self.publish(
+            &self.topics().snapshot(),
+            QoS::AtMostOnce,
+            false,
+            &frame.data,
+        )
+    }
+
+    /// `ON`/`OFF` on the motion topic and the details on the events topic, plus the frame
+    /// if `motion_snapshots` is on
+    pub fn publish_motion(&self, event: &MotionEvent, frame: &FrameBuffer) {
+        let topics = self.topics();
+        let time = frame.wall_time.map(clock::format_rfc3339);
+        let (active, json) = match *event {
+            MotionEvent::Started { at, area } => (
+                true,
+                serde_json::json!({
+                    "event": "motion_started",
+                    "time": time,
+                    "uptime_ms": at.as_millis() as u64,
+                    "area": area,
+                }),
+            ),
+            MotionEvent::Stopped { at, duration } => (
+                false,
+                serde_json::json!({
+                    "event": "motion_stopped",
+                    "time": time,
+                    "uptime_ms": at.as_millis() as u64,
+                    "duration_ms": duration.as_millis() as u64,
+                }),
+            ),
+        };
+        let _ = self.publish(&topics.motion(), QoS::AtLeastOnce, true, on_off(active));
+        let _ = self.publish(
+            &topics.events(),
+            QoS::AtLeastOnce,
+            false,
+            json.to_string().as_bytes(),
+        );
+        if active && self.config.motion_snapshots {
+            let _ = self.publish_snapshot(frame);
+        }
+    }
+
+    /// A trigger input firing, on the events topic
+    pub fn publish_trigger(&self, event: &TriggerEvent) {
+        let json = serde_json::json!({
+            "event": "trigger",
+            "input": event.input.name,
+            "edge": event.edge,
+            "time": clock::wall_clock().map(clock::format_rfc3339),
+            "uptime_ms": event.at.as_millis() as u64,
+        });
+        let _ = self.publish(
+            &self.topics().events(),
+            QoS::AtLeastOnce,
+            false,
+            json.to_string().as_bytes(),
+        );
+    }
+
+    fn command(&self, name: &str, payload: &[u8]) {
+        log::info!("MQTT command: {}", name);
+        self.stats.lock().unwrap().commands += 1;
+        let result = Command::parse(name, payload).and_then(|command| {
+            self.run(command)?;
+            Ok(command)
+        });
+
+        let reply = serde_json::json!({
+            "command": name,
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        });
+        let _ = self.publish(
+            &self.topics().result(),
+            QoS::AtLeastOnce,
+            false,
+            reply.to_string().as_bytes(),
+        );
+
+        if let Ok(Command::Reboot) = result {
+            let topic = self.topics().status();
+            let _ = self.publish(&topic, QoS::AtLeastOnce, true, b"offline");
+            thread::sleep(REBOOT_DELAY);
+            (self.reboot.lock().unwrap())();
+        }
+    }
+
+    /// Carries out `command`, except the reboot itself
+    pub fn run(&self, command: Command) -> anyhow::Result<()> {
+        let app = self
+            .app
+            .upgrade()
+            .ok_or_else(|| anyhow::anyhow!("Shutting down"))?;
+        match command {
+            Command::Snapshot => {
+                let frame = app.capture_with(&[])?;
+                self.publish_snapshot(&frame)
+            }
+            Command::Resolution(size) => app.set_control(Control::FrameSize, size.index()),
+            Command::Flash(switch) => {
+                let on = match switch {
+                    Switch::On => true,
+                    Switch::Off => false,
+                    Switch::Toggle => !app.flash_state().unwrap_or(false),
+                };
+                app.switch_flash(on)?;
+                self.publish_flash(true);
+                Ok(())
+            }
+            Command::FlashBrightness(percent) => {
+                app.configure_flash(|flash| flash.brightness = percent)?;
+                self.publish_flash(true);
+                Ok(())
+            }
+            Command::Reboot => Ok(()),
+        }
+    }
+
+    /// Connection state and counters, as JSON
+    pub fn status_json(&self) -> String {
+        let stats = self.stats.lock().unwrap();
+        serde_json::json!({
+            "connected": self.is_connected(),
+            "base_topic": self.topics().base(),
+            "published": stats.published,
+            "failures": stats.failures,
+            "commands": stats.commands,
+            "last_error": stats.last_error,
+        })
+        .to_string()
+    }
+}
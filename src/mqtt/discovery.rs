@@ -0,0 +1,216 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Each entity is announced with a retained config message on
+//! `<prefix>/<component>/<node id>/<object>/config`, pointing Home Assistant at our own topics:
+//! - `camera`: the snapshot topic
+//! - `button`: takes a snapshot
+//! - `light`: the flash LED and its brightness, on boards that have one
+//! - `binary_sensor`: motion
+//! - `sensor`: RSSI, uptime and free heap, picked out of the telemetry JSON
+//!
+//! Unique ids start with the node id, which comes from the MAC, so renaming the camera or
+//! changing the base topic doesn't make Home Assistant forget its entities.
+
+use serde_json::{json, Value};
+
+use super::Topics;
+
+/// The board, as Home Assistant lists it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    /// `wrover_<MAC>`, or the fallback if there's no MAC
+    pub node_id: String,
+    pub name: String,
+    pub model: String,
+    pub mac: Option<[u8; 6]>,
+    pub sw_version: String,
+}
+
+impl Device {
+    /// `fallback` is only used without a MAC, i.e. on the host
+    pub fn new(name: &str, model: &str, mac: Option<[u8; 6]>, fallback: &str) -> Self {
+        let node_id = match mac {
+            Some(mac) => format!("wrover_{}", hex(&mac, "")),
+            None => fallback
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+                    _ => '_',
+                })
+                .collect(),
+        };
+        Self {
+            node_id,
+            name: name.to_owned(),
+            model: model.to_owned(),
+            mac,
+            sw_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+
+    fn json(&self) -> Value {
+        let mut device = json!({
+            "identifiers": [self.node_id],
+            "name": self.name,
+            "manufacturer": "Espressif",
+            "model": self.model,
+            "sw_version": self.sw_version,
+        });
+        if let Some(mac) = self.mac {
+            device["connections"] = json!([["mac", hex(&mac, ":")]]);
+        }
+        device
+    }
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Config topic and payload for every entity, to be published retained.
+/// The light is left out if `flash` is false, the battery sensors if `battery` is.
+pub fn messages(
+    prefix: &str,
+    device: &Device,
+    topics: &Topics,
+    flash: bool,
+    battery: bool,
+) -> Vec<(String, Value)> {
+    let telemetry_sensor = |name: &str, field: &str, extra: Value| {
+        let mut config = json!({
+            "name": name,
+            "state_topic": topics.telemetry(),
+            "value_template": format!("{{{{ value_json.{} }}}}", field),
+            "state_class": "measurement",
+            "entity_category": "diagnostic",
+        });
+        merge(&mut config, extra);
+        config
+    };
+
+    let mut entities = vec![
+        (
+            "camera",
+            "camera",
+            json!({
+                "name": null,
+                "topic": topics.snapshot(),
+            }),
+        ),
+        (
+            "button",
+            "snapshot",
+            json!({
+                "name": "Snapshot",
+                "command_topic": topics.command("snapshot"),
+                "icon": "mdi:camera",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "motion",
+            json!({
+                "name": "Motion",
+                "device_class": "motion",
+                "state_topic": topics.motion(),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+            }),
+        ),
+        (
+            "sensor",
+            "rssi",
+            telemetry_sensor(
+                "Signal strength",
+                "rssi",
+                json!({ "device_class": "signal_strength", "unit_of_measurement": "dBm" }),
+            ),
+        ),
+        (
+            "sensor",
+            "uptime",
+            telemetry_sensor(
+                "Uptime",
+                "uptime_s",
+                json!({ "device_class": "duration", "unit_of_measurement": "s" }),
+            ),
+        ),
+        (
+            "sensor",
+            "free_heap",
+            telemetry_sensor(
+                "Free heap",
+                "free_heap",
+                json!({ "device_class": "data_size", "unit_of_measurement": "B" }),
+            ),
+        ),
+    ];
+    if flash {
+        entities.push((
+            "light",
+            "flash",
+            json!({
+                "name": "Flash",
+                "command_topic": topics.command("flash"),
+                "state_topic": topics.flash(),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "brightness_command_topic": topics.command("flash_brightness"),
+                "brightness_state_topic": topics.flash_brightness(),
+                "brightness_scale": 100,
+            }),
+        ));
+    }
+    if battery {
+        entities.push((
+            "sensor",
+            "battery_voltage",
+            telemetry_sensor(
+                "Battery voltage",
+                "battery_volts",
+                json!({ "device_class": "voltage", "unit_of_measurement": "V" }),
+            ),
+        ));
+        entities.push((
+            "sensor",
+            "battery",
+            telemetry_sensor(
+                "Battery",
+                "battery_percent",
+                json!({ "device_class": "battery", "unit_of_measurement": "%" }),
+            ),
+        ));
+    }
+
+    let prefix = prefix.trim_end_matches('/');
+    entities
+        .into_iter()
+        .map(|(component, object, mut config)| {
+            merge(
+                &mut config,
+                json!({
+                    "unique_id": format!("{}_{}", device.node_id, object),
+                    "availability_topic": topics.status(),
+                    "payload_available": "online",
+                    "payload_not_available": "offline",
+                    "device": device.json(),
+                }),
+            );
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                prefix, component, device.node_id, object
+            );
+            (topic, config)
+        })
+        .collect()
+}
+
+fn merge(config: &mut Value, extra: Value) {
+    if let (Value::Object(config), Value::Object(extra)) = (config, extra) {
+        config.extend(extra);
+    }
+}
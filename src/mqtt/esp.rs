@@ -0,0 +1,94 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS as EspQoS,
+};
+
+use super::{Mqtt, MqttClient, MqttConfig, MqttEvent, QoS};
+use crate::http::App;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+impl From<QoS> for EspQoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => EspQoS::AtMostOnce,
+            QoS::AtLeastOnce => EspQoS::AtLeastOnce,
+        }
+    }
+}
+
+pub struct EspClient(EspMqttClient<'static>);
+
+impl MqttClient for EspClient {
+    fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        self.0.publish(topic, qos.into(), retain, payload)?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> anyhow::Result<()> {
+        self.0.subscribe(topic, qos.into())?;
+        Ok(())
+    }
+}
+
+/// Connects to the broker in the background; the ESP-IDF client reconnects by itself.
+/// Events are handled on a thread of their own, since publishing from inside the client's
+/// callback would deadlock it.
+pub fn start(weak_app: Weak<App>, config: MqttConfig) -> anyhow::Result<Arc<Mqtt>> {
+    let will = config.will();
+    let conf = MqttClientConfiguration {
+        client_id: Some(&config.client_id),
+        username: config.username.as_deref(),
+        password: config.password.as_deref(),
+        keep_alive_interval: Some(KEEP_ALIVE),
+        lwt: Some(LwtConfiguration {
+            topic: &will.topic,
+            payload: &will.payload,
+            qos: will.qos.into(),
+            retain: will.retain,
+        }),
+        ..Default::default()
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let client = EspMqttClient::new_cb(&config.url, &conf, move |event| {
+        let event = match event.payload() {
+            EventPayload::Connected(_) => MqttEvent::Connected,
+            EventPayload::Disconnected => MqttEvent::Disconnected,
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                details: Details::Complete,
+                ..
+            } => MqttEvent::Message {
+                topic: topic.to_owned(),
+                payload: data.to_vec(),
+            },
+            EventPayload::Error(e) => {
+                log::warn!("MQTT error: {:?}", e);
+                return;
+            }
+            _ => return,
+        };
+        let _ = tx.send(event);
+    })?;
+
+    let mqtt = Mqtt::spawn(weak_app, config, EspClient(client));
+    let handler = mqtt.clone();
+    thread::spawn(move || {
+        for event in rx {
+            handler.handle(event);
+        }
+    });
+    Ok(mqtt)
+}
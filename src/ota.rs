@@ -0,0 +1,395 @@
+//! Firmware updates over the air: an image uploaded to `POST /ota` or pulled from a URL
+//! (`POST /ota/pull`) goes into the inactive slot, and the camera restarts into it.
+//!
+//! The header is checked before anything is written: an ESP32 app image, for this chip and
+//! this project. ESP-IDF checks the rest (segments, checksum, hash) when the image is
+//! complete. After the restart the new image has to bring up Wi-Fi and the camera before
+//! it's marked valid (`esp::EspSlot::require`); if it doesn't, or it crashes first, the
+//! bootloader goes back to the old one.
+//!
+//! Only one update runs at a time, and only with auth turned on: anyone who can flash the
+//! camera owns it.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::status_led::{Status, StatusLed};
+
+#[cfg(target_os = "espidf")]
+pub mod esp;
+
+/// Image header plus the app description after it, all that's needed to tell what an image is
+pub const HEADER_LEN: usize = 32 + 256;
+
+const IMAGE_MAGIC: u8 = 0xe9;
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+
+/// Bytes read from the source at a time
+const CHUNK: usize = 4096;
+
+/// Time for the response to get out before restarting
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The body of a request or download, `read`-style: 0 means it's over
+pub type Body<'a> = dyn FnMut(&mut [u8]) -> anyhow::Result<usize> + 'a;
+
+/// What a `Fetch` hands the download to: its length if known, and the body
+pub type Receive<'a> = dyn FnMut(Option<usize>, &mut Body) -> anyhow::Result<()> + 'a;
+
+/// What an image says about itself, from its `esp_app_desc_t`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ImageInfo {
+    pub project: String,
+    pub version: String,
+    pub idf_version: String,
+    /// `esp_chip_id_t`: 0 for the ESP32, 9 for the ESP32-S3
+    pub chip_id: u16,
+}
+
+impl ImageInfo {
+    /// From the first `HEADER_LEN` bytes of an image
+    pub fn parse(header: &[u8]) -> anyhow::Result<Self> {
+        if header.len() < HEADER_LEN {
+            anyhow::bail!("Too short to be firmware");
+        }
+        if header[0] != IMAGE_MAGIC {
+            anyhow::bail!("Not an ESP32 app image");
+        }
+        let desc = &header[32..];
+        if u32::from_le_bytes([desc[0], desc[1], desc[2], desc[3]]) != APP_DESC_MAGIC {
+            anyhow::bail!("No app description in the image");
+        }
+        let text = |field: &[u8]| {
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        Ok(Self {
+            version: text(&desc[16..48]),
+            project: text(&desc[48..80]),
+            idf_version: text(&desc[112..144]),
+            chip_id: u16::from_le_bytes([header[12], header[13]]),
+        })
+    }
+}
+
+/// Where images go: the inactive OTA partition on the board
+pub trait Slot: Send {
+    /// The image running now
+    fn running(&self) -> ImageInfo;
+    /// Whether the running image is new and still has to prove itself
+    fn pending_verify(&self) -> bool;
+    /// Size of the inactive slot
+    fn capacity(&self) -> usize;
+    fn begin(&mut self) -> anyhow::Result<Box<dyn SlotWriter + '_>>;
+    /// Reboots, into the new image if one was completed
+    fn restart(&mut self);
+}
+
+/// An update being written. Dropping it before `complete` abandons the update.
+pub trait SlotWriter {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// Checks the whole image and makes it the one to boot next
+    fn complete(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+/// Downloads for `POST /ota/pull`
+pub trait Fetch: Send {
+    /// GETs `url` and hands the response to `receive`. Anything but a 2xx is an error.
+    fn fetch(&mut self, url: &str, receive: &mut Receive) -> anyhow::Result<()>;
+}
+
+impl<F: FnMut(&str, &mut Receive) -> anyhow::Result<()> + Send> Fetch for F {
+    fn fetch(&mut self, url: &str, receive: &mut Receive) -> anyhow::Result<()> {
+        self(url, receive)
+    }
+}
+
+/// A `POST /ota/pull` body
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Pull {
+    pub url: String,
+}
+
+/// Another update is already running, or one finished and the camera is about to restart
+#[derive(Debug)]
+pub struct Busy;
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("An update is already in progress")
+    }
+}
+
+impl std::error::Error for Busy {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Idle,
+    Receiving,
+    /// Done, about to boot the new image
+    Restarting,
+    Failed,
+}
+
+struct Progress {
+    state: State,
+    /// `upload`, or the URL
+    source: Option<String>,
+    received: usize,
+    total: Option<usize>,
+    image: Option<ImageInfo>,
+    error: Option<String>,
+}
+
+pub struct Ota {
+    slot: Mutex<Box<dyn Slot>>,
+    fetch: Mutex<Option<Box<dyn Fetch>>>,
+    /// Known up front, the slot and fetcher are held for whole updates
+    running: ImageInfo,
+    pending_verify: bool,
+    can_pull: bool,
+    busy: AtomicBool,
+    progress: Mutex<Progress>,
+    status_led: Mutex<Option<StatusLed>>,
+}
+
+impl Ota {
+    /// `fetch` is `None` to only take uploads. Make it after the running image has been
+    /// marked valid, if it's going to be.
+    pub fn new(slot: impl Slot + 'static, fetch: Option<Box<dyn Fetch>>) -> Arc<Self> {
+        Arc::new(Self {
+            running: slot.running(),
+            pending_verify: slot.pending_verify(),
+            can_pull: fetch.is_some(),
+            slot: Mutex::new(Box::new(slot)),
+            fetch: Mutex::new(fetch),
+            busy: AtomicBool::new(false),
+            progress: Mutex::new(Progress {
+                state: State::Idle,
+                source: None,
+                received: 0,
+                total: None,
+                image: None,
+                error: None,
+            }),
+            status_led: Mutex::new(None),
+        })
+    }
+
+    /// Where `Updating` is raised from the start of an update until it's abandoned or the
+    /// camera restarts
+    pub fn set_status_led(&self, status_led: Option<StatusLed>) {
+        *self.status_led.lock().unwrap() = status_led;
+    }
+
+    pub fn state(&self) -> State {
+        self.progress.lock().unwrap().state
+    }
+
+    /// What `GET /ota` answers
+    pub fn status_json(&self) -> String {
+        let progress = self.progress.lock().unwrap();
+        serde_json::json!({
+            "running": self.running,
+            "pending_verify": self.pending_verify,
+            "pull": self.can_pull,
+            "state": progress.state,
+            "source": progress.source,
+            "received": progress.received,
+            "total": progress.total,
+            "image": progress.image,
+            "error": progress.error,
+        })
+        .to_string()
+    }
+
+    /// Writes the image `read` yields (`total` bytes, if known) to the inactive slot, then
+    /// restarts into it shortly after returning
+    pub fn update(
+        self: &Arc<Self>,
+        total: Option<usize>,
+        read: &mut Body,
+    ) -> anyhow::Result<ImageInfo> {
+        self.claim()?;
+        let result = self.write_image("upload", total, read);
+        self.finish(result.is_ok());
+        result
+    }
+
+    /// Downloads the image at `url` and updates from it, in the background. Follow along
+    /// with `status_json`.
+    pub fn pull(self: &Arc<Self>, url: &str) -> anyhow::Result<()> {
+        if !self.can_pull {
+            anyhow::bail!("Pulling updates is not set up");
+        }
+        self.claim()?;
+        self.start(url, None);
+
+        let ota = self.clone();
+        let url = url.to_owned();
+        let spawned = thread::Builder::new()
+            .name("ota".into())
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                let mut fetch = ota.fetch.lock().unwrap();
+                let fetch = fetch.as_mut().unwrap();
+                let result = fetch.fetch(&url, &mut |total, read| {
+                    ota.write_image(&url, total, read).map(drop)
+                });
+                if let Err(e) = &result {
+                    ota.fail(e);
+                }
+                ota.finish(result.is_ok());
+            });
+        if let Err(e) = spawned {
+            self.busy.store(false, Ordering::SeqCst);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn claim(&self) -> anyhow::Result<()> {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(Busy.into());
+        }
+        Ok(())
+    }
+
+    /// Restarts after a good update, otherwise lets the next one in
+    fn finish(self: &Arc<Self>, success: bool) {
+        if !success {
+            if let Some(led) = self.status_led.lock().unwrap().as_ref() {
+                led.clear(Status::Updating);
+            }
+            self.busy.store(false, Ordering::SeqCst);
+            return;
+        }
+        self.progress.lock().unwrap().state = State::Restarting;
+        let ota = self.clone();
+        thread::spawn(move || {
+            thread::sleep(RESTART_DELAY);
+            log::info!("Restarting into the new firmware");
+            ota.slot.lock().unwrap().restart();
+        });
+    }
+
+    fn start(&self, source: &str, total: Option<usize>) {
+        if let Some(led) = self.status_led.lock().unwrap().as_ref() {
+            led.raise(Status::Updating);
+        }
+        *self.progress.lock().unwrap() = Progress {
+            state: State::Receiving,
+            source: Some(source.to_owned()),
+            received: 0,
+            total,
+            image: None,
+            error: None,
+        };
+    }
+
+    fn fail(&self, e: &anyhow::Error) {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.state != State::Failed {
+            log::warn!("Update failed: {:#}", e);
+            progress.state = State::Failed;
+            progress.error = Some(format!("{:#}", e));
+        }
+    }
+
+    fn write_image(
+        &self,
+        source: &str,
+        total: Option<usize>,
+        read: &mut Body,
+    ) -> anyhow::Result<ImageInfo> {
+        self.start(source, total);
+        log::info!("Updating from {}", source);
+        let result = self.write_to_slot(total, read);
+        match &result {
+            Ok(image) => log::info!("Firmware {} {} written", image.project, image.version),
+            Err(e) => self.fail(e),
+        }
+        result
+    }
+
+    fn write_to_slot(&self, total: Option<usize>, read: &mut Body) -> anyhow::Result<ImageInfo> {
+        let mut slot = self.slot.lock().unwrap();
+        let capacity = slot.capacity();
+        if total.is_some_and(|total| total > capacity) {
+            anyhow::bail!("Image doesn't fit, the slot holds {} bytes", capacity);
+        }
+
+        // The header first, nothing is written until it checks out
+        let mut received = 0;
+        let mut buf = vec![0; CHUNK];
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        while header.len() < HEADER_LEN {
+            let len = self.read_chunk(read, &mut buf, &mut received, capacity)?;
+            if len == 0 {
+                anyhow::bail!("Too short to be firmware");
+            }
+            header.extend_from_slice(&buf[..len]);
+        }
+        let image = ImageInfo::parse(&header)?;
+        check(&image, &self.running)?;
+        self.progress.lock().unwrap().image = Some(image.clone());
+
+        // Opening the slot starts by erasing it
+        let mut writer = slot.begin()?;
+        writer.write(&header)?;
+        loop {
+            let len = self.read_chunk(read, &mut buf, &mut received, capacity)?;
+            if len == 0 {
+                break;
+            }
+            writer.write(&buf[..len])?;
+        }
+
+        if let Some(total) = total.filter(|&total| received < total) {
+            anyhow::bail!("Only got {} of {} bytes", received, total);
+        }
+        writer.complete()?;
+        Ok(image)
+    }
+
+    fn read_chunk(
+        &self,
+        read: &mut Body,
+        buf: &mut [u8],
+        received: &mut usize,
+        capacity: usize,
+    ) -> anyhow::Result<usize> {
+        let len = read(buf)?;
+        *received += len;
+        if *received > capacity {
+            anyhow::bail!("Image doesn't fit, the slot holds {} bytes", capacity);
+        }
+        self.progress.lock().unwrap().received = *received;
+        Ok(len)
+    }
+}
+
+/// Whether `image` can replace `running`
+fn check(image: &ImageInfo, running: &ImageInfo) -> anyhow::Result<()> {
+    if image.chip_id != running.chip_id {
+        anyhow::bail!(
+            "Image is for chip {}, this is chip {}",
+            image.chip_id,
+            running.chip_id
+        );
+    }
+    if image.project != running.project {
+        anyhow::bail!(
+            "Image is {:?}, not {:?} firmware",
+            image.project,
+            running.project
+        );
+    }
+    Ok(())
+}
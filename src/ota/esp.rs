@@ -0,0 +1,116 @@
+use std::ffi::CStr;
+use std::ptr;
+
+use esp_idf_svc::io::Write;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_svc::sys;
+
+use super::{ImageInfo, Slot, SlotWriter};
+
+/// The OTA partitions, through the one `EspOta` there can be. Also what decides at boot
+/// whether a freshly updated image stays.
+pub struct EspSlot {
+    ota: EspOta,
+}
+
+impl EspSlot {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            ota: EspOta::new()?,
+        })
+    }
+
+    /// Something a new image has to get through before it's trusted. If it fails while the
+    /// image is still pending verification, this rolls back and reboots into the old one.
+    pub fn require<T>(&mut self, what: &str, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        if let Err(e) = &result {
+            if self.pending_verify() {
+                log::error!("{} failed on new firmware, rolling back: {:#}", what, e);
+                let e = self.ota.mark_running_slot_invalid_and_reboot();
+                log::error!("Rolling back failed: {}", e);
+            }
+        }
+        result
+    }
+
+    /// Everything `require`d worked: keep the running image, and stop the bootloader from
+    /// going back
+    pub fn mark_valid(&mut self) -> anyhow::Result<()> {
+        if self.pending_verify() {
+            log::info!("New firmware works, keeping it");
+        }
+        self.ota.mark_running_slot_valid()?;
+        Ok(())
+    }
+}
+
+impl Slot for EspSlot {
+    fn running(&self) -> ImageInfo {
+        // SAFETY: points at the description linked into the running app, valid forever
+        let desc = unsafe { &*sys::esp_app_get_description() };
+        let text = |field: &[std::ffi::c_char]| {
+            // SAFETY: the fields are NUL-terminated C strings
+            unsafe { CStr::from_ptr(field.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        ImageInfo {
+            project: text(&desc.project_name),
+            version: text(&desc.version),
+            idf_version: text(&desc.idf_ver),
+            chip_id: sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16,
+        }
+    }
+
+    fn pending_verify(&self) -> bool {
+        let mut state = sys::esp_ota_img_states_t_ESP_OTA_IMG_UNDEFINED;
+        // SAFETY: the running partition is static, `state` outlives the call
+        let err = unsafe {
+            sys::esp_ota_get_state_partition(sys::esp_ota_get_running_partition(), &mut state)
+        };
+        err == sys::ESP_OK && state == sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+    }
+
+    fn capacity(&self) -> usize {
+        // SAFETY: partitions are static; null means there's no second slot
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            return 0;
+        }
+        unsafe { (*partition).size as usize }
+    }
+
+    fn begin(&mut self) -> anyhow::Result<Box<dyn SlotWriter + '_>> {
+        Ok(Box::new(EspWriter(Some(self.ota.initiate_update()?))))
+    }
+
+    fn restart(&mut self) {
+        esp_idf_svc::hal::reset::restart();
+    }
+}
+
+/// `None` once completed
+struct EspWriter<'a>(Option<EspOtaUpdate<'a>>);
+
+impl SlotWriter for EspWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.0.as_mut().unwrap().write_all(data)?;
+        Ok(())
+    }
+
+    fn complete(mut self: Box<Self>) -> anyhow::Result<()> {
+        // Checks the image and sets it to boot next
+        self.0.take().unwrap().complete()?;
+        Ok(())
+    }
+}
+
+impl Drop for EspWriter<'_> {
+    fn drop(&mut self) {
+        if let Some(update) = self.0.take() {
+            if let Err(e) = update.abort() {
+                log::warn!("Abandoning the update failed: {}", e);
+            }
+        }
+    }
+}
@@ -1,21 +1,15 @@
-
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys::camera::*;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::sys::camera::{
-    esp_camera_init,
-    camera_config_t,
-
-    pixformat_t_PIXFORMAT_JPEG,
-    pixformat_t_PIXFORMAT_RGB888,
-
-    framesize_t_FRAMESIZE_SVGA, 
-    framesize_t_FRAMESIZE_QVGA,
-    framesize_t_FRAMESIZE_QXGA,
-
-    ledc_channel_t_LEDC_CHANNEL_0,
-    ledc_timer_t_LEDC_TIMER_0,
-    ESP_OK
-};
-use esp_idf_svc::sys::camera::*; // Import all 
+    camera_config_t, esp_camera_init, framesize_t_FRAMESIZE_QVGA, framesize_t_FRAMESIZE_QXGA,
+    framesize_t_FRAMESIZE_SVGA, ledc_channel_t_LEDC_CHANNEL_0, ledc_timer_t_LEDC_TIMER_0,
+    pixformat_t_PIXFORMAT_JPEG, pixformat_t_PIXFORMAT_RGB888, ESP_OK,
+}; // Import all
 
+#[cfg(target_os = "espidf")]
+use crate::board;
+use crate::camera::{Control, FrameSize};
 
 #[derive(Clone, Copy)]
 pub enum OV3660Format {
@@ -37,6 +31,16 @@ pub enum OV3660Resolution {
     LowRes,
 }
 
+impl OV3660Resolution {
+    pub fn frame_size(self) -> FrameSize {
+        match self {
+            OV3660Resolution::HighRes => FrameSize::Qxga,
+            OV3660Resolution::MedRes => FrameSize::Svga,
+            OV3660Resolution::LowRes => FrameSize::Qvga,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum OV3660ClockSpeed {
     /// 20MHz (Standard)
@@ -53,7 +57,12 @@ pub struct OV3660Config {
 }
 
 impl OV3660Config {
-    pub fn new(format: OV3660Format, camera_resolution: OV3660Resolution, double_buffered: bool, clock_speed: OV3660ClockSpeed) -> Self {
+    pub fn new(
+        format: OV3660Format,
+        camera_resolution: OV3660Resolution,
+        double_buffered: bool,
+        clock_speed: OV3660ClockSpeed,
+    ) -> Self {
         Self {
             format,
             camera_resolution,
@@ -65,10 +74,10 @@ impl OV3660Config {
     /// Best for getting 25+ FPS
     pub fn fast_streaming() -> Self {
         Self::new(
-            OV3660Format::JPEG { quality: 12 }, 
-            OV3660Resolution::LowRes, 
-            true, 
-            OV3660ClockSpeed::High
+            OV3660Format::JPEG { quality: 12 },
+            OV3660Resolution::LowRes,
+            true,
+            OV3660ClockSpeed::High,
         )
     }
 
@@ -76,41 +85,56 @@ impl OV3660Config {
     pub fn high_quality() -> Self {
         Self::new(
             OV3660Format::JPEG { quality: 10 }, // 0 is risky, 10 is safe high-quality
-            OV3660Resolution::HighRes, 
-            false, // HighRes usually requires single buffer due to RAM limits
-            OV3660ClockSpeed::Low // Slower clock for better signal stability on large frames
+            OV3660Resolution::HighRes,
+            false,                 // HighRes usually requires single buffer due to RAM limits
+            OV3660ClockSpeed::Low, // Slower clock for better signal stability on large frames
         )
     }
 
+    /// The same settings as runtime camera controls, for switching to them without
+    /// re-initialising the camera
+    pub fn controls(&self) -> Vec<(Control, i32)> {
+        let mut controls = vec![(
+            Control::FrameSize,
+            self.camera_resolution.frame_size().index(),
+        )];
+        if let OV3660Format::JPEG { quality } = self.format {
+            controls.push((Control::Quality, quality.clamp(4, 63) as i32));
+        }
+        controls
+    }
+
     /// Good balance for general use
     pub fn balanced() -> Self {
         Self::new(
-            OV3660Format::JPEG { quality: 12 }, 
-            OV3660Resolution::MedRes, 
-            true, 
-            OV3660ClockSpeed::High
+            OV3660Format::JPEG { quality: 12 },
+            OV3660Resolution::MedRes,
+            true,
+            OV3660ClockSpeed::High,
         )
     }
 }
 
+#[cfg(target_os = "espidf")]
 pub fn start_ov3660(user_config: OV3660Config) -> anyhow::Result<()> {
     let mut camera_config = camera_config_t::default();
 
-    // Map the pins from your pinout module
-    camera_config.pin_pwdn = pinout::PWDN;
-    camera_config.pin_reset = pinout::RESET;
-    camera_config.pin_xclk = pinout::XCLK;
-    camera_config.pin_d7 = pinout::Y9;
-    camera_config.pin_d6 = pinout::Y8;
-    camera_config.pin_d5 = pinout::Y7;
-    camera_config.pin_d4 = pinout::Y6;
-    camera_config.pin_d3 = pinout::Y5;
-    camera_config.pin_d2 = pinout::Y4;
-    camera_config.pin_d1 = pinout::Y3;
-    camera_config.pin_d0 = pinout::Y2;
-    camera_config.pin_vsync = pinout::VSYNC;
-    camera_config.pin_href = pinout::HREF;
-    camera_config.pin_pclk = pinout::PCLK;
+    // Map the pins from the board profile
+    let pins = board::current().camera;
+    camera_config.pin_pwdn = pins.pwdn;
+    camera_config.pin_reset = pins.reset;
+    camera_config.pin_xclk = pins.xclk;
+    camera_config.pin_d7 = pins.data[7];
+    camera_config.pin_d6 = pins.data[6];
+    camera_config.pin_d5 = pins.data[5];
+    camera_config.pin_d4 = pins.data[4];
+    camera_config.pin_d3 = pins.data[3];
+    camera_config.pin_d2 = pins.data[2];
+    camera_config.pin_d1 = pins.data[1];
+    camera_config.pin_d0 = pins.data[0];
+    camera_config.pin_vsync = pins.vsync;
+    camera_config.pin_href = pins.href;
+    camera_config.pin_pclk = pins.pclk;
 
     // Logic Configuration
     camera_config.xclk_freq_hz = match user_config.clock_speed {
@@ -136,16 +160,16 @@ pub fn start_ov3660(user_config: OV3660Config) -> anyhow::Result<()> {
     };
 
     camera_config.fb_count = if user_config.double_buffered { 2 } else { 1 };
-    camera_config.fb_location = 0; 
-    camera_config.grab_mode = 0;   
+    camera_config.fb_location = 0;
+    camera_config.grab_mode = 0;
     camera_config.ledc_timer = ledc_timer_t_LEDC_TIMER_0;
     camera_config.ledc_channel = ledc_channel_t_LEDC_CHANNEL_0;
 
     // --- UNSAFE BLOCK FOR UNION & INIT ---
     unsafe {
         // Union assignments MUST be inside unsafe
-        camera_config.__bindgen_anon_1.pin_sccb_sda = pinout::SIOD;
-        camera_config.__bindgen_anon_2.pin_sccb_scl = pinout::SIOC;
+        camera_config.__bindgen_anon_1.pin_sccb_sda = pins.siod;
+        camera_config.__bindgen_anon_2.pin_sccb_scl = pins.sioc;
 
         let err = esp_camera_init(&camera_config);
         if err != ESP_OK {
@@ -155,23 +179,3 @@ pub fn start_ov3660(user_config: OV3660Config) -> anyhow::Result<()> {
 
     Ok(())
 }
-// FREENOVE WROVER-E PINOUT
-// (Matches standard WROVER-KIT definition)
-mod pinout {
-    pub const PWDN: i32 = -1;
-    pub const RESET: i32 = -1;
-    pub const XCLK: i32 = 21;
-    pub const SIOD: i32 = 26;
-    pub const SIOC: i32 = 27;
-    pub const Y9: i32 = 35;
-    pub const Y8: i32 = 34;
-    pub const Y7: i32 = 39;
-    pub const Y6: i32 = 36;
-    pub const Y5: i32 = 19;
-    pub const Y4: i32 = 18;
-    pub const Y3: i32 = 5;
-    pub const Y2: i32 = 4;
-    pub const VSYNC: i32 = 25;
-    pub const HREF: i32 = 23;
-    pub const PCLK: i32 = 22;
-}
@@ -0,0 +1,164 @@
+//! Burns a label (camera name + time) into frames.
+//!
+//! Raw frames are drawn on in place. JPEG frames have to be decoded and re-encoded, which
+//! is slow and needs PSRAM for anything above QVGA, so by default only snapshots get it.
+
+mod font;
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::FrameBuffer;
+use crate::clock;
+use crate::codec::Image;
+use crate::settings::OverlaySettings;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Clone, Debug)]
+pub struct OverlayConfig {
+    /// Shown before the time, leave empty for just the time
+    pub name: String,
+    pub corner: Corner,
+    /// 1 = 5x7 pixels per character
+    pub scale: u32,
+    /// Only stamp `/capture`, not every stream frame
+    pub snapshots_only: bool,
+    /// Re-encode quality for JPEG frames, 1-100
+    pub jpeg_quality: u8,
+}
+
+impl OverlayConfig {
+    pub fn new(name: impl Into<String>, corner: Corner) -> Self {
+        Self {
+            name: name.into(),
+            corner,
+            scale: 2,
+            snapshots_only: true,
+            jpeg_quality: 85,
+        }
+    }
+
+    pub fn from_settings(name: impl Into<String>, settings: &OverlaySettings) -> Self {
+        Self {
+            snapshots_only: settings.snapshots_only,
+            ..Self::new(name, settings.corner)
+        }
+    }
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self::new("", Corner::BottomLeft)
+    }
+}
+
+pub struct Overlay {
+    config: OverlayConfig,
+}
+
+impl Overlay {
+    pub fn new(config: OverlayConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &OverlayConfig {
+        &self.config
+    }
+
+    /// Name plus local wall-clock time, or uptime if the clock hasn't been set yet
+    pub fn label(&self, wall_time: Option<SystemTime>, uptime: Duration) -> String {
+        let time = match wall_time {
+            Some(time) => clock::format_local(time),
+            None => clock::format_uptime(uptime),
+        };
+
+        if self.config.name.is_empty() {
+            time
+        } else {
+            format!("{} {}", self.config.name, time)
+        }
+    }
+
+    /// Stamps the label onto `frame`, re-encoding it if it is a JPEG
+    pub fn apply(&self, frame: &mut FrameBuffer) -> anyhow::Result<()> {
+        let label = self.label(frame.wall_time, frame.timestamp);
+        frame.edit(self.config.jpeg_quality, |image| self.draw(image, &label))
+    }
+
+    /// Draws `text` in the configured corner
+    pub fn draw(&self, image: &mut Image, text: &str) {
+        let scale = self.config.scale.max(1);
+        let (text_w, text_h) = text_size(text, scale);
+        let margin = 2 * scale;
+
+        let left = matches!(self.config.corner, Corner::TopLeft | Corner::BottomLeft);
+        let top = matches!(self.config.corner, Corner::TopLeft | Corner::TopRight);
+        let x = if left {
+            margin
+        } else {
+            image.width.saturating_sub(text_w + margin)
+        };
+        let y = if top {
+            margin
+        } else {
+            image.height.saturating_sub(text_h + margin)
+        };
+
+        draw_text(image, x, y, text, scale);
+    }
+}
+
+/// Size of the box `draw_text` fills, including its one-pixel (times scale) padding
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let chars = text.chars().count() as u32;
+    let width = if chars == 0 {
+        0
+    } else {
+        chars * (font::WIDTH + 1) - 1
+    };
+    ((width + 2) * scale, (font::HEIGHT + 2) * scale)
+}
+
+/// White text on a black box with its top-left corner at (`x`, `y`). Clipped to the image.
+pub fn draw_text(image: &mut Image, x: u32, y: u32, text: &str, scale: u32) {
+    let (box_w, box_h) = text_size(text, scale);
+    fill(image, x, y, box_w, box_h, 0);
+
+    let mut pen_x = x + scale;
+    for c in text.chars() {
+        let rows = font::glyph(c);
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..font::WIDTH {
+                if bits & (0b10000 >> col) != 0 {
+                    let px = pen_x + col * scale;
+                    let py = y + scale + row as u32 * scale;
+                    fill(image, px, py, scale, scale, 255);
+                }
+            }
+        }
+        pen_x += (font::WIDTH + 1) * scale;
+    }
+}
+
+fn fill(image: &mut Image, x: u32, y: u32, w: u32, h: u32, level: u8) {
+    let bpp = image.format.bytes_per_pixel();
+    let x_end = (x + w).min(image.width);
+    let y_end = (y + h).min(image.height);
+    if x >= x_end {
+        return;
+    }
+
+    for row in y..y_end {
+        let pixels = image.row_mut(row);
+        pixels[x as usize * bpp..x_end as usize * bpp].fill(level);
+    }
+}
@@ -0,0 +1,385 @@
+//! Keeps the last few seconds of frames, so an event clip can start before the event did.
+//!
+//! `Prebuffer` is the bookkeeping: a ring of recent frames that evicts the oldest once it
+//! covers more than `pre` or goes over its byte budget, and that an event freezes and then
+//! tops up with `post` more seconds. `EventBuffer` feeds it from the camera on a thread and
+//! hands each finished `EventClip` to a `ClipSink` (SD card, uploader, MQTT).
+//!
+//! Frames are plain heap `Vec`s; with `CONFIG_SPIRAM_USE_MALLOC` anything over
+//! `CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL` lands in PSRAM, which every JPEG frame is.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use crate::avi::AviWriter;
+use crate::camera::{FrameBuffer, FrameFormat, Pacer};
+use crate::clock;
+use crate::http::App;
+use crate::settings::PrebufferSettings;
+
+#[derive(Clone, Debug)]
+pub struct PrebufferConfig {
+    /// Frames kept and collected together never take more than this
+    pub budget_bytes: usize,
+    /// How far back a clip starts before the trigger
+    pub pre: Duration,
+    /// How long a clip carries on after the (last) trigger
+    pub post: Duration,
+    /// Longest a clip can run past its first trigger, however often it is re-triggered
+    pub max_post: Duration,
+}
+
+impl PrebufferConfig {
+    pub fn from_settings(settings: &PrebufferSettings) -> Self {
+        Self {
+            budget_bytes: settings.budget_kb as usize * 1024,
+            pre: Duration::from_secs(settings.pre_secs as u64),
+            post: Duration::from_secs(settings.post_secs as u64),
+            max_post: Duration::from_secs(settings.max_post_secs as u64),
+        }
+    }
+}
+
+/// Frames from before and after a trigger
+#[derive(Clone, Debug)]
+pub struct EventClip {
+    pub reason: String,
+    /// Frame timestamp of the first trigger
+    pub triggered_at: Duration,
+    /// Wall-clock time of the first trigger, if the clock was synced
+    pub wall_time: Option<SystemTime>,
+    /// How many of `frames` came before the trigger
+    pub pre_frames: usize,
+    pub frames: Vec<FrameBuffer>,
+}
+
+impl EventClip {
+    pub fn bytes(&self) -> usize {
+        self.frames.iter().map(|frame| frame.data.len()).sum()
+    }
+
+    pub fn duration(&self) -> Duration {
+        match (self.frames.first(), self.frames.last()) {
+            (Some(first), Some(last)) => last.timestamp.saturating_sub(first.timestamp),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// The clip as an MJPEG AVI in memory, for uploading or publishing in one piece
+    pub fn to_avi(&self, fps: u32) -> anyhow::Result<Vec<u8>> {
+        let Some(first) = self.frames.first() else {
+            anyhow::bail!("Clip has no frames");
+        };
+        let mut writer = AviWriter::new(Cursor::new(Vec::new()), first.width, first.height, fps)?;
+        for frame in &self.frames {
+            writer.write_frame(&frame.data, frame.timestamp)?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+}
+
+struct Capture {
+    clip: EventClip,
+    bytes: usize,
+    /// Frames up to this timestamp still belong to the clip
+    until: Duration,
+}
+
+/// Ring of recent frames plus the clip being collected, if any
+pub struct Prebuffer {
+    config: PrebufferConfig,
+    ring: VecDeque<FrameBuffer>,
+    ring_bytes: usize,
+    capture: Option<Capture>,
+}
+
+impl Prebuffer {
+    pub fn new(config: PrebufferConfig) -> Self {
+        Self {
+            config,
+            ring: VecDeque::new(),
+            ring_bytes: 0,
+            capture: None,
+        }
+    }
+
+    pub fn config(&self) -> &PrebufferConfig {
+        &self.config
+    }
+
+    /// Adds the newest frame. Returns the clip once a capture has collected its last frame.
+    pub fn push(&mut self, frame: FrameBuffer) -> Option<EventClip> {
+        if frame.format != FrameFormat::Jpeg {
+            log::warn!("Pre-event buffer only keeps JPEG frames");
+            return None;
+        }
+
+        let Some(capture) = self.capture.as_mut() else {
+            self.keep(frame);
+            return None;
+        };
+
+        let over_budget = capture.bytes + frame.data.len() > self.config.budget_bytes;
+        if over_budget {
+            log::warn!("Event clip hit the memory budget, cutting it short");
+            let clip = self.finish();
+            self.keep(frame);
+            return clip;
+        }
+        let last = frame.timestamp >= capture.until;
+        capture.bytes += frame.data.len();
+        capture.clip.frames.push(frame);
+        if last {
+            self.finish()
+        } else {
+            None
+        }
+    }
+
+    /// Freezes the recent frames into a clip that runs until `post` after `at`. While a clip
+    /// is being collected, triggers push its end back (up to `max_post` past the first).
+    pub fn trigger(&mut self, reason: &str, at: Duration) {
+        if let Some(capture) = self.capture.as_mut() {
+            let limit = capture.clip.triggered_at + self.config.max_post;
+            capture.until = capture.until.max(at + self.config.post).min(limit);
+            return;
+        }
+
+        let frames: Vec<_> = self.ring.drain(..).collect();
+        let wall_time = frames
+            .last()
+            .and_then(|frame| frame.wall_time)
+            .or_else(clock::wall_clock);
+        self.capture = Some(Capture {
+            bytes: std::mem::take(&mut self.ring_bytes),
+            until: at + self.config.post.min(self.config.max_post),
+            clip: EventClip {
+                reason: reason.to_owned(),
+                triggered_at: at,
+                wall_time,
+                pre_frames: frames.len(),
+                frames,
+            },
+        });
+    }
+
+    /// Ends the capture early, with whatever it has
+    pub fn finish(&mut self) -> Option<EventClip> {
+        self.capture.take().map(|capture| capture.clip)
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Frames held, in the ring and the clip being collected
+    pub fn len(&self) -> usize {
+        self.ring.len() + self.capture.as_ref().map_or(0, |c| c.clip.frames.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held, in the ring and the clip being collected
+    pub fn bytes(&self) -> usize {
+        self.ring_bytes + self.capture.as_ref().map_or(0, |c| c.bytes)
+    }
+
+    /// Timestamps of the frames in the ring, oldest first
+    pub fn timestamps(&self) -> Vec<Duration> {
+        self.ring.iter().map(|frame| frame.timestamp).collect()
+    }
+
+    fn keep(&mut self, frame: FrameBuffer) {
+        if frame.data.len() > self.config.budget_bytes {
+            log::warn!(
+                "{} byte frame doesn't fit the {} byte pre-event budget",
+                frame.data.len(),
+                self.config.budget_bytes
+            );
+            return;
+        }
+
+        let newest = frame.timestamp;
+        self.ring_bytes += frame.data.len();
+        self.ring.push_back(frame);
+
+        while let Some(oldest) = self.ring.front() {
+            let too_old = newest.saturating_sub(oldest.timestamp) > self.config.pre;
+            if !too_old && self.ring_bytes <= self.config.budget_bytes {
+                break;
+            }
+            self.ring_bytes -= oldest.data.len();
+            self.ring.pop_front();
+        }
+    }
+}
+
+/// Somewhere to put finished event clips
+pub trait ClipSink: Send {
+    fn store(&mut self, clip: &EventClip) -> anyhow::Result<()>;
+}
+
+impl<F: FnMut(&EventClip) -> anyhow::Result<()> + Send> ClipSink for F {
+    fn store(&mut self, clip: &EventClip) -> anyhow::Result<()> {
+        self(clip)
+    }
+}
+
+/// Writes clips as `<dir>/<local time>_<reason>.avi`, or `<dir>/<uptime ms>_<reason>.avi`
+/// before the clock is synced
+pub struct AviDirSink {
+    dir: PathBuf,
+    fps: u32,
+}
+
+impl AviDirSink {
+    pub fn new(dir: impl Into<PathBuf>, fps: u32) -> Self {
+        Self {
+            dir: dir.into(),
+            fps,
+        }
+    }
+
+    /// Where `clip` would be written
+    pub fn path_for(&self, clip: &EventClip) -> PathBuf {
+        let stamp = match clip.wall_time {
+            Some(time) => clock::format_compact(time),
+            None => clip.triggered_at.as_millis().to_string(),
+        };
+        self.dir.join(format!("{}_{}.avi", stamp, clip.reason))
+    }
+}
+
+impl ClipSink for AviDirSink {
+    fn store(&mut self, clip: &EventClip) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Can't create {}", self.dir.display()))?;
+        let path = self.path_for(clip);
+        fs::write(&path, clip.to_avi(self.fps)?)
+            .with_context(|| format!("Can't write {}", path.display()))?;
+        log::info!(
+            "Saved event clip {} ({} frames, {} before the trigger)",
+            path.display(),
+            clip.frames.len(),
+            clip.pre_frames
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    clips: u64,
+    failures: u64,
+    last_error: Option<String>,
+}
+
+/// A `Prebuffer` fed from the camera on a background thread
+pub struct EventBuffer {
+    prebuffer: Mutex<Prebuffer>,
+    /// Timestamp of the newest frame, for triggers that don't come with one
+    latest: Mutex<Duration>,
+    stats: Mutex<Stats>,
+}
+
+impl EventBuffer {
+    /// Starts buffering stream frames at `fps`. Only holds a weak reference to the app.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        prebuffer: Prebuffer,
+        fps: f32,
+        mut sink: impl ClipSink + 'static,
+    ) -> Arc<Self> {
+        let buffer = Arc::new(Self {
+            prebuffer: Mutex::new(prebuffer),
+            latest: Mutex::new(Duration::ZERO),
+            stats: Mutex::new(Stats::default()),
+        });
+
+        let this = buffer.clone();
+        thread::spawn(move || {
+            let mut pacer = Pacer::new(fps);
+            loop {
+                pacer.wait();
+                let Some(app) = weak_app.upgrade() else {
+                    break;
+                };
+                let frame = match app.stream_frame() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        continue;
+                    }
+                };
+                drop(app);
+
+                *this.latest.lock().unwrap() = frame.timestamp;
+                let clip = this.prebuffer.lock().unwrap().push(frame);
+                // Stored outside the lock, triggers keep working while the card is slow
+                if let Some(clip) = clip {
+                    let result = sink.store(&clip);
+                    this.stored(result);
+                }
+            }
+        });
+
+        buffer
+    }
+
+    /// Starts a clip, or makes the current one run longer
+    pub fn trigger(&self, reason: &str) {
+        let at = *self.latest.lock().unwrap();
+        self.prebuffer.lock().unwrap().trigger(reason, at);
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.prebuffer.lock().unwrap().is_capturing()
+    }
+
+    fn stored(&self, result: anyhow::Result<()>) {
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                stats.clips += 1;
+                stats.last_error = None;
+            }
+            Err(e) => {
+                log::warn!("Couldn't store event clip: {}", e);
+                stats.failures += 1;
+                stats.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Buffer fill and clip counters, as JSON
+    pub fn status_json(&self) -> String {
+        let prebuffer = self.prebuffer.lock().unwrap();
+        let stats = self.stats.lock().unwrap();
+        serde_json::json!({
+            "frames": prebuffer.len(),
+            "bytes": prebuffer.bytes(),
+            "budget_bytes": prebuffer.config().budget_bytes,
+            "capturing": prebuffer.is_capturing(),
+            "clips": stats.clips,
+            "failures": stats.failures,
+            "last_error": stats.last_error,
+        })
+        .to_string()
+    }
+}
+
+/// Free PSRAM, to size the budget by. 0 on boards without it.
+#[cfg(target_os = "espidf")]
+pub fn free_psram() -> usize {
+    // SAFETY: plain query, no pointers involved
+    unsafe { esp_idf_svc::sys::heap_caps_get_free_size(esp_idf_svc::sys::MALLOC_CAP_SPIRAM) }
+}
@@ -0,0 +1,23 @@
+//! Random numbers for ids, sequence numbers, tokens and salts.
+//!
+//! From the OS on a PC, and from `esp_fill_random` on the ESP32, which is a true random
+//! source while the radio is on (always, once WiFi is up).
+
+/// Fills `bytes` with random data
+pub fn fill(bytes: &mut [u8]) {
+    // Only fails on platforms without any source of randomness, and nothing here should run
+    // with predictable tokens
+    getrandom::getrandom(bytes).expect("no random number source");
+}
+
+/// 64 random bits
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// 32 random bits
+pub fn u32() -> u32 {
+    u64() as u32
+}
@@ -0,0 +1,287 @@
+//! Records frames into rotating AVI clips in a directory (the SD card on the board).
+//!
+//! Clips are named `<sequence>_<local time>.avi`, or just `<sequence>.avi` before the clock
+//! is synced, so name order is recording order even across reboots. Clips that were cut
+//! off by a reset are repaired when the recorder starts, and the oldest ones are deleted
+//! once the directory goes over its size budget.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::avi::{self, AviWriter};
+use crate::camera::{FrameBuffer, FrameFormat, Pacer};
+use crate::clock;
+use crate::http::App;
+use crate::settings::RecordingSettings;
+
+/// How often written frames are forced out to the card. Anything newer is lost on a reset.
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    /// Start a new clip once the current one is this big
+    pub max_clip_bytes: u64,
+    /// ... or this long
+    pub max_clip_duration: Duration,
+    /// Delete the oldest clips when all of them together go over this, 0 = never
+    pub max_total_bytes: u64,
+    /// Nominal rate written to the header, the real one is worked out from timestamps
+    pub fps: u32,
+}
+
+impl RecorderConfig {
+    pub fn new(dir: impl Into<PathBuf>, settings: &RecordingSettings) -> Self {
+        Self {
+            dir: dir.into(),
+            max_clip_bytes: settings.max_clip_mb as u64 * 1024 * 1024,
+            max_clip_duration: Duration::from_secs(settings.max_clip_secs as u64),
+            max_total_bytes: settings.max_total_mb as u64 * 1024 * 1024,
+            fps: settings.fps,
+        }
+    }
+}
+
+struct Clip {
+    path: PathBuf,
+    writer: AviWriter<BufWriter<File>>,
+    width: u32,
+    height: u32,
+    /// `None` until the first frame, which is synced straight away so the header is on disk
+    last_sync: Option<Instant>,
+}
+
+pub struct Recorder {
+    config: RecorderConfig,
+    clip: Option<Clip>,
+    next_sequence: u32,
+}
+
+impl Recorder {
+    /// Creates the directory if needed and repairs any clips left unfinished
+    pub fn new(config: RecorderConfig) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Can't create {}", config.dir.display()))?;
+
+        let mut next_sequence = 0;
+        for path in clips(&config.dir)? {
+            if let Some(sequence) = sequence(&path) {
+                next_sequence = next_sequence.max(sequence + 1);
+            }
+            if let Err(e) = recover_file(&path) {
+                log::warn!("Can't repair {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(Self {
+            config,
+            clip: None,
+            next_sequence,
+        })
+    }
+
+    /// Appends a JPEG frame, starting a new clip first if the current one is full or the
+    /// resolution changed
+    pub fn write(&mut self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        if frame.format != FrameFormat::Jpeg {
+            anyhow::bail!("Can only record JPEG frames, got {}", frame.format.name());
+        }
+
+        let rotate = self.clip.as_ref().is_some_and(|clip| {
+            clip.width != frame.width
+                || clip.height != frame.height
+                || clip.writer.bytes_written() + frame.data.len() as u64
+                    > self.config.max_clip_bytes
+                || clip.writer.duration() >= self.config.max_clip_duration
+        });
+        if rotate {
+            self.finish()?;
+        }
+
+        if self.clip.is_none() {
+            self.clip = Some(self.open(frame)?);
+        }
+        let clip = self.clip.as_mut().unwrap();
+        clip.writer.write_frame(&frame.data, frame.timestamp)?;
+
+        let sync_due = match clip.last_sync {
+            Some(at) => at.elapsed() >= SYNC_INTERVAL,
+            None => true,
+        };
+        if sync_due {
+            let out = clip.writer.get_mut();
+            out.flush()?;
+            out.get_ref().sync_data()?;
+            clip.last_sync = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Closes the current clip, if any, and returns its path
+    pub fn finish(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        let Some(clip) = self.clip.take() else {
+            return Ok(None);
+        };
+
+        let frames = clip.writer.frames();
+        let file = clip.writer.finish()?.into_inner()?;
+        file.sync_all()?;
+        log::info!("Recorded {} ({} frames)", clip.path.display(), frames);
+
+        self.prune()?;
+        Ok(Some(clip.path))
+    }
+
+    /// Path of the clip being written
+    pub fn current(&self) -> Option<&Path> {
+        self.clip.as_ref().map(|clip| clip.path.as_path())
+    }
+
+    fn open(&mut self, frame: &FrameBuffer) -> anyhow::Result<Clip> {
+        let name = match frame.wall_time {
+            Some(time) => format!(
+                "{:05}_{}.avi",
+                self.next_sequence,
+                clock::format_compact(time)
+            ),
+            None => format!("{:05}.avi", self.next_sequence),
+        };
+        self.next_sequence += 1;
+
+        let path = self.config.dir.join(name);
+        let file =
+            File::create(&path).with_context(|| format!("Can't create {}", path.display()))?;
+        let writer = AviWriter::new(
+            BufWriter::with_capacity(16 * 1024, file),
+            frame.width,
+            frame.height,
+            self.config.fps,
+        )?;
+
+        Ok(Clip {
+            path,
+            writer,
+            width: frame.width,
+            height: frame.height,
+            last_sync: None,
+        })
+    }
+
+    /// Deletes the oldest finished clips until the total is under budget
+    fn prune(&self) -> anyhow::Result<()> {
+        if self.config.max_total_bytes == 0 {
+            return Ok(());
+        }
+
+        let clips = clips(&self.config.dir)?;
+        let sizes = clips
+            .iter()
+            .map(|path| fs::metadata(path).map(|m| m.len()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut total: u64 = sizes.iter().sum();
+
+        for (path, size) in clips.iter().zip(sizes) {
+            if total <= self.config.max_total_bytes || Some(path.as_path()) == self.current() {
+                break;
+            }
+            fs::remove_file(path)?;
+            log::info!("Deleted {} to make room", path.display());
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!("Couldn't finish recording: {}", e);
+        }
+    }
+}
+
+/// Repairs `path` if it was left unfinished. Returns the number of frames saved, `None` if
+/// the file was fine. Clips that didn't even get their header written are deleted.
+pub fn recover_file(path: &Path) -> anyhow::Result<Option<usize>> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    if file.metadata()?.len() < avi::HEADER_LEN as u64 {
+        drop(file);
+        fs::remove_file(path)?;
+        log::warn!("Deleted empty clip {}", path.display());
+        return Ok(Some(0));
+    }
+    if !avi::is_unfinished(&mut file)? {
+        return Ok(None);
+    }
+
+    let frames = avi::recover(&mut file)?;
+    log::warn!(
+        "Repaired unfinished clip {} ({} frames)",
+        path.display(),
+        frames
+    );
+    Ok(Some(frames))
+}
+
+/// `.avi` files in `dir`, oldest first
+pub fn clips(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut clips = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_avi = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("avi"));
+        if is_avi {
+            clips.push(path);
+        }
+    }
+    clips.sort();
+    Ok(clips)
+}
+
+fn sequence(path: &Path) -> Option<u32> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.split('_').next()?.parse().ok()
+}
+
+/// Records from `app` on a background thread until `stop` is set or the app is gone.
+/// Capture errors are logged and skipped, write errors (card full or pulled) end the
+/// recording.
+pub fn spawn(
+    weak_app: Weak<App>,
+    mut recorder: Recorder,
+    fps: u32,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut pacer = Pacer::new(fps as f32);
+        while !stop.load(Ordering::Relaxed) {
+            pacer.wait();
+            let Some(app) = weak_app.upgrade() else {
+                break;
+            };
+            let frame = match app.stream_frame() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::warn!("{}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = recorder.write(&frame) {
+                log::error!("Recording stopped: {}", e);
+                break;
+            }
+        }
+        if let Err(e) = recorder.finish() {
+            log::error!("Couldn't finish recording: {}", e);
+        }
+    })
+}
@@ -0,0 +1,313 @@
+//! RTSP/1.0 messages (RFC 2326): requests in, responses out, and the `Transport` header.
+//!
+//! A connection carries requests and, once a client plays over TCP, interleaved packets
+//! (`$`, channel, 16-bit length, data) mixed in between them, so `parse` handles both.
+
+use std::net::IpAddr;
+
+/// Longest request head accepted, anything bigger is garbage or an attack
+pub const MAX_REQUEST: usize = 8 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The session id, without any `;timeout=`
+    pub fn session(&self) -> Option<&str> {
+        self.header("Session")
+            .map(|session| session.split(';').next().unwrap_or("").trim())
+    }
+}
+
+/// One thing read off a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Incoming {
+    Request(Request),
+    /// `$`-framed data, RTCP receiver reports from TCP clients
+    Interleaved {
+        channel: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Reads one message off the front of `buf`. `None` if it isn't all there yet, otherwise
+/// the message and how many bytes it took.
+pub fn parse(buf: &[u8]) -> anyhow::Result<Option<(Incoming, usize)>> {
+    if let Some(&b'$') = buf.first() {
+        let [_, channel, len0, len1, ..] = *buf else {
+            return Ok(None);
+        };
+        let end = 4 + u16::from_be_bytes([len0, len1]) as usize;
+        let Some(data) = buf.get(4..end) else {
+            return Ok(None);
+        };
+        let data = data.to_vec();
+        return Ok(Some((Incoming::Interleaved { channel, data }, end)));
+    }
+
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("Request head over {} bytes", MAX_REQUEST);
+        }
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| anyhow::anyhow!("Request head isn't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(url), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("Bad request line {:?}", request_line);
+    };
+    if !version.starts_with("RTSP/1.") {
+        anyhow::bail!("Not an RTSP/1.x request: {:?}", request_line);
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Bad header line {:?}", line))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method: method.to_owned(),
+        url: url.to_owned(),
+        headers,
+        body: Vec::new(),
+    };
+    let body_len: usize = match request.header("Content-Length") {
+        Some(len) => len
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Bad Content-Length {:?}", len))?,
+        None => 0,
+    };
+    if body_len > MAX_REQUEST {
+        anyhow::bail!("Request body over {} bytes", MAX_REQUEST);
+    }
+    let end = head_len + 4 + body_len;
+    let Some(body) = buf.get(head_len + 4..end) else {
+        return Ok(None);
+    };
+    request.body = body.to_vec();
+    Ok(Some((Incoming::Request(request), end)))
+}
+
+/// `$`-frames a packet for sending on the RTSP connection
+pub fn interleave(channel: u8, packet: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(packet.len() + 4);
+    framed.extend_from_slice(&[b'$', channel]);
+    framed.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    framed.extend_from_slice(packet);
+    framed
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn with_body(self, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        let mut response = self.with_header("Content-Type", content_type);
+        response.body = body.into();
+        response
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The whole response, answering the request with sequence number `cseq`
+    pub fn to_bytes(&self, cseq: Option<&str>) -> Vec<u8> {
+        let mut head = format!("RTSP/1.0 {} {}\r\n", self.status, reason(self.status));
+        if let Some(cseq) = cseq {
+            head += &format!("CSeq: {}\r\n", cseq);
+        }
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        if !self.body.is_empty() {
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+        }
+        head += "\r\n";
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        406 => "Not Acceptable",
+        429 => "Too Many Requests",
+        454 => "Session Not Found",
+        455 => "Method Not Valid in This State",
+        461 => "Unsupported Transport",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "RTSP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// How a client wants its RTP, from the `Transport` header of a `SETUP`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Unicast UDP to these client ports
+    Udp { rtp: u16, rtcp: u16 },
+    /// `$`-framed on the RTSP connection, on these channels
+    Interleaved { rtp: u8, rtcp: u8 },
+}
+
+impl Transport {
+    /// The first of the (comma separated) options that we can do. Multicast isn't one of
+    /// them.
+    pub fn parse(header: &str) -> anyhow::Result<Self> {
+        header
+            .split(',')
+            .find_map(|option| Self::parse_one(option.trim()))
+            .ok_or_else(|| anyhow::anyhow!("No supported transport in {:?}", header))
+    }
+
+    fn parse_one(option: &str) -> Option<Self> {
+        let mut params = option.split(';').map(str::trim);
+        let tcp = match params.next()? {
+            "RTP/AVP" | "RTP/AVP/UDP" => false,
+            "RTP/AVP/TCP" => true,
+            _ => return None,
+        };
+
+        let mut client_port = None;
+        let mut interleaved = None;
+        for param in params {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name {
+                "multicast" => return None,
+                "client_port" => client_port = Some(pair(value)?),
+                "interleaved" => {
+                    let (rtp, rtcp) = pair(value)?;
+                    interleaved = Some((u8::try_from(rtp).ok()?, u8::try_from(rtcp).ok()?));
+                }
+                _ => {}
+            }
+        }
+
+        if tcp {
+            let (rtp, rtcp) = interleaved.unwrap_or((0, 1));
+            Some(Transport::Interleaved { rtp, rtcp })
+        } else {
+            let (rtp, rtcp) = client_port?;
+            Some(Transport::Udp { rtp, rtcp })
+        }
+    }
+
+    /// The `Transport` header answering a `SETUP`. `server_ports` are our RTP and RTCP
+    /// ports for UDP.
+    pub fn reply(&self, server_ports: (u16, u16), ssrc: u32) -> String {
+        match *self {
+            Transport::Udp { rtp, rtcp } => format!(
+                "RTP/AVP;unicast;client_port={}-{};server_port={}-{};ssrc={:08X}",
+                rtp, rtcp, server_ports.0, server_ports.1, ssrc
+            ),
+            Transport::Interleaved { rtp, rtcp } => format!(
+                "RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                rtp, rtcp, ssrc
+            ),
+        }
+    }
+}
+
+/// `a-b`, or just `a` meaning `a-(a+1)`
+fn pair(value: &str) -> Option<(u16, u16)> {
+    match value.split_once('-') {
+        Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+        None => {
+            let a: u16 = value.parse().ok()?;
+            Some((a, a.checked_add(1)?))
+        }
+    }
+}
+
+/// Session description for `DESCRIBE`: one JPEG video track, `track1` under the base URL
+pub fn sdp(name: &str, server: IpAddr, session_id: u64, fps: f32) -> String {
+    let family = match server {
+        IpAddr::V4(_) => "IP4",
+        IpAddr::V6(_) => "IP6",
+    };
+    format!(
+        "v=0\r\n\
+         o=- {id} 1 IN {family} {server}\r\n\
+         s={name}\r\n\
+         c=IN {family} {any}\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         a=range:npt=0-\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} JPEG/{rate}\r\n\
+         a=control:track1\r\n\
+         a=framerate:{fps}\r\n",
+        id = session_id,
+        family = family,
+        server = server,
+        name = name,
+        any = match server {
+            IpAddr::V4(_) => "0.0.0.0",
+            IpAddr::V6(_) => "::",
+        },
+        pt = super::rtp::PAYLOAD_TYPE,
+        rate = super::rtp::CLOCK_RATE,
+        fps = fps,
+    )
+}
+
+/// `rtsp://host:port/path/`, for the `Content-Base` of a `DESCRIBE`. Players resolve
+/// `track1` against it.
+pub fn content_base(url: &str) -> String {
+    let url = url.split('?').next().unwrap_or(url);
+    if url.ends_with('/') {
+        url.to_owned()
+    } else {
+        format!("{}/", url)
+    }
+}
@@ -0,0 +1,263 @@
+//! RTP/JPEG (RFC 2435): splitting a baseline JPEG into RTP packets.
+//!
+//! The receiver rebuilds the JPEG headers itself, so only the entropy-coded scan goes out,
+//! plus the two quantization tables in the first packet of every frame (Q = 255). Huffman
+//! tables aren't sent at all, the receiver assumes the standard ones, which is what both
+//! the sensor and `codec::encode_jpeg` use.
+
+use std::time::Duration;
+
+/// Static payload type for JPEG
+pub const PAYLOAD_TYPE: u8 = 26;
+/// RTP timestamp ticks per second for video
+pub const CLOCK_RATE: u32 = 90_000;
+pub const RTP_HEADER_LEN: usize = 12;
+/// Q values of 128 and up mean the tables are in the packet
+const Q_INLINE_TABLES: u8 = 255;
+/// Type bit for a restart marker header after the main one
+const TYPE_RESTART: u8 = 64;
+
+/// The parts of a JPEG that go into RTP/JPEG packets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JpegParts<'a> {
+    /// 0 for 4:2:2, 1 for 4:2:0
+    pub kind: u8,
+    pub width: u16,
+    pub height: u16,
+    /// Luma then chroma table, 64 bytes each, in zigzag order as they are in the file
+    pub tables: [[u8; 64]; 2],
+    /// MCUs between restart markers, 0 for none
+    pub restart_interval: u16,
+    /// Entropy-coded data, without the EOI marker
+    pub scan: &'a [u8],
+}
+
+impl<'a> JpegParts<'a> {
+    /// Picks apart a baseline YCbCr JPEG with 8-bit tables, 4:2:2 or 4:2:0, up to
+    /// 2040x2040. Anything else has no RTP/JPEG type.
+    pub fn parse(jpeg: &'a [u8]) -> anyhow::Result<Self> {
+        if !jpeg.starts_with(&[0xff, 0xd8]) {
+            anyhow::bail!("Not a JPEG");
+        }
+
+        let mut tables = [None; 4];
+        let mut frame = None;
+        let mut restart_interval = 0;
+        let mut pos = 2;
+        loop {
+            // Markers can be padded with any number of 0xff
+            while jpeg.get(pos) == Some(&0xff) && jpeg.get(pos + 1) == Some(&0xff) {
+                pos += 1;
+            }
+            let (Some(&0xff), Some(&marker)) = (jpeg.get(pos), jpeg.get(pos + 1)) else {
+                anyhow::bail!("JPEG ended before the scan");
+            };
+            let len = be16(jpeg, pos + 2)? as usize;
+            let segment = jpeg
+                .get(pos + 4..pos + 2 + len)
+                .filter(|_| len >= 2)
+                .ok_or_else(|| anyhow::anyhow!("Truncated JPEG segment {:02X}", marker))?;
+            pos += 2 + len;
+
+            match marker {
+                // DQT, possibly several tables
+                0xdb => {
+                    let mut rest = segment;
+                    while let [info, table @ ..] = rest {
+                        if info >> 4 != 0 {
+                            anyhow::bail!("16-bit quantization tables can't go over RTP");
+                        }
+                        let table: [u8; 64] = table
+                            .get(..64)
+                            .and_then(|t| t.try_into().ok())
+                            .ok_or_else(|| anyhow::anyhow!("Truncated quantization table"))?;
+                        tables[(info & 3) as usize] = Some(table);
+                        rest = &rest[65..];
+                    }
+                }
+                // SOF0 and SOF1: baseline and extended sequential
+                0xc0 | 0xc1 => frame = Some(Frame::parse(segment)?),
+                0xc2..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                    anyhow::bail!("Only baseline JPEGs can go over RTP")
+                }
+                // DRI
+                0xdd => restart_interval = be16(segment, 0)?,
+                // SOS
+                0xda => break,
+                _ => {}
+            }
+        }
+
+        let frame = frame.ok_or_else(|| anyhow::anyhow!("JPEG has no frame header"))?;
+        let table = |index: u8| {
+            tables[index as usize & 3]
+                .ok_or_else(|| anyhow::anyhow!("JPEG is missing quantization table {}", index))
+        };
+        Ok(Self {
+            kind: frame.kind,
+            width: frame.width,
+            height: frame.height,
+            tables: [table(frame.tables.0)?, table(frame.tables.1)?],
+            restart_interval,
+            scan: &jpeg[pos..scan_end(jpeg, pos)],
+        })
+    }
+}
+
+/// What RTP/JPEG needs out of the frame header
+struct Frame {
+    kind: u8,
+    width: u16,
+    height: u16,
+    /// Luma and chroma quantization table numbers
+    tables: (u8, u8),
+}
+
+impl Frame {
+    fn parse(segment: &[u8]) -> anyhow::Result<Self> {
+        let [precision, h1, h0, w1, w0, 3, components @ ..] = segment else {
+            anyhow::bail!("Only 3-component (YCbCr) JPEGs can go over RTP");
+        };
+        let [_, y_sampling, y_table, _, 0x11, cb_table, _, 0x11, cr_table, ..] = *components else {
+            anyhow::bail!("Chroma has to be subsampled for RTP/JPEG");
+        };
+        if *precision != 8 {
+            anyhow::bail!("{}-bit JPEGs can't go over RTP", precision);
+        }
+        if cb_table != cr_table {
+            anyhow::bail!("Cb and Cr have to share a quantization table");
+        }
+        let kind = match y_sampling {
+            0x21 => 0,
+            0x22 => 1,
+            _ => anyhow::bail!("Only 4:2:2 and 4:2:0 JPEGs can go over RTP"),
+        };
+        let width = u16::from_be_bytes([*w1, *w0]);
+        let height = u16::from_be_bytes([*h1, *h0]);
+        if width > 2040 || height > 2040 {
+            anyhow::bail!(
+                "{}x{} is too big for RTP/JPEG, 2040x2040 at most",
+                width,
+                height
+            );
+        }
+        Ok(Self {
+            kind,
+            width,
+            height,
+            tables: (y_table, cb_table),
+        })
+    }
+}
+
+fn be16(data: &[u8], pos: usize) -> anyhow::Result<u16> {
+    match data.get(pos..pos + 2) {
+        Some(&[a, b]) => Ok(u16::from_be_bytes([a, b])),
+        _ => anyhow::bail!("Truncated JPEG"),
+    }
+}
+
+/// Where the scan starting at `start` ends: the EOI marker, or the end of the buffer if
+/// there isn't one. Camera buffers can have padding after the EOI.
+fn scan_end(jpeg: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while pos + 1 < jpeg.len() {
+        match (jpeg[pos], jpeg[pos + 1]) {
+            (0xff, 0xd9) => return pos,
+            // Stuffed 0xff, or a restart marker
+            (0xff, 0x00 | 0xd0..=0xd7) => pos += 2,
+            _ => pos += 1,
+        }
+    }
+    jpeg.len()
+}
+
+/// RTP timestamp for a frame captured `since_boot` after boot. Wraps, as RTP timestamps do.
+pub fn timestamp(since_boot: Duration) -> u32 {
+    (since_boot.as_micros() * CLOCK_RATE as u128 / 1_000_000) as u32
+}
+
+/// Turns JPEGs into RTP packets for one stream, numbering them as it goes
+#[derive(Debug)]
+pub struct Packetizer {
+    ssrc: u32,
+    sequence: u16,
+    max_packet: usize,
+}
+
+impl Packetizer {
+    /// `max_packet` is the biggest RTP packet to send, headers included. Keep it under the
+    /// path MTU (less IP and UDP headers), ~1400 is safe.
+    pub fn new(ssrc: u32, sequence: u16, max_packet: usize) -> Self {
+        Self {
+            ssrc,
+            sequence,
+            // Room for the headers, the tables and a bit of data
+            max_packet: max_packet.max(256),
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sequence number of the next packet
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// RTP packets for one frame, the last one with the marker bit set
+    pub fn packetize(&mut self, jpeg: &JpegParts, timestamp: u32) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut packet = Vec::with_capacity(self.max_packet);
+            self.rtp_header(&mut packet, timestamp);
+
+            let kind = match jpeg.restart_interval {
+                0 => jpeg.kind,
+                _ => jpeg.kind | TYPE_RESTART,
+            };
+            packet.push(0);
+            packet.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            packet.extend_from_slice(&[
+                kind,
+                Q_INLINE_TABLES,
+                jpeg.width.div_ceil(8) as u8,
+                jpeg.height.div_ceil(8) as u8,
+            ]);
+
+            if jpeg.restart_interval > 0 {
+                // Packets don't line up with restart intervals: F and L set, count 0x3fff
+                packet.extend_from_slice(&jpeg.restart_interval.to_be_bytes());
+                packet.extend_from_slice(&[0xff, 0xff]);
+            }
+            if offset == 0 {
+                packet.extend_from_slice(&[0, 0]);
+                packet.extend_from_slice(&128u16.to_be_bytes());
+                packet.extend_from_slice(&jpeg.tables[0]);
+                packet.extend_from_slice(&jpeg.tables[1]);
+            }
+
+            let room = self.max_packet - packet.len();
+            let end = (offset + room).min(jpeg.scan.len());
+            packet.extend_from_slice(&jpeg.scan[offset..end]);
+            offset = end;
+            if offset == jpeg.scan.len() {
+                packet[1] |= 0x80;
+                packets.push(packet);
+                return packets;
+            }
+            packets.push(packet);
+        }
+    }
+
+    fn rtp_header(&mut self, packet: &mut Vec<u8>, timestamp: u32) {
+        // Version 2, no padding, extension or CSRCs
+        packet.extend_from_slice(&[0x80, PAYLOAD_TYPE]);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
@@ -0,0 +1,90 @@
+//! Mounts the microSD card as FAT under `MOUNT_POINT`, using whichever bus the board
+//! profile says it is wired to. After that it's plain `std::fs`.
+
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::sd::mmc::{SdMmcHostConfiguration, SdMmcHostDriver, SDMMC1};
+use esp_idf_svc::hal::sd::spi::SdSpiHostDriver;
+use esp_idf_svc::hal::sd::{SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::{SpiDriver, SpiDriverConfig, SPI2};
+use esp_idf_svc::io::vfs::MountedFatfs;
+
+use crate::board::SdPins;
+
+pub const MOUNT_POINT: &str = "/sdcard";
+
+/// Most files open at once. The recorder only needs one, plus one for whoever is reading.
+const MAX_OPEN_FILES: usize = 4;
+
+type MmcCard = SdCardDriver<SdMmcHostDriver<'static>>;
+type SpiCard = SdCardDriver<SdSpiHostDriver<'static, SpiDriver<'static>>>;
+
+enum Mount {
+    Mmc(MountedFatfs<Fatfs<MmcCard>>),
+    Spi(MountedFatfs<Fatfs<SpiCard>>),
+}
+
+/// The card stays mounted for as long as this is alive
+pub struct SdCard {
+    _mount: Mount,
+}
+
+impl SdCard {
+    /// Only the peripheral matching `pins` is used, but both are taken so the caller
+    /// doesn't have to care which one the board has.
+    pub fn mount(pins: SdPins, sdmmc: SDMMC1, spi: SPI2) -> anyhow::Result<Self> {
+        // The profile holds plain GPIO numbers, and they are ours once the board is chosen
+        let pin = |n: i32| unsafe { AnyIOPin::new(n) };
+
+        let mount = match pins {
+            SdPins::Mmc1Bit { clk, cmd, d0 } => {
+                let host = SdMmcHostDriver::new_1bit(
+                    sdmmc,
+                    pin(cmd),
+                    pin(clk),
+                    pin(d0),
+                    None::<AnyIOPin>,
+                    None::<AnyIOPin>,
+                    &SdMmcHostConfiguration::new(),
+                )?;
+                let card = SdCardDriver::new_mmc(host, &SdCardConfiguration::new())?;
+                Mount::Mmc(MountedFatfs::mount(
+                    Fatfs::new_sdcard(0, card)?,
+                    MOUNT_POINT,
+                    MAX_OPEN_FILES,
+                )?)
+            }
+            SdPins::Spi {
+                sclk,
+                mosi,
+                miso,
+                cs,
+            } => {
+                let bus = SpiDriver::new(
+                    spi,
+                    pin(sclk),
+                    pin(mosi),
+                    Some(pin(miso)),
+                    &SpiDriverConfig::default(),
+                )?;
+                let host = SdSpiHostDriver::new(
+                    bus,
+                    Some(pin(cs)),
+                    None::<AnyIOPin>,
+                    None::<AnyIOPin>,
+                    None::<AnyIOPin>,
+                    None,
+                )?;
+                let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+                Mount::Spi(MountedFatfs::mount(
+                    Fatfs::new_sdcard(0, card)?,
+                    MOUNT_POINT,
+                    MAX_OPEN_FILES,
+                )?)
+            }
+        };
+
+        log::info!("SD card mounted at {}", MOUNT_POINT);
+        Ok(Self { _mount: mount })
+    }
+}
@@ -0,0 +1,952 @@
+//! Persistent user settings.
+//!
+//! Stored as one JSON blob: in NVS on the board (`NvsSettings`), in a file on the host.
+//! Every field has a default and unknown fields are ignored, so old blobs keep loading
+//! after fields are added or removed.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Scope;
+use crate::board;
+use crate::mask::Shape;
+use crate::motion::{MotionConfig, MotionDetector};
+use crate::overlay::Corner;
+use crate::system;
+use crate::timelapse::TimelapseConfig;
+use crate::udp::UdpConfig;
+use crate::upload::UploadConfig;
+use crate::webhook::WebhookConfig;
+use crate::{battery, sleep, trigger};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Shown in overlays and reported in `/status`
+    pub camera_name: String,
+    pub overlay: OverlaySettings,
+    pub time: TimeSettings,
+    pub recording: RecordingSettings,
+    pub timelapse: TimelapseSettings,
+    pub motion: MotionSettings,
+    pub masks: MaskSettings,
+    pub prebuffer: PrebufferSettings,
+    pub mqtt: MqttSettings,
+    pub webhook: WebhookSettings,
+    pub upload: UploadSettings,
+    pub rtsp: RtspSettings,
+    pub websocket: WebSocketSettings,
+    pub udp: UdpSettings,
+    pub auth: AuthSettings,
+    pub tls: TlsSettings,
+    pub flash: FlashSettings,
+    pub triggers: Vec<TriggerSettings>,
+    pub sleep: SleepSettings,
+    pub battery: BatterySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            camera_name: "wrover".into(),
+            overlay: OverlaySettings::default(),
+            time: TimeSettings::default(),
+            recording: RecordingSettings::default(),
+            timelapse: TimelapseSettings::default(),
+            motion: MotionSettings::default(),
+            masks: MaskSettings::default(),
+            prebuffer: PrebufferSettings::default(),
+            mqtt: MqttSettings::default(),
+            webhook: WebhookSettings::default(),
+            upload: UploadSettings::default(),
+            rtsp: RtspSettings::default(),
+            websocket: WebSocketSettings::default(),
+            udp: UdpSettings::default(),
+            auth: AuthSettings::default(),
+            tls: TlsSettings::default(),
+            flash: FlashSettings::default(),
+            triggers: Vec::new(),
+            sleep: SleepSettings::default(),
+            battery: BatterySettings::default(),
+        }
+    }
+}
+
+/// The name + time label, see `overlay`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverlaySettings {
+    /// `top_left`, `top_right`, `bottom_left` or `bottom_right`
+    pub corner: Corner,
+    /// Only stamp `/capture`, not every stream frame (stamping JPEGs means re-encoding them)
+    pub snapshots_only: bool,
+}
+
+impl Default for OverlaySettings {
+    fn default() -> Self {
+        Self {
+            corner: Corner::BottomLeft,
+            snapshots_only: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeSettings {
+    /// NTP servers, `host` or `host:port`. ESP-IDF only uses as many as
+    /// `CONFIG_LWIP_SNTP_MAX_SERVERS` allows (1 by default).
+    pub servers: Vec<String>,
+    /// POSIX `TZ` string, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`
+    pub timezone: String,
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        Self {
+            servers: vec!["pool.ntp.org".into()],
+            timezone: "UTC0".into(),
+        }
+    }
+}
+
+/// Recording to the SD card, see `recorder`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingSettings {
+    pub enabled: bool,
+    pub fps: u32,
+    /// Clips are split at whichever limit comes first
+    pub max_clip_mb: u32,
+    pub max_clip_secs: u32,
+    /// Oldest clips are deleted past this, 0 = fill the card
+    pub max_total_mb: u32,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fps: 5,
+            max_clip_mb: 64,
+            max_clip_secs: 300,
+            max_total_mb: 0,
+        }
+    }
+}
+
+/// See `timelapse::Schedule` and `timelapse::Window` for the formats
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimelapseSettings {
+    pub enabled: bool,
+    /// `every 10m` or cron, e.g. `0 */2 * * *`
+    pub schedule: String,
+    /// Empty for any time, `HH:MM-HH:MM`, or `daylight` (needs the location below)
+    pub window: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Switch to `OV3660Config::high_quality()` resolution and quality for each shot
+    pub high_quality: bool,
+}
+
+impl Default for TimelapseSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedule: "every 10m".into(),
+            window: String::new(),
+            latitude: 0.0,
+            longitude: 0.0,
+            high_quality: false,
+        }
+    }
+}
+
+/// Motion detection, see `motion`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionSettings {
+    pub enabled: bool,
+    /// Frames checked per second
+    pub fps: f32,
+    /// 1-100, higher reacts to smaller changes
+    pub sensitivity: u8,
+    /// How much of the frame has to change to count as motion
+    pub min_area_percent: f32,
+    /// Seconds without motion before it counts as stopped
+    pub hold_secs: u32,
+    /// Save a JPEG to the SD card when motion starts
+    pub snapshot: bool,
+    /// Record a clip to the SD card for as long as it lasts
+    pub record: bool,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fps: 2.0,
+            sensitivity: 60,
+            min_area_percent: 1.0,
+            hold_secs: 5,
+            snapshot: true,
+            record: false,
+        }
+    }
+}
+
+/// Event clips with the seconds before the trigger, see `prebuffer`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrebufferSettings {
+    pub enabled: bool,
+    pub fps: u32,
+    /// Memory for buffered frames, capped at half the free PSRAM
+    pub budget_kb: u32,
+    pub pre_secs: u32,
+    pub post_secs: u32,
+    /// Longest a clip runs past its trigger while motion goes on
+    pub max_post_secs: u32,
+}
+
+impl Default for PrebufferSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fps: 5,
+            budget_kb: 1536,
+            pre_secs: 5,
+            post_secs: 10,
+            max_post_secs: 60,
+        }
+    }
+}
+
+/// Status, events and commands over MQTT, see `mqtt`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    /// `mqtt://host:port`
+    pub url: String,
+    /// Empty for the device id, `wrover-<last 3 MAC bytes>`
+    pub client_id: String,
+    /// Empty to connect anonymously
+    pub username: String,
+    pub password: String,
+    /// Empty for `wrover/<client id>`
+    pub base_topic: String,
+    pub telemetry_secs: u32,
+    /// Publish a JPEG whenever motion starts
+    pub motion_snapshots: bool,
+    /// Announce the camera to Home Assistant
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "mqtt://homeassistant.local:1883".into(),
+            client_id: String::new(),
+            username: String::new(),
+            password: String::new(),
+            base_topic: String::new(),
+            telemetry_secs: 60,
+            motion_snapshots: false,
+            discovery: true,
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+}
+
+/// Snapshots POSTed to a URL, see `webhook`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    /// `http://` or `https://`
+    pub url: String,
+    /// `multipart` (metadata and image parts) or `raw` (just the JPEG)
+    pub format: String,
+    /// Signs each body with HMAC-SHA256, empty to leave them unsigned
+    pub secret: String,
+    pub on_motion: bool,
+    pub on_boot: bool,
+    /// 0 = no timer
+    pub interval_secs: u32,
+    pub retries: u32,
+    /// Deliveries waiting to be sent, the oldest is dropped past this
+    pub queue_len: u32,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            format: "multipart".into(),
+            secret: String::new(),
+            on_motion: true,
+            on_boot: false,
+            interval_secs: 0,
+            retries: 3,
+            queue_len: 4,
+        }
+    }
+}
+
+/// Captures PUT to a file server, see `upload`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadSettings {
+    pub enabled: bool,
+    /// Base URL, `http://` or `https://`
+    pub url: String,
+    /// Appended to the URL, see `PathTemplate` for the placeholders
+    pub path: String,
+    /// Basic auth, empty for none
+    pub username: String,
+    pub password: String,
+    /// Bearer auth, used instead of basic if set
+    pub token: String,
+    /// Upload time-lapse shots (instead of saving them to the card)
+    pub timelapse: bool,
+    /// Upload the snapshot when motion starts
+    pub motion: bool,
+    pub retries: u32,
+    pub queue_len: u32,
+    /// Keep what can't be uploaded on the SD card and send it later
+    pub spool: bool,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            path: "{camera}/{date}/{time}.jpg".into(),
+            username: String::new(),
+            password: String::new(),
+            token: String::new(),
+            timelapse: true,
+            motion: true,
+            retries: 3,
+            queue_len: 8,
+            spool: true,
+        }
+    }
+}
+
+/// The RTSP server, see `rtsp`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RtspSettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Most frames per second sent to each client
+    pub fps: f32,
+}
+
+impl Default for RtspSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 554,
+            fps: 10.0,
+        }
+    }
+}
+
+/// The `/ws` stream, see `ws`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketSettings {
+    pub enabled: bool,
+    /// Most frames per second captured for WebSocket clients
+    pub fps: f32,
+    pub max_clients: u32,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fps: 10.0,
+            max_clients: 4,
+        }
+    }
+}
+
+/// The UDP stream, see `udp`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpSettings {
+    pub enabled: bool,
+    /// `host:port` or a multicast `group:port`, the port defaults to 5000
+    pub target: String,
+    pub fps: f32,
+    /// Biggest datagram, header included
+    pub max_packet: u32,
+    /// Hops for multicast
+    pub ttl: u32,
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target: String::new(),
+            fps: 15.0,
+            max_packet: 1400,
+            ttl: 1,
+        }
+    }
+}
+
+/// Who may use the API, see `auth`. Off until turned on through `POST /auth`, which wants
+/// an admin to exist first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    pub enabled: bool,
+    /// Basic auth logins
+    pub users: Vec<UserSettings>,
+    /// Bearer tokens
+    pub tokens: Vec<TokenSettings>,
+    /// Failed logins in a row before a peer is locked out
+    pub max_failures: u32,
+    pub lockout_secs: u32,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            users: Vec::new(),
+            tokens: Vec::new(),
+            max_failures: 5,
+            lockout_secs: 60,
+        }
+    }
+}
+
+/// HTTPS, see `tls`. The certificate and key are kept next to the settings, not in them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Whether plain HTTP on port 80 redirects to HTTPS. Otherwise it isn't served at all.
+    pub redirect: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 443,
+            redirect: true,
+        }
+    }
+}
+
+/// The flash LED, see `flash`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlashSettings {
+    /// Percent of full power when it's on
+    pub brightness: u8,
+    /// Percent it never goes above, whatever `brightness` says. The LED has no heatsink.
+    pub max_brightness: u8,
+    /// Turned off after being on this long
+    pub max_on_secs: u32,
+    /// Lit for each `/capture`
+    pub on_capture: bool,
+}
+
+impl Default for FlashSettings {
+    fn default() -> Self {
+        Self {
+            brightness: 50,
+            max_brightness: 60,
+            max_on_secs: 60,
+            on_capture: false,
+        }
+    }
+}
+
+impl FlashSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=100).contains(&self.brightness) {
+            anyhow::bail!("brightness has to be 1-100, not {}", self.brightness);
+        }
+        if !(1..=100).contains(&self.max_brightness) {
+            anyhow::bail!(
+                "max_brightness has to be 1-100, not {}",
+                self.max_brightness
+            );
+        }
+        if !(1..=3600).contains(&self.max_on_secs) {
+            anyhow::bail!("max_on_secs has to be 1-3600, not {}", self.max_on_secs);
+        }
+        Ok(())
+    }
+
+    /// What the LED is actually driven at
+    pub fn duty(&self) -> u8 {
+        self.brightness.min(self.max_brightness)
+    }
+}
+
+/// Which way a trigger input has to change to fire
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// The chip's internal pull resistor on a trigger input
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerAction {
+    /// Save a frame like motion snapshots do
+    Snapshot,
+    /// Record a clip for `record_secs`
+    Record,
+    /// Wake the chip from deep sleep
+    Wake,
+    /// Send an event over MQTT
+    Publish,
+    /// In battery mode, a wake by this input serves `/settings` for `sleep.settings_secs`
+    /// before sleeping again, instead of capturing
+    Settings,
+}
+
+/// A PIR sensor or button, see `trigger`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TriggerSettings {
+    /// Shown in events and file names
+    pub name: String,
+    /// One of `Board::trigger_pins`
+    pub gpio: i32,
+    pub pull: Pull,
+    pub edge: Edge,
+    /// How long the level has to hold before a change counts. Buttons bounce for a few
+    /// ms, PIR sensors don't.
+    pub debounce_ms: u32,
+    pub actions: Vec<TriggerAction>,
+    /// How long `Record` keeps going after the last time it fired
+    pub record_secs: u32,
+}
+
+impl Default for TriggerSettings {
+    /// A button to ground
+    fn default() -> Self {
+        Self {
+            name: "button".into(),
+            gpio: -1,
+            pull: Pull::Up,
+            edge: Edge::Falling,
+            debounce_ms: 50,
+            actions: vec![TriggerAction::Publish],
+            record_secs: 10,
+        }
+    }
+}
+
+impl TriggerSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() {
+            anyhow::bail!("Triggers need a name");
+        }
+        let board = board::current();
+        if !board.trigger_pins.contains(&self.gpio) {
+            anyhow::bail!(
+                "GPIO{} can't be a trigger on the {}, it has {:?}",
+                self.gpio,
+                board.name,
+                board.trigger_pins
+            );
+        }
+        if self.debounce_ms > 1000 {
+            anyhow::bail!("debounce_ms has to be 0-1000, not {}", self.debounce_ms);
+        }
+        if self.actions.is_empty() {
+            anyhow::bail!("Trigger {} does nothing, it needs an action", self.name);
+        }
+        if !(1..=3600).contains(&self.record_secs) {
+            anyhow::bail!("record_secs has to be 1-3600, not {}", self.record_secs);
+        }
+        Ok(())
+    }
+
+    /// The level it changes to when it fires. With `Both`, the one away from where the
+    /// pull leaves it.
+    pub fn active_level(&self) -> bool {
+        match self.edge {
+            Edge::Rising => true,
+            Edge::Falling => false,
+            Edge::Both => self.pull != Pull::Up,
+        }
+    }
+}
+
+/// Battery mode, see `sleep`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SleepSettings {
+    /// Deep sleep between captures instead of serving anything
+    pub enabled: bool,
+    /// Wakes on a timer this often, 0 = only for trigger inputs with `wake`
+    pub interval_secs: u32,
+    /// Kept each time it wakes
+    pub frames: u32,
+    /// Most frames thrown away waiting for auto exposure
+    pub max_settle_frames: u32,
+    /// Exposure counts as settled once the mean brightness (0-255) moves less than this
+    /// from one frame to the next
+    pub settle_tolerance: f32,
+    /// Back to sleep after this long whatever's still going, e.g. uploads
+    pub max_awake_secs: u32,
+    /// How long a power on, or a wake by a trigger with `settings`, runs the camera as usual
+    /// so `/settings` can change things (battery mode included) before it sleeps. 0 = never.
+    pub settings_secs: u32,
+}
+
+impl Default for SleepSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 900,
+            frames: 3,
+            max_settle_frames: 30,
+            settle_tolerance: 2.0,
+            max_awake_secs: 60,
+            settings_secs: 120,
+        }
+    }
+}
+
+impl SleepSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=20).contains(&self.frames) {
+            anyhow::bail!("frames has to be 1-20, not {}", self.frames);
+        }
+        if self.max_settle_frames > 100 {
+            anyhow::bail!(
+                "max_settle_frames has to be 0-100, not {}",
+                self.max_settle_frames
+            );
+        }
+        if !(self.settle_tolerance > 0.0 && self.settle_tolerance <= 255.0) {
+            anyhow::bail!(
+                "settle_tolerance has to be above 0 and at most 255, not {}",
+                self.settle_tolerance
+            );
+        }
+        if !(10..=600).contains(&self.max_awake_secs) {
+            anyhow::bail!(
+                "max_awake_secs has to be 10-600, not {}",
+                self.max_awake_secs
+            );
+        }
+        if self.settings_secs > 3600 {
+            anyhow::bail!("settings_secs has to be 0-3600, not {}", self.settings_secs);
+        }
+        Ok(())
+    }
+}
+
+/// What to do once the battery's below a threshold
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryAction {
+    /// Streams slow down to `low_fps`
+    ReduceFps,
+    /// Streams pause, `/stream` clients are let go
+    StopStreaming,
+    /// Deep sleep, waking every `sleep.interval_secs` to check again
+    DeepSleep,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatteryThreshold {
+    pub below_percent: u8,
+    pub action: BatteryAction,
+}
+
+/// The battery monitor, see `battery`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatterySettings {
+    pub enabled: bool,
+    /// Between readings
+    pub interval_secs: u32,
+    /// Cell voltage to charge, `[volts, percent]` from full to empty. The default is one
+    /// Li-ion cell at rest; under load it reads a little low.
+    pub curve: Vec<(f32, u8)>,
+    pub thresholds: Vec<BatteryThreshold>,
+    /// How far above a threshold the charge has to get back before its action is undone
+    pub hysteresis_percent: u8,
+    /// Stream rate with `ReduceFps`
+    pub low_fps: f32,
+}
+
+impl Default for BatterySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 30,
+            curve: vec![
+                (4.20, 100),
+                (4.10, 90),
+                (4.02, 80),
+                (3.95, 70),
+                (3.87, 60),
+                (3.84, 50),
+                (3.80, 40),
+                (3.77, 30),
+                (3.73, 20),
+                (3.69, 10),
+                (3.61, 5),
+                (3.27, 0),
+            ],
+            thresholds: vec![
+                BatteryThreshold {
+                    below_percent: 30,
+                    action: BatteryAction::ReduceFps,
+                },
+                BatteryThreshold {
+                    below_percent: 15,
+                    action: BatteryAction::StopStreaming,
+                },
+                BatteryThreshold {
+                    below_percent: 5,
+                    action: BatteryAction::DeepSleep,
+                },
+            ],
+            hysteresis_percent: 5,
+            low_fps: 2.0,
+        }
+    }
+}
+
+impl BatterySettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=3600).contains(&self.interval_secs) {
+            anyhow::bail!("interval_secs has to be 1-3600, not {}", self.interval_secs);
+        }
+        if self.curve.len() < 2 {
+            anyhow::bail!("The curve needs at least 2 points");
+        }
+        for pair in self.curve.windows(2) {
+            let ((volts, percent), (next_volts, next_percent)) = (pair[0], pair[1]);
+            if next_volts >= volts || next_percent > percent {
+                anyhow::bail!(
+                    "The curve has to go from full to empty, {:?} can't follow {:?}",
+                    pair[1],
+                    pair[0]
+                );
+            }
+        }
+        if let Some(&(_, percent)) = self.curve.first().filter(|&&(_, percent)| percent > 100) {
+            anyhow::bail!("The curve goes up to {}%", percent);
+        }
+        for threshold in &self.thresholds {
+            if !(1..=100).contains(&threshold.below_percent) {
+                anyhow::bail!(
+                    "below_percent has to be 1-100, not {}",
+                    threshold.below_percent
+                );
+            }
+        }
+        if self.hysteresis_percent > 50 {
+            anyhow::bail!(
+                "hysteresis_percent has to be 0-50, not {}",
+                self.hysteresis_percent
+            );
+        }
+        if !(self.low_fps > 0.0 && self.low_fps <= 30.0) {
+            anyhow::bail!(
+                "low_fps has to be above 0 and at most 30, not {}",
+                self.low_fps
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserSettings {
+    pub name: String,
+    /// `auth::SecretHash` of the password
+    pub hash: String,
+    pub scope: Scope,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenSettings {
+    /// What it's for, tokens themselves aren't kept
+    pub name: String,
+    pub hash: String,
+    pub scope: Scope,
+}
+
+/// Regions of the frame, see `mask`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaskSettings {
+    /// Blacked out of everything streamed and stored, and not watched for motion either
+    pub privacy: Vec<Shape>,
+    /// Left out of motion detection only
+    pub motion: Vec<Shape>,
+}
+
+impl MaskSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for shape in self.privacy.iter().chain(&self.motion) {
+            shape.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Somewhere settings changed at runtime are saved
+pub trait SettingsStore: Send {
+    fn save(&mut self, settings: &Settings) -> anyhow::Result<()>;
+}
+
+/// Settings in a JSON file, for the host
+pub struct FileSettings {
+    path: PathBuf,
+}
+
+impl FileSettings {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn load(&self) -> anyhow::Result<Settings> {
+        Settings::load_file(&self.path)
+    }
+}
+
+impl SettingsStore for FileSettings {
+    fn save(&mut self, settings: &Settings) -> anyhow::Result<()> {
+        settings.save_file(&self.path)
+    }
+}
+
+impl Settings {
+    pub fn from_json(json: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// Each section's own checks, and that whatever boot builds from the enabled ones
+    /// would come up, so a bad `/settings` can't leave the camera failing at every boot
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.masks.validate()?;
+        self.flash.validate()?;
+        trigger::validate(&self.triggers)?;
+        if self.sleep.enabled {
+            sleep::validate(self)?;
+        }
+        if self.battery.enabled {
+            battery::validate(self)?;
+        }
+        MotionDetector::new(MotionConfig::from_settings(&self.motion))?;
+        TimelapseConfig::from_settings(&self.timelapse)?;
+        let device_id = system::device_id();
+        if self.upload.enabled {
+            UploadConfig::from_settings(&self.upload, &device_id)?;
+        }
+        if self.webhook.enabled {
+            WebhookConfig::from_settings(&self.webhook, &device_id)?;
+        }
+        if self.udp.enabled {
+            UdpConfig::from_settings(&self.udp)?;
+        }
+        Ok(())
+    }
+
+    /// Defaults if the file doesn't exist yet
+    pub fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(json) => Self::from_json(&json),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_file(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use nvs::NvsSettings;
+
+#[cfg(target_os = "espidf")]
+mod nvs {
+    use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+    use super::{Settings, SettingsStore};
+
+    const NAMESPACE: &str = "wrover";
+    const KEY: &str = "settings";
+
+    pub struct NvsSettings {
+        nvs: EspNvs<NvsDefault>,
+    }
+
+    impl NvsSettings {
+        pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+            Ok(Self {
+                nvs: EspNvs::new(partition, NAMESPACE, true)?,
+            })
+        }
+
+        /// Saved settings, or defaults if there are none (or they don't parse)
+        pub fn load(&self) -> Settings {
+            let Ok(Some(len)) = self.nvs.blob_len(KEY) else {
+                return Settings::default();
+            };
+
+            let mut buf = vec![0; len];
+            match self.nvs.get_blob(KEY, &mut buf) {
+                Ok(Some(json)) => Settings::from_json(json).unwrap_or_else(|e| {
+                    log::warn!("Saved settings are corrupt, using defaults: {}", e);
+                    Settings::default()
+                }),
+                _ => Settings::default(),
+            }
+        }
+    }
+
+    impl SettingsStore for NvsSettings {
+        fn save(&mut self, settings: &Settings) -> anyhow::Result<()> {
+            self.nvs.set_blob(KEY, &settings.to_json())?;
+            Ok(())
+        }
+    }
+}
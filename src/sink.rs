@@ -0,0 +1,67 @@
+//! Somewhere to put captured frames: a directory, an uploader, a closure in a test.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::camera::{FrameBuffer, FrameFormat};
+use crate::clock;
+
+pub trait FrameSink: Send {
+    fn store(&mut self, frame: &FrameBuffer) -> anyhow::Result<()>;
+}
+
+impl<F: FnMut(&FrameBuffer) -> anyhow::Result<()> + Send> FrameSink for F {
+    fn store(&mut self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        self(frame)
+    }
+}
+
+/// Saves JPEGs as `<dir>/<YYYYMMDD>/<HHMMSS_mmm>.jpg` in local time, or
+/// `<dir>/boot/<uptime ms>.jpg` before the clock is synced
+pub struct DirSink {
+    dir: PathBuf,
+}
+
+impl DirSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Where `frame` would be written
+    pub fn path_for(&self, frame: &FrameBuffer) -> PathBuf {
+        match frame.wall_time {
+            Some(time) => {
+                let stamp = clock::format_compact(time);
+                let (date, time_of_day) = stamp.split_at(8);
+                let millis = time
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .subsec_millis();
+                self.dir
+                    .join(date)
+                    .join(format!("{}_{:03}.jpg", &time_of_day[1..], millis))
+            }
+            None => self
+                .dir
+                .join("boot")
+                .join(format!("{}.jpg", frame.timestamp.as_millis())),
+        }
+    }
+}
+
+impl FrameSink for DirSink {
+    fn store(&mut self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        if frame.format != FrameFormat::Jpeg {
+            anyhow::bail!("Can only save JPEG frames, got {}", frame.format.name());
+        }
+
+        let path = self.path_for(frame);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Can't create {}", parent.display()))?;
+        }
+        fs::write(&path, &frame.data).with_context(|| format!("Can't write {}", path.display()))
+    }
+}
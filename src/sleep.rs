@@ -0,0 +1,325 @@
+//! Battery mode: wake, take a few frames, put them somewhere, deep sleep again.
+//!
+//! With `sleep.enabled` nothing is served, but for the settings window below. Each wake
+//! (the timer, or a trigger input with `wake`) runs `run_cycle`: frames are captured and
+//! thrown away until auto exposure has settled (`Settle`, the mean brightness stops
+//! moving), then `frames` more are kept and handed to a sink that stores or uploads them.
+//! `Counters` live in RTC memory, which lasts through deep sleep but not a power cut, so
+//! each wake knows how many came before it and why.
+//!
+//! A power on, or a wake by a trigger with `settings`, instead brings the camera up as usual
+//! for `settings_secs` (`settings_window`), so `/settings` can change or turn off battery
+//! mode without erasing flash.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::camera::{FrameBuffer, FrameFormat};
+use crate::codec;
+use crate::http::App;
+use crate::settings::{Settings, SleepSettings, TriggerAction, TriggerSettings};
+use crate::sink::FrameSink;
+
+/// Size frames are shrunk to for `brightness`
+const BRIGHTNESS_SIZE: (u16, u16) = (80, 60);
+
+/// Why the chip is awake
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WakeReason {
+    /// Power on or reset, not a wake from deep sleep
+    PowerOn,
+    Timer,
+    /// The first trigger input with `wake`
+    Ext0,
+    /// Any of the others, with a bit set for each GPIO that did it
+    Ext1(u64),
+    /// Some other ESP-IDF wake cause
+    Other(u32),
+}
+
+/// The trigger inputs that woke the chip, in the order `trigger::esp::enable_wake` set
+/// them up: the first with `wake` on EXT0, the rest on EXT1
+pub fn woken_by(reason: WakeReason, inputs: &[TriggerSettings]) -> Vec<&TriggerSettings> {
+    let mut waking = inputs
+        .iter()
+        .filter(|input| input.actions.contains(&TriggerAction::Wake));
+    match reason {
+        WakeReason::Ext0 => waking.next().into_iter().collect(),
+        WakeReason::Ext1(pins) => waking
+            .skip(1)
+            .filter(|input| pins & (1 << input.gpio) != 0)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// How long this boot should serve before battery mode takes over: `settings_secs` after a
+/// power on, or a wake by a trigger input with `settings`. `None` to capture and sleep.
+pub fn settings_window(reason: WakeReason, settings: &Settings) -> Option<Duration> {
+    let opens = reason == WakeReason::PowerOn
+        || woken_by(reason, &settings.triggers)
+            .iter()
+            .any(|input| input.actions.contains(&TriggerAction::Settings));
+    let secs = settings.sleep.settings_secs;
+    (opens && secs > 0).then(|| Duration::from_secs(secs as u64))
+}
+
+/// Checks the sleep settings, and that something will wake it up again
+pub fn validate(settings: &Settings) -> anyhow::Result<()> {
+    settings.sleep.validate()?;
+    validate_wakes(settings)
+}
+
+/// Checks that the timer or a trigger with the wake action will bring the chip back
+pub fn validate_wakes(settings: &Settings) -> anyhow::Result<()> {
+    let wakes = settings
+        .triggers
+        .iter()
+        .any(|input| input.actions.contains(&TriggerAction::Wake));
+    if settings.sleep.interval_secs == 0 && !wakes {
+        anyhow::bail!("Without interval_secs a trigger needs the wake action, or it never wakes");
+    }
+    Ok(())
+}
+
+/// Kept in RTC memory between wakes, see the module docs
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    /// Wakes from deep sleep since power on
+    pub wakes: u32,
+    pub timer_wakes: u32,
+    pub trigger_wakes: u32,
+    /// Frames kept, over all wakes
+    pub frames: u32,
+    /// Wakes that didn't store all their frames
+    pub failed_cycles: u32,
+    /// How long the last wake was up for, in ms
+    pub last_awake_ms: u32,
+}
+
+impl Counters {
+    /// All zero, what RTC memory starts as
+    pub const fn new() -> Self {
+        Self {
+            wakes: 0,
+            timer_wakes: 0,
+            trigger_wakes: 0,
+            frames: 0,
+            failed_cycles: 0,
+            last_awake_ms: 0,
+        }
+    }
+
+    pub fn woke(&mut self, reason: WakeReason) {
+        match reason {
+            WakeReason::PowerOn => return,
+            WakeReason::Timer => self.timer_wakes += 1,
+            WakeReason::Ext0 | WakeReason::Ext1(_) => self.trigger_wakes += 1,
+            WakeReason::Other(_) => {}
+        }
+        self.wakes += 1;
+    }
+
+    /// Counts a wake's work, `None` if it didn't get as far as a cycle
+    pub fn cycle_done(&mut self, report: Option<&CycleReport>, awake: Duration) {
+        match report {
+            Some(report) => {
+                self.frames += report.stored;
+                if report.failed > 0 {
+                    self.failed_cycles += 1;
+                }
+            }
+            None => self.failed_cycles += 1,
+        }
+        self.last_awake_ms = awake.as_millis().min(u32::MAX as u128) as u32;
+    }
+}
+
+/// Waits out auto exposure: fed the brightness of each frame, done once two in a row are
+/// within `tolerance` of each other, or after `max_frames` regardless
+#[derive(Clone, Debug)]
+pub struct Settle {
+    tolerance: f32,
+    max_frames: u32,
+    frames: u32,
+    last: Option<f32>,
+    steady: bool,
+}
+
+impl Settle {
+    pub fn new(tolerance: f32, max_frames: u32) -> Self {
+        Self {
+            tolerance,
+            max_frames,
+            frames: 0,
+            last: None,
+            steady: false,
+        }
+    }
+
+    /// Takes a frame's brightness, true once it's settled
+    pub fn feed(&mut self, brightness: f32) -> bool {
+        self.frames += 1;
+        self.steady = self
+            .last
+            .is_some_and(|last| (brightness - last).abs() < self.tolerance);
+        self.last = Some(brightness);
+        self.steady || self.frames >= self.max_frames
+    }
+
+    /// Whether the last two frames were within the tolerance, i.e. it settled rather than
+    /// gave up
+    pub fn is_steady(&self) -> bool {
+        self.steady
+    }
+
+    /// Frames fed so far
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+}
+
+/// Mean brightness of a frame, 0-255
+pub fn brightness(frame: &FrameBuffer) -> anyhow::Result<f32> {
+    let (width, height) = BRIGHTNESS_SIZE;
+    let luma = match frame.format {
+        FrameFormat::Jpeg => codec::decode_jpeg_scaled(&frame.data, width, height)?,
+        _ => frame.to_image()?,
+    }
+    .to_grayscale();
+    let sum: u64 = luma.data.iter().map(|&v| v as u64).sum();
+    Ok(sum as f32 / luma.data.len().max(1) as f32)
+}
+
+/// What one wake got done
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CycleReport {
+    /// Frames thrown away waiting for exposure
+    pub settle_frames: u32,
+    /// Whether exposure settled, rather than running out of frames
+    pub settled: bool,
+    pub stored: u32,
+    pub failed: u32,
+    /// How long capturing and storing took, in ms
+    pub took_ms: u64,
+}
+
+/// Settles exposure, then captures `settings.frames` frames (with the overlay, like
+/// snapshots) into `sink`. Fails only if the camera does; frames the sink can't take are
+/// counted in `failed`.
+pub fn run_cycle(
+    app: &App,
+    settings: &SleepSettings,
+    sink: &mut impl FrameSink,
+) -> anyhow::Result<CycleReport> {
+    let started = Instant::now();
+    let mut settle = Settle::new(settings.settle_tolerance, settings.max_settle_frames);
+    if settings.max_settle_frames > 0 {
+        loop {
+            let frame = app.capture_frame()?;
+            if settle.feed(brightness(&frame)?) {
+                break;
+            }
+        }
+    }
+
+    let (mut stored, mut failed) = (0, 0);
+    for _ in 0..settings.frames {
+        let frame = app.stream_frame()?;
+        match sink.store(&frame) {
+            Ok(()) => stored += 1,
+            Err(e) => {
+                log::warn!("Couldn't store a frame: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(CycleReport {
+        settle_frames: settle.frames(),
+        settled: settle.is_steady(),
+        stored,
+        failed,
+        took_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+/// One line of the wake log on the card
+#[derive(Clone, Debug, Serialize)]
+pub struct WakeRecord<'a> {
+    pub reason: WakeReason,
+    /// Names of the trigger inputs that woke it
+    pub woken_by: Vec<&'a str>,
+    /// `None` if the cycle failed
+    pub report: Option<&'a CycleReport>,
+    pub error: Option<String>,
+    pub counters: Counters,
+}
+
+impl WakeRecord<'_> {
+    /// Adds the record to a JSON-lines file
+    pub fn append_to(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub mod esp {
+    use std::ptr::addr_of_mut;
+    use std::time::Duration;
+
+    use esp_idf_svc::sys;
+
+    use super::{Counters, WakeReason};
+    use crate::settings::{SleepSettings, TriggerSettings};
+    use crate::trigger;
+
+    // Lasts through deep sleep, zeroed again at power on
+    #[link_section = ".rtc.data"]
+    static mut COUNTERS: Counters = Counters::new();
+
+    pub fn wake_reason() -> WakeReason {
+        match unsafe { sys::esp_sleep_get_wakeup_cause() } {
+            sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => WakeReason::PowerOn,
+            sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => WakeReason::Timer,
+            sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => WakeReason::Ext0,
+            sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => {
+                WakeReason::Ext1(unsafe { sys::esp_sleep_get_ext1_wakeup_status() })
+            }
+            other => WakeReason::Other(other),
+        }
+    }
+
+    /// Changes the counters in RTC memory, returning them after
+    pub fn update_counters(change: impl FnOnce(&mut Counters)) -> Counters {
+        // SAFETY: only the main task touches them, one call at a time
+        let counters = unsafe { &mut *addr_of_mut!(COUNTERS) };
+        change(counters);
+        *counters
+    }
+
+    /// Sets up the timer and the trigger inputs with `wake`, and goes to sleep. Waking up
+    /// is a reset, so this never returns.
+    pub fn deep_sleep(settings: &SleepSettings, triggers: &[TriggerSettings]) -> ! {
+        if settings.interval_secs > 0 {
+            let interval = Duration::from_secs(settings.interval_secs as u64);
+            unsafe { sys::esp_sleep_enable_timer_wakeup(interval.as_micros() as u64) };
+        }
+        if let Err(e) = trigger::esp::enable_wake(triggers) {
+            log::warn!("Triggers won't wake it: {}", e);
+        }
+        log::info!("Deep sleep, waking in {} s", settings.interval_secs);
+        unsafe { sys::esp_deep_sleep_start() }
+    }
+}
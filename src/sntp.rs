@@ -0,0 +1,181 @@
+//! SNTP (RFC 4330) time sync.
+//!
+//! On the board `start` hands the servers to ESP-IDF's SNTP service, which sets the system
+//! clock. Everywhere else `sync` does a one-shot query and stores the result as an offset in
+//! `clock`. The packet helpers are shared with the host's stand-in NTP server.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::clock;
+#[cfg(target_os = "espidf")]
+use crate::settings::TimeSettings;
+
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from 1900-01-01 (NTP era 0) to 1970-01-01
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// NTP 32.32 fixed point timestamp
+pub fn to_ntp(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() + NTP_UNIX_OFFSET;
+    let frac = ((since.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+pub fn from_ntp(ntp: u64) -> SystemTime {
+    let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let nanos = ((ntp & 0xffff_ffff) * 1_000_000_000) >> 32;
+    UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+/// Client request (version 4, mode 3) with our transmit time filled in
+pub fn request(transmit: SystemTime) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = 0b00_100_011;
+    packet[40..48].copy_from_slice(&to_ntp(transmit).to_be_bytes());
+    packet
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reply {
+    pub stratum: u8,
+    /// Our transmit time, echoed back
+    pub originate: u64,
+    pub receive: u64,
+    pub transmit: u64,
+}
+
+pub fn parse_reply(packet: &[u8]) -> anyhow::Result<Reply> {
+    if packet.len() < PACKET_LEN {
+        anyhow::bail!("NTP reply too short ({} bytes)", packet.len());
+    }
+    let mode = packet[0] & 0b111;
+    if mode != 4 && mode != 5 {
+        anyhow::bail!("Not an NTP server reply (mode {})", mode);
+    }
+    if packet[1] == 0 {
+        anyhow::bail!("NTP server sent kiss-of-death");
+    }
+
+    let timestamp = |at: usize| u64::from_be_bytes(packet[at..at + 8].try_into().unwrap());
+    Ok(Reply {
+        stratum: packet[1],
+        originate: timestamp(24),
+        receive: timestamp(32),
+        transmit: timestamp(40),
+    })
+}
+
+/// Server side: answers `request` as a stratum `stratum` server whose clock reads `now`
+pub fn reply(request: &[u8], now: SystemTime, stratum: u8) -> anyhow::Result<[u8; PACKET_LEN]> {
+    if request.len() < PACKET_LEN || request[0] & 0b111 != 3 {
+        anyhow::bail!("Not an NTP client request");
+    }
+
+    let now = to_ntp(now);
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = (request[0] & 0b00_111_000) | 4;
+    packet[1] = stratum;
+    packet[2] = request[2];
+    packet[3] = 0xec; // ~1us precision
+    packet[16..24].copy_from_slice(&now.to_be_bytes()); // reference
+    packet[24..32].copy_from_slice(&request[40..48]); // originate
+    packet[32..40].copy_from_slice(&now.to_be_bytes()); // receive
+    packet[40..48].copy_from_slice(&now.to_be_bytes()); // transmit
+    Ok(packet)
+}
+
+/// Asks one server how far off the system clock is, in microseconds
+pub fn query(server: impl ToSocketAddrs, timeout: Duration) -> anyhow::Result<i64> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+
+    let sent = SystemTime::now();
+    let request = request(sent);
+    socket.send(&request)?;
+
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf).context("No reply from NTP server")?;
+    let received = SystemTime::now();
+
+    let reply = parse_reply(&buf[..len])?;
+    if reply.originate != to_ntp(sent) {
+        anyhow::bail!("NTP reply doesn't match our request");
+    }
+
+    // offset = ((t2 - t1) + (t3 - t4)) / 2
+    let t1 = clock_micros(sent);
+    let t2 = clock_micros(from_ntp(reply.receive));
+    let t3 = clock_micros(from_ntp(reply.transmit));
+    let t4 = clock_micros(received);
+    Ok(((t2 - t1) + (t3 - t4)) / 2)
+}
+
+/// Tries `servers` (`host` or `host:port`) in order and applies the first answer to
+/// `clock`. Returns the offset that was applied.
+pub fn sync(servers: &[String]) -> anyhow::Result<i64> {
+    let mut last_error = anyhow::anyhow!("No NTP servers configured");
+    for server in servers {
+        let addr = if server.contains(':') {
+            server.clone()
+        } else {
+            format!("{}:123", server)
+        };
+
+        match query(addr.as_str(), Duration::from_secs(2)) {
+            Ok(offset) => {
+                clock::set_offset(offset);
+                log::info!("Time synced from {} (offset {}us)", server, offset);
+                return Ok(offset);
+            }
+            Err(e) => {
+                log::warn!("NTP query to {} failed: {}", server, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn clock_micros(time: SystemTime) -> i64 {
+    clock::unix_seconds(time) * 1_000_000
+        + time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_micros() as i64)
+}
+
+/// Starts ESP-IDF's SNTP service and applies the time zone. Call after `wait_netif_up()`,
+/// and keep the returned handle alive.
+#[cfg(target_os = "espidf")]
+pub fn start(settings: &TimeSettings) -> anyhow::Result<esp_idf_svc::sntp::EspSntp<'static>> {
+    use esp_idf_svc::sntp::{EspSntp, SntpConf};
+
+    apply_timezone(&settings.timezone)?;
+
+    let mut conf = SntpConf::default();
+    for (slot, server) in conf.servers.iter_mut().zip(&settings.servers) {
+        *slot = server.as_str();
+    }
+
+    let sntp = EspSntp::new(&conf)?;
+    log::info!("SNTP started ({})", settings.servers.join(", "));
+    Ok(sntp)
+}
+
+/// Sets the zone for our own formatters and, on the board, `TZ` for newlib's `localtime()`
+pub fn apply_timezone(tz: &str) -> anyhow::Result<()> {
+    clock::set_timezone(clock::TimeZone::parse(tz)?);
+
+    #[cfg(target_os = "espidf")]
+    {
+        std::env::set_var("TZ", tz);
+        unsafe { esp_idf_svc::sys::tzset() };
+    }
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! The status LED: what the camera is up to, as blink patterns.
+//!
+//! Subsystems `raise` a `Status` while it applies and `clear` it after, through a
+//! `StatusLed` handle that's just the sending end of a channel. The LED thread shows the most
+//! important one raised (`Status` is ordered by that) and waits on the channel between pin
+//! changes, so nobody ever blocks on the LED. Once every handle is gone the LED goes off and
+//! the thread stops.
+//!
+//! - `Ready`: a blip every 3 s
+//! - `Streaming`: on
+//! - `Booting`: fast blinks
+//! - `Connecting`: slower blinks
+//! - `Provisioning`: double blinks, waiting to be set up
+//! - `Updating`: a flicker, while firmware is written
+//! - `Error(n)`: `n` blinks, a pause, and again
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// In order of importance, the least first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    Ready,
+    Streaming,
+    Booting,
+    Connecting,
+    Provisioning,
+    Updating,
+    /// Blinks the code out, 1-9
+    Error(u8),
+}
+
+/// Codes `Error` is raised with
+pub mod errors {
+    pub const WIFI: u8 = 1;
+    pub const CAMERA: u8 = 2;
+    pub const SD_CARD: u8 = 3;
+}
+
+/// Longest pause before an error code starts again
+const ERROR_PAUSE: Duration = Duration::from_millis(1500);
+
+impl Status {
+    /// One cycle of the pattern, as `(on, how long)`. Repeats for as long as it's shown.
+    pub fn pattern(self) -> Vec<(bool, Duration)> {
+        let ms = Duration::from_millis;
+        match self {
+            Status::Ready => vec![(true, ms(50)), (false, ms(2950))],
+            Status::Streaming => vec![(true, ms(1000))],
+            Status::Booting => vec![(true, ms(100)), (false, ms(100))],
+            Status::Connecting => vec![(true, ms(250)), (false, ms(250))],
+            Status::Provisioning => vec![
+                (true, ms(100)),
+                (false, ms(100)),
+                (true, ms(100)),
+                (false, ms(700)),
+            ],
+            Status::Updating => vec![(true, ms(50)), (false, ms(50))],
+            Status::Error(code) => {
+                let mut pattern = Vec::new();
+                for _ in 0..code.clamp(1, 9) {
+                    pattern.extend([(true, ms(200)), (false, ms(300))]);
+                }
+                pattern.last_mut().unwrap().1 = ERROR_PAUSE;
+                pattern
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Report {
+    Raise(Status),
+    Clear(Status),
+}
+
+/// The LED itself
+pub trait StatusPin: Send {
+    fn set(&mut self, on: bool) -> anyhow::Result<()>;
+}
+
+/// What the LED should be doing: which statuses are raised, and where the shown one's
+/// pattern is up to. Time is passed in, the thread in `StatusLed::spawn` drives it.
+#[derive(Debug, Default)]
+pub struct Engine {
+    raised: Vec<Status>,
+    pattern: Vec<(bool, Duration)>,
+    step: usize,
+    /// When the current step ends, `None` with nothing to show
+    step_ends: Option<Instant>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a report in. The pattern starts over if the status shown changes.
+    pub fn report(&mut self, report: Report, now: Instant) {
+        let before = self.shown();
+        match report {
+            Report::Raise(status) if !self.raised.contains(&status) => self.raised.push(status),
+            Report::Raise(_) => {}
+            Report::Clear(status) => self.raised.retain(|&raised| raised != status),
+        }
+        let shown = self.shown();
+        if shown == before {
+            return;
+        }
+        self.pattern = shown.map(Status::pattern).unwrap_or_default();
+        self.step = 0;
+        self.step_ends = self.pattern.first().map(|&(_, length)| now + length);
+    }
+
+    /// The most important status raised
+    pub fn shown(&self) -> Option<Status> {
+        self.raised.iter().max().copied()
+    }
+
+    /// Moves past every step that's over by `now`
+    pub fn advance(&mut self, now: Instant) {
+        while let Some(ends) = self.step_ends.filter(|&ends| ends <= now) {
+            self.step = (self.step + 1) % self.pattern.len();
+            self.step_ends = Some(ends + self.pattern[self.step].1);
+        }
+    }
+
+    /// Whether the LED should be lit
+    pub fn level(&self) -> bool {
+        self.pattern.get(self.step).is_some_and(|&(on, _)| on)
+    }
+
+    /// When `advance` next has something to do
+    pub fn next_change(&self) -> Option<Instant> {
+        self.step_ends
+    }
+}
+
+/// Where statuses are reported, see the module docs. Cheap to clone, one for each subsystem.
+#[derive(Clone, Debug)]
+pub struct StatusLed {
+    reports: Sender<Report>,
+}
+
+impl StatusLed {
+    /// Starts the LED thread, with the LED off and nothing raised
+    pub fn spawn(pin: impl StatusPin + 'static) -> anyhow::Result<Self> {
+        let (reports, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("status-led".into())
+            .spawn(move || run(pin, rx))?;
+        Ok(Self { reports })
+    }
+
+    /// For boards without the LED: reports go nowhere
+    pub fn none() -> Self {
+        let (reports, _) = mpsc::channel();
+        Self { reports }
+    }
+
+    pub fn raise(&self, status: Status) {
+        // Gone only if the thread died, nothing to be done about that here
+        let _ = self.reports.send(Report::Raise(status));
+    }
+
+    pub fn clear(&self, status: Status) {
+        let _ = self.reports.send(Report::Clear(status));
+    }
+}
+
+fn run(mut pin: impl StatusPin, reports: Receiver<Report>) {
+    let mut engine = Engine::new();
+    let mut lit = None;
+    loop {
+        let level = engine.level();
+        if lit != Some(level) {
+            match pin.set(level) {
+                Ok(()) => lit = Some(level),
+                Err(e) => log::warn!("Couldn't set the status LED: {}", e),
+            }
+        }
+
+        let report = match engine.next_change() {
+            Some(at) => reports.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => reports.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match report {
+            Ok(report) => engine.report(report, Instant::now()),
+            Err(RecvTimeoutError::Timeout) => engine.advance(Instant::now()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    let _ = pin.set(false);
+}
+
+#[cfg(target_os = "espidf")]
+pub use esp::GpioStatusPin;
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use esp_idf_svc::hal::gpio::{AnyOutputPin, Output, PinDriver};
+
+    use super::StatusPin;
+    use crate::board::LedPin;
+
+    pub struct GpioStatusPin {
+        pin: PinDriver<'static, AnyOutputPin, Output>,
+        active_low: bool,
+    }
+
+    impl GpioStatusPin {
+        /// Takes over the pin. Starts off.
+        pub fn new(led: LedPin) -> anyhow::Result<Self> {
+            // SAFETY: the board profile reserves this pin for the LED, nothing else drives it
+            let pin = unsafe { AnyOutputPin::new(led.gpio) };
+            let mut this = Self {
+                pin: PinDriver::output(pin)?,
+                active_low: led.active_low,
+            };
+            this.set(false)?;
+            Ok(this)
+        }
+    }
+
+    impl StatusPin for GpioStatusPin {
+        fn set(&mut self, on: bool) -> anyhow::Result<()> {
+            if on != self.active_low {
+                self.pin.set_high()?;
+            } else {
+                self.pin.set_low()?;
+            }
+            Ok(())
+        }
+    }
+}
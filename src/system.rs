@@ -0,0 +1,88 @@
+//! Facts about the board itself: identity, memory, signal strength.
+//!
+//! Everything answers on the host too, with `None` (or a fixed id) where there's no board
+//! to ask.
+
+/// Free heap in bytes, internal RAM and PSRAM together
+pub fn free_heap() -> Option<u32> {
+    #[cfg(target_os = "espidf")]
+    {
+        // SAFETY: plain query
+        Some(unsafe { esp_idf_svc::sys::esp_get_free_heap_size() })
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        None
+    }
+}
+
+/// Lowest the free heap has been since boot
+pub fn min_free_heap() -> Option<u32> {
+    #[cfg(target_os = "espidf")]
+    {
+        // SAFETY: plain query
+        Some(unsafe { esp_idf_svc::sys::esp_get_minimum_free_heap_size() })
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        None
+    }
+}
+
+/// Signal strength of the access point we're connected to, in dBm
+pub fn rssi() -> Option<i32> {
+    #[cfg(target_os = "espidf")]
+    {
+        let mut info = esp_idf_svc::sys::wifi_ap_record_t::default();
+        // SAFETY: `info` outlives the call, which only writes into it
+        let err = unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut info) };
+        (err == esp_idf_svc::sys::ESP_OK).then_some(info.rssi as i32)
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        None
+    }
+}
+
+/// Wi-Fi station MAC address
+pub fn mac() -> Option<[u8; 6]> {
+    #[cfg(target_os = "espidf")]
+    {
+        let mut mac = [0; 6];
+        // SAFETY: `mac` is the 6 bytes the call writes
+        let err = unsafe {
+            esp_idf_svc::sys::esp_read_mac(
+                mac.as_mut_ptr(),
+                esp_idf_svc::sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+            )
+        };
+        (err == esp_idf_svc::sys::ESP_OK).then_some(mac)
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        None
+    }
+}
+
+/// `wrover-` and the last three MAC bytes, e.g. `wrover-a1b2c3`. Stays the same across
+/// reflashes, so it's good for MQTT client ids and Home Assistant unique ids. Plain
+/// `wrover` when there's no MAC.
+pub fn device_id() -> String {
+    match mac() {
+        Some(mac) => format!("wrover-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]),
+        None => "wrover".to_owned(),
+    }
+}
+
+/// Reboots the board. Only returns on the host, where there's nothing to reboot.
+pub fn restart() {
+    #[cfg(target_os = "espidf")]
+    {
+        // SAFETY: doesn't return
+        unsafe { esp_idf_svc::sys::esp_restart() };
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        log::warn!("Restart requested, but there's no board to restart");
+    }
+}
@@ -0,0 +1,202 @@
+//! Time-lapse: one shot per scheduled moment, handed to a `FrameSink` (SD card, uploader).
+//!
+//! Runs on its own thread and sleeps until the next shot is due, so start/stop from
+//! `/timelapse` take effect straight away. Shots can optionally switch the sensor to the
+//! `high_quality()` resolution and quality just for the capture.
+
+mod schedule;
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub use schedule::{sun_times, Cron, Schedule, Sun, Window};
+
+use crate::camera::Control;
+use crate::clock;
+use crate::http::App;
+use crate::ov3660::OV3660Config;
+use crate::settings::TimelapseSettings;
+use crate::sink::FrameSink;
+
+/// How long to wait before checking again for a synced clock
+const CLOCK_RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct TimelapseConfig {
+    pub schedule: Schedule,
+    pub window: Window,
+    /// Controls to switch to for each shot, empty to shoot with whatever is set
+    pub shot_controls: Vec<(Control, i32)>,
+    /// The schedule and window as the user wrote them, for `/timelapse`
+    pub description: (String, String),
+}
+
+impl TimelapseConfig {
+    pub fn from_settings(settings: &TimelapseSettings) -> anyhow::Result<Self> {
+        let shot_controls = if settings.high_quality {
+            OV3660Config::high_quality().controls()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            schedule: Schedule::parse(&settings.schedule)?,
+            window: Window::parse(&settings.window, settings.latitude, settings.longitude)?,
+            shot_controls,
+            description: (settings.schedule.clone(), settings.window.clone()),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: bool,
+    shots: u64,
+    failures: u64,
+    last_shot: Option<SystemTime>,
+    next_shot: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+pub struct Timelapse {
+    config: TimelapseConfig,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Timelapse {
+    /// Starts the scheduler thread. It only holds a weak reference to the app, so it can be
+    /// stored in the app (`App::set_timelapse`) without keeping it alive.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        config: TimelapseConfig,
+        mut sink: impl FrameSink + 'static,
+        running: bool,
+    ) -> Arc<Self> {
+        let timelapse = Arc::new(Self {
+            config,
+            state: Mutex::new(State {
+                running,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        });
+
+        let this = timelapse.clone();
+        thread::spawn(move || {
+            while let Some(app) = this.wait_for_shot().and_then(|()| weak_app.upgrade()) {
+                let result = app
+                    .capture_with(&this.config.shot_controls)
+                    .and_then(|frame| sink.store(&frame).map(|()| frame.wall_time));
+                this.shot_taken(result);
+            }
+        });
+
+        timelapse
+    }
+
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = true;
+        state.last_error = None;
+        self.changed.notify_all();
+    }
+
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.next_shot = None;
+        self.changed.notify_all();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().running
+    }
+
+    pub fn config(&self) -> &TimelapseConfig {
+        &self.config
+    }
+
+    /// Blocks until a shot is due. Only returns `None` if the schedule can never fire.
+    fn wait_for_shot(&self) -> Option<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !state.running {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+
+            // Only a plain interval makes sense without knowing what time it is
+            let now = clock::now();
+            let needs_clock = !matches!(
+                (&self.config.schedule, &self.config.window),
+                (Schedule::Every(_), Window::Always)
+            );
+            if needs_clock && !clock::is_valid(now) {
+                state.next_shot = None;
+                state.last_error = Some("Waiting for time sync".into());
+                state = self.changed.wait_timeout(state, CLOCK_RETRY).unwrap().0;
+                continue;
+            }
+
+            let tz = clock::timezone();
+            let Some(next) = self
+                .config
+                .schedule
+                .next_after(now, &self.config.window, &tz)
+            else {
+                log::error!("Time-lapse schedule never fires, stopping");
+                state.running = false;
+                state.last_error = Some("Schedule never fires".into());
+                return None;
+            };
+            state.next_shot = Some(next);
+
+            match next.duration_since(clock::now()) {
+                Ok(wait) if !wait.is_zero() => {
+                    // Woken early by start/stop, or just spuriously: go round again
+                    state = self.changed.wait_timeout(state, wait).unwrap().0;
+                    if !state.running || clock::now() < next {
+                        continue;
+                    }
+                    return Some(());
+                }
+                _ => return Some(()),
+            }
+        }
+    }
+
+    fn shot_taken(&self, result: anyhow::Result<Option<SystemTime>>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(wall_time) => {
+                state.shots += 1;
+                state.last_shot = wall_time.or_else(|| Some(clock::now()));
+                state.last_error = None;
+            }
+            Err(e) => {
+                log::warn!("Time-lapse shot failed: {}", e);
+                state.failures += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// What `/timelapse` reports
+    pub fn status_json(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let time = |t: Option<SystemTime>| t.map(clock::format_rfc3339);
+        serde_json::json!({
+            "running": state.running,
+            "schedule": self.config.description.0,
+            "window": self.config.description.1,
+            "high_quality": !self.config.shot_controls.is_empty(),
+            "shots": state.shots,
+            "failures": state.failures,
+            "last_shot": time(state.last_shot),
+            "next_shot": time(state.next_shot),
+            "last_error": state.last_error,
+        })
+        .to_string()
+    }
+}
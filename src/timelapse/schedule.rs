@@ -0,0 +1,389 @@
+//! When time-lapse shots are due: a fixed interval or a cron expression, optionally limited
+//! to a window of the day (fixed hours, or sunrise to sunset for a location).
+//!
+//! Everything is evaluated in the local zone from `clock`, so "every day at 12:00" follows
+//! DST. Pure functions of the time passed in, which keeps them testable.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::clock::{self, TimeZone};
+
+/// Give up looking for the next shot after this many steps (a schedule that can never fire,
+/// like `0 0 31 2 *`)
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    /// Shots on multiples of the interval, counted in local time from midnight, so
+    /// `every 15m` fires at :00, :15, :30 and :45
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// `every 30s` / `every 5m` / `every 2h` / `every 500ms`, or a five-field cron expression
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        let Some(interval) = text.strip_prefix("every") else {
+            return Ok(Schedule::Cron(Cron::parse(text)?));
+        };
+
+        let interval = interval.trim();
+        let split = interval
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(interval.len());
+        let (number, unit) = interval.split_at(split);
+        let number: u64 = number.parse().context("Expected e.g. 'every 30s'")?;
+        let too_long = || anyhow::anyhow!("Interval too long");
+        let interval = match unit.trim() {
+            "ms" => Duration::from_millis(number),
+            "s" | "" => Duration::from_secs(number),
+            "m" | "min" => Duration::from_secs(number.checked_mul(60).ok_or_else(too_long)?),
+            "h" => Duration::from_secs(number.checked_mul(3600).ok_or_else(too_long)?),
+            other => anyhow::bail!("Unknown interval unit '{}'", other),
+        };
+        if interval < Duration::from_millis(100) {
+            anyhow::bail!("Interval must be at least 100ms");
+        }
+        Ok(Schedule::Every(interval))
+    }
+
+    /// First shot strictly after `after`, or `None` if there isn't one within a few years
+    pub fn next_after(
+        &self,
+        after: SystemTime,
+        window: &Window,
+        tz: &TimeZone,
+    ) -> Option<SystemTime> {
+        let mut candidate = self.next_ignoring_window(after, tz)?;
+        for _ in 0..MAX_STEPS {
+            if window.contains(candidate, tz) {
+                return Some(candidate);
+            }
+            // Skip to when the window opens, then to the first shot from there on
+            let open = window.next_open(candidate, tz)?;
+            candidate = self.next_ignoring_window(open - Duration::from_millis(1), tz)?;
+        }
+        None
+    }
+
+    fn next_ignoring_window(&self, after: SystemTime, tz: &TimeZone) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(interval) => {
+                let interval = interval.as_millis() as i64;
+                let utc = unix_millis(after);
+                let offset = tz.offset_at(utc.div_euclid(1000)) as i64 * 1000;
+                let local = utc + offset;
+                let next = (local.div_euclid(interval) + 1) * interval - offset;
+                Some(from_unix_millis(next))
+            }
+            Schedule::Cron(cron) => cron.next_after(after, tz),
+        }
+    }
+}
+
+/// `minute hour day-of-month month day-of-week`, each field `*`, `n`, `a-b`, `*/step`,
+/// `a-b/step` or a comma list of those. Day of week is 0-7, both 0 and 7 being Sunday.
+/// As in Vixie cron, if both day fields are restricted a day matching either one counts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!(
+                "Cron needs 5 fields (minute hour day month weekday), got '{}'",
+                text
+            );
+        };
+
+        let mut weekdays = field(weekday, 0, 7).context("Bad weekday field")?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field(minute, 0, 59).context("Bad minute field")?,
+            hours: field(hour, 0, 23).context("Bad hour field")?,
+            days: field(day, 1, 31).context("Bad day field")?,
+            months: field(month, 1, 12).context("Bad month field")?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    fn day_matches(&self, day: u32, weekday: u32) -> bool {
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    pub fn next_after(&self, after: SystemTime, tz: &TimeZone) -> Option<SystemTime> {
+        let utc = unix_millis(after).div_euclid(1000);
+        // Next whole minute, in local time
+        let mut local = (local_seconds(utc, tz).div_euclid(60) + 1) * 60;
+
+        for _ in 0..MAX_STEPS {
+            let ((year, month, day), (hour, minute, _)) = clock::split(local);
+            let days = local.div_euclid(86_400);
+            let weekday = (days + 4).rem_euclid(7) as u32;
+
+            if self.months & (1 << month) == 0 {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                local = clock::days_from_civil(year, month, 1) * 86_400;
+            } else if !self.day_matches(day, weekday) {
+                local = (days + 1) * 86_400;
+            } else if self.hours & (1 << hour) == 0 {
+                local = (local.div_euclid(3600) + 1) * 3600;
+            } else if self.minutes & (1 << minute) == 0 {
+                local += 60;
+            } else {
+                return Some(from_local_seconds(local, tz));
+            }
+        }
+        None
+    }
+}
+
+/// Bitmask of the values a cron field allows
+fn field(text: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (a.parse()?, b.parse()?),
+                // `5/15` means 5 to the end in steps of 15
+                None if part.contains('/') => (range.parse()?, max),
+                None => {
+                    let value = range.parse()?;
+                    (value, value)
+                }
+            },
+        };
+
+        if step == 0 || start < min || end > max || start > end {
+            anyhow::bail!("'{}' is outside {}-{}", part, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Part of the day shots are allowed in
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum Window {
+    #[default]
+    Always,
+    /// Local minutes since midnight. `start > end` wraps past midnight.
+    Hours { start: u32, end: u32 },
+    /// Sunrise to sunset at this spot, widened by `margin` minutes on both sides
+    Daylight {
+        latitude: f64,
+        longitude: f64,
+        margin: i32,
+    },
+}
+
+impl Window {
+    /// `""` (always), `HH:MM-HH:MM` or `daylight` (needs the location)
+    pub fn parse(text: &str, latitude: f64, longitude: f64) -> anyhow::Result<Self> {
+        let text = text.trim();
+        if text.is_empty() || text == "always" {
+            return Ok(Window::Always);
+        }
+        if let Some(margin) = text.strip_prefix("daylight") {
+            let margin = match margin.trim() {
+                "" => 0,
+                // `daylight+30` / `daylight-15`
+                m => m
+                    .trim_start_matches('+')
+                    .parse()
+                    .context("Bad daylight margin")?,
+            };
+            if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                anyhow::bail!("Daylight window needs a valid latitude and longitude");
+            }
+            return Ok(Window::Daylight {
+                latitude,
+                longitude,
+                margin,
+            });
+        }
+
+        let (start, end) = text
+            .split_once('-')
+            .context("Expected HH:MM-HH:MM, 'daylight' or nothing")?;
+        Ok(Window::Hours {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+
+    pub fn contains(&self, time: SystemTime, tz: &TimeZone) -> bool {
+        let utc = unix_millis(time).div_euclid(1000);
+        match *self {
+            Window::Always => true,
+            Window::Hours { start, end } => {
+                let minute = (local_seconds(utc, tz).rem_euclid(86_400) / 60) as u32;
+                if start <= end {
+                    minute >= start && minute < end
+                } else {
+                    minute >= start || minute < end
+                }
+            }
+            Window::Daylight { .. } => {
+                let day = utc.div_euclid(86_400);
+                // Daylight can straddle UTC midnight, so check the neighbouring days too
+                (day - 1..=day + 1).any(|day| {
+                    self.daylight(day)
+                        .is_some_and(|(rise, set)| utc >= rise && utc < set)
+                })
+            }
+        }
+    }
+
+    /// Next moment after `time` the window opens, `None` if it never does
+    pub fn next_open(&self, time: SystemTime, tz: &TimeZone) -> Option<SystemTime> {
+        let utc = unix_millis(time).div_euclid(1000);
+        match *self {
+            Window::Always => Some(time),
+            Window::Hours { start, .. } => {
+                let local = local_seconds(utc, tz);
+                let mut open = local.div_euclid(86_400) * 86_400 + start as i64 * 60;
+                if open <= local {
+                    open += 86_400;
+                }
+                Some(from_local_seconds(open, tz))
+            }
+            Window::Daylight { .. } => {
+                let today = utc.div_euclid(86_400);
+                (today - 1..today + 370)
+                    .filter_map(|day| self.daylight(day))
+                    .map(|(rise, _)| rise)
+                    .find(|&rise| rise > utc)
+                    .map(|rise| UNIX_EPOCH + Duration::from_secs(rise as u64))
+            }
+        }
+    }
+
+    /// (open, close) in Unix seconds for the UTC day `day`, or `None` for polar night
+    fn daylight(&self, day: i64) -> Option<(i64, i64)> {
+        let Window::Daylight {
+            latitude,
+            longitude,
+            margin,
+        } = *self
+        else {
+            return None;
+        };
+        let margin = margin as i64 * 60;
+        match sun_times(day, latitude, longitude) {
+            Sun::Rises(rise, set) => Some((rise - margin, set + margin)),
+            Sun::AlwaysUp => Some((day * 86_400, (day + 1) * 86_400)),
+            Sun::AlwaysDown => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sun {
+    /// Sunrise and sunset, Unix seconds
+    Rises(i64, i64),
+    AlwaysUp,
+    AlwaysDown,
+}
+
+/// Sunrise and sunset around the solar noon nearest to UTC day `day` (days since 1970),
+/// using the NOAA approximation. Good to a minute or two, which is plenty for this.
+/// Longitude is positive east.
+pub fn sun_times(day: i64, latitude: f64, longitude: f64) -> Sun {
+    const J2000: f64 = 2_451_545.0;
+    const UNIX_JULIAN: f64 = 2_440_587.5;
+
+    let n = (day as f64 + UNIX_JULIAN + 0.5 - J2000 + 0.0008).round();
+    let mean_solar_noon = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_solar_noon).rem_euclid(360.0);
+    let m = anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic = (anomaly + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + mean_solar_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic).sin();
+
+    let declination = (ecliptic.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if cos_hour_angle < -1.0 {
+        return Sun::AlwaysUp;
+    }
+    if cos_hour_angle > 1.0 {
+        return Sun::AlwaysDown;
+    }
+
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+    let to_unix = |julian: f64| ((julian - UNIX_JULIAN) * 86_400.0).round() as i64;
+    Sun::Rises(to_unix(transit - half_day), to_unix(transit + half_day))
+}
+
+/// `HH:MM` to minutes since midnight
+fn minutes(text: &str) -> anyhow::Result<u32> {
+    let (h, m) = text.trim().split_once(':').context("Expected HH:MM")?;
+    let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        anyhow::bail!("'{}' isn't a time of day", text);
+    }
+    Ok(h * 60 + m)
+}
+
+fn local_seconds(utc: i64, tz: &TimeZone) -> i64 {
+    utc + tz.offset_at(utc) as i64
+}
+
+/// Local back to UTC. For times DST skips or repeats this picks one of the two, which is
+/// all a schedule needs.
+fn from_local_seconds(local: i64, tz: &TimeZone) -> SystemTime {
+    let utc = local - tz.offset_at(local - tz.offset as i64) as i64;
+    from_unix_millis(utc * 1000)
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+fn from_unix_millis(millis: i64) -> SystemTime {
+    if millis >= 0 {
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(millis.unsigned_abs())
+    }
+}
@@ -0,0 +1,210 @@
+//! HTTPS for the API: the certificate and key the server presents, and the plain HTTP port
+//! that only redirects to it.
+//!
+//! The pair is either uploaded (`POST /tls`) or, the first time TLS is on without one, made
+//! on the board as a self-signed P-256 certificate (`esp::generate`). Either way it's kept in
+//! NVS apart from the settings, so the key never shows up in them. Browsers can't verify a
+//! self-signed certificate, so `/status` and `GET /tls` show its SHA-256 fingerprint to
+//! compare against (or pin). A new pair is only picked up at the next boot.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac_sha256::Hash;
+use serde::Deserialize;
+
+use crate::client::base64_decode;
+use crate::clock;
+use crate::http::Response;
+use crate::settings::TlsSettings;
+
+#[cfg(target_os = "espidf")]
+pub mod esp;
+
+/// Used as the start of validity when the clock hasn't been set yet: 2024-01-01
+const FALLBACK_START: u64 = 1_704_067_200;
+
+/// How long generated certificates are valid for
+const VALID_YEARS: i64 = 20;
+
+/// A certificate (possibly followed by its chain) and its private key, both PEM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertPair {
+    pub cert: String,
+    pub key: String,
+}
+
+impl CertPair {
+    /// Checks both are PEM of the right kind. Whether the key goes with the certificate is
+    /// only found out when the server starts with them.
+    pub fn new(cert: impl Into<String>, key: impl Into<String>) -> anyhow::Result<Self> {
+        let (cert, key) = (cert.into(), key.into());
+        if pem_blocks(&cert, |label| label == "CERTIFICATE")?.is_empty() {
+            anyhow::bail!("No CERTIFICATE block in the certificate");
+        }
+        if !pem_blocks(&key, |label| label == "ENCRYPTED PRIVATE KEY")?.is_empty() {
+            anyhow::bail!("Encrypted private keys aren't supported");
+        }
+        if pem_blocks(&key, |label| label.ends_with("PRIVATE KEY"))?.len() != 1 {
+            anyhow::bail!("Expected exactly one PRIVATE KEY block in the key");
+        }
+        Ok(Self { cert, key })
+    }
+
+    /// Of the first certificate, the server's own
+    pub fn fingerprint(&self) -> String {
+        let certs = pem_blocks(&self.cert, |label| label == "CERTIFICATE").unwrap_or_default();
+        certs
+            .first()
+            .map_or_else(String::new, |der| fingerprint(der))
+    }
+}
+
+/// SHA-256 of DER, as `AB:CD:...` like browsers and `openssl x509 -fingerprint` show it
+pub fn fingerprint(der: &[u8]) -> String {
+    Hash::hash(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The DER in every `-----BEGIN <label>-----` block whose label `wanted` accepts
+pub fn pem_blocks(pem: &str, wanted: impl Fn(&str) -> bool) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut blocks = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find("-----BEGIN ") {
+        rest = &rest[start + "-----BEGIN ".len()..];
+        let Some((label, after)) = rest.split_once("-----") else {
+            anyhow::bail!("Unterminated BEGIN line");
+        };
+        let end = format!("-----END {}-----", label);
+        let Some((body, after)) = after.split_once(end.as_str()) else {
+            anyhow::bail!("No END line for {}", label);
+        };
+        rest = after;
+        if !wanted(label) {
+            continue;
+        }
+
+        let body: String = body.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        let der = base64_decode(&body)?;
+        // Everything in PEM this is used for is an ASN.1 SEQUENCE
+        if der.first() != Some(&0x30) {
+            anyhow::bail!("{} isn't DER", label);
+        }
+        blocks.push(der);
+    }
+    Ok(blocks)
+}
+
+/// Where pairs are kept between boots
+pub trait CertStore: Send {
+    fn load(&self) -> anyhow::Result<Option<CertPair>>;
+    fn save(&mut self, pair: &CertPair) -> anyhow::Result<()>;
+    /// Forgets the pair, so a new one is generated at the next boot
+    fn clear(&mut self) -> anyhow::Result<()>;
+}
+
+/// A `POST /tls` body:
+/// - `{"action":"upload","cert":"-----BEGIN CERTIFICATE-----...","key":"-----BEGIN ..."}`
+/// - `{"action":"regenerate"}`: drop the stored pair, a new one is made at the next boot
+/// - `{"action":"enable"}`, `{"action":"disable"}`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Change {
+    Upload { cert: String, key: String },
+    Regenerate,
+    Enable,
+    Disable,
+}
+
+/// What `GET /tls` answers: settings, and the fingerprints of the pair being served and of
+/// the stored one, which only takes over after a restart
+pub fn summary_json(settings: &TlsSettings, serving: Option<&str>, stored: Option<&str>) -> String {
+    let restart_required = if settings.enabled {
+        stored.is_none() || serving != stored
+    } else {
+        serving.is_some()
+    };
+    serde_json::json!({
+        "enabled": settings.enabled,
+        "port": settings.port,
+        "redirect": settings.redirect,
+        "serving": serving,
+        "stored": stored,
+        "restart_required": restart_required,
+    })
+    .to_string()
+}
+
+/// What the plain HTTP port answers everything with: a permanent redirect to the same URI
+/// over HTTPS, on the host the client asked for
+pub fn redirect(host: Option<&str>, uri: &str, https_port: u16) -> Response {
+    let Some(host) = host.and_then(host_name) else {
+        return Response::text(400, "This camera only answers over HTTPS");
+    };
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let uri = if uri.starts_with('/') && !uri.bytes().any(|b| b <= b' ') {
+        uri
+    } else {
+        "/"
+    };
+    Response::text(301, "Moved to HTTPS")
+        .with_header("Location", format!("https://{}{}{}", host, port, uri))
+}
+
+/// The name in a `Host` header, without its port. `None` for anything that couldn't be one,
+/// so it can't end up smuggled into `Location`.
+fn host_name(header: &str) -> Option<&str> {
+    let header = header.trim();
+    let name = match header.strip_prefix('[') {
+        Some(v6) => &header[..v6.find(']')? + 2],
+        None => header.split(':').next()?,
+    };
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-._[]:".contains(&b));
+    (valid && !name.is_empty()).then_some(name)
+}
+
+/// Subject (and issuer) for a generated certificate, in mbedtls' `CN=...,O=...` form.
+/// Anything that could break out of the name becomes `-`.
+pub fn subject_name(camera_name: &str) -> String {
+    let name: String = camera_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || " -_.".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = match name.trim() {
+        "" => "wrover",
+        name => name,
+    };
+    format!("CN={},O=wrover", name)
+}
+
+/// `(not_before, not_after)` for a generated certificate, as mbedtls wants them
+/// (`YYYYMMDDhhmmss`, UTC). Starts a day early in case the client's clock is behind.
+pub fn validity(now: Option<SystemTime>) -> (String, String) {
+    let start = now
+        .filter(|now| clock::is_valid(*now))
+        .unwrap_or(UNIX_EPOCH + Duration::from_secs(FALLBACK_START))
+        - Duration::from_secs(86_400);
+    let ((year, month, day), (hour, minute, second)) = clock::split(clock::unix_seconds(start));
+    let format = |year: i64, day: u32| {
+        format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        )
+    };
+    // 29 February doesn't come round every year
+    let end_day = if (month, day) == (2, 29) { 28 } else { day };
+    (format(year, day), format(year + VALID_YEARS, end_day))
+}
@@ -0,0 +1,187 @@
+use std::ffi::{c_int, c_uchar, c_void, CStr, CString};
+use std::ptr;
+
+use esp_idf_svc::http::server::Configuration;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+use esp_idf_svc::tls::X509;
+
+use super::{subject_name, validity, CertPair, CertStore};
+use crate::clock;
+
+const NAMESPACE: &str = "tls";
+const CERT_KEY: &str = "cert";
+const KEY_KEY: &str = "key";
+
+/// Room for a PEM certificate or key, generated ones are well under half of it
+const PEM_BUF: usize = 4096;
+
+/// The pair in its own NVS namespace, away from the settings blob
+pub struct NvsCerts {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsCerts {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.nvs.str_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len + 1];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_owned))
+    }
+}
+
+impl CertStore for NvsCerts {
+    fn load(&self) -> anyhow::Result<Option<CertPair>> {
+        match (self.get(CERT_KEY)?, self.get(KEY_KEY)?) {
+            (Some(cert), Some(key)) => Ok(Some(CertPair::new(cert, key)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn save(&mut self, pair: &CertPair) -> anyhow::Result<()> {
+        self.nvs.set_str(CERT_KEY, &pair.cert)?;
+        self.nvs.set_str(KEY_KEY, &pair.key)?;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        self.nvs.remove(CERT_KEY)?;
+        self.nvs.remove(KEY_KEY)?;
+        Ok(())
+    }
+}
+
+/// The stored pair, or a newly generated one that's then stored. A stored pair that doesn't
+/// load is replaced.
+pub fn load_or_generate(store: &mut dyn CertStore, camera_name: &str) -> anyhow::Result<CertPair> {
+    match store.load() {
+        Ok(Some(pair)) => return Ok(pair),
+        Ok(None) => {}
+        Err(e) => log::warn!("Stored certificate is unusable, making a new one: {}", e),
+    }
+    log::info!("Generating a self-signed certificate, this takes a few seconds");
+    let pair = generate(camera_name)?;
+    store.save(&pair)?;
+    Ok(pair)
+}
+
+/// Switches `config` to HTTPS with `pair`. The server keeps pointers to the PEM for as long
+/// as it runs, which is until reboot, so they're leaked.
+pub fn configure(config: &mut Configuration, pair: &CertPair, port: u16) {
+    let leak = |pem: &str| -> &'static [u8] {
+        Box::leak(format!("{}\0", pem).into_bytes().into_boxed_slice())
+    };
+    config.https_port = port;
+    config.server_certificate = Some(X509::pem_until_nul(leak(&pair.cert)));
+    config.private_key = Some(X509::pem_until_nul(leak(&pair.key)));
+    // Every TLS session holds tens of KB of buffers
+    config.max_open_sockets = 4;
+}
+
+/// A self-signed P-256 certificate for `camera_name`, through mbedtls
+pub fn generate(camera_name: &str) -> anyhow::Result<CertPair> {
+    let subject = CString::new(subject_name(camera_name))?;
+    let (not_before, not_after) = validity(clock::wall_clock());
+    let (not_before, not_after) = (CString::new(not_before)?, CString::new(not_after)?);
+    let mut serial = [0u8; 16];
+    unsafe { sys::esp_fill_random(serial.as_mut_ptr().cast(), serial.len()) };
+    // Positive, and not starting with a zero byte
+    serial[0] = serial[0] & 0x7f | 0x40;
+
+    let mut key: sys::mbedtls_pk_context = unsafe { std::mem::zeroed() };
+    let mut crt: sys::mbedtls_x509write_cert = unsafe { std::mem::zeroed() };
+    unsafe {
+        sys::mbedtls_pk_init(&mut key);
+        sys::mbedtls_x509write_crt_init(&mut crt);
+    }
+
+    let result = (|| unsafe {
+        let info = sys::mbedtls_pk_info_from_type(sys::mbedtls_pk_type_t_MBEDTLS_PK_ECKEY);
+        check(sys::mbedtls_pk_setup(&mut key, info), "pk_setup")?;
+        check(
+            sys::mbedtls_ecp_gen_key(
+                sys::mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                key.private_pk_ctx.cast(),
+                Some(random),
+                ptr::null_mut(),
+            ),
+            "ecp_gen_key",
+        )?;
+
+        sys::mbedtls_x509write_crt_set_version(&mut crt, sys::MBEDTLS_X509_CRT_VERSION_3 as _);
+        sys::mbedtls_x509write_crt_set_md_alg(&mut crt, sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        sys::mbedtls_x509write_crt_set_subject_key(&mut crt, &mut key);
+        sys::mbedtls_x509write_crt_set_issuer_key(&mut crt, &mut key);
+        check(
+            sys::mbedtls_x509write_crt_set_subject_name(&mut crt, subject.as_ptr()),
+            "set_subject_name",
+        )?;
+        check(
+            sys::mbedtls_x509write_crt_set_issuer_name(&mut crt, subject.as_ptr()),
+            "set_issuer_name",
+        )?;
+        check(
+            sys::mbedtls_x509write_crt_set_serial_raw(&mut crt, serial.as_mut_ptr(), serial.len()),
+            "set_serial_raw",
+        )?;
+        check(
+            sys::mbedtls_x509write_crt_set_validity(
+                &mut crt,
+                not_before.as_ptr(),
+                not_after.as_ptr(),
+            ),
+            "set_validity",
+        )?;
+        check(
+            sys::mbedtls_x509write_crt_set_basic_constraints(&mut crt, 0, -1),
+            "set_basic_constraints",
+        )?;
+
+        let mut buf = vec![0u8; PEM_BUF];
+        check(
+            sys::mbedtls_x509write_crt_pem(
+                &mut crt,
+                buf.as_mut_ptr(),
+                buf.len(),
+                Some(random),
+                ptr::null_mut(),
+            ),
+            "x509write_crt_pem",
+        )?;
+        let cert = CStr::from_ptr(buf.as_ptr().cast()).to_str()?.to_owned();
+        check(
+            sys::mbedtls_pk_write_key_pem(&mut key, buf.as_mut_ptr(), buf.len()),
+            "pk_write_key_pem",
+        )?;
+        let pem = CStr::from_ptr(buf.as_ptr().cast()).to_str()?.to_owned();
+        // The key shouldn't outlive this in memory more than it has to
+        buf.fill(0);
+        CertPair::new(cert, pem)
+    })();
+
+    unsafe {
+        sys::mbedtls_x509write_crt_free(&mut crt);
+        sys::mbedtls_pk_free(&mut key);
+    }
+    result
+}
+
+/// mbedtls' RNG callback, backed by the hardware RNG (random once Wi-Fi is up)
+extern "C" fn random(_: *mut c_void, out: *mut c_uchar, len: usize) -> c_int {
+    unsafe { sys::esp_fill_random(out.cast(), len) };
+    0
+}
+
+fn check(code: c_int, what: &str) -> anyhow::Result<()> {
+    if code != 0 {
+        anyhow::bail!("mbedtls {} failed: -0x{:04x}", what, -code);
+    }
+    Ok(())
+}
@@ -0,0 +1,486 @@
+//! Trigger inputs: PIR sensors and buttons on spare GPIOs (`Board::trigger_pins`).
+//!
+//! An input's interrupt only says that the pin changed. The `Triggers` thread waits until it
+//! has held still for `debounce_ms`, reads the level, and fires if that's a new level the
+//! input's `edge` cares about (`Debouncer`, which is given the time so the host can play
+//! edges at it). Firing runs every `TriggerHandler`, which act on the input's `actions`:
+//! save a snapshot, record a clip, publish an event. `Wake` is for deep sleep, those inputs
+//! are set up as wake sources before the chip goes down (`esp::enable_wake`).
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::http::App;
+use crate::mqtt::Mqtt;
+use crate::recorder::{self, Recorder, RecorderConfig};
+use crate::settings::{Edge, TriggerAction, TriggerSettings};
+use crate::sink::FrameSink;
+
+/// Longest the thread waits between looking at the handlers and whether the app is gone
+const TICK: Duration = Duration::from_secs(1);
+
+/// Turns raw pin changes into settled edges, see the module docs
+#[derive(Clone, Debug)]
+pub struct Debouncer {
+    edge: Edge,
+    debounce: Duration,
+    level: bool,
+    /// The last raw change that hasn't settled yet
+    changed: Option<Instant>,
+}
+
+impl Debouncer {
+    /// Starting out settled at `level`
+    pub fn new(edge: Edge, debounce: Duration, level: bool) -> Self {
+        Self {
+            edge,
+            debounce,
+            level,
+            changed: None,
+        }
+    }
+
+    /// The settled level
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// The pin changed at `at`. Each change starts the wait over.
+    pub fn changed(&mut self, at: Instant) {
+        self.changed = Some(at);
+    }
+
+    /// When the pin's worth reading, `None` while it's quiet
+    pub fn due(&self) -> Option<Instant> {
+        self.changed.map(|at| at + self.debounce)
+    }
+
+    /// The pin read `level` at `now`. Returns `Rising` or `Falling` if that's a settled
+    /// change the edge setting fires on.
+    pub fn settle(&mut self, level: bool, now: Instant) -> Option<Edge> {
+        if !self.due().is_some_and(|due| due <= now) {
+            return None;
+        }
+        self.changed = None;
+        if level == self.level {
+            // Bounced back to where it was
+            return None;
+        }
+        self.level = level;
+        let edge = if level { Edge::Rising } else { Edge::Falling };
+        (self.edge == Edge::Both || self.edge == edge).then_some(edge)
+    }
+}
+
+/// Where the inputs are read: GPIO interrupts on the board, made-up edges on the host.
+/// Inputs are numbered in the order they were configured.
+pub trait TriggerPins: Send {
+    /// Waits for a change on any input, at most `timeout`. The inputs that changed since
+    /// the last call, none if it timed out.
+    fn wait(&mut self, timeout: Duration) -> Vec<usize>;
+
+    fn level(&self, input: usize) -> bool;
+}
+
+#[derive(Clone, Debug)]
+pub struct TriggerEvent {
+    /// The input that fired
+    pub input: TriggerSettings,
+    /// `Rising` or `Falling`
+    pub edge: Edge,
+    /// Uptime
+    pub at: Duration,
+}
+
+impl TriggerEvent {
+    pub fn wants(&self, action: TriggerAction) -> bool {
+        self.input.actions.contains(&action)
+    }
+}
+
+/// Something to do when an input fires
+pub trait TriggerHandler: Send {
+    fn fired(&mut self, event: &TriggerEvent, app: &Arc<App>);
+
+    /// Called at least once a second, for handlers with something running
+    fn tick(&mut self, _app: &Arc<App>) {}
+}
+
+impl<F: FnMut(&TriggerEvent, &Arc<App>) + Send> TriggerHandler for F {
+    fn fired(&mut self, event: &TriggerEvent, app: &Arc<App>) {
+        self(event, app)
+    }
+}
+
+/// Saves a streamed frame for inputs with `Snapshot`
+pub struct SnapshotOnTrigger<S>(pub S);
+
+impl<S: FrameSink> TriggerHandler for SnapshotOnTrigger<S> {
+    fn fired(&mut self, event: &TriggerEvent, app: &Arc<App>) {
+        if !event.wants(TriggerAction::Snapshot) {
+            return;
+        }
+        let result = app.stream_frame().and_then(|frame| self.0.store(&frame));
+        if let Err(e) = result {
+            log::warn!("Couldn't save a snapshot for {}: {}", event.input.name, e);
+        }
+    }
+}
+
+struct Recording {
+    stop: Arc<AtomicBool>,
+    until: Instant,
+    thread: JoinHandle<()>,
+}
+
+/// Records a clip for inputs with `Record`, until `record_secs` after the last time one
+/// fired
+pub struct RecordOnTrigger {
+    config: RecorderConfig,
+    recording: Option<Recording>,
+}
+
+impl RecordOnTrigger {
+    pub fn new(config: RecorderConfig) -> Self {
+        Self {
+            config,
+            recording: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    fn stop(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            recording.stop.store(true, Ordering::Relaxed);
+            let _ = recording.thread.join();
+        }
+    }
+}
+
+impl TriggerHandler for RecordOnTrigger {
+    fn fired(&mut self, event: &TriggerEvent, app: &Arc<App>) {
+        if !event.wants(TriggerAction::Record) {
+            return;
+        }
+        let until = Instant::now() + Duration::from_secs(event.input.record_secs as u64);
+        if let Some(recording) = &mut self.recording {
+            recording.until = recording.until.max(until);
+            return;
+        }
+        match Recorder::new(self.config.clone()) {
+            Ok(recorder) => {
+                log::info!("Recording for {}", event.input.name);
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread =
+                    recorder::spawn(Arc::downgrade(app), recorder, self.config.fps, stop.clone());
+                self.recording = Some(Recording {
+                    stop,
+                    until,
+                    thread,
+                });
+            }
+            Err(e) => log::error!("Couldn't record for {}: {}", event.input.name, e),
+        }
+    }
+
+    fn tick(&mut self, _app: &Arc<App>) {
+        let done = self.recording.as_ref().is_some_and(|recording| {
+            recording.until <= Instant::now() || recording.thread.is_finished()
+        });
+        if done {
+            self.stop();
+        }
+    }
+}
+
+impl Drop for RecordOnTrigger {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Sends inputs with `Publish` to the MQTT events topic
+pub struct PublishOnTrigger(pub Arc<Mqtt>);
+
+impl TriggerHandler for PublishOnTrigger {
+    fn fired(&mut self, event: &TriggerEvent, _app: &Arc<App>) {
+        if event.wants(TriggerAction::Publish) {
+            self.0.publish_trigger(event);
+        }
+    }
+}
+
+/// Checks each input and that no two share a pin
+pub fn validate(inputs: &[TriggerSettings]) -> anyhow::Result<()> {
+    let mut pins = HashSet::new();
+    for input in inputs {
+        input.validate()?;
+        if !pins.insert(input.gpio) {
+            anyhow::bail!("GPIO{} is used by more than one trigger", input.gpio);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default)]
+struct Counts {
+    fired: u32,
+    last: Option<Duration>,
+}
+
+/// The inputs being watched, see the module docs
+pub struct Triggers {
+    inputs: Vec<TriggerSettings>,
+    counts: Mutex<Vec<Counts>>,
+    handlers: Mutex<Vec<Box<dyn TriggerHandler>>>,
+}
+
+impl Triggers {
+    /// Starts watching `pins` on a background thread, which stops once the app is gone.
+    /// `inputs` are in the same order as the pins.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        inputs: Vec<TriggerSettings>,
+        mut pins: impl TriggerPins + 'static,
+    ) -> anyhow::Result<Arc<Self>> {
+        validate(&inputs)?;
+        let triggers = Arc::new(Self {
+            counts: Mutex::new(vec![Counts::default(); inputs.len()]),
+            inputs,
+            handlers: Mutex::new(Vec::new()),
+        });
+
+        // Levels are read here, so changes from as soon as this returns count
+        let mut debouncers: Vec<_> = triggers
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let debounce = Duration::from_millis(input.debounce_ms as u64);
+                Debouncer::new(input.edge, debounce, pins.level(i))
+            })
+            .collect();
+        let this = triggers.clone();
+        thread::Builder::new()
+            .name("triggers".into())
+            .spawn(move || loop {
+                let due = debouncers.iter().filter_map(Debouncer::due).min();
+                let timeout = due.map_or(TICK, |due| {
+                    due.saturating_duration_since(Instant::now()).min(TICK)
+                });
+                let changed = pins.wait(timeout);
+                let Some(app) = weak_app.upgrade() else {
+                    break;
+                };
+
+                let now = Instant::now();
+                for input in changed {
+                    debouncers[input].changed(now);
+                }
+                for (input, debouncer) in debouncers.iter_mut().enumerate() {
+                    if let Some(edge) = debouncer.settle(pins.level(input), now) {
+                        this.fire(input, edge, &app);
+                    }
+                }
+                for handler in this.handlers.lock().unwrap().iter_mut() {
+                    handler.tick(&app);
+                }
+            })?;
+
+        Ok(triggers)
+    }
+
+    /// Adds something to do when an input fires
+    pub fn add_handler(&self, handler: impl TriggerHandler + 'static) {
+        self.handlers.lock().unwrap().push(Box::new(handler));
+    }
+
+    pub fn inputs(&self) -> &[TriggerSettings] {
+        &self.inputs
+    }
+
+    /// How many times the input called `name` has fired
+    pub fn fired(&self, name: &str) -> Option<u32> {
+        let input = self.inputs.iter().position(|input| input.name == name)?;
+        Some(self.counts.lock().unwrap()[input].fired)
+    }
+
+    /// Inputs, counts and when each last fired (uptime)
+    pub fn status_json(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let inputs: Vec<_> = self
+            .inputs
+            .iter()
+            .zip(counts.iter())
+            .map(|(input, counts)| {
+                serde_json::json!({
+                    "name": input.name,
+                    "gpio": input.gpio,
+                    "actions": input.actions,
+                    "fired": counts.fired,
+                    "last_fired_ms": counts.last.map(|last| last.as_millis() as u64),
+                })
+            })
+            .collect();
+        serde_json::json!({ "inputs": inputs }).to_string()
+    }
+
+    fn fire(&self, input: usize, edge: Edge, app: &Arc<App>) {
+        let event = TriggerEvent {
+            input: self.inputs[input].clone(),
+            edge,
+            at: app.uptime(),
+        };
+        log::info!("Trigger {} fired ({:?})", event.input.name, edge);
+        {
+            let mut counts = self.counts.lock().unwrap();
+            counts[input].fired += 1;
+            counts[input].last = Some(event.at);
+        }
+        for handler in self.handlers.lock().unwrap().iter_mut() {
+            handler.fired(&event, app);
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub mod esp {
+    use std::num::NonZeroU32;
+    use std::time::Duration;
+
+    use esp_idf_svc::hal::delay::TickType;
+    use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull as GpioPull};
+    use esp_idf_svc::hal::task::notification::Notification;
+    use esp_idf_svc::sys::{self, esp};
+
+    use super::TriggerPins;
+    use crate::settings::{Pull, TriggerAction, TriggerSettings};
+
+    /// The inputs on their GPIOs, each interrupt setting its input's bit in a task
+    /// notification
+    pub struct GpioTriggerPins {
+        pins: Vec<PinDriver<'static, AnyIOPin, Input>>,
+        /// Made on the first `wait`: notifications go to the task that made them, which has
+        /// to be the one waiting
+        notification: Option<Notification>,
+    }
+
+    // SAFETY: the notification is only made and used on the thread that calls `wait`
+    unsafe impl Send for GpioTriggerPins {}
+
+    impl GpioTriggerPins {
+        /// Takes over the inputs' pins. At most 32 inputs, one notification bit each.
+        pub fn new(inputs: &[TriggerSettings]) -> anyhow::Result<Self> {
+            if inputs.len() > 32 {
+                anyhow::bail!("At most 32 triggers, not {}", inputs.len());
+            }
+            let mut pins = Vec::new();
+            for input in inputs {
+                // SAFETY: `Board::trigger_pins` are kept free for triggers, and `validate`
+                // made sure no two inputs share one
+                let pin = unsafe { AnyIOPin::new(input.gpio) };
+                let mut pin = PinDriver::input(pin)?;
+                pin.set_pull(match input.pull {
+                    Pull::None => GpioPull::Floating,
+                    Pull::Up => GpioPull::Up,
+                    Pull::Down => GpioPull::Down,
+                })?;
+                pin.set_interrupt_type(InterruptType::AnyEdge)?;
+                pins.push(pin);
+            }
+            Ok(Self {
+                pins,
+                notification: None,
+            })
+        }
+    }
+
+    impl TriggerPins for GpioTriggerPins {
+        fn wait(&mut self, timeout: Duration) -> Vec<usize> {
+            let notification = match &mut self.notification {
+                Some(notification) => notification,
+                None => {
+                    let notification = Notification::new();
+                    for (i, pin) in self.pins.iter_mut().enumerate() {
+                        let notifier = notification.notifier();
+                        let bit = NonZeroU32::new(1 << i).unwrap();
+                        // SAFETY: the callback runs in the ISR and only notifies, which is
+                        // ISR safe. The pins unsubscribe when dropped, before the notifier is.
+                        let subscribed = unsafe {
+                            pin.subscribe(move || {
+                                notifier.notify_and_yield(bit);
+                            })
+                        };
+                        if let Err(e) = subscribed.and_then(|()| pin.enable_interrupt()) {
+                            log::error!("Trigger {} has no interrupt: {}", i, e);
+                        }
+                    }
+                    self.notification.insert(notification)
+                }
+            };
+
+            let bits = notification
+                .wait(TickType::from(timeout).ticks())
+                .map_or(0, NonZeroU32::get);
+            let changed: Vec<_> = (0..self.pins.len())
+                .filter(|i| bits & (1 << i) != 0)
+                .collect();
+            // Each interrupt turns itself off when it fires
+            for &i in &changed {
+                if let Err(e) = self.pins[i].enable_interrupt() {
+                    log::warn!("Couldn't re-arm trigger {}: {}", i, e);
+                }
+            }
+            changed
+        }
+
+        fn level(&self, input: usize) -> bool {
+            self.pins[input].is_high()
+        }
+    }
+
+    /// Sets the inputs with `Wake` up to wake the chip from deep sleep, at their
+    /// `active_level`. The first one uses EXT0, any others EXT1, which can only wake on
+    /// any of them going high or all of them going low.
+    pub fn enable_wake(inputs: &[TriggerSettings]) -> anyhow::Result<()> {
+        let waking: Vec<_> = inputs
+            .iter()
+            .filter(|input| input.actions.contains(&TriggerAction::Wake))
+            .collect();
+        let Some((first, rest)) = waking.split_first() else {
+            return Ok(());
+        };
+
+        for input in &waking {
+            // Only the RTC domain is powered in deep sleep, so the pulls have to be its own
+            match input.pull {
+                Pull::Up => esp!(unsafe { sys::rtc_gpio_pullup_en(input.gpio) })?,
+                Pull::Down => esp!(unsafe { sys::rtc_gpio_pulldown_en(input.gpio) })?,
+                Pull::None => {}
+            }
+        }
+        let level = first.active_level() as i32;
+        esp!(unsafe { sys::esp_sleep_enable_ext0_wakeup(first.gpio, level) })?;
+
+        let Some(level) = rest.first().map(|input| input.active_level()) else {
+            return Ok(());
+        };
+        if rest.iter().any(|input| input.active_level() != level) {
+            anyhow::bail!("Triggers after the first that wake the chip have to share a level");
+        }
+        let mask = rest.iter().fold(0u64, |mask, input| mask | 1 << input.gpio);
+        let mode = if level {
+            sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_HIGH
+        } else {
+            sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ALL_LOW
+        };
+        esp!(unsafe { sys::esp_sleep_enable_ext1_wakeup(mask, mode) })?;
+        Ok(())
+    }
+}
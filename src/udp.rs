@@ -0,0 +1,449 @@
+//! Low-latency stream over UDP, for robots and anything else that would rather lose a frame
+//! than wait for it.
+//!
+//! Each JPEG is split into datagrams of at most `max_packet` bytes, sent to one host or a
+//! multicast group. Every fragment starts with a `Header` (28 bytes, big endian):
+//!
+//! | bytes | field                                                     |
+//! |-------|-----------------------------------------------------------|
+//! | 0-1   | magic, `WU`                                               |
+//! | 2     | version, 1                                                |
+//! | 3     | flags, 0                                                  |
+//! | 4-7   | frame id, counting up from a random start and wrapping    |
+//! | 8-9   | fragment index                                            |
+//! | 10-11 | fragments in the frame                                    |
+//! | 12-15 | frame length                                              |
+//! | 16-19 | offset of this fragment's data in the frame               |
+//! | 20-27 | capture time, microseconds (since the epoch once synced)  |
+//!
+//! Nothing is resent. `Reassembler` puts frames back together on the other end, giving up
+//! on a frame as soon as a newer one is complete, and ignoring frames that claim to be
+//! bigger than it's willing to hold.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::battery;
+use crate::camera::FrameBuffer;
+use crate::http::App;
+use crate::random;
+use crate::settings::UdpSettings;
+
+pub const MAGIC: [u8; 2] = *b"WU";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 28;
+
+/// Port used when the target doesn't name one
+pub const DEFAULT_PORT: u16 = 5000;
+
+/// Smallest `max_packet` that leaves room for some data
+const MIN_PACKET: usize = HEADER_LEN + 64;
+
+/// Biggest frame `Reassembler` holds by default, well over a UXGA JPEG at high quality
+pub const MAX_FRAME: usize = 512 * 1024;
+
+/// How long to back off when the network stack runs out of buffers mid-frame
+const SEND_RETRY: Duration = Duration::from_millis(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub frame_id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub frame_len: u32,
+    pub offset: u32,
+    pub timestamp_us: u64,
+}
+
+impl Header {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[VERSION, 0]);
+        out.extend_from_slice(&self.frame_id.to_be_bytes());
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.count.to_be_bytes());
+        out.extend_from_slice(&self.frame_len.to_be_bytes());
+        out.extend_from_slice(&self.offset.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_us.to_be_bytes());
+    }
+
+    /// The header and data of one datagram
+    pub fn parse(packet: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN {
+            anyhow::bail!("{} bytes is too short for a fragment", packet.len());
+        }
+        if packet[..2] != MAGIC {
+            anyhow::bail!("Not a wrover fragment");
+        }
+        if packet[2] != VERSION {
+            anyhow::bail!("Unsupported version {}", packet[2]);
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().unwrap());
+        let header = Header {
+            frame_id: u32_at(4),
+            index: u16_at(8),
+            count: u16_at(10),
+            frame_len: u32_at(12),
+            offset: u32_at(16),
+            timestamp_us: u64::from_be_bytes(packet[20..28].try_into().unwrap()),
+        };
+
+        let data = &packet[HEADER_LEN..];
+        if header.index >= header.count {
+            anyhow::bail!("Fragment {} of {}", header.index, header.count);
+        }
+        if header.offset as u64 + data.len() as u64 > header.frame_len as u64 {
+            anyhow::bail!(
+                "{} bytes at {} overrun a {} byte frame",
+                data.len(),
+                header.offset,
+                header.frame_len
+            );
+        }
+        Ok((header, data))
+    }
+}
+
+/// Splits a frame into datagrams of at most `max_packet` bytes, headers included
+pub fn fragment(
+    frame_id: u32,
+    timestamp_us: u64,
+    data: &[u8],
+    max_packet: usize,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    if max_packet < MIN_PACKET {
+        anyhow::bail!("Packets of {} bytes are too small", max_packet);
+    }
+    let chunk = max_packet - HEADER_LEN;
+    let count = data.len().div_ceil(chunk).max(1);
+    let (Ok(count), Ok(frame_len)) = (u16::try_from(count), u32::try_from(data.len())) else {
+        anyhow::bail!("A {} byte frame is too big", data.len());
+    };
+
+    let mut packets = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let start = index as usize * chunk;
+        let part = &data[start..(start + chunk).min(data.len())];
+        let mut packet = Vec::with_capacity(HEADER_LEN + part.len());
+        Header {
+            frame_id,
+            index,
+            count,
+            frame_len,
+            offset: start as u32,
+            timestamp_us,
+        }
+        .write(&mut packet);
+        packet.extend_from_slice(part);
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+/// A frame put back together
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u32,
+    pub timestamp_us: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveStats {
+    /// Frames put back together
+    pub frames: u64,
+    /// Frames that never completed, counted once a newer one has
+    pub lost: u64,
+    pub fragments: u64,
+    /// Duplicates, and fragments of frames already delivered or given up on
+    pub late: u64,
+    /// Datagrams that weren't fragments, or were for frames over the size limit
+    pub invalid: u64,
+}
+
+struct Partial {
+    header: Header,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    missing: usize,
+}
+
+/// Puts fragments back into frames, in whatever order they arrive
+pub struct Reassembler {
+    /// Frames in progress, oldest first
+    partial: Vec<Partial>,
+    /// Most frames in progress at once, the oldest is given up on past this
+    window: usize,
+    /// Frames claiming to be bigger than this are dropped rather than allocated
+    max_frame: usize,
+    newest: Option<u32>,
+    stats: ReceiveStats,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl Reassembler {
+    pub fn new(window: usize) -> Self {
+        Self {
+            partial: Vec::new(),
+            window: window.max(1),
+            max_frame: MAX_FRAME,
+            newest: None,
+            stats: ReceiveStats::default(),
+        }
+    }
+
+    /// Drops frames over `max_frame` bytes instead of `MAX_FRAME`
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    pub fn stats(&self) -> ReceiveStats {
+        self.stats
+    }
+
+    /// Takes one datagram, returning the frame it completes if any
+    pub fn push(&mut self, packet: &[u8]) -> Option<Frame> {
+        let header = Header::parse(packet)
+            .ok()
+            .filter(|(header, _)| header.frame_len as usize <= self.max_frame);
+        let Some((header, data)) = header else {
+            self.stats.invalid += 1;
+            return None;
+        };
+        self.stats.fragments += 1;
+        if self
+            .newest
+            .is_some_and(|newest| !is_after(header.frame_id, newest))
+        {
+            self.stats.late += 1;
+            return None;
+        }
+
+        let position = match self
+            .partial
+            .iter()
+            .position(|p| p.header.frame_id == header.frame_id)
+        {
+            Some(position) => position,
+            None => {
+                if self.partial.len() >= self.window {
+                    self.partial.remove(0);
+                }
+                self.partial.push(Partial {
+                    header,
+                    data: vec![0; header.frame_len as usize],
+                    received: vec![false; header.count as usize],
+                    missing: header.count as usize,
+                });
+                self.partial.len() - 1
+            }
+        };
+
+        let partial = &mut self.partial[position];
+        let first = partial.header;
+        if (header.count, header.frame_len) != (first.count, first.frame_len)
+            || partial.received[header.index as usize]
+        {
+            self.stats.late += 1;
+            return None;
+        }
+        let offset = header.offset as usize;
+        partial.data[offset..offset + data.len()].copy_from_slice(data);
+        partial.received[header.index as usize] = true;
+        partial.missing -= 1;
+        if partial.missing > 0 {
+            return None;
+        }
+
+        let done = self.partial.remove(position);
+        if let Some(newest) = self.newest {
+            self.stats.lost += done.header.frame_id.wrapping_sub(newest) as u64 - 1;
+        }
+        self.newest = Some(done.header.frame_id);
+        // Anything older can't be delivered any more
+        self.partial
+            .retain(|p| is_after(p.header.frame_id, done.header.frame_id));
+        self.stats.frames += 1;
+        Some(Frame {
+            id: done.header.frame_id,
+            timestamp_us: done.header.timestamp_us,
+            data: done.data,
+        })
+    }
+}
+
+/// Whether frame id `a` comes after `b`, allowing for wrapping
+fn is_after(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+#[derive(Clone, Debug)]
+pub struct UdpConfig {
+    /// A host, or a multicast group
+    pub target: SocketAddr,
+    pub fps: f32,
+    /// Biggest datagram, header included. Keep it under the MTU to avoid IP fragmentation.
+    pub max_packet: usize,
+    /// Hops for multicast
+    pub ttl: u32,
+}
+
+impl UdpConfig {
+    /// Resolves `settings.target`, so fails if it doesn't
+    pub fn from_settings(settings: &UdpSettings) -> anyhow::Result<Self> {
+        Ok(Self {
+            target: resolve(&settings.target)?,
+            fps: settings.fps.max(0.1),
+            max_packet: (settings.max_packet as usize).max(MIN_PACKET),
+            ttl: settings.ttl,
+        })
+    }
+}
+
+/// `host:port`, or just `host` for `DEFAULT_PORT`
+fn resolve(target: &str) -> anyhow::Result<SocketAddr> {
+    if target.is_empty() {
+        anyhow::bail!("No UDP target set");
+    }
+    let addrs = match target.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => (target, DEFAULT_PORT)
+            .to_socket_addrs()
+            .with_context(|| format!("Can't resolve UDP target {}", target))?,
+    };
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("UDP target {} has no address", target))
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    frames: u64,
+    packets: u64,
+    errors: u64,
+    last_error: Option<String>,
+}
+
+pub struct UdpStream {
+    config: UdpConfig,
+    local_addr: SocketAddr,
+    stats: Mutex<Stats>,
+}
+
+impl UdpStream {
+    /// Starts sending to `config.target`. Only holds a weak reference to the app, and stops
+    /// once it's gone.
+    pub fn spawn(weak_app: Weak<App>, config: UdpConfig) -> anyhow::Result<Arc<Self>> {
+        let bind = if config.target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind).context("Binding the UDP stream socket failed")?;
+        if let SocketAddr::V4(target) = config.target {
+            if target.ip().is_multicast() {
+                socket.set_multicast_ttl_v4(config.ttl)?;
+            }
+        }
+        let udp = Arc::new(Self {
+            config,
+            local_addr: socket.local_addr()?,
+            stats: Mutex::new(Stats::default()),
+        });
+        log::info!("Streaming over UDP to {}", udp.config.target);
+
+        let this = udp.clone();
+        thread::spawn(move || {
+            let interval = Duration::from_secs_f32(1.0 / this.config.fps);
+            let mut frame_id = random::u32();
+            loop {
+                let started = Instant::now();
+                let Some(app) = weak_app.upgrade() else {
+                    break;
+                };
+                let Some(interval) = app.stream_limits().interval(interval) else {
+                    drop(app);
+                    thread::sleep(battery::PAUSED_CHECK);
+                    continue;
+                };
+                let frame = app.stream_frame();
+                drop(app);
+
+                match frame.and_then(|frame| this.send(&socket, frame_id, &frame)) {
+                    Ok(()) => frame_id = frame_id.wrapping_add(1),
+                    Err(e) => {
+                        log::debug!("UDP stream: {}", e);
+                        let mut stats = this.stats.lock().unwrap();
+                        stats.errors += 1;
+                        stats.last_error = Some(e.to_string());
+                    }
+                }
+                thread::sleep(interval.saturating_sub(started.elapsed()));
+            }
+        });
+
+        Ok(udp)
+    }
+
+    fn send(&self, socket: &UdpSocket, frame_id: u32, frame: &FrameBuffer) -> anyhow::Result<()> {
+        let packets = fragment(
+            frame_id,
+            timestamp_us(frame),
+            &frame.data,
+            self.config.max_packet,
+        )?;
+        for packet in &packets {
+            // lwIP runs out of buffers when a whole frame goes out at once, give it a moment
+            if socket.send_to(packet, self.config.target).is_err() {
+                thread::sleep(SEND_RETRY);
+                socket
+                    .send_to(packet, self.config.target)
+                    .context("Sending a fragment failed")?;
+            }
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.frames += 1;
+        stats.packets += packets.len() as u64;
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Target and counts, as JSON
+    pub fn status_json(&self) -> String {
+        let stats = self.stats.lock().unwrap();
+        serde_json::json!({
+            "target": self.config.target.to_string(),
+            "fps": self.config.fps,
+            "max_packet": self.config.max_packet,
+            "frames": stats.frames,
+            "packets": stats.packets,
+            "errors": stats.errors,
+            "last_error": stats.last_error,
+        })
+        .to_string()
+    }
+}
+
+/// Same clock as the `X-Timestamp` header
+fn timestamp_us(frame: &FrameBuffer) -> u64 {
+    let since = match frame.wall_time {
+        Some(time) => time.duration_since(UNIX_EPOCH).unwrap_or_default(),
+        None => frame.timestamp,
+    };
+    since.as_micros() as u64
+}
@@ -0,0 +1,615 @@
+//! Uploads: captures PUT to a WebDAV share, an S3-style bucket or anything else that takes
+//! plain `PUT`s, named from a path template like `{camera}/{date}/{time}.jpg`.
+//!
+//! Uploads wait in a `RetryQueue` and are retried with a doubling delay. Ones that run out
+//! of retries, or get pushed out of a full queue, go to a `Spool` on the SD card instead,
+//! and are replayed oldest first once the server answers again.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+
+use crate::camera::{FrameBuffer, FrameFormat};
+use crate::client::{self, Auth, HttpClient, Method, Request};
+use crate::clock;
+use crate::http::App;
+use crate::settings::UploadSettings;
+use crate::sink::FrameSink;
+
+/// How often an idle worker checks whether the app is still there
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Camera,
+    Device,
+    Date,
+    Time,
+    Uptime,
+}
+
+/// Where a capture goes, relative to the base URL. Placeholders:
+/// - `{camera}`: the camera name, with any `/` replaced
+/// - `{device}`: `wrover-<last 3 MAC bytes>`
+/// - `{date}`: `YYYYMMDD` in local time, `boot` before the clock is synced
+/// - `{time}`: `HHMMSS_mmm` in local time, the uptime in ms before the clock is synced
+/// - `{uptime}`: ms since boot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathTemplate {
+    pieces: Vec<Piece>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                pieces.push(Piece::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unclosed {{ in {:?}", template))?;
+            pieces.push(match &rest[start + 1..start + end] {
+                "camera" => Piece::Camera,
+                "device" => Piece::Device,
+                "date" => Piece::Date,
+                "time" => Piece::Time,
+                "uptime" => Piece::Uptime,
+                other => anyhow::bail!("Unknown placeholder {{{}}} in {:?}", other, template),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            anyhow::bail!("Stray }} in {:?}", template);
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_owned()));
+        }
+        if pieces.is_empty() {
+            anyhow::bail!("Empty path template");
+        }
+        Ok(Self { pieces })
+    }
+
+    pub fn render(&self, camera: &str, device: &str, frame: &FrameBuffer) -> String {
+        let uptime = frame.timestamp.as_millis().to_string();
+        let (date, time) = match frame.wall_time {
+            Some(wall_time) => {
+                let stamp = clock::format_compact(wall_time);
+                let millis = wall_time
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .subsec_millis();
+                (
+                    stamp[..8].to_owned(),
+                    format!("{}_{:03}", &stamp[9..], millis),
+                )
+            }
+            None => ("boot".to_owned(), uptime.clone()),
+        };
+
+        let mut path = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => path += text,
+                Piece::Camera => path += &camera.replace('/', "_"),
+                Piece::Device => path += device,
+                Piece::Date => path += &date,
+                Piece::Time => path += &time,
+                Piece::Uptime => path += &uptime,
+            }
+        }
+        path.trim_start_matches('/').to_owned()
+    }
+}
+
+/// `path` under `base`, with anything but unreserved characters and `/` %-encoded
+pub fn url_for(base: &str, path: &str) -> String {
+    let mut url = base.trim_end_matches('/').to_owned();
+    url.push('/');
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                url.push(byte as char)
+            }
+            _ => url += &format!("%{:02X}", byte),
+        }
+    }
+    url
+}
+
+/// One capture on its way
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upload {
+    /// Relative to the base URL
+    pub path: String,
+    pub jpeg: Vec<u8>,
+    /// Failed tries so far
+    pub attempts: u32,
+}
+
+impl Upload {
+    pub fn new(path: impl Into<String>, jpeg: Vec<u8>) -> Self {
+        Self {
+            path: path.into(),
+            jpeg,
+            attempts: 0,
+        }
+    }
+}
+
+/// Uploads waiting for their (next) turn, each with the time it's due
+#[derive(Debug)]
+pub struct RetryQueue {
+    pending: VecDeque<(Instant, Upload)>,
+    capacity: usize,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl RetryQueue {
+    /// `retries` after the first attempt, the first one `backoff` later and each one after
+    /// that twice as long as the one before
+    pub fn new(capacity: usize, retries: u32, backoff: Duration) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            capacity: capacity.max(1),
+            retries,
+            backoff,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Due straight away. If the queue is full the oldest upload makes room, and is returned.
+    pub fn push(&mut self, upload: Upload, now: Instant) -> Option<Upload> {
+        let evicted = if self.pending.len() >= self.capacity {
+            self.pending.pop_front().map(|(_, upload)| upload)
+        } else {
+            None
+        };
+        self.pending.push_back((now, upload));
+        evicted
+    }
+
+    /// When the next upload is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|(due, _)| *due).min()
+    }
+
+    /// The upload that has been due the longest, oldest first on a tie
+    pub fn pop_due(&mut self, now: Instant) -> Option<Upload> {
+        let (index, _) = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, (due, _))| *due <= now)
+            .min_by_key(|(_, (due, _))| *due)?;
+        self.pending.remove(index).map(|(_, upload)| upload)
+    }
+
+    /// Queues a failed upload again after its backoff. Hands it back once it has used up its
+    /// retries. Doesn't push anything out, so the queue can go one over while an upload is
+    /// being sent.
+    pub fn retry(&mut self, mut upload: Upload, now: Instant) -> Option<Upload> {
+        upload.attempts += 1;
+        if upload.attempts > self.retries {
+            return Some(upload);
+        }
+        let delay = self.backoff * 2u32.saturating_pow(upload.attempts - 1);
+        self.pending.push_back((now + delay, upload));
+        None
+    }
+}
+
+/// Uploads that couldn't be sent, as files under `dir` at their upload path
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn store(&self, upload: &Upload) -> anyhow::Result<()> {
+        let path = self.dir.join(&upload.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Can't create {}", parent.display()))?;
+        }
+        fs::write(&path, &upload.jpeg).with_context(|| format!("Can't write {}", path.display()))
+    }
+
+    /// Every spooled upload path, in order
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        collect(&self.dir, &self.dir, &mut paths);
+        paths.sort();
+        paths
+    }
+
+    /// The first spooled upload in path order, which is oldest first for time-based paths
+    pub fn next(&self) -> anyhow::Result<Option<Upload>> {
+        let Some(path) = self.paths().into_iter().next() else {
+            return Ok(None);
+        };
+        let jpeg = fs::read(self.dir.join(&path))?;
+        Ok(Some(Upload::new(path, jpeg)))
+    }
+
+    /// Deletes a replayed upload, and any directories that leaves empty
+    pub fn remove(&self, path: &str) -> anyhow::Result<()> {
+        let file = self.dir.join(path);
+        fs::remove_file(&file).with_context(|| format!("Can't delete {}", file.display()))?;
+        let mut dir = file.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != self.dir) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+}
+
+fn collect(root: &Path, dir: &Path, paths: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(root, &path, paths);
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+            paths.push(parts.join("/"));
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    /// Paths are appended to this
+    pub base_url: String,
+    pub template: PathTemplate,
+    pub auth: Auth,
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+    pub queue_len: usize,
+    /// How often to try the spool again while nothing else is going on
+    pub replay_interval: Duration,
+    /// For `{device}`
+    pub device_id: String,
+}
+
+impl UploadConfig {
+    /// A token means bearer auth, a username basic auth
+    pub fn from_settings(settings: &UploadSettings, device_id: &str) -> anyhow::Result<Self> {
+        if !settings.url.starts_with("http://") && !settings.url.starts_with("https://") {
+            anyhow::bail!(
+                "Upload URL has to be http:// or https://, got {:?}",
+                settings.url
+            );
+        }
+        let auth = if !settings.token.is_empty() {
+            Auth::Bearer(settings.token.clone())
+        } else if !settings.username.is_empty() {
+            Auth::Basic {
+                username: settings.username.clone(),
+                password: settings.password.clone(),
+            }
+        } else {
+            Auth::None
+        };
+        Ok(Self {
+            base_url: settings.url.clone(),
+            template: PathTemplate::parse(&settings.path)?,
+            auth,
+            retries: settings.retries,
+            backoff: Duration::from_secs(5),
+            queue_len: settings.queue_len.max(1) as usize,
+            replay_interval: Duration::from_secs(60),
+            device_id: device_id.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    queue: RetryQueue,
+    sending: bool,
+    /// When to try the spool next, `None` while it's known to be empty
+    replay_at: Option<Instant>,
+    uploaded: u64,
+    replayed: u64,
+    spooled: u64,
+    failed: u64,
+    last_upload: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+enum Job {
+    Upload(Upload),
+    Replay,
+}
+
+pub struct Uploader {
+    app: Weak<App>,
+    config: UploadConfig,
+    spool: Option<Spool>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Uploader {
+    /// Starts the upload thread. Without a spool, uploads that can't be sent are dropped.
+    /// Only holds a weak reference to the app.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        config: UploadConfig,
+        mut client: impl HttpClient + 'static,
+        spool: Option<Spool>,
+    ) -> Arc<Self> {
+        let queue = RetryQueue::new(config.queue_len, config.retries, config.backoff);
+        let uploader = Arc::new(Self {
+            app: weak_app,
+            config,
+            // Whatever was left from before the last reboot goes first
+            state: Mutex::new(State {
+                queue,
+                sending: false,
+                replay_at: spool.as_ref().map(|_| Instant::now()),
+                uploaded: 0,
+                replayed: 0,
+                spooled: 0,
+                failed: 0,
+                last_upload: None,
+                last_error: None,
+            }),
+            spool,
+            changed: Condvar::new(),
+        });
+
+        let this = uploader.clone();
+        thread::spawn(move || {
+            while let Some(job) = this.next_job() {
+                match job {
+                    Job::Upload(upload) => {
+                        let result = this.put(&mut client, &upload);
+                        this.uploaded(upload, result);
+                    }
+                    Job::Replay => this.replay(&mut client),
+                }
+            }
+        });
+
+        uploader
+    }
+
+    pub fn config(&self) -> &UploadConfig {
+        &self.config
+    }
+
+    /// Where `frame` would be uploaded, relative to the base URL
+    pub fn path_for(&self, frame: &FrameBuffer) -> String {
+        let camera = self
+            .app
+            .upgrade()
+            .map(|app| app.settings().camera_name)
+            .unwrap_or_default();
+        self.config
+            .template
+            .render(&camera, &self.config.device_id, frame)
+    }
+
+    /// Queues `frame` for upload
+    pub fn upload(&self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        if frame.format != FrameFormat::Jpeg {
+            anyhow::bail!("Can only upload JPEG frames, got {}", frame.format.name());
+        }
+        let upload = Upload::new(self.path_for(frame), frame.data.clone());
+        let evicted = self
+            .state
+            .lock()
+            .unwrap()
+            .queue
+            .push(upload, Instant::now());
+        self.changed.notify_all();
+        if let Some(evicted) = evicted {
+            log::warn!("Upload queue full, setting {} aside", evicted.path);
+            self.give_up(evicted);
+        }
+        Ok(())
+    }
+
+    /// Nothing queued and nothing being sent. The spool may still hold some.
+    pub fn is_idle(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.queue.is_empty() && !state.sending
+    }
+
+    /// Blocks until something is due. `None` once the app is gone.
+    fn next_job(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        state.sending = false;
+        loop {
+            if self.app.strong_count() == 0 {
+                return None;
+            }
+            let now = Instant::now();
+            if let Some(upload) = state.queue.pop_due(now) {
+                state.sending = true;
+                return Some(Job::Upload(upload));
+            }
+            if state.queue.is_empty() && state.replay_at.is_some_and(|at| at <= now) {
+                state.sending = true;
+                return Some(Job::Replay);
+            }
+            let wake = [state.queue.next_due(), state.replay_at]
+                .into_iter()
+                .flatten()
+                .min()
+                .map_or(IDLE_CHECK, |at| at.saturating_duration_since(now))
+                .min(IDLE_CHECK);
+            state = self.changed.wait_timeout(state, wake).unwrap().0;
+        }
+    }
+
+    fn put(&self, client: &mut impl HttpClient, upload: &Upload) -> anyhow::Result<u16> {
+        let mut request = Request::new(Method::Put, url_for(&self.config.base_url, &upload.path))
+            .with_header("Content-Type", "image/jpeg")
+            .with_body(upload.jpeg.clone());
+        if let Some(auth) = self.config.auth.header() {
+            request = request.with_header("Authorization", auth);
+        }
+        client.send(&request)
+    }
+
+    fn uploaded(&self, upload: Upload, result: anyhow::Result<u16>) {
+        match result {
+            Ok(status) if client::is_success(status) => {
+                let mut state = self.state.lock().unwrap();
+                state.uploaded += 1;
+                state.last_upload = Some(clock::now());
+                state.last_error = None;
+                // The server is back, so whatever piled up can go now
+                if self.spool.is_some() && state.replay_at.is_some() {
+                    state.replay_at = Some(Instant::now());
+                }
+            }
+            result => {
+                let error = match &result {
+                    Ok(status) => format!("HTTP {}", status),
+                    Err(e) => e.to_string(),
+                };
+                log::warn!("Upload of {} failed: {}", upload.path, error);
+                let mut state = self.state.lock().unwrap();
+                state.last_error = Some(error);
+                if !client::should_retry(&result) {
+                    state.failed += 1;
+                    return;
+                }
+                let gave_up = state.queue.retry(upload, Instant::now());
+                drop(state);
+                if let Some(upload) = gave_up {
+                    self.give_up(upload);
+                }
+            }
+        }
+    }
+
+    /// Spools an upload that can't be sent now, or drops it without a spool
+    fn give_up(&self, upload: Upload) {
+        let result = match &self.spool {
+            Some(spool) => spool.store(&upload),
+            None => Err(anyhow::anyhow!("No spool, dropped")),
+        };
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(()) => {
+                state.spooled += 1;
+                state
+                    .replay_at
+                    .get_or_insert(Instant::now() + self.config.replay_interval);
+            }
+            Err(e) => {
+                log::warn!("Lost upload {}: {}", upload.path, e);
+                state.failed += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Sends spooled uploads until the spool is empty, one fails, or live ones are waiting
+    fn replay(&self, client: &mut impl HttpClient) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        loop {
+            let upload = match spool.next() {
+                Ok(Some(upload)) => upload,
+                Ok(None) => {
+                    self.state.lock().unwrap().replay_at = None;
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("Can't read the upload spool: {}", e);
+                    self.retry_replay_later(Some(e.to_string()));
+                    return;
+                }
+            };
+            match self.put(client, &upload) {
+                Ok(status) if client::is_success(status) => {
+                    if let Err(e) = spool.remove(&upload.path) {
+                        log::warn!("{}", e);
+                        self.retry_replay_later(Some(e.to_string()));
+                        return;
+                    }
+                    self.state.lock().unwrap().replayed += 1;
+                }
+                Ok(status) => {
+                    self.retry_replay_later(Some(format!("HTTP {}", status)));
+                    return;
+                }
+                Err(e) => {
+                    self.retry_replay_later(Some(e.to_string()));
+                    return;
+                }
+            }
+            if !self.state.lock().unwrap().queue.is_empty() {
+                self.retry_replay_later(None);
+                return;
+            }
+        }
+    }
+
+    fn retry_replay_later(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.replay_at = Some(Instant::now() + self.config.replay_interval);
+        if error.is_some() {
+            state.last_error = error;
+        }
+    }
+
+    /// Queue, spool and counters, as JSON
+    pub fn status_json(&self) -> String {
+        let spooled_now = self.spool.as_ref().map(|spool| spool.paths().len());
+        let state = self.state.lock().unwrap();
+        serde_json::json!({
+            "url": self.config.base_url,
+            "queued": state.queue.len(),
+            "spool": spooled_now,
+            "uploaded": state.uploaded,
+            "replayed": state.replayed,
+            "spooled": state.spooled,
+            "failed": state.failed,
+            "last_upload": state.last_upload.map(clock::format_rfc3339),
+            "last_error": state.last_error,
+        })
+        .to_string()
+    }
+}
+
+/// Uploads everything stored in it, e.g. time-lapse shots or motion snapshots
+pub struct UploadSink(pub Arc<Uploader>);
+
+impl FrameSink for UploadSink {
+    fn store(&mut self, frame: &FrameBuffer) -> anyhow::Result<()> {
+        self.0.upload(frame)
+    }
+}
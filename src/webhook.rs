@@ -0,0 +1,385 @@
+//! Webhooks: POST a JPEG plus JSON metadata to a URL on motion, at boot or on a timer.
+//!
+//! Deliveries wait in a bounded queue (the oldest goes when it's full) and a worker thread
+//! sends them one at a time, retrying server errors with a doubling delay. The body is
+//! either `multipart/form-data` with a `metadata` JSON part and an `image` JPEG part, or
+//! the bare JPEG with the metadata in an `X-Wrover-Metadata` header. With a secret set,
+//! every request carries `X-Wrover-Signature: sha256=<hex HMAC-SHA256 of the body>`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+
+use crate::camera::{FrameBuffer, FrameFormat};
+use crate::client::{self, HttpClient, Method, Request};
+use crate::clock;
+use crate::http::App;
+use crate::settings::WebhookSettings;
+
+pub const SIGNATURE_HEADER: &str = "X-Wrover-Signature";
+pub const METADATA_HEADER: &str = "X-Wrover-Metadata";
+const BOUNDARY: &str = "wrover-webhook-6b1f0c2e9d";
+
+/// How often an idle worker checks whether the app is still there
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// `metadata` and `image` parts
+    Multipart,
+    /// Just the JPEG, metadata in a header
+    Raw,
+}
+
+impl BodyFormat {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "multipart" => Ok(BodyFormat::Multipart),
+            "raw" => Ok(BodyFormat::Raw),
+            _ => anyhow::bail!("Unknown webhook format {:?}, expected multipart or raw", s),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BodyFormat::Multipart => "multipart",
+            BodyFormat::Raw => "raw",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub format: BodyFormat,
+    /// HMAC key, `None` to send unsigned
+    pub secret: Option<Vec<u8>>,
+    pub on_boot: bool,
+    /// Whether `motion::PostOnMotion` posts motion starts
+    pub on_motion: bool,
+    /// `None` for no timer
+    pub interval: Option<Duration>,
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+    pub queue_len: usize,
+    /// Reported in the metadata
+    pub device_id: String,
+}
+
+impl WebhookConfig {
+    pub fn from_settings(settings: &WebhookSettings, device_id: &str) -> anyhow::Result<Self> {
+        if !settings.url.starts_with("http://") && !settings.url.starts_with("https://") {
+            anyhow::bail!(
+                "Webhook URL has to be http:// or https://, got {:?}",
+                settings.url
+            );
+        }
+        Ok(Self {
+            url: settings.url.clone(),
+            format: BodyFormat::parse(&settings.format)?,
+            secret: (!settings.secret.is_empty()).then(|| settings.secret.as_bytes().to_vec()),
+            on_boot: settings.on_boot,
+            on_motion: settings.on_motion,
+            interval: (settings.interval_secs > 0)
+                .then(|| Duration::from_secs(settings.interval_secs as u64)),
+            retries: settings.retries,
+            backoff: Duration::from_secs(2),
+            queue_len: settings.queue_len.max(1) as usize,
+            device_id: device_id.to_owned(),
+        })
+    }
+}
+
+/// One JPEG waiting to go out
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub metadata: Value,
+    pub jpeg: Vec<u8>,
+}
+
+impl Delivery {
+    /// `event` says why (`motion`, `boot`, `timer`), `extra` fields are added to the
+    /// metadata as they are
+    pub fn new(
+        event: &str,
+        camera: &str,
+        device_id: &str,
+        frame: &FrameBuffer,
+        extra: Value,
+    ) -> Self {
+        let mut metadata = serde_json::json!({
+            "event": event,
+            "camera": camera,
+            "device": device_id,
+            "time": frame.wall_time.map(clock::format_rfc3339),
+            "uptime_ms": frame.timestamp.as_millis() as u64,
+            "width": frame.width,
+            "height": frame.height,
+            "bytes": frame.data.len(),
+        });
+        if let (Value::Object(metadata), Value::Object(extra)) = (&mut metadata, extra) {
+            metadata.extend(extra);
+        }
+        Self {
+            metadata,
+            jpeg: frame.data.clone(),
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC of `body`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mac = hmac_sha256::HMAC::mac(body, secret);
+    let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// The request for one delivery, signed if there's a secret
+pub fn build_request(config: &WebhookConfig, delivery: &Delivery) -> Request {
+    let metadata = delivery.metadata.to_string();
+    let request = Request::new(Method::Post, &config.url);
+    let request = match config.format {
+        BodyFormat::Multipart => {
+            let mut body = Vec::with_capacity(delivery.jpeg.len() + metadata.len() + 512);
+            body.extend_from_slice(
+                format!(
+                    "--{b}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\
+                     Content-Type: application/json\r\n\r\n{m}\r\n\
+                     --{b}\r\nContent-Disposition: form-data; name=\"image\"; \
+                     filename=\"snapshot.jpg\"\r\nContent-Type: image/jpeg\r\n\r\n",
+                    b = BOUNDARY,
+                    m = metadata
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&delivery.jpeg);
+            body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+            request
+                .with_header(
+                    "Content-Type",
+                    format!("multipart/form-data; boundary={}", BOUNDARY),
+                )
+                .with_body(body)
+        }
+        BodyFormat::Raw => request
+            .with_header("Content-Type", "image/jpeg")
+            .with_header(METADATA_HEADER, metadata)
+            .with_body(delivery.jpeg.clone()),
+    };
+    match &config.secret {
+        Some(secret) => {
+            let signature = sign(secret, &request.body);
+            request.with_header(SIGNATURE_HEADER, signature)
+        }
+        None => request,
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Delivery>,
+    sending: bool,
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+    last_status: Option<u16>,
+    last_delivery: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+pub struct Webhook {
+    app: Weak<App>,
+    config: Mutex<WebhookConfig>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Webhook {
+    /// Starts the sending thread, plus one for the boot post and the timer if either is
+    /// on. Only holds a weak reference to the app.
+    pub fn spawn(
+        weak_app: Weak<App>,
+        config: WebhookConfig,
+        mut client: impl HttpClient + 'static,
+    ) -> Arc<Self> {
+        let webhook = Arc::new(Self {
+            app: weak_app,
+            config: Mutex::new(config),
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+
+        let this = webhook.clone();
+        thread::spawn(move || {
+            while let Some(delivery) = this.next_delivery() {
+                let result = this.deliver(&mut client, &delivery);
+                this.delivered(result);
+            }
+        });
+
+        let config = webhook.config();
+        if config.on_boot || config.interval.is_some() {
+            let this = webhook.clone();
+            thread::spawn(move || {
+                if config.on_boot {
+                    this.capture("boot");
+                }
+                let Some(interval) = config.interval else {
+                    return;
+                };
+                loop {
+                    thread::sleep(interval);
+                    if this.app.strong_count() == 0 {
+                        break;
+                    }
+                    this.capture("timer");
+                }
+            });
+        }
+
+        webhook
+    }
+
+    pub fn config(&self) -> WebhookConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Sends to `config` from the next delivery on. The boot post and the timer are set up
+    /// once by `spawn`, so `on_boot` and `interval` are left as they were.
+    pub fn configure(&self, config: WebhookConfig) {
+        let mut current = self.config.lock().unwrap();
+        *current = WebhookConfig {
+            on_boot: current.on_boot,
+            interval: current.interval,
+            ..config
+        };
+    }
+
+    /// Queues `frame`, pushing out the oldest delivery if the queue is full
+    pub fn post(&self, event: &str, frame: &FrameBuffer, extra: Value) -> anyhow::Result<()> {
+        if frame.format != FrameFormat::Jpeg {
+            anyhow::bail!("Webhooks take JPEG, not {}", frame.format.name());
+        }
+        let camera = match self.app.upgrade() {
+            Some(app) => app.settings().camera_name,
+            None => anyhow::bail!("Shutting down"),
+        };
+        let config = self.config();
+        let delivery = Delivery::new(event, &camera, &config.device_id, frame, extra);
+
+        let mut state = self.state.lock().unwrap();
+        while state.queue.len() >= config.queue_len {
+            state.queue.pop_front();
+            state.dropped += 1;
+            log::warn!("Webhook queue full, dropped the oldest delivery");
+        }
+        state.queue.push_back(delivery);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Takes a snapshot and queues it
+    pub fn capture(&self, event: &str) {
+        let result = match self.app.upgrade() {
+            Some(app) => app.capture_with(&[]),
+            None => return,
+        };
+        if let Err(e) = result.and_then(|frame| self.post(event, &frame, Value::Null)) {
+            log::warn!("Webhook {} snapshot failed: {}", event, e);
+            self.state.lock().unwrap().last_error = Some(e.to_string());
+        }
+    }
+
+    /// Nothing queued and nothing being sent
+    pub fn is_idle(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.queue.is_empty() && !state.sending
+    }
+
+    /// Blocks until there's something to send. `None` once the app is gone.
+    fn next_delivery(&self) -> Option<Delivery> {
+        let mut state = self.state.lock().unwrap();
+        state.sending = false;
+        loop {
+            if let Some(delivery) = state.queue.pop_front() {
+                state.sending = true;
+                return Some(delivery);
+            }
+            if self.app.strong_count() == 0 {
+                return None;
+            }
+            state = self.changed.wait_timeout(state, IDLE_CHECK).unwrap().0;
+        }
+    }
+
+    /// Tries until it works, the retries run out or the server says no for good
+    fn deliver(&self, client: &mut impl HttpClient, delivery: &Delivery) -> anyhow::Result<u16> {
+        let config = self.config();
+        let request = build_request(&config, delivery);
+        let mut backoff = config.backoff;
+        let mut attempt = 0;
+        loop {
+            let result = client.send(&request);
+            let success = matches!(result, Ok(status) if client::is_success(status));
+            if success || !client::should_retry(&result) || attempt == config.retries {
+                return result;
+            }
+            attempt += 1;
+            match &result {
+                Ok(status) => {
+                    log::warn!("Webhook got {}, retry {} in {:?}", status, attempt, backoff)
+                }
+                Err(e) => log::warn!("Webhook failed: {}, retry {} in {:?}", e, attempt, backoff),
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    fn delivered(&self, result: anyhow::Result<u16>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(status) if client::is_success(status) => {
+                state.delivered += 1;
+                state.last_status = Some(status);
+                state.last_delivery = Some(clock::now());
+                state.last_error = None;
+            }
+            Ok(status) => {
+                log::warn!("Webhook gave up, server said {}", status);
+                state.failed += 1;
+                state.last_status = Some(status);
+                state.last_error = Some(format!("HTTP {}", status));
+            }
+            Err(e) => {
+                log::warn!("Webhook gave up: {}", e);
+                state.failed += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Queue and counters, as JSON
+    pub fn status_json(&self) -> String {
+        let config = self.config();
+        let state = self.state.lock().unwrap();
+        serde_json::json!({
+            "url": config.url,
+            "format": config.format.name(),
+            "signed": config.secret.is_some(),
+            "queued": state.queue.len(),
+            "delivered": state.delivered,
+            "failed": state.failed,
+            "dropped": state.dropped,
+            "last_status": state.last_status,
+            "last_delivery": state.last_delivery.map(clock::format_rfc3339),
+            "last_error": state.last_error,
+        })
+        .to_string()
+    }
+}
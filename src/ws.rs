@@ -0,0 +1,461 @@
+//! `/ws`: the stream over a WebSocket, for browsers and webviews that handle MJPEG badly and
+//! for clients that want metadata with each frame or to send commands back.
+//!
+//! Every frame goes out as a text message with JSON metadata, then a binary message with the
+//! JPEG. Clients can send JSON commands on the same socket:
+//! - `{"cmd":"pause"}`, `{"cmd":"resume"}`
+//! - `{"cmd":"fps","value":5}`: at most this many frames a second for this client, from 0.1
+//!   to 30, or 0 for as many as there are
+//! - `{"cmd":"control","var":"quality","val":10}`: same as `/control`, for admin clients
+//! - `{"cmd":"flash","on":true}`
+//!
+//! and get `{"type":"ok","cmd":...}` or `{"type":"error","cmd":...,"error":...}` back.
+//!
+//! One thread captures for everybody, and each client has a sending thread with room for
+//! one frame. A client that can't keep up gets the newest frame whenever it's ready for
+//! another, the ones in between are dropped (and counted) rather than piling up. The server
+//! adapters only do the handshake and framing: `WsStream::connect` when a client arrives,
+//! `WsClient::receive` for each text message, and drop the `WsClient` when it leaves.
+
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::auth::Scope;
+use crate::battery;
+use crate::camera::{Control, FrameBuffer};
+use crate::clock;
+use crate::http::{self, App};
+use crate::settings::WebSocketSettings;
+
+/// How often idle threads check whether the app is still there
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Replies waiting to go out to one client, the oldest is dropped past this
+const MAX_REPLIES: usize = 8;
+
+/// What gets sent to a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+/// Sends messages to one client. An error means it's gone.
+pub trait WsSender: Send {
+    fn send(&mut self, message: Message<'_>) -> anyhow::Result<()>;
+}
+
+impl<F> WsSender for F
+where
+    F: FnMut(Message<'_>) -> anyhow::Result<()> + Send,
+{
+    fn send(&mut self, message: Message<'_>) -> anyhow::Result<()> {
+        self(message)
+    }
+}
+
+/// Slowest and fastest rates a client can ask for with `fps`
+pub const CLIENT_FPS: RangeInclusive<f32> = 0.1..=30.0;
+
+/// A command from a client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    /// Frames per second for this client, `None` for no limit
+    Fps(Option<f32>),
+    Control(Control, i32),
+    Flash(bool),
+}
+
+impl Command {
+    /// A message from a client, already parsed as JSON
+    pub fn parse(json: &Value) -> anyhow::Result<Self> {
+        match json["cmd"].as_str().unwrap_or_default() {
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "fps" => match json["value"].as_f64().map(|fps| fps as f32) {
+                Some(fps) if CLIENT_FPS.contains(&fps) => Ok(Command::Fps(Some(fps))),
+                Some(0.0) => Ok(Command::Fps(None)),
+                _ => anyhow::bail!(
+                    "Expected a \"value\" of {}-{}, or 0 for no limit",
+                    CLIENT_FPS.start(),
+                    CLIENT_FPS.end()
+                ),
+            },
+            "control" => {
+                let Some(var) = json["var"].as_str() else {
+                    anyhow::bail!("Expected \"var\" and \"val\"");
+                };
+                let control = Control::from_name(var)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown control: {}", var))?;
+                match json["val"].as_i64().and_then(|val| i32::try_from(val).ok()) {
+                    Some(val) => Ok(Command::Control(control, val)),
+                    None => anyhow::bail!("Expected a whole number \"val\""),
+                }
+            }
+            "flash" => match json["on"].as_bool() {
+                Some(on) => Ok(Command::Flash(on)),
+                None => anyhow::bail!("Expected \"on\": true or false"),
+            },
+            "" => anyhow::bail!("Expected a \"cmd\""),
+            other => anyhow::bail!("Unknown command: {}", other),
+        }
+    }
+}
+
+/// The text message sent ahead of each JPEG
+pub fn metadata(frame: &FrameBuffer, seq: u64, dropped: u64) -> String {
+    serde_json::json!({
+        "type": "frame",
+        "seq": seq,
+        "width": frame.width,
+        "height": frame.height,
+        "format": frame.format.name(),
+        "bytes": frame.data.len(),
+        "timestamp": http::timestamp(frame),
+        "time": frame.wall_time.map(clock::format_rfc3339),
+        "dropped": dropped,
+    })
+    .to_string()
+}
+
+#[derive(Clone, Debug)]
+pub struct WsConfig {
+    /// Most frames per second captured for WebSocket clients
+    pub fps: f32,
+    pub max_clients: usize,
+}
+
+impl WsConfig {
+    pub fn from_settings(settings: &WebSocketSettings) -> Self {
+        Self {
+            fps: settings.fps.max(0.1),
+            max_clients: settings.max_clients as usize,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Outbox {
+    replies: VecDeque<String>,
+    /// The newest frame this client hasn't been sent yet
+    frame: Option<(Arc<FrameBuffer>, u64)>,
+    paused: bool,
+    /// Per-client limit from an `fps` command
+    interval: Option<Duration>,
+    last_frame: Option<Instant>,
+    sent: u64,
+    dropped: u64,
+    closed: bool,
+}
+
+struct Client {
+    id: u64,
+    outbox: Mutex<Outbox>,
+    ready: Condvar,
+}
+
+impl Client {
+    /// Takes `frame` unless paused or it's too soon. Replaces (and counts) one that hasn't
+    /// gone out yet.
+    fn offer(&self, frame: &Arc<FrameBuffer>, seq: u64, now: Instant) {
+        let mut outbox = self.outbox.lock().unwrap();
+        if outbox.paused || outbox.closed {
+            return;
+        }
+        if let (Some(interval), Some(last)) = (outbox.interval, outbox.last_frame) {
+            if now < last + interval {
+                return;
+            }
+        }
+        if outbox.frame.replace((frame.clone(), seq)).is_some() {
+            outbox.dropped += 1;
+        }
+        outbox.last_frame = Some(now);
+        self.ready.notify_all();
+    }
+
+    fn wants_frames(&self) -> bool {
+        let outbox = self.outbox.lock().unwrap();
+        !outbox.paused && !outbox.closed
+    }
+
+    fn reply(&self, reply: Value) {
+        let mut outbox = self.outbox.lock().unwrap();
+        if outbox.replies.len() >= MAX_REPLIES {
+            outbox.replies.pop_front();
+        }
+        outbox.replies.push_back(reply.to_string());
+        self.ready.notify_all();
+    }
+}
+
+/// What a sending thread does next
+enum Job {
+    Reply(String),
+    Frame(Arc<FrameBuffer>, u64, u64),
+}
+
+pub struct WsStream {
+    app: Weak<App>,
+    config: WsConfig,
+    clients: Mutex<Vec<Arc<Client>>>,
+    /// Signalled when a client may want frames again
+    wanted: Condvar,
+    next_id: AtomicU64,
+    /// Counts from clients that have left
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl WsStream {
+    /// Starts the capture thread, which idles while nobody is watching. Only holds a weak
+    /// reference to the app.
+    pub fn spawn(weak_app: Weak<App>, config: WsConfig) -> Arc<Self> {
+        let ws = Arc::new(Self {
+            app: weak_app,
+            config,
+            clients: Mutex::new(Vec::new()),
+            wanted: Condvar::new(),
+            next_id: AtomicU64::new(1),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+
+        let this = ws.clone();
+        thread::spawn(move || {
+            let interval = Duration::from_secs_f32(1.0 / this.config.fps);
+            let mut seq = 0;
+            while this.wait_for_viewers() {
+                let started = Instant::now();
+                let Some(app) = this.app.upgrade() else {
+                    break;
+                };
+                let Some(interval) = app.stream_limits().interval(interval) else {
+                    drop(app);
+                    thread::sleep(battery::PAUSED_CHECK);
+                    continue;
+                };
+                let frame = app.stream_frame();
+                drop(app);
+
+                match frame {
+                    Ok(frame) => {
+                        seq += 1;
+                        let frame = Arc::new(frame);
+                        let clients = this.clients.lock().unwrap().clone();
+                        for client in clients {
+                            client.offer(&frame, seq, Instant::now());
+                        }
+                    }
+                    Err(e) => log::warn!("WebSocket capture failed: {}", e),
+                }
+                thread::sleep(interval.saturating_sub(started.elapsed()));
+            }
+        });
+
+        ws
+    }
+
+    /// Blocks until some client wants frames. `false` once the app is gone.
+    fn wait_for_viewers(&self) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        loop {
+            if self.app.strong_count() == 0 {
+                return false;
+            }
+            if clients.iter().any(|client| client.wants_frames()) {
+                return true;
+            }
+            clients = self.wanted.wait_timeout(clients, IDLE_CHECK).unwrap().0;
+        }
+    }
+
+    /// A new client, with its own sending thread. Fails if there are `max_clients` already.
+    pub fn connect(
+        self: &Arc<Self>,
+        mut sender: impl WsSender + 'static,
+    ) -> anyhow::Result<WsClient> {
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            outbox: Mutex::new(Outbox::default()),
+            ready: Condvar::new(),
+        });
+        {
+            let mut clients = self.clients.lock().unwrap();
+            if clients.len() >= self.config.max_clients {
+                anyhow::bail!("Already {} WebSocket clients", clients.len());
+            }
+            clients.push(client.clone());
+        }
+        log::info!("WebSocket client {} connected", client.id);
+        self.wanted.notify_all();
+
+        let this = self.clone();
+        let sending = client.clone();
+        thread::spawn(move || {
+            while let Some(job) = this.next_job(&sending) {
+                let result = match &job {
+                    Job::Reply(reply) => sender.send(Message::Text(reply)),
+                    Job::Frame(frame, seq, dropped) => sender
+                        .send(Message::Text(&metadata(frame, *seq, *dropped)))
+                        .and_then(|()| sender.send(Message::Binary(&frame.data))),
+                };
+                match result {
+                    Ok(()) if matches!(job, Job::Frame(..)) => {
+                        sending.outbox.lock().unwrap().sent += 1
+                    }
+                    Ok(()) => {}
+                    Err(e) => {
+                        log::debug!("WebSocket client {} send failed: {}", sending.id, e);
+                        break;
+                    }
+                }
+            }
+            this.remove(&sending);
+        });
+
+        Ok(WsClient {
+            ws: self.clone(),
+            client,
+            scope: Scope::Read,
+        })
+    }
+
+    /// Blocks until there's something to send. `None` once the client or app is gone.
+    fn next_job(&self, client: &Client) -> Option<Job> {
+        let mut outbox = client.outbox.lock().unwrap();
+        loop {
+            if outbox.closed || self.app.strong_count() == 0 {
+                return None;
+            }
+            if let Some(reply) = outbox.replies.pop_front() {
+                return Some(Job::Reply(reply));
+            }
+            if let Some((frame, seq)) = outbox.frame.take() {
+                return Some(Job::Frame(frame, seq, outbox.dropped));
+            }
+            outbox = client.ready.wait_timeout(outbox, IDLE_CHECK).unwrap().0;
+        }
+    }
+
+    fn remove(&self, client: &Client) {
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+        clients.retain(|c| c.id != client.id);
+        let mut outbox = client.outbox.lock().unwrap();
+        if !outbox.closed {
+            outbox.closed = true;
+            client.ready.notify_all();
+        }
+        if clients.len() < before {
+            self.sent.fetch_add(outbox.sent, Ordering::Relaxed);
+            self.dropped.fetch_add(outbox.dropped, Ordering::Relaxed);
+            log::info!("WebSocket client {} disconnected", client.id);
+        }
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Clients and frame counts, as JSON
+    pub fn status_json(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut sent = self.sent.load(Ordering::Relaxed);
+        let mut dropped = self.dropped.load(Ordering::Relaxed);
+        for client in clients.iter() {
+            let outbox = client.outbox.lock().unwrap();
+            sent += outbox.sent;
+            dropped += outbox.dropped;
+        }
+        serde_json::json!({
+            "clients": clients.len(),
+            "max_clients": self.config.max_clients,
+            "fps": self.config.fps,
+            "sent": sent,
+            "dropped": dropped,
+        })
+        .to_string()
+    }
+}
+
+/// One connected client. Dropping it disconnects it.
+pub struct WsClient {
+    ws: Arc<WsStream>,
+    client: Arc<Client>,
+    scope: Scope,
+}
+
+impl WsClient {
+    pub fn id(&self) -> u64 {
+        self.client.id
+    }
+
+    /// What the client may do, from `App::authorize`. Changing controls or the flash needs
+    /// admin; clients only get read until told otherwise.
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Handles a text message from the client, queueing the reply
+    pub fn receive(&self, text: &str) {
+        let (name, result) = match serde_json::from_str::<Value>(text) {
+            Ok(json) => (
+                json["cmd"].clone(),
+                Command::parse(&json).and_then(|command| self.apply(command)),
+            ),
+            Err(e) => (Value::Null, Err(anyhow::anyhow!("Not JSON: {}", e))),
+        };
+        self.client.reply(match result {
+            Ok(()) => serde_json::json!({ "type": "ok", "cmd": name }),
+            Err(e) => serde_json::json!({ "type": "error", "cmd": name, "error": e.to_string() }),
+        });
+    }
+
+    fn apply(&self, command: Command) -> anyhow::Result<()> {
+        let app = || {
+            self.ws
+                .app
+                .upgrade()
+                .ok_or_else(|| anyhow::anyhow!("Shutting down"))
+        };
+        match command {
+            Command::Pause => {
+                let mut outbox = self.client.outbox.lock().unwrap();
+                outbox.paused = true;
+                outbox.frame = None;
+            }
+            Command::Resume => {
+                self.client.outbox.lock().unwrap().paused = false;
+                self.ws.wanted.notify_all();
+            }
+            Command::Fps(fps) => {
+                let interval = fps
+                    .map(|fps| Duration::try_from_secs_f32(1.0 / fps))
+                    .transpose()?;
+                self.client.outbox.lock().unwrap().interval = interval;
+            }
+            Command::Control(..) | Command::Flash(_) if self.scope < Scope::Admin => {
+                anyhow::bail!("Needs admin access")
+            }
+            Command::Control(control, value) => app()?.set_control(control, value)?,
+            Command::Flash(on) => app()?.switch_flash(on)?,
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WsClient {
+    fn drop(&mut self) {
+        self.ws.remove(&self.client);
+    }
+}